@@ -0,0 +1,154 @@
+//! Benchmarks of the tree's hot paths, run with `cargo bench`.
+//!
+//!   put         writes of keys in order and at random, flushes and compactions included
+//!   get         point lookups of keys spread over 1, 4 and 16 sstables
+//!   scan        full scans, merging several sstables and the memtable
+//!   flush       writing a full memtable to an sstable
+//!   compaction  merging sstables down below the compaction trigger
+//!
+//! Trees live in `test_data/benches`, on the local filesystem, and their data comes from
+//! `testkit::DataGenerator`, so every run writes the same keys and values. Every put syncs the
+//! log, so the trees a benchmark starts from are filled with a `WriteBatch` per memtable instead.
+
+use std::{hint::black_box, path::PathBuf};
+
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use rootconf_25_lsmtree::{LSMTree, Options, WriteBatch, testkit::DataGenerator};
+
+// entries per memtable, and so per sstable written by a flush.
+const MEMTABLE_LIMIT: u64 = 1000;
+const VALUE_SIZE: usize = 100;
+
+// an empty directory named `name` for a tree, removing whatever was left in it.
+fn empty_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from("test_data").join("benches").join(name);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    dir
+}
+
+// options that flush every `MEMTABLE_LIMIT` writes and compact once there are `compaction_trigger`
+// sstables.
+fn options(compaction_trigger: usize) -> Options {
+    Options {
+        memtable_limit: MEMTABLE_LIMIT as usize,
+        compaction_trigger,
+        ..Options::default()
+    }
+}
+
+// a tree in `name` holding `sstables` sstables, none of them compacted. Their keys are interleaved,
+// the `i`th sstable holds every key whose number is `i` modulo `sstables`, so they all span the
+// whole key range while each key is in a single one of them.
+fn tree_with_sstables(name: &str, sstables: u64) -> LSMTree {
+    let mut lsmtree = LSMTree::open_with_options(empty_dir(name), options(1000));
+    let mut generator = DataGenerator::new(sstables, VALUE_SIZE);
+    for i in 0..sstables {
+        let mut batch = WriteBatch::new();
+        for j in 0..MEMTABLE_LIMIT {
+            batch.put(&DataGenerator::key(j * sstables + i), &generator.value());
+        }
+        lsmtree.write_batch(batch).unwrap();
+    }
+    assert_eq!(lsmtree.stats().sstables, sstables as usize);
+    lsmtree
+}
+
+fn put(c: &mut Criterion) {
+    let n = 10 * MEMTABLE_LIMIT;
+    let mut group = c.benchmark_group("put");
+    group.throughput(Throughput::Elements(n));
+    group.sample_size(10);
+    let mut generator = DataGenerator::new(1, VALUE_SIZE);
+    let inputs = [
+        ("sequential", generator.sequential(n)),
+        ("random", generator.random(n, n)),
+    ];
+    for (name, entries) in inputs {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || LSMTree::open_with_options(empty_dir("put"), options(4)),
+                |mut lsmtree| {
+                    for (k, v) in &entries {
+                        lsmtree.put(k, v).unwrap();
+                    }
+                    lsmtree
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for sstables in [1, 4, 16] {
+        let lsmtree = tree_with_sstables(&format!("get{}", sstables), sstables);
+        let mut generator = DataGenerator::new(2, VALUE_SIZE);
+        let keys = sstables * MEMTABLE_LIMIT;
+        group.bench_function(format!("{} sstables", sstables), |b| {
+            b.iter(|| lsmtree.get(&generator.random_key(keys)))
+        });
+    }
+    group.finish();
+}
+
+fn scan(c: &mut Criterion) {
+    let lsmtree = tree_with_sstables("scan", 4);
+    let mut group = c.benchmark_group("scan");
+    group.throughput(Throughput::Elements(lsmtree.scan(..).count() as u64));
+    group.bench_function("full", |b| b.iter(|| black_box(lsmtree.scan(..).count())));
+    group.finish();
+}
+
+fn flush(c: &mut Criterion) {
+    let mut generator = DataGenerator::new(3, VALUE_SIZE);
+    let entries = generator.sequential(MEMTABLE_LIMIT);
+    let (last, rest) = entries.split_last().unwrap();
+    let mut group = c.benchmark_group("flush");
+    group.throughput(Throughput::Elements(MEMTABLE_LIMIT));
+    // the memtable is one write short of full, and the write measured flushes it.
+    group.bench_function("memtable", |b| {
+        b.iter_batched(
+            || {
+                let mut lsmtree = LSMTree::open_with_options(empty_dir("flush"), options(4));
+                let mut batch = WriteBatch::new();
+                for (k, v) in rest {
+                    batch.put(k, v);
+                }
+                lsmtree.write_batch(batch).unwrap();
+                assert_eq!(lsmtree.stats().sstables, 0);
+                lsmtree
+            },
+            |mut lsmtree| {
+                lsmtree.put(&last.0, &last.1).unwrap();
+                lsmtree
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn compaction(c: &mut Criterion) {
+    let sstables = 4;
+    let mut group = c.benchmark_group("compaction");
+    group.throughput(Throughput::Elements(sstables * MEMTABLE_LIMIT));
+    group.sample_size(10);
+    group.bench_function(format!("{} sstables", sstables), |b| {
+        b.iter_batched(
+            || tree_with_sstables("compaction", sstables),
+            |mut lsmtree| {
+                lsmtree.set_option("compaction_trigger", "2").unwrap();
+                lsmtree
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, put, get, scan, flush, compaction);
+criterion_main!(benches);
@@ -0,0 +1,158 @@
+//! Background I/O: flushes and compactions that stay out of the way of foreground reads.
+//!
+//! A compaction reads and writes whole sstables, and while it does, it competes for the disk with
+//! the reads waiting on it, and fills the page cache with blocks that are read once, evicting the
+//! ones reads keep coming back to. Two things keep it in check, both set through `Options`:
+//!
+//! - `background_io_priority` lowers the I/O priority of the thread while it flushes or compacts,
+//!   and puts it back afterwards. The I/O scheduler serves higher priorities first, so the
+//!   background I/O mostly fills the gaps between reads.
+//! - `drop_compaction_inputs_from_cache` tells the OS that the sstables a compaction read won't be
+//!   read again, with `posix_fadvise(DONTNEED)`, so their pages are the first to go.
+//!
+//!   foreground:  get get get .... get get ....... get
+//!   background:     (waits) ====  (waits)  ======       compaction at idle priority
+//!
+//! Both are Linux only, elsewhere they do nothing.
+
+use crate::IoPriority;
+
+// Lowers the I/O priority of the current thread while it's held, see `lower_priority`.
+pub(crate) struct PriorityGuard {
+    // the priority to put back when dropped, `None` if it was never changed.
+    previous: Option<i32>,
+}
+
+// lowers the I/O priority of the current thread to `priority` until the guard returned is dropped.
+// Failing to change it is no reason to fail the flush or compaction, so it's ignored.
+pub(crate) fn lower_priority(priority: IoPriority) -> PriorityGuard {
+    let previous = match priority {
+        IoPriority::Normal => None,
+        _ => sys::set_priority(priority),
+    };
+    PriorityGuard { previous }
+}
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous {
+            sys::restore_priority(previous);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{fs::File, os::fd::AsRawFd};
+
+    use crate::IoPriority;
+
+    // see `ioprio_set(2)`, which libc has no constants for.
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: i32 = 13;
+    const IOPRIO_CLASS_BE: i32 = 2;
+    const IOPRIO_CLASS_IDLE: i32 = 3;
+
+    // sets the I/O priority of the current thread, returning the one it had.
+    pub(super) fn set_priority(priority: IoPriority) -> Option<i32> {
+        let ioprio = match priority {
+            IoPriority::Normal => return None,
+            IoPriority::Low => IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | 7,
+            IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        };
+        let previous = get_priority()?;
+        (set(ioprio) == 0).then_some(previous)
+    }
+
+    pub(super) fn restore_priority(ioprio: i32) {
+        set(ioprio);
+    }
+
+    // the I/O priority of the current thread.
+    pub(super) fn get_priority() -> Option<i32> {
+        // SAFETY: `ioprio_get` only reads its integer arguments, and with `who` 0 it's about the
+        // calling thread.
+        let ioprio = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+        (ioprio >= 0).then_some(ioprio as i32)
+    }
+
+    fn set(ioprio: i32) -> libc::c_long {
+        // SAFETY: as for `ioprio_get`, the arguments are plain integers.
+        unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) }
+    }
+
+    // advises the OS that the data of `file` won't be read again soon.
+    pub(crate) fn drop_from_page_cache(file: &File) {
+        // SAFETY: the descriptor is open for as long as `file` is borrowed.
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::fs::File;
+
+    use crate::IoPriority;
+
+    pub(super) fn set_priority(_priority: IoPriority) -> Option<i32> {
+        None
+    }
+
+    pub(super) fn restore_priority(_ioprio: i32) {}
+
+    pub(crate) fn drop_from_page_cache(_file: &File) {}
+}
+
+pub(crate) use sys::drop_from_page_cache;
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{lower_priority, sys};
+    use crate::{IoPriority, LSMTree, Options};
+
+    #[test]
+    fn test_priority_is_lowered_while_guard_is_held() {
+        let before = sys::get_priority().unwrap();
+        {
+            let _guard = lower_priority(IoPriority::Idle);
+            assert_eq!(sys::get_priority(), Some(3 << 13));
+            {
+                let _guard = lower_priority(IoPriority::Low);
+                assert_eq!(sys::get_priority(), Some(2 << 13 | 7));
+            }
+            assert_eq!(sys::get_priority(), Some(3 << 13));
+        }
+        assert_eq!(sys::get_priority(), Some(before));
+        drop(lower_priority(IoPriority::Normal));
+        assert_eq!(sys::get_priority(), Some(before));
+    }
+
+    #[test]
+    fn test_flush_and_compaction_at_idle_priority() {
+        let dir = PathBuf::from("test_data").join("background_io");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        let before = sys::get_priority().unwrap();
+        let options = Options {
+            memtable_limit: 10,
+            compaction_trigger: 2,
+            background_io_priority: IoPriority::Idle,
+            drop_compaction_inputs_from_cache: true,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(&dir, options);
+        for i in 0..50 {
+            lsmtree
+                .put(&format!("key{:02}", i % 25), &i.to_string())
+                .unwrap();
+        }
+        assert_eq!(lsmtree.stats().sstables, 1);
+        assert_eq!(lsmtree.get("key03"), Some("28".to_string()));
+        assert_eq!(lsmtree.scan(..).count(), 25);
+        // the thread writing to the tree is back to its own priority.
+        assert_eq!(sys::get_priority(), Some(before));
+    }
+}
@@ -0,0 +1,665 @@
+//! Backups of a tree, as checkpoints and single archive files.
+//!
+//! `LSMTree::checkpoint` makes a copy of the tree as of now in a directory of its own, which can be
+//! opened as a tree, e.g. read-only by another process with `LSMTree::open_read_only` while the
+//! tree keeps running. It's cheap: the memtable is flushed, and the sstables and blob files are
+//! hard linked into the checkpoint rather than copied (see `Storage::link`), so the two share the
+//! files' contents until one of them removes its link. That's safe because the tree never modifies
+//! an sstable or blob file once it's written, it only ever removes them, e.g. when a compaction is
+//! done with its inputs, and never reuses a file's id for a new file (see `new_file_id`). A removed
+//! link leaves the file in place for the checkpoint, which gets its own manifest and options.
+//! `LSMTree::clone_to` goes one step further and opens the checkpoint for writes, as a tree of its
+//! own that diverges from the original from then on, e.g. a test copy of production data.
+//!
+//! A data dir holds many files that only make sense together: the manifest, the sstables and blob
+//! files it lists, and the options they were written with. Copying the directory file by file while
+//! the tree runs can catch a compaction halfway, and shipping it to object storage means one upload
+//! per file. `LSMTree::backup_to_archive` instead writes a consistent checkpoint of the tree into a
+//! single tar file:
+//!
+//!   OPTIONS       the options the tree was opened with
+//!   3.sst         every live sstable, hot or cold
+//!   7.sst
+//!   3.blob        every live blob file
+//!   MANIFEST      listing all of the above, written last
+//!
+//! The memtable is flushed first, so the sstables hold every write and the log isn't needed.
+//! Sstables on a cold tier are archived along with the rest, and the archived manifest lists them
+//! as regular sstables, so a restored tree has all its files in its data dir.
+//!
+//! The archive is a plain ustar file, which `tar tf backup.tar` lists and `tar xf` unpacks into a
+//! data dir just as well as `LSMTree::restore_from_archive`. Each file is a 512 byte header with its
+//! name and size in octal, followed by its contents padded to a multiple of 512 bytes, and the
+//! archive ends with two zeroed blocks.
+//!
+//! A backup that turns out broken when it's needed is no backup at all, so
+//! `LSMTree::verify_backup` checks one without unpacking it: the archive is read in place as a
+//! read-only storage, the manifest is parsed and every file it lists must be in the archive. Every
+//! block of every sstable is checked against its checksum, every record is decoded and the number
+//! of entries and deletions is compared with the one recorded in the sstable's footer. Values kept in
+//! blob files are read back and checked against their checksums too.
+//! 💡 RocksDB's `BackupEngine` copies sstables to a backup directory instead, skipping the ones that
+//! an earlier backup already holds, so that each backup only adds the files written since.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    CorruptFile, LSMTree, Options, Value, VerifyReport,
+    blob::{self, blob_path},
+    copy_file,
+    manifest::{MANIFEST_FILE, Manifest},
+    options::OPTIONS_FILE,
+    sstable,
+    storage::{ReadableFile, Storage, WritableFile},
+};
+
+const BLOCK_SIZE: usize = 512;
+
+impl LSMTree {
+    // writes a consistent checkpoint of the tree to a tar archive at `path`, on the tree's storage.
+    // The memtable is flushed to an sstable first.
+    pub fn backup_to_archive(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.flush_memtable();
+        let mgr = &self.sstable_mgr;
+        let storage = &*mgr.storage;
+        let mtime = storage.now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut archive = storage.create(path.as_ref())?;
+
+        let options = mgr.data_dir.join(OPTIONS_FILE);
+        append_file(&mut archive, OPTIONS_FILE, storage, &options, mtime)?;
+        for sst in mgr.sstables.iter() {
+            let name = format!("{}.sst", sst.id);
+            append_file(&mut archive, &name, &*sst.storage, &sst.path, mtime)?;
+        }
+        for blob_file in &mgr.blob_files {
+            let name = format!("{}.blob", blob_file.id);
+            append_file(&mut archive, &name, storage, &blob_file.path, mtime)?;
+        }
+        // the archived sstables all end up in the data dir.
+        let mut manifest = mgr.manifest();
+        manifest.cold_sstables.clear();
+        let mut contents = Vec::new();
+        manifest.write_to(&mut contents)?;
+        append(
+            &mut archive,
+            MANIFEST_FILE,
+            &contents[..],
+            contents.len() as u64,
+            mtime,
+        )?;
+
+        archive.write_all(&[0; 2 * BLOCK_SIZE])?;
+        archive.sync()
+    }
+
+    // writes a checkpoint of the tree to `dir`, on the tree's storage: a data dir of its own, holding
+    // the tree as of now, that can be opened with `open_read_only` or `open_with_options`. The
+    // memtable is flushed to an sstable first. Sstables and blob files are linked, except for the
+    // ones on a cold tier, which are copied over. Fails if `dir` already holds a tree.
+    pub fn checkpoint(&mut self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        self.flush_memtable();
+        let mgr = &self.sstable_mgr;
+        let storage = &*mgr.storage;
+        if storage.exists(&dir.join(MANIFEST_FILE)) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a tree", dir.display()),
+            ));
+        }
+        storage.create_dir_all(dir)?;
+
+        // files left behind by a checkpoint that didn't finish are replaced.
+        let link = |from: &Path, to: PathBuf| {
+            if storage.exists(&to) {
+                storage.remove(&to)?;
+            }
+            storage.link(from, &to)
+        };
+        for sst in mgr.sstables.iter() {
+            let to = dir.join(format!("{}.sst", sst.id));
+            if sst.cold {
+                copy_file(&*sst.storage, &sst.path, storage, &to)?;
+            } else {
+                link(&sst.path, to)?;
+            }
+        }
+        for blob_file in &mgr.blob_files {
+            link(&blob_file.path, blob_path(dir, blob_file.id))?;
+        }
+        let options = dir.join(OPTIONS_FILE);
+        copy_file(storage, &mgr.data_dir.join(OPTIONS_FILE), storage, &options)?;
+
+        // the manifest goes last, once the files it lists are durable.
+        storage.sync_dir(dir)?;
+        let mut manifest = mgr.manifest();
+        manifest.cold_sstables.clear();
+        manifest.save(storage, dir)
+    }
+
+    // checkpoints the tree to `target_dir` (see `checkpoint`) and opens the checkpoint as a tree of
+    // its own, taking writes independently of this one. It's opened with the same options, except
+    // that it has no cold tier and no log archive, which would otherwise be shared with this tree
+    // and mix up the files of both. All its sstables are in its data dir, cold ones were copied
+    // over. Reopening it with a cold tier or log archive of its own is fine.
+    pub fn clone_to(&mut self, target_dir: impl AsRef<Path>) -> io::Result<LSMTree> {
+        self.checkpoint(target_dir.as_ref())?;
+        let options = Options {
+            cold_tier: None,
+            wal_archive: None,
+            ..self.options.clone()
+        };
+        Ok(LSMTree::open_with_options(target_dir, options))
+    }
+
+    // unpacks an archive written by `backup_to_archive` into `data_dir`, to be opened as a tree.
+    // Fails if `data_dir` already holds a tree. The manifest is the last file unpacked, so a restore
+    // that's cut short can simply be run again.
+    pub fn restore_from_archive(
+        storage: &dyn Storage,
+        archive: impl AsRef<Path>,
+        data_dir: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let data_dir = data_dir.as_ref();
+        if storage.exists(&data_dir.join(MANIFEST_FILE)) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a tree", data_dir.display()),
+            ));
+        }
+        storage.create_dir_all(data_dir)?;
+
+        let mut archive = storage.open(archive.as_ref())?;
+        let mut header = [0; BLOCK_SIZE];
+        loop {
+            archive.read_exact(&mut header)?;
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+            let (name, size) = parse_header(&header)?;
+            let mut file = storage.create(&data_dir.join(&name))?;
+            let copied = io::copy(&mut (&mut archive).take(size), &mut file)?;
+            if copied != size {
+                return Err(invalid(format!("{} is cut short", name)));
+            }
+            file.sync()?;
+            let mut padding = [0; BLOCK_SIZE];
+            archive.read_exact(&mut padding[..padded(size) - size as usize])?;
+        }
+        storage.sync_dir(data_dir)
+    }
+
+    // checks an archive written by `backup_to_archive` without unpacking it: that its manifest and
+    // the files it lists are all there, and that every sstable and value in it reads back intact.
+    // Fails if the archive itself or its manifest can't be read, and reports the files that are
+    // missing or corrupt otherwise.
+    pub fn verify_backup(
+        storage: &dyn Storage,
+        archive: impl AsRef<Path>,
+    ) -> io::Result<VerifyReport> {
+        let archive = ArchiveStorage::open(storage, archive.as_ref())?;
+        let root = Path::new("");
+        let manifest = Manifest::load(&archive, root)?
+            .ok_or_else(|| invalid("the archive holds no manifest"))?;
+
+        let mut report = VerifyReport::default();
+        let mut check = |path: PathBuf, verify: &mut dyn FnMut(&Path) -> Result<usize, String>| {
+            report.files_checked += 1;
+            let result = if archive.exists(&path) {
+                verify(&path)
+            } else {
+                Err("missing from the archive".to_string())
+            };
+            match result {
+                Ok(blocks) => report.blocks_checked += blocks,
+                Err(reason) => report.corrupt_files.push(CorruptFile { path, reason }),
+            }
+        };
+
+        for id in &manifest.sstables {
+            check(root.join(format!("{}.sst", id)), &mut |path| {
+                sstable::verify_records(&archive, path, |_, record| match &record.value {
+                    Some(Value::Blob(pointer))
+                        if manifest.blob_files.contains(&pointer.file_id) =>
+                    {
+                        let blob_file = blob_path(root, pointer.file_id);
+                        let value = blob::read_value(&archive, &blob_file, pointer);
+                        value.map(|_| ()).map_err(|e| format!("blob value: {}", e))
+                    }
+                    Some(Value::Blob(pointer)) => Err(format!(
+                        "points to blob file {}, which isn't in the manifest",
+                        pointer.file_id
+                    )),
+                    _ => Ok(()),
+                })
+            });
+        }
+        for id in &manifest.blob_files {
+            // the values were read through the sstables pointing to them.
+            check(blob_path(root, *id), &mut |_| Ok(0));
+        }
+        Ok(report)
+    }
+}
+
+// A backup archive read in place, as a read-only storage holding the archived files.
+#[derive(Debug)]
+struct ArchiveStorage<'a> {
+    storage: &'a dyn Storage,
+    path: PathBuf,
+    // (name, offset of the contents in the archive, size, modification time) of each file.
+    files: Vec<(PathBuf, u64, u64, SystemTime)>,
+}
+
+impl<'a> ArchiveStorage<'a> {
+    // reads the headers of every file in the archive at `path`.
+    fn open(storage: &'a dyn Storage, path: &Path) -> io::Result<Self> {
+        let len = storage.len(path)?;
+        let mut archive = storage.open(path)?;
+        let mut files = Vec::new();
+        let mut header = [0; BLOCK_SIZE];
+        loop {
+            archive.read_exact(&mut header)?;
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+            let (name, size) = parse_header(&header)?;
+            let offset = archive.stream_position()?;
+            if offset + size > len {
+                return Err(invalid(format!("{} is cut short", name)));
+            }
+            let modified = UNIX_EPOCH + Duration::from_secs(octal(&header[136..148])?);
+            files.push((PathBuf::from(name), offset, size, modified));
+            archive.seek(SeekFrom::Start(offset + padded(size) as u64))?;
+        }
+        Ok(ArchiveStorage {
+            storage,
+            path: path.to_path_buf(),
+            files,
+        })
+    }
+
+    fn file(&self, path: &Path) -> io::Result<&(PathBuf, u64, u64, SystemTime)> {
+        let file = self.files.iter().find(|(name, ..)| name == path);
+        file.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.display().to_string()))
+    }
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "backup archives are read-only",
+    )
+}
+
+impl Storage for ArchiveStorage<'_> {
+    fn create(&self, _: &Path) -> io::Result<Box<dyn WritableFile>> {
+        Err(read_only())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>> {
+        let (_, offset, size, _) = *self.file(path)?;
+        let mut file = self.storage.open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(Section {
+            file,
+            start: offset,
+            len: size,
+            pos: 0,
+        }))
+    }
+
+    fn remove(&self, _: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn truncate(&self, _: &Path, _: u64) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn rename(&self, _: &Path, _: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn list(&self, _: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self.files.iter().map(|(name, ..)| name.clone()).collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.file(path).is_ok()
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.file(path)?.2)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        Ok(self.file(path)?.3)
+    }
+
+    fn create_dir_all(&self, _: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn sync_dir(&self, _: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+}
+
+// The contents of one file in an archive, `len` bytes at `start`.
+struct Section {
+    file: Box<dyn ReadableFile>,
+    start: u64,
+    len: u64,
+    // position within the section, which `file` is kept at.
+    pos: u64,
+}
+
+impl Read for Section {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+        let n = self.file.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Section {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        let pos = pos.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.file.seek(SeekFrom::Start(self.start + pos))?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+// appends the file at `path` on `storage` to the archive, under `name`.
+fn append_file(
+    archive: &mut impl Write,
+    name: &str,
+    storage: &dyn Storage,
+    path: &Path,
+    mtime: u64,
+) -> io::Result<()> {
+    let size = storage.len(path)?;
+    append(archive, name, storage.open(path)?, size, mtime)
+}
+
+// appends a file of `size` bytes read from `contents` to the archive, under `name`.
+fn append(
+    archive: &mut impl Write,
+    name: &str,
+    contents: impl Read,
+    size: u64,
+    mtime: u64,
+) -> io::Result<()> {
+    archive.write_all(&header(name, size, mtime))?;
+    let copied = io::copy(&mut contents.take(size), archive)?;
+    if copied != size {
+        return Err(invalid(format!("{} changed while being archived", name)));
+    }
+    archive.write_all(&vec![0; padded(size) - size as usize])
+}
+
+// the ustar header of a regular file.
+fn header(name: &str, size: u64, mtime: u64) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, format!("{:011o}\0", mtime).as_bytes());
+    // the checksum is computed with its own field filled with spaces.
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\x0000");
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+// returns the name and size of the file that `header` starts, rejecting names that would
+// unpack outside of the data dir.
+fn parse_header(header: &[u8; BLOCK_SIZE]) -> io::Result<(String, u64)> {
+    let mut unsummed = *header;
+    unsummed[148..156].fill(b' ');
+    let checksum: u32 = unsummed.iter().map(|b| *b as u32).sum();
+    if octal(&header[148..156])? != checksum as u64 {
+        return Err(invalid("archive header checksum mismatch"));
+    }
+
+    let name = &header[..100];
+    let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+    let name = String::from_utf8(name.to_vec()).map_err(invalid)?;
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(invalid(format!("unexpected file `{}` in archive", name)));
+    }
+    Ok((name, octal(&header[124..136])?))
+}
+
+// parses a numeric header field, octal digits up to a NUL or space.
+fn octal(field: &[u8]) -> io::Result<u64> {
+    let digits = std::str::from_utf8(field).map_err(invalid)?;
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+    u64::from_str_radix(digits, 8).map_err(invalid)
+}
+
+// the size of a file's contents in the archive, padded to whole blocks.
+fn padded(size: u64) -> usize {
+    (size as usize).div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        panic::{AssertUnwindSafe, catch_unwind},
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use super::BLOCK_SIZE;
+    use crate::{
+        ColdTierOptions, LSMTree, Options,
+        sim::SimStorage,
+        storage::{FsStorage, Storage},
+    };
+
+    #[test]
+    fn test_backup_to_archive_and_restore() {
+        let storage = SimStorage::new(1, Default::default());
+        let cold = SimStorage::new(2, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            blob_threshold: Some(16),
+            cold_tier: Some(ColdTierOptions {
+                dir: PathBuf::from("cold"),
+                storage: Arc::new(cold.clone()),
+                cold_after: Duration::from_secs(3600),
+            }),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        lsmtree.put("large", "a value kept in a blob file").unwrap();
+        lsmtree.put("key03", "value3").unwrap();
+        lsmtree.flush_memtable();
+        storage.advance_clock(Duration::from_secs(7200));
+        for i in 0..25 {
+            lsmtree
+                .put(&format!("key{:02}", i), &format!("value{}", i))
+                .unwrap();
+        }
+        lsmtree.delete("key03");
+        // still in the memtable when the backup is taken.
+        lsmtree.put("last", "unflushed").unwrap();
+        assert!(lsmtree.sstable_mgr.sstables[0].cold);
+        lsmtree.backup_to_archive("backup.tar").unwrap();
+        lsmtree.put("after", "not in the backup").unwrap();
+
+        let restored = Path::new("restored");
+        LSMTree::restore_from_archive(&storage, "backup.tar", restored).unwrap();
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let restored_tree = LSMTree::open_with_options(restored, options);
+        assert_eq!(
+            restored_tree.scan(..).collect::<Vec<_>>(),
+            lsmtree
+                .scan(..)
+                .filter(|(k, _)| k != "after")
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(restored_tree.get("key03"), None);
+        assert_eq!(
+            restored_tree.get("large"),
+            Some("a value kept in a blob file".to_string())
+        );
+        assert_eq!(restored_tree.get("last"), Some("unflushed".to_string()));
+
+        // a tree is never restored over another.
+        assert!(LSMTree::restore_from_archive(&storage, "backup.tar", restored).is_err());
+
+        let report = LSMTree::verify_backup(&storage, "backup.tar").unwrap();
+        assert!(
+            report.corrupt_files.is_empty(),
+            "{:?}",
+            report.corrupt_files
+        );
+        let stats = lsmtree.stats();
+        assert_eq!(report.files_checked, stats.sstables + stats.blob_files);
+
+        // a byte flipped in the first sstable is caught by its block checksums.
+        let mut bytes = Vec::new();
+        let mut archive = storage.open(Path::new("backup.tar")).unwrap();
+        archive.read_to_end(&mut bytes).unwrap();
+        let header = bytes.windows(6).position(|w| w == b"1.sst\0").unwrap();
+        bytes[header + BLOCK_SIZE + 10] ^= 0xff;
+        let mut corrupt = storage.create(Path::new("corrupt.tar")).unwrap();
+        corrupt.write_all(&bytes).unwrap();
+        corrupt.sync().unwrap();
+        let report = LSMTree::verify_backup(&storage, "corrupt.tar").unwrap();
+        assert_eq!(report.corrupt_files.len(), 1);
+        assert_eq!(report.corrupt_files[0].path, Path::new("1.sst"));
+
+        // the archive is cut short.
+        let len = storage.len(Path::new("backup.tar")).unwrap();
+        storage
+            .truncate(Path::new("backup.tar"), len - 2000)
+            .unwrap();
+        assert!(LSMTree::restore_from_archive(&storage, "backup.tar", "truncated").is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_opens_read_only_while_source_compacts() {
+        let dir = PathBuf::from("test_data").join("checkpoint_source");
+        let checkpoint = PathBuf::from("test_data").join("checkpoint");
+        for dir in [&dir, &checkpoint] {
+            if dir.exists() {
+                std::fs::remove_dir_all(dir).unwrap();
+            }
+        }
+        let options = Options {
+            memtable_limit: 10,
+            compaction_trigger: 2,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(&dir, options.clone());
+        for i in 0..30 {
+            lsmtree.put(&format!("key{:02}", i), "v1").unwrap();
+        }
+        lsmtree.put("unflushed", "v1").unwrap();
+        lsmtree.checkpoint(&checkpoint).unwrap();
+        let linked: Vec<usize> = lsmtree.sstables().iter().map(|s| s.id).collect();
+
+        let mut snapshot = LSMTree::open_read_only(&checkpoint, options.clone());
+        let files = || {
+            let mut files = FsStorage.list(&checkpoint).unwrap();
+            files.sort();
+            files
+        };
+        let files_before = files();
+
+        // the source keeps going, and compaction removes every file the checkpoint links to.
+        for i in 0..30 {
+            lsmtree.put(&format!("key{:02}", i), "v2").unwrap();
+        }
+        lsmtree.compact_now();
+        let ids: Vec<usize> = lsmtree.sstables().iter().map(|s| s.id).collect();
+        assert!(ids.iter().all(|id| !linked.contains(id)), "{:?}", ids);
+
+        let check = |snapshot: &LSMTree| {
+            assert_eq!(snapshot.get("key07"), Some("v1".to_string()));
+            assert_eq!(snapshot.get("unflushed"), Some("v1".to_string()));
+            assert_eq!(snapshot.scan(..).filter(|(_, v)| v == "v1").count(), 31);
+        };
+        check(&snapshot);
+        let written = catch_unwind(AssertUnwindSafe(|| snapshot.put("key07", "v3")));
+        assert!(written.is_err());
+        snapshot.close().unwrap();
+
+        let snapshot = LSMTree::open_read_only(&checkpoint, options);
+        check(&snapshot);
+        assert_eq!(files(), files_before);
+    }
+
+    #[test]
+    fn test_clone_to_diverges_from_source() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 10,
+            compaction_trigger: 2,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..25 {
+            lsmtree.put(&format!("key{:02}", i), "v1").unwrap();
+        }
+        let mut clone = lsmtree.clone_to("clone").unwrap();
+
+        // both take writes and compact, each removing the links to the files they shared.
+        for i in 0..25 {
+            lsmtree.put(&format!("key{:02}", i), "source").unwrap();
+            clone.delete(&format!("key{:02}", i * 2));
+        }
+        clone.put("new", "only in the clone").unwrap();
+        lsmtree.compact_now();
+        clone.compact_now();
+
+        assert_eq!(lsmtree.get("key04"), Some("source".to_string()));
+        assert_eq!(lsmtree.get("new"), None);
+        assert_eq!(clone.get("key04"), None);
+        assert_eq!(clone.get("key05"), Some("v1".to_string()));
+        assert_eq!(clone.scan(..).count(), 12 + 1);
+        clone.close().unwrap();
+
+        let clone = LSMTree::open_with_options(
+            "clone",
+            Options {
+                storage: Arc::new(storage.clone()),
+                ..Options::default()
+            },
+        );
+        assert_eq!(clone.get("new"), Some("only in the clone".to_string()));
+        assert_eq!(lsmtree.scan(..).count(), 25);
+    }
+}
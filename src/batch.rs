@@ -0,0 +1,244 @@
+//! Write batches: writes staged together and applied to a tree all at once.
+//!
+//! A `WriteBatch` collects puts and deletes, and `LSMTree::write_batch` applies them in order as a
+//! single batch record in the log, so a crash keeps all of them or none, with one sync for all of
+//! them. A `WriteBatchWithIndex` also keeps the newest staged write of every key in a sorted index,
+//! so that reads through it see what was staged on top of what the tree holds:
+//!
+//!   tree:    a=1  b=2  c=3
+//!   batch:        b=5       delete c  put d=4
+//!   get, scan through the batch: a=1  b=5  d=4
+//!
+//! which is what a transaction, or a request handler reading back what it just staged, needs
+//! before any of it is committed.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, btree_map},
+    iter::Peekable,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{Error, LSMTree, ScanIter};
+
+// Writes to apply to a tree all at once, with `LSMTree::write_batch`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    // pairs of a key and its value, or `None` for a delete, in the order they were made.
+    writes: Vec<(String, Option<String>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, k: &str, v: &str) {
+        self.writes.push((k.to_string(), Some(v.to_string())));
+    }
+
+    pub fn delete(&mut self, k: &str) {
+        self.writes.push((k.to_string(), None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+// A `WriteBatch` that can be read through, see `batch.rs`.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatchWithIndex {
+    batch: WriteBatch,
+    // the newest write staged for every key.
+    index: BTreeMap<String, Option<String>>,
+}
+
+impl WriteBatchWithIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, k: &str, v: &str) {
+        self.batch.put(k, v);
+        self.index.insert(k.to_string(), Some(v.to_string()));
+    }
+
+    pub fn delete(&mut self, k: &str) {
+        self.batch.delete(k);
+        self.index.insert(k.to_string(), None);
+    }
+
+    // returns the newest write staged for `k`: `Some(None)` for a delete, `None` if there's none.
+    pub fn get_from_batch(&self, k: &str) -> Option<Option<String>> {
+        self.index.get(k).cloned()
+    }
+
+    // returns the value of `k` in `tree` once the batch is applied to it.
+    pub fn get(&self, tree: &LSMTree, k: &str) -> Option<String> {
+        match self.index.get(k) {
+            Some(staged) => staged.clone(),
+            None => tree.get(k),
+        }
+    }
+
+    // returns an iterator over the live key value pairs within `range` in `tree` once the batch is
+    // applied to it.
+    pub fn scan<'a>(
+        &'a self,
+        tree: &LSMTree,
+        range: impl RangeBounds<&'a str>,
+    ) -> BatchScanIter<'a> {
+        let bounds: (Bound<&str>, Bound<&str>) =
+            (range.start_bound().cloned(), range.end_bound().cloned());
+        BatchScanIter {
+            tree: tree.scan(bounds).peekable(),
+            batch: self.index.range::<str, _>(bounds).peekable(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.batch.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    // the writes staged, in the order they were made.
+    pub fn batch(&self) -> &WriteBatch {
+        &self.batch
+    }
+
+    pub fn into_batch(self) -> WriteBatch {
+        self.batch
+    }
+}
+
+// Iterator returned by `WriteBatchWithIndex::scan`, where the writes of the batch win over the
+// entries of the tree.
+pub struct BatchScanIter<'a> {
+    tree: Peekable<ScanIter>,
+    batch: Peekable<btree_map::Range<'a, String, Option<String>>>,
+}
+
+impl Iterator for BatchScanIter<'_> {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<(String, String)> {
+        loop {
+            let order = match (self.tree.peek(), self.batch.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((tree_key, _)), Some((batch_key, _))) => tree_key.cmp(batch_key),
+            };
+            if order == Ordering::Less {
+                return self.tree.next();
+            }
+            if order == Ordering::Equal {
+                self.tree.next();
+            }
+            // a staged delete hides the key.
+            if let Some((k, Some(v))) = self.batch.next() {
+                return Some((k.clone(), v.clone()));
+            }
+        }
+    }
+}
+
+impl LSMTree {
+    // applies the writes of `batch` in order, atomically, see `batch.rs`. Refuses the whole batch
+    // if a key or value in it is larger than the options allow.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Error> {
+        for (k, v) in &batch.writes {
+            self.check_size(k, v.as_ref().map_or(0, String::len))?;
+        }
+        self.write_group(batch.writes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{WriteBatch, WriteBatchWithIndex};
+    use crate::{Error, LSMTree, Options, sim::SimStorage};
+
+    fn open(storage: &SimStorage) -> LSMTree {
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 10,
+            max_value_size: Some(10),
+            ..Options::default()
+        };
+        LSMTree::open_with_options("data", options)
+    }
+
+    #[test]
+    fn test_write_batch_applies_all_writes_in_order() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lsmtree = open(&storage);
+        let mut batch = WriteBatch::new();
+        batch.put("a", "1");
+        batch.put("b", "1");
+        batch.delete("a");
+        batch.put("c", "1");
+        assert_eq!(batch.len(), 4);
+        lsmtree.write_batch(batch).unwrap();
+        let entries: Vec<(String, String)> = lsmtree.scan(..).collect();
+        assert_eq!(
+            entries,
+            [("b".into(), "1".into()), ("c".into(), "1".into())]
+        );
+
+        let mut batch = WriteBatch::new();
+        batch.put("d", "1");
+        batch.put("e", &"x".repeat(11));
+        let refused = lsmtree.write_batch(batch);
+        assert_eq!(refused, Err(Error::ValueTooLarge { size: 11, max: 10 }));
+        assert_eq!(lsmtree.get("d"), None);
+        drop(lsmtree);
+
+        let lsmtree = open(&storage);
+        assert_eq!(lsmtree.scan(..).count(), 2);
+        assert_eq!(lsmtree.last_seq, 4);
+    }
+
+    #[test]
+    fn test_write_batch_with_index_reads_through_batch() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lsmtree = open(&storage);
+        for i in 0..15 {
+            lsmtree.put(&format!("key{:02}", i), "tree").unwrap();
+        }
+        let mut batch = WriteBatchWithIndex::new();
+        batch.put("key03", "batch");
+        batch.delete("key05");
+        batch.put("key20", "batch");
+        batch.put("key05", "first");
+        batch.delete("key05");
+        assert_eq!(batch.get_from_batch("key05"), Some(None));
+        assert_eq!(batch.get_from_batch("key06"), None);
+        assert_eq!(batch.get(&lsmtree, "key03"), Some("batch".to_string()));
+        assert_eq!(batch.get(&lsmtree, "key04"), Some("tree".to_string()));
+        assert_eq!(batch.get(&lsmtree, "key05"), None);
+        assert_eq!(lsmtree.get("key05"), Some("tree".to_string()));
+
+        let scanned: Vec<(String, String)> = batch.scan(&lsmtree, "key02".."key21").collect();
+        let keys: Vec<&str> = scanned.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys.len(), 13);
+        assert_eq!(&keys[..4], ["key02", "key03", "key04", "key06"]);
+        assert_eq!(scanned[1].1, "batch");
+        assert_eq!(scanned.last().unwrap(), &("key20".into(), "batch".into()));
+
+        let expected: Vec<(String, String)> = batch.scan(&lsmtree, ..).collect();
+        lsmtree.write_batch(batch.into_batch()).unwrap();
+        assert_eq!(lsmtree.scan(..).collect::<Vec<_>>(), expected);
+    }
+}
@@ -0,0 +1,56 @@
+//! Command line tool for maintenance tasks on the data directory of an LSM Tree.
+//!
+//!   lsm-cli migrate <data dir>
+//!       rewrites the sstables written in older on-disk formats, e.g. line based text sstables,
+//!       in the current format, in place.
+//!
+//!   lsm-cli migrate <data dir> --into <new data dir>
+//!       copies the live contents of the tree into a new tree, written in the current format, and
+//!       leaves the original data dir untouched.
+//!
+//!   lsm-cli stats <data dir>
+//!       prints a report of the sstables in the tree, see `LSMTree::stats_report`.
+
+use std::{path::Path, process::ExitCode};
+
+use rootconf_25_lsmtree::LSMTree;
+
+const USAGE: &str = "usage: lsm-cli migrate <data dir> [--into <new data dir>]
+       lsm-cli stats <data dir>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["migrate", dir] => migrate_in_place(Path::new(dir)),
+        ["migrate", dir, "--into", into] => migrate_into(Path::new(dir), Path::new(into)),
+        ["stats", dir] => print!("{}", LSMTree::open(dir).stats_report()),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn migrate_in_place(dir: &Path) {
+    let mut lsmtree = LSMTree::open(dir);
+    let upgraded = lsmtree.upgrade_format();
+    println!("upgraded {} sstables in {}", upgraded, dir.display());
+}
+
+fn migrate_into(dir: &Path, into: &Path) {
+    let lsmtree = LSMTree::open(dir);
+    let mut migrated = LSMTree::open(into);
+    let mut keys = 0;
+    for (k, v) in lsmtree.scan(..) {
+        migrated.put(&k, &v).unwrap();
+        keys += 1;
+    }
+    println!(
+        "copied {} keys from {} to {}",
+        keys,
+        dir.display(),
+        into.display()
+    );
+}
@@ -0,0 +1,226 @@
+//! Crash tester for the tree: kills processes in the middle of writing to a tree, and checks that
+//! reopening the tree recovers every acknowledged write and nothing that was never written.
+//!
+//!   lsm-crashtest <data dir> [--runs <n>] [--seed <seed>]
+//!       starts a child process doing random writes to a new tree in <data dir>, kills it after a
+//!       random time, reopens the tree and verifies it, <n> times over (100 by default). Then runs
+//!       as many simulated crashes with torn writes and I/O errors, see `sim::Simulation`, which
+//!       killing a process on a real filesystem doesn't produce.
+//!
+//!   lsm-crashtest child <data dir> <seed>
+//!       the child process. Prints every write before making it, and `ack` once it returned.
+//!
+//! A write that's acknowledged must survive the kill. The one write the child may have been in the
+//! middle of when it was killed may or may not survive, but nothing else may: every value written
+//! is unique, so an older value coming back, or a value that was never written, is caught.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process::{Command, ExitCode, Stdio},
+    sync::mpsc::channel,
+    thread,
+    time::Duration,
+};
+
+use rootconf_25_lsmtree::{
+    LSMTree, Options,
+    sim::{SimRng, Simulation},
+};
+
+const USAGE: &str = "usage: lsm-crashtest <data dir> [--runs <n>] [--seed <seed>]
+       lsm-crashtest child <data dir> <seed>";
+
+// number of distinct keys written to, few enough for keys to be overwritten and deleted often.
+const KEYS: u64 = 200;
+
+// the newest write of a key, `None` for a delete.
+type PendingWrite = (String, Option<String>);
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args[..] {
+        ["child", dir, seed] => match seed.parse() {
+            Ok(seed) => run_child(Path::new(dir), seed),
+            Err(_) => return usage(),
+        },
+        [dir, ref flags @ ..] => {
+            let (mut runs, mut seed) = (100, 1);
+            for flag in flags.chunks(2) {
+                match flag {
+                    ["--runs", n] if n.parse::<usize>().is_ok() => runs = n.parse().unwrap(),
+                    ["--seed", s] if s.parse::<u64>().is_ok() => seed = s.parse().unwrap(),
+                    _ => return usage(),
+                }
+            }
+            crash_test(Path::new(dir), runs, seed)
+        }
+        _ => return usage(),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::FAILURE
+}
+
+// small memtables and a low compaction trigger, so that kills land in flushes and compactions too.
+fn options() -> Options {
+    Options {
+        memtable_limit: 64,
+        compaction_trigger: 4,
+        blob_threshold: Some(64),
+        ..Options::default()
+    }
+}
+
+fn run_child(dir: &Path, seed: u64) -> Result<(), String> {
+    let mut lsmtree = LSMTree::open_with_options(dir, options());
+    let mut rng = SimRng::new(seed);
+    let mut stdout = std::io::stdout().lock();
+    for i in 0.. {
+        let key = format!("key{:03}", rng.below(KEYS));
+        // every value is unique, and some are long enough to go to blob files.
+        let value = format!("{:x}-{}", seed, i);
+        let value = match rng.below(10) {
+            0 => format!("{}{}", value, ".".repeat(100)),
+            _ => value,
+        };
+        // the line is flushed on the newline, so the parent sees it even if killed right after.
+        if rng.below(5) == 0 {
+            writeln!(stdout, "delete {}", key).map_err(|e| e.to_string())?;
+            lsmtree.delete(&key);
+        } else {
+            writeln!(stdout, "put {} {}", key, value).map_err(|e| e.to_string())?;
+            lsmtree.put(&key, &value).map_err(|e| e.to_string())?;
+        }
+        writeln!(stdout, "ack").map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn crash_test(dir: &Path, runs: usize, seed: u64) -> Result<(), String> {
+    if dir.exists() {
+        return Err(format!("{} already exists, pick a new dir", dir.display()));
+    }
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    let mut rng = SimRng::new(seed);
+    // the newest acknowledged write of every key written so far.
+    let mut acked: BTreeMap<String, Option<String>> = BTreeMap::new();
+
+    for run in 0..runs {
+        let child_seed = rng.next_u64();
+        let mut child = Command::new(&exe)
+            .args(["child", &dir.to_string_lossy(), &child_seed.to_string()])
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        let (sender, lines) = channel();
+        let stdout = child.stdout.take().unwrap();
+        let reader = thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                let _ = sender.send(line);
+            }
+        });
+        thread::sleep(Duration::from_millis(10 + rng.below(300)));
+        child.kill().map_err(|e| e.to_string())?;
+        let status = child.wait().map_err(|e| e.to_string())?;
+        reader.join().unwrap();
+
+        // the child never stops on its own, so it failed if it has an exit code rather than being
+        // killed by a signal.
+        if status.code().is_some() {
+            return Err(format!(
+                "run {} (seed {}): child exited with {}",
+                run, seed, status
+            ));
+        }
+
+        let mut acks = 0;
+        let mut pending: Option<PendingWrite> = None;
+        for line in lines.try_iter() {
+            match line.split(' ').collect::<Vec<_>>()[..] {
+                ["put", key, value] => pending = Some((key.to_string(), Some(value.to_string()))),
+                ["delete", key] => pending = Some((key.to_string(), None)),
+                ["ack"] => {
+                    let (key, value) = pending.take().ok_or("ack without a write")?;
+                    acked.insert(key, value);
+                    acks += 1;
+                }
+                _ => return Err(format!("unexpected line from child: {}", line)),
+            }
+        }
+
+        let keys = verify(dir, &mut acked, pending)
+            .map_err(|e| format!("run {} (seed {}): {}", run, seed, e))?;
+        println!(
+            "run {}: killed after {} writes, verified {} keys",
+            run, acks, keys
+        );
+    }
+
+    for i in 0..runs as u64 {
+        let simulation = Simulation::new(seed.wrapping_add(i));
+        let crashes = simulation.run()?;
+        println!("simulation {}: recovered from {} crashes", i, crashes);
+    }
+    Ok(())
+}
+
+// reopens the tree in `dir` and checks that it holds exactly the acknowledged writes, plus possibly
+// the `pending` write that was made but not acknowledged, which is then taken as acknowledged.
+// Returns the number of live keys.
+fn verify(
+    dir: &Path,
+    acked: &mut BTreeMap<String, Option<String>>,
+    pending: Option<PendingWrite>,
+) -> Result<usize, String> {
+    let lsmtree = LSMTree::open_with_options(dir, options());
+    let scanned: BTreeMap<String, String> = lsmtree.scan(..).collect();
+
+    let mut keys: BTreeSet<&String> = acked.keys().chain(scanned.keys()).collect();
+    if let Some((key, _)) = &pending {
+        keys.insert(key);
+    }
+    let mut pending_applied = false;
+    for key in keys {
+        let found = lsmtree.get(key);
+        if found.as_ref() != scanned.get(key) {
+            return Err(format!(
+                "get({}) returned {:?}, but scan {:?}",
+                key,
+                found,
+                scanned.get(key)
+            ));
+        }
+        let expected = acked.get(key).cloned().flatten();
+        if found == expected {
+            continue;
+        }
+        match &pending {
+            Some((pending_key, value)) if pending_key == key && *value == found => {
+                pending_applied = true;
+            }
+            _ => {
+                return Err(format!(
+                    "{} holds {:?}, expected {:?} (unacknowledged write {:?})",
+                    key, found, expected, pending
+                ));
+            }
+        }
+    }
+    if let (true, Some((key, value))) = (pending_applied, pending) {
+        acked.insert(key, value);
+    }
+    lsmtree.close().map_err(|e| e.to_string())?;
+    Ok(scanned.len())
+}
@@ -0,0 +1,406 @@
+//! Blob files, holding large values outside of the sstables (key-value separation, as in WiscKey).
+//!
+//! When `Options::blob_threshold` is set, a memtable flush appends every value of at least that many
+//! bytes to a blob file with the same id as the sstable being written, and the sstable only stores a
+//! small pointer to it. Compactions then copy pointers instead of values, which cuts down their write
+//! amplification when values make up most of the data. Values written with `LSMTree::put_reader`
+//! are streamed into a blob file of their own right away, so they never have to fit in memory.
+//!
+//! A blob file is a sequence of records, each followed by its checksum and length, so that a value
+//! can be streamed into the file before its size is known:
+//!
+//!   | key: length prefixed | value bytes | crc32 (u32) | payload length (u32) |
+//!
+//! where the payload is the key and the value. A pointer is the id of the blob file along with the
+//! offset and size of a record in it. The key is stored next to the value so that garbage collection
+//! can tell whether a record is still live: it is, if the tree still maps the key to this very
+//! pointer. Values that were overwritten or deleted since leave dead records behind, and
+//! `LSMTree::collect_blob_garbage` rewrites the live records of files that are mostly dead, so that
+//! those files can be removed.
+//! 💡 Titan and RocksDB's BlobDB instead track the garbage of each blob file as compactions drop
+//! pointers to it, so they don't have to look every key up.
+
+use std::{
+    io::{self, Cursor, Read, Seek, SeekFrom, Take, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::{
+    Record, Value,
+    checksum::{Crc32, crc32},
+    storage::{ReadableFile, Storage, WritableFile},
+    varint::{Decoder, MAX_VARINT_LEN, put_length_prefixed, put_varint},
+};
+
+// size of the crc and length after every record.
+const TRAILER_SIZE: usize = 8;
+
+// how much of a streamed value is read into memory at a time.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// Where a value lives in a blob file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlobPointer {
+    pub file_id: usize,
+    // offset and size of the whole record, including its trailer.
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlobPointer {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        put_varint(out, self.file_id as u64);
+        put_varint(out, self.offset);
+        put_varint(out, self.size);
+    }
+
+    pub fn decode(decoder: &mut Decoder) -> Option<Self> {
+        Some(BlobPointer {
+            file_id: decoder.varint()? as usize,
+            offset: decoder.varint()?,
+            size: decoder.varint()?,
+        })
+    }
+}
+
+// A blob file that's part of the tree.
+// Like sstables, it's reference counted, so that iterators can keep reading from a file that garbage
+// collection is done with. It's only removed once the last reference goes away.
+pub(crate) struct BlobFile {
+    pub id: usize,
+    pub path: PathBuf,
+    storage: Arc<dyn Storage>,
+    // set by garbage collection once no live key points into the file anymore.
+    obsolete: AtomicBool,
+}
+
+impl BlobFile {
+    pub fn new(storage: &Arc<dyn Storage>, data_dir: &Path, id: usize) -> Self {
+        BlobFile {
+            id,
+            path: blob_path(data_dir, id),
+            storage: Arc::clone(storage),
+            obsolete: AtomicBool::new(false),
+        }
+    }
+
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    // reads the value that `pointer` points to.
+    pub fn read(&self, pointer: &BlobPointer) -> io::Result<String> {
+        read_value(&*self.storage, &self.path, pointer)
+    }
+
+    // opens a reader over the value that `pointer` points to, which keeps the file alive until
+    // it's dropped.
+    pub fn open_value(self: &Arc<Self>, pointer: &BlobPointer) -> io::Result<ValueReader> {
+        let mut value = BlobValue::open(&*self.storage, &self.path, pointer)?;
+        value.pinned = Some(Arc::clone(self));
+        Ok(ValueReader(ValueSource::Blob(value)))
+    }
+
+    // returns the key of every record in the file, along with a pointer to the record.
+    pub fn records(&self) -> io::Result<Vec<(String, BlobPointer)>> {
+        let mut data = Vec::new();
+        self.storage.open(&self.path)?.read_to_end(&mut data)?;
+
+        // the length of a record is in its trailer, so we walk the file from the end.
+        let mut records = Vec::new();
+        let mut end = data.len();
+        while end > 0 {
+            let decoded = record_size(&data[..end])
+                .and_then(|size| Some((decode_record(&data[end - size..end])?.0, size)));
+            let Some((key, size)) = decoded else {
+                return Err(invalid(format!("corrupt blob record ending at {}", end)));
+            };
+            let pointer = BlobPointer {
+                file_id: self.id,
+                offset: (end - size) as u64,
+                size: size as u64,
+            };
+            records.push((key, pointer));
+            end -= size;
+        }
+        records.reverse();
+        Ok(records)
+    }
+}
+
+impl Drop for BlobFile {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            let _ = self.storage.remove(&self.path);
+            if let Some(dir) = self.path.parent() {
+                let _ = self.storage.sync_dir(dir);
+            }
+        }
+    }
+}
+
+// Moves the large values of the records written to an sstable into a blob file.
+// The blob file is only created along with the first value that goes into it, so flushes of small
+// values don't leave empty blob files behind.
+pub(crate) struct BlobWriter {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    file_id: usize,
+    // values of at least this many bytes are moved to the blob file.
+    threshold: usize,
+    file: Option<Box<dyn WritableFile>>,
+    // number of bytes written to `file` so far.
+    offset: u64,
+}
+
+impl BlobWriter {
+    pub fn new(
+        storage: &Arc<dyn Storage>,
+        data_dir: &Path,
+        file_id: usize,
+        threshold: usize,
+    ) -> Self {
+        BlobWriter {
+            storage: Arc::clone(storage),
+            path: blob_path(data_dir, file_id),
+            file_id,
+            threshold,
+            file: None,
+            offset: 0,
+        }
+    }
+
+    // returns the record to store in the sstable for `key`: either `record` itself, or a copy
+    // pointing to its value in the blob file if the value is large.
+    pub fn separate(&mut self, key: &str, record: &Record) -> io::Result<Record> {
+        let value = match &record.value {
+            Some(Value::Inline(v)) if v.len() >= self.threshold => v,
+            _ => return Ok(record.clone()),
+        };
+
+        let mut encoded = Vec::with_capacity(key.len() + value.len() + TRAILER_SIZE + 4);
+        put_length_prefixed(&mut encoded, key.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+        encoded.extend_from_slice(&crc32(&encoded).to_le_bytes());
+        let payload_len = (encoded.len() - 4) as u32;
+        encoded.extend_from_slice(&payload_len.to_le_bytes());
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(self.storage.create(&self.path)?),
+        };
+        file.write_all(&encoded)?;
+
+        let pointer = BlobPointer {
+            file_id: self.file_id,
+            offset: self.offset,
+            size: encoded.len() as u64,
+        };
+        self.offset += encoded.len() as u64;
+        Ok(Record {
+            seq: record.seq,
+            value: Some(Value::Blob(pointer)),
+        })
+    }
+
+    // syncs the blob file, returning whether any value was moved to it at all.
+    pub fn finish(self) -> io::Result<bool> {
+        match self.file {
+            Some(mut file) => file.sync().map(|_| true),
+            None => Ok(false),
+        }
+    }
+}
+
+// streams `value` into a new blob file at `path` as the only record in it, and syncs the file.
+// Only a chunk of the value is held in memory at a time. Fails if the value isn't valid UTF-8.
+pub(crate) fn write_stream(
+    storage: &dyn Storage,
+    path: &Path,
+    file_id: usize,
+    key: &str,
+    mut value: impl Read,
+) -> io::Result<BlobPointer> {
+    let mut file = storage.create(path)?;
+    let mut crc = Crc32::new();
+    let mut prefix = Vec::new();
+    put_length_prefixed(&mut prefix, key.as_bytes());
+    file.write_all(&prefix)?;
+    crc.update(&prefix);
+    let mut payload_len = prefix.len();
+
+    let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+    // the bytes of a character that was split between two chunks.
+    let mut pending = Vec::new();
+    loop {
+        let n = match value.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        pending.extend_from_slice(&chunk[..n]);
+        let valid = match std::str::from_utf8(&pending) {
+            Ok(_) => pending.len(),
+            // the last character may be completed by the next chunk.
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err(invalid("value is not valid UTF-8")),
+        };
+        file.write_all(&pending[..valid])?;
+        crc.update(&pending[..valid]);
+        payload_len += valid;
+        pending.drain(..valid);
+    }
+    if !pending.is_empty() {
+        return Err(invalid("value is not valid UTF-8"));
+    }
+    let payload_len = u32::try_from(payload_len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "value is too large"))?;
+
+    file.write_all(&crc.finish().to_le_bytes())?;
+    file.write_all(&payload_len.to_le_bytes())?;
+    file.sync()?;
+    Ok(BlobPointer {
+        file_id,
+        offset: 0,
+        size: payload_len as u64 + TRAILER_SIZE as u64,
+    })
+}
+
+// reads the value that `pointer` points to from the blob file at `path`.
+pub(crate) fn read_value(
+    storage: &dyn Storage,
+    path: &Path,
+    pointer: &BlobPointer,
+) -> io::Result<String> {
+    let mut value = String::new();
+    BlobValue::open(storage, path, pointer)?.read_to_string(&mut value)?;
+    Ok(value)
+}
+
+pub(crate) fn blob_path(data_dir: &Path, id: usize) -> PathBuf {
+    data_dir.join(format!("{}.blob", id))
+}
+
+// A reader over a single value, returned by `LSMTree::get_stream`.
+// Values in blob files are read from disk as the reader is consumed, and checked against their
+// checksum once the end is reached. A mismatch is returned as an `InvalidData` error.
+pub struct ValueReader(ValueSource);
+
+enum ValueSource {
+    Inline(Cursor<Vec<u8>>),
+    Blob(BlobValue),
+}
+
+impl ValueReader {
+    pub(crate) fn inline(value: String) -> Self {
+        ValueReader(ValueSource::Inline(Cursor::new(value.into_bytes())))
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            ValueSource::Inline(cursor) => cursor.read(buf),
+            ValueSource::Blob(value) => value.read(buf),
+        }
+    }
+}
+
+// The value bytes of a blob record, checksummed as they are read.
+struct BlobValue {
+    value: Take<Box<dyn ReadableFile>>,
+    crc: Crc32,
+    expected_crc: u32,
+    // the blob file, kept alive while the value is read from it.
+    pinned: Option<Arc<BlobFile>>,
+}
+
+impl BlobValue {
+    fn open(storage: &dyn Storage, path: &Path, pointer: &BlobPointer) -> io::Result<Self> {
+        let corrupt = || invalid(format!("corrupt blob record at offset {}", pointer.offset));
+        let payload_len = pointer
+            .size
+            .checked_sub(TRAILER_SIZE as u64)
+            .ok_or_else(corrupt)?;
+        let mut file = storage.open(path)?;
+
+        let mut trailer = [0u8; TRAILER_SIZE];
+        file.seek(SeekFrom::Start(pointer.offset + payload_len))?;
+        file.read_exact(&mut trailer)?;
+        let expected_crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        if u32::from_le_bytes(trailer[4..].try_into().unwrap()) as u64 != payload_len {
+            return Err(corrupt());
+        }
+
+        // the checksum covers the key in front of the value too.
+        let mut head = vec![0u8; (payload_len as usize).min(MAX_VARINT_LEN)];
+        file.seek(SeekFrom::Start(pointer.offset))?;
+        file.read_exact(&mut head)?;
+        let mut decoder = Decoder::new(&head);
+        let key_len = decoder.varint().ok_or_else(corrupt)?;
+        let key_end = (decoder.position() as u64)
+            .checked_add(key_len)
+            .filter(|end| *end <= payload_len)
+            .ok_or_else(corrupt)?;
+        let mut key = vec![0u8; key_end as usize];
+        file.seek(SeekFrom::Start(pointer.offset))?;
+        file.read_exact(&mut key)?;
+        let mut crc = Crc32::new();
+        crc.update(&key);
+
+        Ok(BlobValue {
+            value: file.take(payload_len - key_end),
+            crc,
+            expected_crc,
+            pinned: None,
+        })
+    }
+}
+
+impl Read for BlobValue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.value.read(buf)?;
+        if n == 0 && self.value.limit() > 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.crc.update(&buf[..n]);
+        if n == 0 && self.crc.finish() != self.expected_crc {
+            return Err(invalid("blob record checksum mismatch"));
+        }
+        Ok(n)
+    }
+}
+
+// returns the size of the record at the end of `data`, trailer included, if it fits in `data`.
+fn record_size(data: &[u8]) -> Option<usize> {
+    let trailer = data.get(data.len().checked_sub(TRAILER_SIZE)?..)?;
+    let len = u32::from_le_bytes(trailer[4..].try_into().unwrap()) as usize;
+    len.checked_add(TRAILER_SIZE)
+        .filter(|size| *size <= data.len())
+}
+
+// decodes the key and value of `record`, if it's intact.
+fn decode_record(record: &[u8]) -> Option<(String, String)> {
+    let (payload, trailer) = record.split_at(record.len().checked_sub(TRAILER_SIZE)?);
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    if crc32(payload) != crc {
+        return None;
+    }
+
+    let mut decoder = Decoder::new(payload);
+    let key = String::from_utf8(decoder.length_prefixed()?.to_vec()).ok()?;
+    let value = String::from_utf8(decoder.remaining().to_vec()).ok()?;
+    Some((key, value))
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
@@ -0,0 +1,267 @@
+//! Blocks are the unit in which sstables are written and read.
+//!
+//! Keys within a block are sorted, so consecutive keys usually share a common prefix. Instead of
+//! writing every key in full, each entry only stores the length of the prefix it shares with the
+//! previous key, followed by the remaining suffix:
+//!
+//!   | shared_len: varint | unshared_len: varint | value_len: varint | key suffix | value |
+//!
+//! To decode a key we need the previous key, which would force a linear scan from the start of the
+//! block. So every `restart_interval` entries we store a full key (shared_len = 0) and remember its
+//! offset as a "restart point". The restart offsets are appended at the end of the block:
+//!
+//!   | entries... | restart offset: u32 | ... | num_restarts: u32 |
+//!
+//! A lookup binary searches the restart points and then scans at most `restart_interval` entries.
+
+use crate::varint::{Decoder, put_varint};
+
+const U32_SIZE: usize = std::mem::size_of::<u32>();
+
+pub(crate) struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    restart_interval: usize,
+    // number of entries written since the last restart point.
+    counter: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    pub fn new(restart_interval: usize) -> Self {
+        BlockBuilder {
+            buf: Vec::new(),
+            restarts: vec![0],
+            restart_interval: restart_interval.max(1),
+            counter: 0,
+            last_key: Vec::new(),
+        }
+    }
+
+    // appends an entry to the block, keys must be added in sorted order.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = if self.counter < self.restart_interval {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            self.restarts.push(self.buf.len() as u32);
+            self.counter = 0;
+            0
+        };
+
+        put_varint(&mut self.buf, shared as u64);
+        put_varint(&mut self.buf, (key.len() - shared) as u64);
+        put_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.counter += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    // size of the block if it was finished now.
+    pub fn estimated_size(&self) -> usize {
+        self.buf.len() + (self.restarts.len() + 1) * U32_SIZE
+    }
+
+    // appends the restart points and returns the encoded block, resetting the builder for reuse.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.buf);
+        for r in &self.restarts {
+            block.extend_from_slice(&r.to_le_bytes());
+        }
+        block.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+
+        self.restarts = vec![0];
+        self.counter = 0;
+        self.last_key.clear();
+        block
+    }
+}
+
+// A decoded block, ready to be searched or iterated.
+pub(crate) struct Block {
+    data: Vec<u8>,
+    // offset where the restart points start, which is also where the entries end.
+    restarts_offset: usize,
+    num_restarts: usize,
+}
+
+impl Block {
+    pub fn new(data: Vec<u8>) -> Self {
+        let num_restarts = read_u32(&data, data.len() - U32_SIZE) as usize;
+        let restarts_offset = data.len() - (num_restarts + 1) * U32_SIZE;
+        Block {
+            data,
+            restarts_offset,
+            num_restarts,
+        }
+    }
+
+    fn restart_point(&self, i: usize) -> usize {
+        read_u32(&self.data, self.restarts_offset + i * U32_SIZE) as usize
+    }
+
+    // size of the block's contents in bytes.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    // returns an iterator over all entries of the block.
+    pub fn iter(&self) -> BlockIter<'_> {
+        BlockIter {
+            block: self,
+            offset: 0,
+            key: Vec::new(),
+        }
+    }
+
+    // returns an iterator positioned at the first entry with a key >= `target`.
+    pub fn seek(&self, target: &[u8]) -> BlockIter<'_> {
+        // find the last restart point whose key is < target. Keys at restart points are stored
+        // in full, so they can be compared without decoding any previous entry.
+        let (mut left, mut right) = (0, self.num_restarts);
+        while right - left > 1 {
+            let mid = (left + right) / 2;
+            let (_, key, _, _) = decode_entry(&self.data, self.restart_point(mid));
+            if key < target {
+                left = mid;
+            } else {
+                right = mid;
+            }
+        }
+
+        let mut iter = BlockIter {
+            block: self,
+            offset: self.restart_point(left),
+            key: Vec::new(),
+        };
+        // scan forward from the restart point, at most `restart_interval` entries.
+        loop {
+            let offset = iter.offset;
+            let key = iter.key.clone();
+            match iter.next() {
+                Some((k, _)) if k.as_slice() < target => continue,
+                Some(_) => {
+                    // rewind so that the entry we just peeked at is returned next.
+                    iter.offset = offset;
+                    iter.key = key;
+                    return iter;
+                }
+                None => return iter,
+            }
+        }
+    }
+}
+
+pub(crate) struct BlockIter<'a> {
+    block: &'a Block,
+    offset: usize,
+    // previously decoded key, the base for prefix compression of the next entry.
+    key: Vec<u8>,
+}
+
+impl Iterator for BlockIter<'_> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.block.restarts_offset {
+            return None;
+        }
+
+        let (shared, suffix, value, len) = decode_entry(&self.block.data, self.offset);
+        self.key.truncate(shared);
+        self.key.extend_from_slice(suffix);
+        self.offset += len;
+
+        Some((self.key.clone(), value.to_vec()))
+    }
+}
+
+// decodes the entry at `offset`, returning the shared prefix length, key suffix, value and the
+// encoded length of the entry.
+fn decode_entry(data: &[u8], offset: usize) -> (usize, &[u8], &[u8], usize) {
+    let mut decoder = Decoder::new(&data[offset..]);
+    let shared = decoder.varint().unwrap() as usize;
+    let unshared = decoder.varint().unwrap() as usize;
+    let value_len = decoder.varint().unwrap() as usize;
+    let suffix = decoder.bytes(unshared).unwrap();
+    let value = decoder.bytes(value_len).unwrap();
+    (shared, suffix, value, decoder.position())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + U32_SIZE].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Block, BlockBuilder};
+
+    fn build_block(keys: &[&str], restart_interval: usize) -> Block {
+        let mut builder = BlockBuilder::new(restart_interval);
+        for k in keys {
+            builder.add(k.as_bytes(), format!("v-{}", k).as_bytes());
+        }
+        Block::new(builder.finish())
+    }
+
+    #[test]
+    fn test_block_roundtrip_with_prefix_compression() {
+        let keys = [
+            "user:1000:email",
+            "user:1000:name",
+            "user:1001:email",
+            "user:1001:name",
+            "user:1002:email",
+        ];
+        let block = build_block(&keys, 2);
+
+        let decoded: Vec<(Vec<u8>, Vec<u8>)> = block.iter().collect();
+        assert_eq!(decoded.len(), keys.len());
+        for ((k, v), expected) in decoded.iter().zip(keys) {
+            assert_eq!(k, expected.as_bytes());
+            assert_eq!(v, format!("v-{}", expected).as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_block_shared_prefixes_shrink_size() {
+        let keys: Vec<String> = (0..100)
+            .map(|i| format!("a/long/common/prefix/{:04}", i))
+            .collect();
+        let mut builder = BlockBuilder::new(16);
+        let mut raw_size = 0;
+        for k in &keys {
+            builder.add(k.as_bytes(), b"");
+            raw_size += k.len();
+        }
+        assert!(builder.finish().len() < raw_size);
+    }
+
+    #[test]
+    fn test_block_seek() {
+        let keys = ["b", "d", "f", "h", "j", "l", "n"];
+        let block = build_block(&keys, 3);
+
+        let first = |target: &str| block.seek(target.as_bytes()).next().map(|(k, _)| k);
+        assert_eq!(first("a"), Some(b"b".to_vec()));
+        assert_eq!(first("d"), Some(b"d".to_vec()));
+        assert_eq!(first("e"), Some(b"f".to_vec()));
+        assert_eq!(first("k"), Some(b"l".to_vec()));
+        assert_eq!(first("n"), Some(b"n".to_vec()));
+        assert_eq!(first("z"), None);
+    }
+}
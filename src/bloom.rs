@@ -0,0 +1,114 @@
+//! Bloom filters, used to skip sstables and blocks that can't contain a key.
+//!
+//! A bloom filter is a bit array where every key sets `k` bits, picked by hashing the key. To check
+//! a key we test the same `k` bits: if any of them is unset the key was definitely never added,
+//! otherwise it *may* have been added. With 10 bits per key and 6 hashes, about 1% of the lookups
+//! for absent keys are false positives, which is far cheaper than reading a block from disk for
+//! every one of them.
+//!
+//! The `k` bits are derived from a single hash of the key by double hashing. The encoded filter is
+//! the bit array followed by a single byte holding `k`.
+
+// Collects the hashes of keys to build a filter from.
+#[derive(Default)]
+pub(crate) struct FilterBuilder {
+    hashes: Vec<u64>,
+}
+
+impl FilterBuilder {
+    pub fn new() -> Self {
+        FilterBuilder::default()
+    }
+
+    pub fn add_key(&mut self, key: &[u8]) {
+        self.hashes.push(hash(key));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    // encodes a filter over the keys added so far and resets the builder.
+    pub fn finish(&mut self, bits_per_key: usize) -> Vec<u8> {
+        // k = ln(2) * bits_per_key minimizes the false positive rate.
+        let k = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        // tiny filters have a high false positive rate, so use at least 64 bits.
+        let bits = (self.hashes.len() * bits_per_key).max(64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+
+        let mut filter = vec![0u8; bytes + 1];
+        for h in self.hashes.drain(..) {
+            for bit in probes(h, k, bits) {
+                filter[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter[bytes] = k as u8;
+        filter
+    }
+}
+
+// returns false if `key` was definitely not added to the filter.
+pub(crate) fn may_contain(filter: &[u8], key: &[u8]) -> bool {
+    let Some((&k, bitmap)) = filter.split_last() else {
+        // an empty filter doesn't tell us anything.
+        return true;
+    };
+    let bits = bitmap.len() * 8;
+    if bits == 0 {
+        return true;
+    }
+    probes(hash(key), k as u32, bits).all(|bit| bitmap[bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+// the bit positions checked for a key with hash `h`.
+fn probes(h: u64, k: u32, bits: usize) -> impl Iterator<Item = usize> {
+    let h1 = h as u32;
+    // an odd step, so that probes don't repeat before covering the whole array.
+    let h2 = ((h >> 32) as u32) | 1;
+    (0..k).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) as usize) % bits)
+}
+
+// 64 bit FNV-1a hash of `key`.
+fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in key {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    // FNV mixes the low bits poorly into the high bits, which we use as the second hash.
+    h ^= h >> 29;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^ (h >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilterBuilder, may_contain};
+
+    #[test]
+    fn test_bloom_filter_has_no_false_negatives() {
+        let mut builder = FilterBuilder::new();
+        for i in 0..1000 {
+            builder.add_key(format!("key{}", i).as_bytes());
+        }
+        let filter = builder.finish(10);
+
+        for i in 0..1000 {
+            assert!(may_contain(&filter, format!("key{}", i).as_bytes()));
+        }
+
+        let false_positives = (1000..11000)
+            .filter(|i| may_contain(&filter, format!("key{}", i).as_bytes()))
+            .count();
+        // expect around 1%, allow some slack.
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_bloom_empty_filter_matches_everything() {
+        assert!(may_contain(&[], b"key"));
+        let filter = FilterBuilder::new().finish(10);
+        assert!(!may_contain(&filter, b"key"));
+    }
+}
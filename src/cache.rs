@@ -0,0 +1,200 @@
+//! A cache of sstable data blocks, shared by every tree it's passed to.
+//!
+//! Reading a block means a read from disk, a checksum and possibly decompressing it, so hot blocks
+//! are kept in memory, decoded, up to a capacity in bytes. When a process opens many trees, e.g. one
+//! per tenant, giving them all the same cache bounds the memory used for blocks across all of them,
+//! and lets busy trees use the room idle ones don't need.
+//!
+//! Blocks are cached under the id of the open sstable they belong to and their offset in the file.
+//! Ids are unique within the process, so trees never see each other's blocks, and a file that's
+//! reopened or moved starts out uncached rather than serving stale blocks.
+//! When the cache is full, the least recently used blocks are evicted first.
+//!
+//! A restarted process starts out with an empty cache, and would serve reads from disk until the
+//! hot blocks are read again. To avoid that, `LSMTree::close` records which of the tree's blocks
+//! are cached in a `HOT_BLOCKS` file in the data dir, one `<sstable id> <block offset>` line per
+//! block, and opening the tree reads those blocks back into the cache. Ids are never reused, so
+//! lines about sstables that were compacted away in the meantime are simply skipped.
+//! 💡 RocksDB's LRU cache is sharded by key hash to reduce lock contention between threads.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::block::Block;
+
+// (sstable cache id, block offset)
+type CacheKey = (u64, u64);
+
+// A handle to a block cache. Clones share the same cache.
+#[derive(Clone)]
+pub struct BlockCache {
+    state: Arc<Mutex<CacheState>>,
+}
+
+struct CacheState {
+    // the total size in bytes of the blocks the cache holds at most.
+    capacity: usize,
+    usage: usize,
+    entries: HashMap<CacheKey, CacheEntry>,
+    // keys by the tick they were last used at, least recently used first.
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    // lookups that found their block in the cache, and ones that didn't.
+    hits: u64,
+    misses: u64,
+}
+
+struct CacheEntry {
+    block: Arc<Block>,
+    last_used: u64,
+}
+
+impl BlockCache {
+    // a cache holding up to `capacity` bytes of blocks.
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            state: Arc::new(Mutex::new(CacheState {
+                capacity,
+                usage: 0,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                hits: 0,
+                misses: 0,
+            })),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.state().capacity
+    }
+
+    // the total size in bytes of the blocks in the cache.
+    pub fn usage(&self) -> usize {
+        self.state().usage
+    }
+
+    // the number of blocks looked up in the cache that were found in it, and that weren't.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        let state = self.state();
+        (state.hits, state.misses)
+    }
+
+    // changes the capacity of the cache, for every tree sharing it. Shrinking it evicts the least
+    // recently used blocks until the rest fit.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut state = self.state();
+        state.capacity = capacity;
+        state.evict(0);
+    }
+
+    // the keys of the cached blocks, least recently used first.
+    pub(crate) fn keys(&self) -> Vec<CacheKey> {
+        self.state().lru.values().copied().collect()
+    }
+
+    pub(crate) fn get(&self, key: CacheKey) -> Option<Arc<Block>> {
+        let mut state = self.state();
+        state.tick += 1;
+        let tick = state.tick;
+        let Some(entry) = state.entries.get_mut(&key) else {
+            state.misses += 1;
+            return None;
+        };
+        let last_used = std::mem::replace(&mut entry.last_used, tick);
+        let block = Arc::clone(&entry.block);
+        state.lru.remove(&last_used);
+        state.lru.insert(tick, key);
+        state.hits += 1;
+        Some(block)
+    }
+
+    // adds `block` to the cache, evicting the least recently used blocks to make room for it.
+    // Blocks larger than the whole cache aren't cached at all.
+    pub(crate) fn insert(&self, key: CacheKey, block: Arc<Block>) {
+        let mut state = self.state();
+        let size = block.size();
+        if size > state.capacity {
+            return;
+        }
+        if let Some(old) = state.entries.remove(&key) {
+            state.usage -= old.block.size();
+            state.lru.remove(&old.last_used);
+        }
+        state.evict(size);
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.usage += size;
+        state.lru.insert(tick, key);
+        state.entries.insert(
+            key,
+            CacheEntry {
+                block,
+                last_used: tick,
+            },
+        );
+    }
+
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl CacheState {
+    // evicts the least recently used blocks until there's room for `size` more bytes.
+    fn evict(&mut self, size: usize) {
+        while self.usage + size > self.capacity {
+            let (_, evicted) = self.lru.pop_first().unwrap();
+            let entry = self.entries.remove(&evicted).unwrap();
+            self.usage -= entry.block.size();
+        }
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity())
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::BlockCache;
+    use crate::block::{Block, BlockBuilder};
+
+    fn block(value: &str) -> Arc<Block> {
+        let mut builder = BlockBuilder::new(16);
+        builder.add(b"key", value.as_bytes());
+        Arc::new(Block::new(builder.finish()))
+    }
+
+    #[test]
+    fn test_block_cache_evicts_least_recently_used() {
+        let size = block("v1").size();
+        let cache = BlockCache::new(size * 2);
+        cache.insert((1, 0), block("v1"));
+        cache.insert((1, 100), block("v2"));
+        assert!(cache.get((1, 0)).is_some());
+
+        // the block at offset 100 was used least recently, so it makes room for the new one.
+        cache.clone().insert((2, 0), block("v3"));
+        assert!(cache.get((1, 100)).is_none());
+        assert!(cache.get((1, 0)).is_some() && cache.get((2, 0)).is_some());
+        assert_eq!(cache.usage(), size * 2);
+        assert_eq!(cache.hits_and_misses(), (3, 1));
+
+        assert_eq!(cache.keys(), vec![(1, 0), (2, 0)]);
+
+        cache.insert((3, 0), block(&"v".repeat(size * 2)));
+        assert!(cache.get((3, 0)).is_none());
+    }
+}
@@ -0,0 +1,90 @@
+//! CRC32 checksums, used to detect corrupted blocks and records.
+//!
+//! A checksum is computed over the bytes when they are written and stored next to them. When the
+//! bytes are read back, a mismatch between the stored and the recomputed checksum tells us that the
+//! data was damaged on disk (bit rot, torn writes, a bad copy) instead of silently serving garbage.
+//! 💡 LevelDB and RocksDB use the CRC32C (Castagnoli) variant, here we use the common IEEE polynomial.
+
+const POLYNOMIAL: u32 = 0xedb8_8320;
+// the Castagnoli polynomial of CRC32C, only used to read tables written by RocksDB and LevelDB.
+const CASTAGNOLI: u32 = 0x82f6_3b78;
+
+// lookup table with the checksum of every possible byte, built at compile time.
+const TABLE: [u32; 256] = table(POLYNOMIAL);
+const CASTAGNOLI_TABLE: [u32; 256] = table(CASTAGNOLI);
+
+const fn table(polynomial: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ polynomial
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+// the CRC32C checksum of the pieces of `data`, computed as one.
+pub(crate) fn crc32c(data: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for b in data.iter().flat_map(|piece| piece.iter()) {
+        crc = CASTAGNOLI_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// Computes the checksum of data that comes in pieces, e.g. a value streamed into a file. The result
+// is the same as `crc32` over all the pieces at once.
+#[derive(Debug, Clone)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(!0u32)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.0 = TABLE[((self.0 ^ *b as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crc32, crc32, crc32c};
+
+    #[test]
+    fn test_crc32() {
+        // the standard check value for CRC32 (IEEE).
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_ne!(crc32(b"hello world"), crc32(b"hello worle"));
+
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+
+        // and for CRC32C.
+        assert_eq!(crc32c(&[b"1234", b"56789"]), 0xe306_9283);
+    }
+}
@@ -0,0 +1,74 @@
+//! The clock the tree reads the time from: for sstable TTLs, FIFO compaction by age, compaction
+//! schedules, history retention, the audit log, moving sstables to a cold tier and the retention of
+//! archived log segments.
+//!
+//! By default that's the clock of the tree's storage, see `Storage::now`, which is the system clock
+//! for files on the local filesystem and the simulated clock of a `SimStorage`. `Options::clock`
+//! replaces it, e.g. with a `MockClock` that a test moves forward by hand instead of sleeping.
+//! Ages are never measured from the modification times of files, which come from the storage's
+//! clock: sstables record when they were created, and archived log segments when they were
+//! archived, see `wal.rs`. Only files written before that go by their modification time, along
+//! with the storage's clock.
+
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::storage::Storage;
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+// The system's wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// A clock that stands still until it's moved. Clones share the same time, so a test can keep one
+// and pass another to the tree.
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<Mutex<SystemTime>>);
+
+impl MockClock {
+    pub fn new(start: SystemTime) -> Self {
+        MockClock(Arc::new(Mutex::new(start)))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+
+    pub fn set(&self, time: SystemTime) {
+        *self.0.lock().unwrap() = time;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+// The clock of a storage, used when `Options::clock` isn't set.
+#[derive(Debug)]
+pub(crate) struct StorageClock(pub Arc<dyn Storage>);
+
+impl Clock for StorageClock {
+    fn now(&self) -> SystemTime {
+        self.0.now()
+    }
+}
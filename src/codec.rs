@@ -0,0 +1,381 @@
+//! Record codecs: how records, i.e. a sequence number and a value or a deletion, are turned into
+//! bytes and back, on their own or along with their key.
+//!
+//! A data block entry maps a key to its encoded record, and the block, the index and the footer
+//! around it don't care how the record is encoded. That's left to a `RecordCodec`, so that a new
+//! encoding is a new codec rather than changes all over the writer and the readers. The tree
+//! writes with the codec of `Options::record_format`: flushes and compactions through
+//! `TableOptions`, with its id in the footer of every sstable, and the log, with its id in the
+//! header of every segment, so every file is read with its own whatever the tree is opened with.
+//! Sstables written before the footer held the id are in `BinaryCodec`, or in `KindlessCodec` for
+//! the first block based ones, and log segments without a header in `BinaryCodec`. The log has no
+//! keys apart from its records, and encodes whole entries instead:
+//!
+//!   BinaryCodec     record: | seq: varint | kind: u8 | payload |
+//!                   entry:  | seq: varint | key: length prefixed | kind: u8 | payload |
+//!   TextCodec       record: <seq>:<value>   <seq>🪦   <seq>@<file id>:<offset>:<size>
+//!                   entry:  <key length>:<key><record>
+//!   KindlessCodec   record: | seq: varint | value bytes |
+//!
+//! The payload is the value bytes, length prefixed in entries, a blob pointer, or nothing for a
+//! deletion. `TextCodec` stores the same in decimal and UTF-8, a value after a `:`, a deletion as
+//! `🪦` and a blob pointer after an `@`, for files that can be read with a text editor, at the
+//! cost of larger records. Entries never start with a 0 byte, which the log starts batches with,
+//! see `wal.rs`: `BinaryCodec` ones start with the sequence number, which is never 0, and
+//! `TextCodec` ones with a digit.
+//!
+//! The line based text sstables written before sstables had blocks hold one `key:value` entry per
+//! line, without sequence numbers, and with a value of `🪦` for a deletion, see `decode_line`.
+//! `KindlessCodec`, the format of the first block based files, kept those values as they were,
+//! deletion marker included.
+//! 💡 RocksDB packs the sequence number and the kind of a record into an 8 byte trailer of the
+//! key instead, so that versions of a key sort by sequence number within a block.
+
+use std::fmt;
+
+use crate::{
+    Record, TOMBSTONE_MARKER, Value,
+    blob::BlobPointer,
+    varint::{Decoder, put_length_prefixed, put_varint},
+};
+
+// kinds of the records in `BinaryCodec` records and entries.
+const DELETION: u8 = 0;
+const VALUE: u8 = 1;
+const BLOB_POINTER: u8 = 2;
+
+// The encoding of the records the tree writes, see `codec.rs`. Files are read with the codec they
+// were written with, so it can change from one open to the next.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordFormat {
+    // compact binary records, see `BinaryCodec`.
+    #[default]
+    Binary,
+    // records in plain text, see `TextCodec`.
+    Text,
+}
+
+impl RecordFormat {
+    pub(crate) fn codec(self) -> &'static dyn RecordCodec {
+        match self {
+            RecordFormat::Binary => &BinaryCodec,
+            RecordFormat::Text => &TextCodec,
+        }
+    }
+}
+
+// Encodes records, or whole entries, into bytes and decodes them back.
+pub(crate) trait RecordCodec: Send + Sync + fmt::Debug {
+    // the id recorded in the footer of the sstables and the header of the log segments whose
+    // records are in this codec.
+    fn id(&self) -> u8;
+
+    // appends the encoding of `record` to `out`.
+    fn encode(&self, record: &Record, out: &mut Vec<u8>);
+
+    // decodes a record, returning why it can't be decoded if it's malformed.
+    fn decode(&self, encoded: &[u8]) -> Result<Record, String>;
+
+    // appends the encoding of `key` along with its record to `out`, by default the length prefixed
+    // key followed by the record. It must not start with a 0 byte, see `codec.rs`.
+    fn encode_entry(&self, key: &str, record: &Record, out: &mut Vec<u8>) {
+        put_length_prefixed(out, key.as_bytes());
+        self.encode(record, out);
+    }
+
+    // decodes an entry encoded by `encode_entry`, returning why it can't be decoded if it's
+    // malformed.
+    fn decode_entry(&self, encoded: &[u8]) -> Result<(String, Record), String> {
+        let mut decoder = Decoder::new(encoded);
+        let key = decoder.length_prefixed().ok_or("malformed key")?;
+        Ok((utf8(key)?, self.decode(decoder.remaining())?))
+    }
+}
+
+// the codec with the given id, `None` if there's none.
+pub(crate) fn codec(id: u8) -> Option<&'static dyn RecordCodec> {
+    [&KindlessCodec as &dyn RecordCodec, &BinaryCodec, &TextCodec]
+        .into_iter()
+        .find(|codec| codec.id() == id)
+}
+
+// The encoding of the records of current sstables and of the log, in which any value can be
+// stored.
+#[derive(Debug)]
+pub(crate) struct BinaryCodec;
+
+impl BinaryCodec {
+    // appends the kind and the payload of `value`, with inline values length prefixed if they're
+    // followed by something else.
+    fn encode_value(value: &Option<Value>, prefixed: bool, out: &mut Vec<u8>) {
+        match value {
+            Some(Value::Inline(v)) if prefixed => {
+                out.push(VALUE);
+                put_length_prefixed(out, v.as_bytes());
+            }
+            Some(Value::Inline(v)) => {
+                out.push(VALUE);
+                out.extend_from_slice(v.as_bytes());
+            }
+            Some(Value::Blob(pointer)) => {
+                out.push(BLOB_POINTER);
+                pointer.encode(out);
+            }
+            None => out.push(DELETION),
+        }
+    }
+
+    fn decode_value(decoder: &mut Decoder, prefixed: bool) -> Result<Option<Value>, String> {
+        let value = match decoder.bytes(1).ok_or("missing record kind")?[0] {
+            DELETION => None,
+            VALUE if prefixed => {
+                let v = decoder.length_prefixed().ok_or("malformed value")?;
+                Some(Value::Inline(utf8(v)?))
+            }
+            VALUE => Some(Value::Inline(utf8(decoder.remaining())?)),
+            BLOB_POINTER => Some(Value::Blob(
+                BlobPointer::decode(decoder).ok_or("malformed blob pointer")?,
+            )),
+            kind => return Err(format!("unknown record kind {}", kind)),
+        };
+        Ok(value)
+    }
+}
+
+impl RecordCodec for BinaryCodec {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn encode(&self, record: &Record, out: &mut Vec<u8>) {
+        put_varint(out, record.seq);
+        Self::encode_value(&record.value, false, out);
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Record, String> {
+        let mut decoder = Decoder::new(encoded);
+        let seq = decoder.varint().ok_or("malformed sequence number")?;
+        let value = Self::decode_value(&mut decoder, false)?;
+        Ok(Record { seq, value })
+    }
+
+    fn encode_entry(&self, key: &str, record: &Record, out: &mut Vec<u8>) {
+        put_varint(out, record.seq);
+        put_length_prefixed(out, key.as_bytes());
+        Self::encode_value(&record.value, true, out);
+    }
+
+    fn decode_entry(&self, encoded: &[u8]) -> Result<(String, Record), String> {
+        let mut decoder = Decoder::new(encoded);
+        let seq = decoder.varint().ok_or("malformed sequence number")?;
+        let key = utf8(decoder.length_prefixed().ok_or("malformed key")?)?;
+        let value = Self::decode_value(&mut decoder, true)?;
+        Ok((key, Record { seq, value }))
+    }
+}
+
+// The encoding of the records of block based sstables written before records had a kind: the
+// sequence number followed by the value as text sstable lines hold it.
+#[derive(Debug)]
+pub(crate) struct KindlessCodec;
+
+impl RecordCodec for KindlessCodec {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn encode(&self, record: &Record, out: &mut Vec<u8>) {
+        put_varint(out, record.seq);
+        match &record.value {
+            Some(Value::Inline(v)) => out.extend_from_slice(v.as_bytes()),
+            Some(Value::Blob(_)) => panic!("blob pointers can't be encoded without a kind"),
+            None => out.extend_from_slice(TOMBSTONE_MARKER.to_string().as_bytes()),
+        }
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Record, String> {
+        let mut decoder = Decoder::new(encoded);
+        let seq = decoder.varint().ok_or("malformed sequence number")?;
+        let value = line_value(utf8(decoder.remaining())?);
+        Ok(Record { seq, value })
+    }
+}
+
+// Records in plain text, with the same contents as `BinaryCodec` ones, see `codec.rs`.
+#[derive(Debug)]
+pub(crate) struct TextCodec;
+
+impl RecordCodec for TextCodec {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn encode(&self, record: &Record, out: &mut Vec<u8>) {
+        let encoded = match &record.value {
+            Some(Value::Inline(v)) => format!("{}:{}", record.seq, v),
+            Some(Value::Blob(p)) => format!("{}@{}:{}:{}", record.seq, p.file_id, p.offset, p.size),
+            None => format!("{}{}", record.seq, TOMBSTONE_MARKER),
+        };
+        out.extend_from_slice(encoded.as_bytes());
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Record, String> {
+        let encoded = utf8(encoded)?;
+        let digits = encoded
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(encoded.len());
+        let (seq, rest) = encoded.split_at(digits);
+        let seq = seq.parse().map_err(|_| "malformed sequence number")?;
+        let value = if let Some(v) = rest.strip_prefix(':') {
+            Some(Value::Inline(v.to_string()))
+        } else if let Some(p) = rest.strip_prefix('@') {
+            let mut fields = p.split(':').map(str::parse::<u64>);
+            let mut field = || {
+                fields
+                    .next()
+                    .and_then(Result::ok)
+                    .ok_or("malformed blob pointer")
+            };
+            Some(Value::Blob(BlobPointer {
+                file_id: field()? as usize,
+                offset: field()?,
+                size: field()?,
+            }))
+        } else if rest == TOMBSTONE_MARKER.to_string() {
+            None
+        } else {
+            return Err(format!("unknown record `{}`", rest));
+        };
+        Ok(Record { seq, value })
+    }
+
+    fn encode_entry(&self, key: &str, record: &Record, out: &mut Vec<u8>) {
+        out.extend_from_slice(format!("{}:{}", key.len(), key).as_bytes());
+        self.encode(record, out);
+    }
+
+    fn decode_entry(&self, encoded: &[u8]) -> Result<(String, Record), String> {
+        let colon = (encoded.iter().position(|b| *b == b':')).ok_or("missing key length")?;
+        let len: usize = (utf8(&encoded[..colon])?.parse()).map_err(|_| "malformed key length")?;
+        let key = encoded
+            .get(colon + 1..colon + 1 + len)
+            .ok_or("malformed key")?;
+        Ok((utf8(key)?, self.decode(&encoded[colon + 1 + len..])?))
+    }
+}
+
+// decodes a line of a line based text sstable, see `codec.rs`. A value ends at the next `:`, as
+// the text files have always been read.
+pub(crate) fn decode_line(line: &[u8]) -> Result<(String, Record), String> {
+    let line = utf8(line)?;
+    let mut kv = line.split(':');
+    let k = kv.next().unwrap();
+    let v = kv.next().ok_or("missing `:`")?;
+    let value = line_value(v.to_string());
+    Ok((k.to_string(), Record { seq: 0, value }))
+}
+
+// the value of a text sstable line or a `KindlessCodec` record, where `🪦` stands for a deletion.
+fn line_value(v: String) -> Option<Value> {
+    (v != TOMBSTONE_MARKER.to_string()).then_some(Value::Inline(v))
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BinaryCodec, KindlessCodec, RecordCodec, TextCodec, codec, decode_line};
+    use crate::{Record, Value, blob::BlobPointer};
+
+    #[test]
+    fn test_codecs_round_trip_what_they_can_store() {
+        let record = |seq, value: Option<Value>| Record { seq, value };
+        let inline = |v: &str| Some(Value::Inline(v.to_string()));
+        let pointer = BlobPointer {
+            file_id: 3,
+            offset: 100,
+            size: 4000,
+        };
+        let binary = [
+            record(7, inline("value")),
+            record(300, None),
+            record(1, Some(Value::Blob(pointer))),
+            record(2, inline("🪦")),
+            record(0, inline("")),
+        ];
+        for r in &binary {
+            for codec in [&BinaryCodec as &dyn RecordCodec, &TextCodec] {
+                let mut encoded = Vec::new();
+                codec.encode(r, &mut encoded);
+                assert_eq!(codec.decode(&encoded).as_ref(), Ok(r));
+            }
+        }
+        let mut encoded = Vec::new();
+        TextCodec.encode(&record(1, Some(Value::Blob(pointer))), &mut encoded);
+        assert_eq!(encoded, b"1@3:100:4000");
+
+        let kindless = [record(7, inline("value")), record(300, None)];
+        for r in &kindless {
+            let mut encoded = Vec::new();
+            KindlessCodec.encode(r, &mut encoded);
+            assert_eq!(KindlessCodec.decode(&encoded).as_ref(), Ok(r));
+        }
+        // a value that looks like the marker reads back as a deletion.
+        let mut encoded = Vec::new();
+        KindlessCodec.encode(&record(2, inline("🪦")), &mut encoded);
+        assert_eq!(KindlessCodec.decode(&encoded), Ok(record(2, None)));
+
+        assert!(BinaryCodec.decode(&[1, 9]).is_err());
+        assert!(BinaryCodec.decode(&[1]).is_err());
+        assert!(TextCodec.decode(b"7").is_err());
+        assert!(TextCodec.decode(b"7@3:100").is_err());
+        for id in 0..3 {
+            assert_eq!(codec(id).unwrap().id(), id);
+        }
+        assert!(codec(3).is_none());
+    }
+
+    #[test]
+    fn test_codecs_round_trip_entries() {
+        let record = |seq, value: Option<Value>| Record { seq, value };
+        let inline = |v: &str| Some(Value::Inline(v.to_string()));
+        let entries = [
+            ("a", record(7, inline("value"))),
+            ("", record(300, None)),
+            ("c", record(1, inline(""))),
+            ("key:with:colons", record(2, inline("value:with:colons"))),
+        ];
+        for (key, r) in &entries {
+            for codec in [&BinaryCodec as &dyn RecordCodec, &KindlessCodec, &TextCodec] {
+                let mut encoded = Vec::new();
+                codec.encode_entry(key, r, &mut encoded);
+                assert_eq!(
+                    codec.decode_entry(&encoded),
+                    Ok((key.to_string(), r.clone()))
+                );
+            }
+        }
+        // the key comes right after the sequence number, which the log tells batches apart by.
+        let mut encoded = Vec::new();
+        BinaryCodec.encode_entry("k", &record(5, inline("v")), &mut encoded);
+        assert_eq!(encoded, [5, 1, b'k', 1, 1, b'v']);
+
+        let mut encoded = Vec::new();
+        TextCodec.encode_entry("ab", &record(7, inline("1")), &mut encoded);
+        assert_eq!(encoded, b"2:ab7:1");
+        assert!(TextCodec.decode_entry(b"3:ab7:1").is_err());
+
+        // text sstable lines are `key:value`, without sequence numbers.
+        assert_eq!(
+            decode_line(b"a:1"),
+            Ok(("a".into(), record(0, inline("1"))))
+        );
+        assert_eq!(
+            decode_line("b:🪦".as_bytes()),
+            Ok(("b".into(), record(0, None)))
+        );
+        assert!(decode_line(b"no separator").is_err());
+    }
+}
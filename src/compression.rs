@@ -0,0 +1,440 @@
+//! Compression of sstable data blocks.
+//!
+//! Data blocks are compressed one at a time, right before they're written, so that a lookup only
+//! decompresses the single block it reads. The codec used for a block is recorded in its index
+//! entry, next to its offset and size, so sstables written with different settings (or before
+//! compression existed) can all be read. A block that doesn't shrink by at least an eighth is
+//! stored uncompressed, as decompressing it wouldn't be worth the little space saved.
+//!
+//! The codec of a block depends on where its sstable goes in the tree, as if the tree had levels,
+//! each set in `Options`:
+//!
+//!   level 0   flushed or ingested sstables                     compression
+//!   middle    compaction outputs with older sstables below     compaction_compression
+//!   bottom    compaction outputs in place of the oldest one    bottommost_compression
+//!
+//! each one falling back to the one above it if not set. Flushes are on the write path and are
+//! rewritten soon after, so they're usually best left uncompressed, while the bottom holds most of
+//! the data and is read far more often than it's rewritten, which makes zstd at a high level pay off.
+//!
+//! There are two codecs. Zstd comes from its C library, which isn't built for wasm32, where blocks
+//! meant for zstd are stored uncompressed and files compressed with it elsewhere can't be read.
+//! LZ4 is implemented here in its block format: a sequence of literal runs, each followed by a match
+//! that copies bytes from earlier in the output.
+//!
+//!   sequence: | token: u8 | literal length: 0+ bytes | literals | offset: u16 | match length: 0+ bytes |
+//!
+//! The high 4 bits of the token hold the literal length and the low 4 bits the match length minus 4,
+//! with 15 meaning that more length bytes follow, each adding up to 255. The last sequence of a block
+//! only holds literals.
+//!
+//! A block of small records compresses poorly on its own: most of what its records have in common
+//! with each other, like field names in JSON values, shows up only once or twice within a block,
+//! too few times to pay for itself. Bottom level sstables can get a dictionary instead, with
+//! `Options::compression_dictionary_size`: a sample of the byte sequences that repeat the most
+//! across the first blocks of the file, stored once in the file (see `sstable.rs`). Every block of
+//! the file is then compressed as if it followed the dictionary, so its matches can copy from the
+//! dictionary too, and decompressed by starting the output with the dictionary:
+//!
+//!   | dictionary | block |  -> matches in the block reach back into the dictionary
+//!
+//! For LZ4, the dictionary is trained by counting how often every 8 byte sequence occurs in the
+//! sample, then picking the 32 byte segments of the sample made of the most frequent sequences,
+//! skipping the sequences already covered by a segment picked before. For zstd, it's trained by
+//! zstd's own trainer, which picks segments much the same way and adds the entropy tables of the
+//! sample to the dictionary, so that blocks don't each need their own. It needs a large enough
+//! sample and fails otherwise, e.g. when a file has only a few blocks, and those files get a
+//! dictionary trained like the LZ4 ones instead, which zstd uses as plain content.
+
+use std::collections::HashMap;
+
+// Codec used to compress the data blocks of an sstable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    // zstd at the given level, from 1 for the fastest to 22 for the smallest output. Negative levels
+    // trade even more of the ratio for speed.
+    Zstd {
+        level: i32,
+    },
+}
+
+impl Compression {
+    // the byte identifying the codec in block index entries.
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd { .. } => 2,
+        }
+    }
+
+    // the codec of a block with the codec `id`. The zstd level isn't recorded, it's only needed to
+    // compress, and comes back as 0, zstd's default.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd { level: 0 }),
+            _ => None,
+        }
+    }
+
+    // compresses `data` with matches into `dictionary` too, empty for none, returning it along
+    // with the codec actually used, which is `None` if compressing didn't pay off.
+    pub(crate) fn compress(&self, data: &[u8], dictionary: &[u8]) -> (Vec<u8>, Compression) {
+        let compressed = match self {
+            Compression::None => return (data.to_vec(), Compression::None),
+            Compression::Lz4 => lz4_compress(data, dictionary),
+            Compression::Zstd { level } => match zstd_codec::compress(data, dictionary, *level) {
+                Some(compressed) => compressed,
+                None => return (data.to_vec(), Compression::None),
+            },
+        };
+        if compressed.len() > data.len() - data.len() / 8 {
+            return (data.to_vec(), Compression::None);
+        }
+        (compressed, *self)
+    }
+
+    // reverses `compress` with the same `dictionary`, returning `None` if `data` is malformed.
+    pub(crate) fn decompress(&self, data: Vec<u8>, dictionary: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Compression::None => Some(data),
+            Compression::Lz4 => lz4_decompress(&data, dictionary),
+            Compression::Zstd { .. } => zstd_codec::decompress(&data, dictionary),
+        }
+    }
+
+    // trains a dictionary of at most `size` bytes for this codec out of the `samples`.
+    pub(crate) fn train_dictionary(&self, samples: &[&[u8]], size: usize) -> Vec<u8> {
+        match self {
+            Compression::Zstd { .. } => zstd_codec::train_dictionary(samples, size)
+                .unwrap_or_else(|| train_dictionary(samples, size)),
+            _ => train_dictionary(samples, size),
+        }
+    }
+}
+
+// zstd, through its C library. A dictionary that isn't in zstd's own format is used as raw
+// content, the bytes blocks are compressed as if they followed, like the LZ4 ones.
+#[cfg(not(target_arch = "wasm32"))]
+mod zstd_codec {
+    use std::io::Read;
+
+    pub(super) fn compress(data: &[u8], dictionary: &[u8], level: i32) -> Option<Vec<u8>> {
+        let mut compressor = zstd::bulk::Compressor::with_dictionary(level, dictionary).ok()?;
+        compressor.compress(data).ok()
+    }
+
+    pub(super) fn decompress(data: &[u8], dictionary: &[u8]) -> Option<Vec<u8>> {
+        let mut decoder = zstd::stream::read::Decoder::with_dictionary(data, dictionary).ok()?;
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).ok()?;
+        Some(out)
+    }
+
+    // a dictionary in zstd's format, `None` if the samples are too few to train one.
+    pub(super) fn train_dictionary(samples: &[&[u8]], size: usize) -> Option<Vec<u8>> {
+        zstd::dict::from_samples(samples, size).ok()
+    }
+}
+
+// without zstd, blocks are stored as they are, and blocks compressed with it can't be read.
+#[cfg(target_arch = "wasm32")]
+mod zstd_codec {
+    pub(super) fn compress(_data: &[u8], _dictionary: &[u8], _level: i32) -> Option<Vec<u8>> {
+        None
+    }
+
+    pub(super) fn decompress(_data: &[u8], _dictionary: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    pub(super) fn train_dictionary(_samples: &[&[u8]], _size: usize) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+// length of the sequences whose occurrences are counted, and of the segments picked for the
+// dictionary, see the module docs.
+const GRAM_SIZE: usize = 8;
+const SEGMENT_SIZE: usize = 32;
+
+// trains a dictionary of at most `size` bytes out of the `samples`, see the module docs. LZ4
+// matches can't reach further back than `MAX_OFFSET`, so only about that much of a larger
+// dictionary is ever used with it.
+fn train_dictionary(samples: &[&[u8]], size: usize) -> Vec<u8> {
+    let mut counts: HashMap<&[u8], u32> = HashMap::new();
+    for sample in samples {
+        for gram in sample.windows(GRAM_SIZE) {
+            *counts.entry(gram).or_default() += 1;
+        }
+    }
+    let score = |segment: &[u8], counts: &HashMap<&[u8], u32>| -> u32 {
+        segment.windows(GRAM_SIZE).map(|gram| counts[gram]).sum()
+    };
+
+    let mut segments: Vec<&[u8]> = samples
+        .iter()
+        .flat_map(|s| s.chunks(SEGMENT_SIZE))
+        .collect();
+    segments.retain(|segment| segment.len() >= GRAM_SIZE);
+    segments.sort_by_cached_key(|segment| std::cmp::Reverse(score(segment, &counts)));
+
+    let mut dictionary = Vec::new();
+    for segment in segments {
+        if dictionary.len() + segment.len() > size {
+            break;
+        }
+        // a sequence that occurs only once, or is covered by a segment picked already, saves
+        // nothing, so segments made mostly of those are skipped.
+        let grams = segment.len() - GRAM_SIZE + 1;
+        let saving = segment.windows(GRAM_SIZE).filter(|gram| counts[*gram] > 1);
+        if saving.count() * 2 < grams {
+            continue;
+        }
+        for gram in segment.windows(GRAM_SIZE) {
+            counts.insert(gram, 0);
+        }
+        dictionary.extend_from_slice(segment);
+    }
+    dictionary
+}
+
+// matches are at least this long, shorter ones take more space than the literals they replace.
+const MIN_MATCH: usize = 4;
+// the last 5 bytes are always literals, and the last match starts at least 12 bytes before the end.
+const LAST_LITERALS: usize = 5;
+const MATCH_FIND_LIMIT: usize = 12;
+// matches copy from at most this far back, so that the offset fits in a u16.
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+// compresses `data` as if it followed `dictionary`, which matches may copy from.
+fn lz4_compress(data: &[u8], dictionary: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    // position + 1 of the last occurrence of every hashed 4 byte sequence, 0 if none.
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let hash = |sequence: u32| (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+    // positions are within the dictionary followed by the data.
+    let input = &[dictionary, data].concat()[..];
+    for i in 0..dictionary.len().saturating_sub(3) {
+        table[hash(read_u32(input, i))] = i + 1;
+    }
+    let mut anchor = dictionary.len();
+    let mut i = dictionary.len();
+
+    if data.len() > MATCH_FIND_LIMIT {
+        let match_limit = input.len() - MATCH_FIND_LIMIT;
+        while i < match_limit {
+            let sequence = read_u32(input, i);
+            let candidate = std::mem::replace(&mut table[hash(sequence)], i + 1);
+            let found = candidate
+                .checked_sub(1)
+                .filter(|c| i - c <= MAX_OFFSET && read_u32(input, *c) == sequence);
+            let Some(start) = found else {
+                i += 1;
+                continue;
+            };
+
+            let mut len = MIN_MATCH;
+            while i + len < input.len() - LAST_LITERALS && input[start + len] == input[i + len] {
+                len += 1;
+            }
+            put_sequence(&mut out, &input[anchor..i], Some((i - start, len)));
+            i += len;
+            anchor = i;
+        }
+    }
+
+    put_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+// appends a sequence of `literals` followed by a match at `offset` of `len` bytes, if any.
+fn put_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let literal_len = literals.len();
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literal_len.min(15) as u8) << 4) | match_len.min(15) as u8);
+    put_length(out, literal_len);
+    out.extend_from_slice(literals);
+
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        put_length(out, match_len);
+    }
+}
+
+// appends the bytes of a length that didn't fit in its 4 bits of the token.
+fn put_length(out: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return;
+    }
+    let mut rest = len - 15;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+// reverses `lz4_compress` with the same `dictionary`.
+fn lz4_decompress(input: &[u8], dictionary: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(dictionary.len() + input.len() * 2);
+    out.extend_from_slice(dictionary);
+    let mut i = 0;
+    loop {
+        let token = *input.get(i)?;
+        i += 1;
+
+        let literal_len = read_length(input, &mut i, (token >> 4) as usize)?;
+        out.extend_from_slice(input.get(i..i.checked_add(literal_len)?)?);
+        i += literal_len;
+        if i == input.len() {
+            return Some(out.split_off(dictionary.len()));
+        }
+
+        let offset = u16::from_le_bytes(input.get(i..i + 2)?.try_into().unwrap()) as usize;
+        i += 2;
+        let match_len = read_length(input, &mut i, (token & 0x0f) as usize)? + MIN_MATCH;
+        let start = out.len().checked_sub(offset).filter(|_| offset > 0)?;
+        // the match may overlap the bytes it produces, e.g. to repeat a run, so copy byte by byte.
+        for k in start..start + match_len {
+            out.push(out[k]);
+        }
+    }
+}
+
+// reads a length that starts with the given 4 bits of the token.
+fn read_length(input: &[u8], i: &mut usize, len: usize) -> Option<usize> {
+    let mut len = len;
+    if len == 15 {
+        loop {
+            let b = *input.get(*i)?;
+            *i += 1;
+            len = len.checked_add(b as usize)?;
+            if b != 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+fn read_u32(data: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(data[i..i + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, lz4_compress, lz4_decompress, train_dictionary};
+
+    #[test]
+    fn test_lz4_roundtrip() {
+        let repetitive: Vec<u8> = "key0001:value|".repeat(300).into_bytes();
+        let compressed = lz4_compress(&repetitive, &[]);
+        assert!(compressed.len() < repetitive.len() / 10);
+        assert_eq!(lz4_decompress(&compressed, &[]).unwrap(), repetitive);
+
+        // long literal runs and matches, along with inputs too short to hold a match.
+        let mut mixed: Vec<u8> = (0..1000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        mixed.extend(std::iter::repeat_n(b'x', 1000));
+        for input in [&mixed[..], b"", b"short", b"abcdabcdabcdabcd"] {
+            assert_eq!(
+                lz4_decompress(&lz4_compress(input, &[]), &[]).unwrap(),
+                input
+            );
+        }
+
+        // a block encoded by hand: "abc", a match repeating it three times, then "abcde".
+        assert_eq!(
+            lz4_decompress(
+                &[
+                    0x35, b'a', b'b', b'c', 3, 0, 0x50, b'a', b'b', b'c', b'd', b'e'
+                ],
+                &[]
+            ),
+            Some(b"abcabcabcabcabcde".to_vec())
+        );
+        assert_eq!(lz4_decompress(&[0x1f, b'a', 2, 0], &[]), None);
+    }
+
+    #[test]
+    fn test_zstd_roundtrip() {
+        let repetitive: Vec<u8> = "key0001:value|".repeat(300).into_bytes();
+        let (compressed, codec) = Compression::Zstd { level: 19 }.compress(&repetitive, &[]);
+        assert_eq!(codec, Compression::Zstd { level: 19 });
+        let (lz4, _) = Compression::Lz4.compress(&repetitive, &[]);
+        assert!(compressed.len() < lz4.len());
+        // blocks are read back with the codec recorded in their index entries.
+        let codec = Compression::from_id(codec.id()).unwrap();
+        assert_eq!(codec.decompress(compressed, &[]).unwrap(), repetitive);
+        assert_eq!(codec.decompress(b"not zstd".to_vec(), &[]), None);
+    }
+
+    #[test]
+    fn test_zstd_dictionary() {
+        let records: Vec<String> = (0..400)
+            .map(|i| format!("{{\"id\": {}, \"email\": \"user{}@example.com\"}}", i, i))
+            .collect();
+        let blocks: Vec<Vec<u8>> = records.chunks(4).map(|r| r.concat().into_bytes()).collect();
+        let samples: Vec<&[u8]> = blocks.iter().map(|b| &b[..]).collect();
+        let codec = Compression::Zstd { level: 3 };
+        let compressed_size = |dictionary: &[u8]| -> usize {
+            let sizes = samples.iter().map(|block| {
+                let (compressed, _) = codec.compress(block, dictionary);
+                assert_eq!(
+                    codec.decompress(compressed.clone(), dictionary).unwrap(),
+                    *block
+                );
+                compressed.len()
+            });
+            sizes.sum()
+        };
+        let dictionary = codec.train_dictionary(&samples, 1024);
+        assert!(dictionary.len() <= 1024);
+        assert!(compressed_size(&dictionary) < compressed_size(&[]) * 2 / 3);
+
+        // too few samples for zstd's trainer, the dictionary is trained like the LZ4 ones instead.
+        let dictionary = codec.train_dictionary(&samples[..2], 1024);
+        assert_eq!(dictionary, train_dictionary(&samples[..2], 1024));
+        assert!(!dictionary.is_empty());
+    }
+
+    #[test]
+    fn test_compression_skips_incompressible_data() {
+        let random: Vec<u8> = (0..256u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let (stored, codec) = Compression::Lz4.compress(&random, &[]);
+        assert_eq!(codec, Compression::None);
+        assert_eq!(stored, random);
+    }
+
+    #[test]
+    fn test_lz4_with_a_trained_dictionary() {
+        let record = |i: u32| {
+            format!(
+                "{{\"id\": {}, \"name\": \"user{}\", \"active\": true}}",
+                i, i
+            )
+        };
+        let sample: Vec<u8> = (0..100).flat_map(|i| record(i).into_bytes()).collect();
+        let dictionary = train_dictionary(&[&sample], 256);
+        assert!(!dictionary.is_empty() && dictionary.len() <= 256);
+
+        // a block of a couple of records compresses better with the dictionary.
+        let block: Vec<u8> = (500..502).flat_map(|i| record(i).into_bytes()).collect();
+        let (plain, _) = Compression::Lz4.compress(&block, &[]);
+        let (with_dictionary, codec) = Compression::Lz4.compress(&block, &dictionary);
+        assert_eq!(codec, Compression::Lz4);
+        assert!(with_dictionary.len() < plain.len() * 3 / 4);
+        let decompressed = codec.decompress(with_dictionary.clone(), &dictionary);
+        assert_eq!(decompressed.unwrap(), block);
+        // the block can't be read without it.
+        assert_ne!(codec.decompress(with_dictionary, &[]), Some(block));
+    }
+}
@@ -0,0 +1,269 @@
+//! Deadlines and cancellation for reads and manual compactions, so that a slow disk can't hold up
+//! the thread waiting on them for ever.
+//!
+//! A `Deadline` is a point in time by the tree's clock, a `CancellationToken` that another thread
+//! can cancel, or both. Operations that take one check it before each step that may go to disk:
+//!
+//!   get_with_deadline          before reading each sstable, and the value from a blob file
+//!   scan_with_deadline         before returning each entry
+//!   compact_now_with_deadline  before each compaction, and each entry read from its inputs
+//!
+//! Once it has passed, they fail with `Error::TimedOut`, or `Error::Cancelled` if the token was
+//! cancelled. Nothing is left half done: reads have no effects, and a compaction writes its output
+//! only once it has read all of its inputs, so one that gives up leaves its inputs in place as if
+//! it never ran. The compactions it finished before that remain. A check can't interrupt a read
+//! that's already waiting on the disk, so an operation may overrun its deadline by one such read.
+
+use std::{
+    ops::RangeBounds,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::{Error, LSMTree, Lookup, ScanIter, Value, clock::Clock};
+
+// Cancels the operations given a deadline with it, from any thread. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// When an operation should give up. The default never passes.
+#[derive(Debug, Clone, Default)]
+pub struct Deadline {
+    at: Option<SystemTime>,
+    token: Option<CancellationToken>,
+}
+
+impl Deadline {
+    // passes at `time`, by the clock of the tree the operation runs on. See `LSMTree::deadline_in`.
+    pub fn at(time: SystemTime) -> Self {
+        Deadline {
+            at: Some(time),
+            token: None,
+        }
+    }
+
+    // passes once `token` is cancelled.
+    pub fn cancelled_by(token: CancellationToken) -> Self {
+        Deadline {
+            at: None,
+            token: Some(token),
+        }
+    }
+
+    // also passes once `token` is cancelled.
+    pub fn or_cancelled_by(self, token: CancellationToken) -> Self {
+        Deadline {
+            token: Some(token),
+            ..self
+        }
+    }
+
+    // fails if the deadline has passed by `clock`.
+    pub(crate) fn check(&self, clock: &dyn Clock) -> Result<(), Error> {
+        if self.token.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Err(Error::Cancelled);
+        }
+        match self.at {
+            Some(at) if clock.now() >= at => Err(Error::TimedOut),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Iterator returned by `LSMTree::scan_with_deadline`. Ends after the error of a passed deadline.
+pub struct DeadlineScanIter {
+    inner: ScanIter,
+    deadline: Deadline,
+    clock: Arc<dyn Clock>,
+    failed: bool,
+}
+
+impl Iterator for DeadlineScanIter {
+    type Item = Result<(String, String), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if let Err(e) = self.deadline.check(&*self.clock) {
+            self.failed = true;
+            return Some(Err(e));
+        }
+        self.inner.next().map(Ok)
+    }
+}
+
+impl LSMTree {
+    // a deadline `timeout` from now, by the tree's clock.
+    pub fn deadline_in(&self, timeout: Duration) -> Deadline {
+        Deadline::at(self.sstable_mgr.clock.now() + timeout)
+    }
+
+    // returns the value associated with `k` like `get`, unless `deadline` passes first.
+    pub fn get_with_deadline(&self, k: &str, deadline: &Deadline) -> Result<Option<String>, Error> {
+        match self.lookup_until(k, deadline)? {
+            Lookup::Found(v) => {
+                if let Value::Blob(_) = v {
+                    deadline.check(&*self.sstable_mgr.clock)?;
+                }
+                Ok(Some(self.sstable_mgr.read_value(v)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // returns an iterator over the live key value pairs within `range` like `scan`, which fails
+    // with the error of `deadline` once it passes.
+    pub fn scan_with_deadline<'a>(
+        &self,
+        range: impl RangeBounds<&'a str>,
+        deadline: &Deadline,
+    ) -> DeadlineScanIter {
+        DeadlineScanIter {
+            inner: self.scan(range),
+            deadline: deadline.clone(),
+            clock: Arc::clone(&self.sstable_mgr.clock),
+            failed: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{CancellationToken, Deadline};
+    use crate::{
+        CompactionSchedule, Error, LSMTree, Options, clock::Clock, sim::SimStorage,
+        storage::Storage,
+    };
+
+    // a clock that moves a millisecond forward every time it's read.
+    #[derive(Debug, Default)]
+    struct TickingClock(Mutex<Duration>);
+
+    impl Clock for TickingClock {
+        fn now(&self) -> SystemTime {
+            let mut elapsed = self.0.lock().unwrap();
+            *elapsed += Duration::from_millis(1);
+            UNIX_EPOCH + *elapsed
+        }
+    }
+
+    // a tree with 4 sstables left to compact: the clock starts at midnight, outside of the window.
+    fn open(storage: &SimStorage, clock: Arc<dyn Clock>) -> LSMTree {
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            clock: Some(clock),
+            memtable_limit: 10,
+            compaction_trigger: 3,
+            compaction_schedule: CompactionSchedule::between_hours(2, 6),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..40 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        assert_eq!(lsmtree.sstable_mgr.sstables.len(), 4);
+        lsmtree
+    }
+
+    #[test]
+    fn test_cancelled_operations_fail() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lsmtree = open(&storage, Arc::new(TickingClock::default()));
+        let token = CancellationToken::new();
+        let deadline = Deadline::cancelled_by(token.clone());
+        assert_eq!(
+            lsmtree.get_with_deadline("key05", &deadline),
+            Ok(Some("value".to_string()))
+        );
+
+        let mut scan = lsmtree.scan_with_deadline(.., &deadline);
+        assert_eq!(
+            scan.next(),
+            Some(Ok(("key00".to_string(), "value".to_string())))
+        );
+        token.cancel();
+        assert_eq!(scan.next(), Some(Err(Error::Cancelled)));
+        assert_eq!(scan.next(), None);
+
+        assert_eq!(
+            lsmtree.get_with_deadline("key05", &deadline),
+            Err(Error::Cancelled)
+        );
+        assert_eq!(
+            lsmtree.compact_now_with_deadline(&deadline),
+            Err(Error::Cancelled)
+        );
+        assert_eq!(lsmtree.sstable_mgr.sstables.len(), 4);
+    }
+
+    #[test]
+    fn test_get_times_out_only_when_reading_sstables() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lsmtree = open(&storage, Arc::new(TickingClock::default()));
+        lsmtree.put("key40", "value").unwrap();
+        let passed = Deadline::at(UNIX_EPOCH);
+        assert_eq!(
+            lsmtree.get_with_deadline("key40", &passed),
+            Ok(Some("value".to_string()))
+        );
+        assert_eq!(
+            lsmtree.get_with_deadline("key05", &passed),
+            Err(Error::TimedOut)
+        );
+        let deadline = lsmtree.deadline_in(Duration::from_secs(60));
+        assert_eq!(
+            lsmtree.get_with_deadline("key05", &deadline),
+            Ok(Some("value".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_compaction_past_deadline_leaves_tree_unchanged() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lsmtree = open(&storage, Arc::new(TickingClock::default()));
+        let ids = |lsmtree: &LSMTree| {
+            let sstables = lsmtree.sstable_mgr.sstables.iter();
+            sstables.map(|sst| sst.id).collect::<Vec<_>>()
+        };
+        let before = ids(&lsmtree);
+        let files = storage.list(Path::new("data")).unwrap();
+
+        // expires after a few entries of the inputs were read.
+        let deadline = lsmtree.deadline_in(Duration::from_millis(10));
+        assert_eq!(
+            lsmtree.compact_now_with_deadline(&deadline),
+            Err(Error::TimedOut)
+        );
+        assert_eq!(ids(&lsmtree), before);
+        assert_eq!(storage.list(Path::new("data")).unwrap(), files);
+        assert_eq!(lsmtree.scan(..).count(), 40);
+
+        lsmtree.compact_now();
+        assert!(lsmtree.sstable_mgr.sstables.len() < 4);
+        assert_eq!(lsmtree.scan(..).count(), 40);
+    }
+}
@@ -0,0 +1,373 @@
+//! Differences between two states of a tree, for syncing the changes since the last sync to a
+//! downstream system rather than all of the data every time.
+//!
+//! `LSMTree::diff` compares a tree with an older snapshot of it, e.g. a checkpoint taken at the
+//! last sync and opened read-only, see `backup.rs`. Both are scanned side by side in key order,
+//! so the changes come out in key order too, without holding either tree in memory:
+//!
+//!   older:  a=1  b=2  c=3
+//!   newer:  a=1  b=5       d=4
+//!   diff:        Changed(b=5)  Deleted(c)  Added(d=4)
+//!
+//! `LSMTree::changes_between` does the same between two sequence numbers of the same tree, from
+//! the versions kept by `Options::history_retention`. Where the retention may have dropped the
+//! version as of the older sequence number, a key written since is reported as changed or deleted
+//! rather than added, which applies just as well to a downstream copy that has it or doesn't.
+//!
+//! `LSMTree::write_delta` writes changes to a delta file, and `LSMTree::apply_delta` applies one to
+//! another tree. A delta file is a sequence of changes followed by a checksum of all of them:
+//!
+//!   | "LSMDELTA" | change count (varint) | op (u8) | key | value | ... | crc32 (u32) |
+//!
+//! with the key and value length prefixed, and no value for a deletion. The checksum is checked
+//! before anything is applied, so a damaged or truncated delta file is refused as a whole.
+//! 💡 RocksDB has no diff of its own, applications compare two snapshots with iterators the same
+//! way. Its `GetUpdatesSince` replays the log since a sequence number instead, with every write in
+//! between rather than only the last one of each key.
+
+use std::{
+    cmp::Ordering,
+    io::{self, Read},
+    iter::Peekable,
+    path::Path,
+};
+
+use crate::{
+    HistoryRetention, LSMTree, ScanIter,
+    checksum::crc32,
+    history::{Version, decode_version},
+    varint::{Decoder, put_length_prefixed, put_varint},
+};
+
+const MAGIC: &[u8] = b"LSMDELTA";
+
+const DELETED: u8 = 0;
+const ADDED: u8 = 1;
+const CHANGED: u8 = 2;
+
+// A change made to a key between two states of a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    // the key didn't exist in the older state.
+    Added { key: String, value: String },
+    // the key had another value in the older state.
+    Changed { key: String, value: String },
+    Deleted { key: String },
+}
+
+impl Change {
+    pub fn key(&self) -> &str {
+        match self {
+            Change::Added { key, .. } | Change::Changed { key, .. } | Change::Deleted { key } => {
+                key
+            }
+        }
+    }
+}
+
+// Iterator returned by `LSMTree::diff`, yielding changes in key order.
+pub struct DiffIter {
+    newer: Peekable<ScanIter>,
+    older: Peekable<ScanIter>,
+}
+
+impl Iterator for DiffIter {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Change> {
+        loop {
+            let order = match (self.newer.peek(), self.older.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((newer, _)), Some((older, _))) => newer.cmp(older),
+            };
+            match order {
+                Ordering::Less => {
+                    let (key, value) = self.newer.next().unwrap();
+                    return Some(Change::Added { key, value });
+                }
+                Ordering::Greater => {
+                    let (key, _) = self.older.next().unwrap();
+                    return Some(Change::Deleted { key });
+                }
+                Ordering::Equal => {
+                    let (key, value) = self.newer.next().unwrap();
+                    let (_, old) = self.older.next().unwrap();
+                    if value != old {
+                        return Some(Change::Changed { key, value });
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl LSMTree {
+    // returns the changes that turn `older`, e.g. an earlier checkpoint of this tree, into this
+    // tree, in key order.
+    pub fn diff(&self, older: &LSMTree) -> DiffIter {
+        DiffIter {
+            newer: self.scan(..).peekable(),
+            older: older.scan(..).peekable(),
+        }
+    }
+
+    // returns the changes made by the writes with sequence numbers after `from` up to `to`, in key
+    // order. Fails if the tree keeps no history, see `Options::history_retention`. Like the
+    // history, it doesn't see values streamed in with `put_reader` and entries added with `ingest`.
+    pub fn changes_between(&self, from: u64, to: u64) -> io::Result<Vec<Change>> {
+        let Some(history) = &self.history else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the tree keeps no history to diff",
+            ));
+        };
+        let retention = self.options.history_retention;
+        let mut changes = Vec::new();
+        let mut entries = (history.scan(..))
+            .filter_map(|(k, v)| decode_version(&k, &v))
+            .peekable();
+        while let Some((key, version)) = entries.next() {
+            // the versions of the key, newest first.
+            let mut versions = vec![version];
+            while let Some((_, version)) = entries.next_if(|(k, _)| *k == key) {
+                versions.push(version);
+            }
+            // whether the retention kept every version of the key since its first write.
+            let complete = match retention {
+                Some(HistoryRetention::LastVersions(n)) => versions.len() < n.max(1),
+                _ => false,
+            };
+            changes.extend(change_between(key, &versions, from, to, complete));
+        }
+        Ok(changes)
+    }
+
+    // writes `changes` to a delta file at `path`, on the tree's storage. Returns the number of
+    // changes written.
+    pub fn write_delta(
+        &self,
+        path: impl AsRef<Path>,
+        changes: impl IntoIterator<Item = Change>,
+    ) -> io::Result<usize> {
+        let mut body = Vec::new();
+        let mut count = 0;
+        for change in changes {
+            let (op, key, value) = match &change {
+                Change::Added { key, value } => (ADDED, key, Some(value)),
+                Change::Changed { key, value } => (CHANGED, key, Some(value)),
+                Change::Deleted { key } => (DELETED, key, None),
+            };
+            body.push(op);
+            put_length_prefixed(&mut body, key.as_bytes());
+            if let Some(value) = value {
+                put_length_prefixed(&mut body, value.as_bytes());
+            }
+            count += 1;
+        }
+        let mut contents = MAGIC.to_vec();
+        put_varint(&mut contents, count as u64);
+        contents.extend_from_slice(&body);
+        contents.extend_from_slice(&crc32(&contents).to_le_bytes());
+
+        let mut file = self.sstable_mgr.storage.create(path.as_ref())?;
+        file.write_all(&contents)?;
+        file.sync()?;
+        Ok(count)
+    }
+
+    // applies the changes of the delta file at `path`, on the tree's storage, as regular writes.
+    // Returns the number of changes applied.
+    pub fn apply_delta(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let changes = self.read_delta(path.as_ref())?;
+        for change in &changes {
+            match change {
+                Change::Added { key, value } | Change::Changed { key, value } => {
+                    self.put(key, value)?
+                }
+                Change::Deleted { key } => self.delete(key),
+            }
+        }
+        Ok(changes.len())
+    }
+
+    // reads the changes of the delta file at `path`, on the tree's storage.
+    pub fn read_delta(&self, path: impl AsRef<Path>) -> io::Result<Vec<Change>> {
+        let path = path.as_ref();
+        let mut contents = Vec::new();
+        (self.sstable_mgr.storage.open(path)?).read_to_end(&mut contents)?;
+        decode_delta(&contents).ok_or_else(|| {
+            let message = format!("{}: not a valid delta file", path.display());
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        })
+    }
+}
+
+// the change `key` went through between `from` and `to`, given its versions newest first. With
+// `complete` set, they go back to its first write, so a key without a version as of `from` didn't
+// exist then.
+fn change_between(
+    key: String,
+    versions: &[Version],
+    from: u64,
+    to: u64,
+    complete: bool,
+) -> Option<Change> {
+    let as_of = |seq: u64| versions.iter().find(|v| v.seq <= seq);
+    let new = as_of(to).filter(|v| v.seq > from)?;
+    let old = match as_of(from) {
+        Some(old) => Some(&old.value),
+        None if complete => Some(&None),
+        // unknown, so the new value is written over whatever there is.
+        None => None,
+    };
+    match (old, new.value.clone()) {
+        (Some(old), new) if *old == new => None,
+        (Some(None), Some(value)) => Some(Change::Added { key, value }),
+        (_, Some(value)) => Some(Change::Changed { key, value }),
+        (_, None) => Some(Change::Deleted { key }),
+    }
+}
+
+fn decode_delta(contents: &[u8]) -> Option<Vec<Change>> {
+    let (body, crc) = contents.split_at_checked(contents.len().checked_sub(4)?)?;
+    if crc32(body) != u32::from_le_bytes(crc.try_into().ok()?) {
+        return None;
+    }
+    let mut decoder = Decoder::new(body.strip_prefix(MAGIC)?);
+    let count = decoder.varint()?;
+    let mut changes = Vec::new();
+    let string = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).ok();
+    for _ in 0..count {
+        let op = *decoder.bytes(1)?.first()?;
+        let key = string(decoder.length_prefixed()?)?;
+        let change = match op {
+            ADDED => Change::Added {
+                key,
+                value: string(decoder.length_prefixed()?)?,
+            },
+            CHANGED => Change::Changed {
+                key,
+                value: string(decoder.length_prefixed()?)?,
+            },
+            DELETED => Change::Deleted { key },
+            _ => return None,
+        };
+        changes.push(change);
+    }
+    decoder.is_empty().then_some(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Change;
+    use crate::{HistoryRetention, LSMTree, Options, sim::SimStorage, storage::Storage};
+
+    fn options(storage: &SimStorage) -> Options {
+        Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 10,
+            ..Options::default()
+        }
+    }
+
+    fn added(key: &str, value: &str) -> Change {
+        let (key, value) = (key.to_string(), value.to_string());
+        Change::Added { key, value }
+    }
+
+    fn changed(key: &str, value: &str) -> Change {
+        let (key, value) = (key.to_string(), value.to_string());
+        Change::Changed { key, value }
+    }
+
+    fn deleted(key: &str) -> Change {
+        let key = key.to_string();
+        Change::Deleted { key }
+    }
+
+    #[test]
+    fn test_diff_against_checkpoint_and_apply_delta() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lsmtree = LSMTree::open_with_options("data", options(&storage));
+        for i in 0..30 {
+            lsmtree.put(&format!("key{:02}", i), "v1").unwrap();
+        }
+        lsmtree.checkpoint("synced").unwrap();
+        // the downstream copy as of the last sync.
+        lsmtree.clone_to("downstream").unwrap();
+
+        lsmtree.put("key05", "v2").unwrap();
+        lsmtree.put("key07", "v1").unwrap();
+        lsmtree.delete("key10");
+        lsmtree.put("key30", "v1").unwrap();
+        lsmtree.delete("key31");
+        let synced = LSMTree::open_read_only("synced", options(&storage));
+        let changes: Vec<Change> = lsmtree.diff(&synced).collect();
+        let expected = vec![
+            changed("key05", "v2"),
+            deleted("key10"),
+            added("key30", "v1"),
+        ];
+        assert_eq!(changes, expected);
+
+        assert_eq!(lsmtree.write_delta("delta", changes).unwrap(), 3);
+        let mut downstream = LSMTree::open_with_options("downstream", options(&storage));
+        assert_eq!(downstream.read_delta("delta").unwrap(), expected);
+        assert_eq!(downstream.apply_delta("delta").unwrap(), 3);
+        assert_eq!(downstream.diff(&lsmtree).count(), 0);
+
+        // a damaged delta is refused without applying any of it.
+        let mut file = storage.create("delta".as_ref()).unwrap();
+        file.write_all(b"LSMDELTA\x01\x00").unwrap();
+        assert!(downstream.apply_delta("delta").is_err());
+    }
+
+    #[test]
+    fn test_changes_between_sequence_numbers() {
+        let storage = SimStorage::new(1, Default::default());
+        let lsmtree = LSMTree::open_with_options("data", options(&storage));
+        assert!(lsmtree.changes_between(0, 1).is_err());
+
+        let options = Options {
+            history_retention: Some(HistoryRetention::LastVersions(3)),
+            ..options(&storage)
+        };
+        let mut lsmtree = LSMTree::open_with_options("history", options);
+        lsmtree.put("a", "1").unwrap();
+        lsmtree.put("b", "1").unwrap();
+        lsmtree.put("c", "1").unwrap();
+        lsmtree.put("d", "1").unwrap();
+        let from = lsmtree.last_seq;
+        lsmtree.put("a", "2").unwrap();
+        lsmtree.delete("b");
+        lsmtree.put("c", "2").unwrap();
+        lsmtree.put("c", "1").unwrap();
+        lsmtree.put("e", "1").unwrap();
+        lsmtree.put("f", "1").unwrap();
+        lsmtree.delete("f");
+        let to = lsmtree.last_seq;
+        lsmtree.put("d", "2").unwrap();
+
+        let expected = vec![changed("a", "2"), deleted("b"), added("e", "1")];
+        assert_eq!(lsmtree.changes_between(from, to).unwrap(), expected);
+        assert_eq!(lsmtree.changes_between(to, to).unwrap(), vec![]);
+
+        // with the versions as of `from` dropped, `a` might not have existed then.
+        for v in 3..6 {
+            lsmtree.put("a", &v.to_string()).unwrap();
+        }
+        lsmtree.delete("a");
+        let changes = lsmtree.changes_between(from, lsmtree.last_seq).unwrap();
+        let expected = vec![
+            deleted("a"),
+            deleted("b"),
+            changed("d", "2"),
+            added("e", "1"),
+        ];
+        assert_eq!(changes, expected);
+    }
+}
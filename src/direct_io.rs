@@ -0,0 +1,205 @@
+//! Direct I/O: writing flush and compaction outputs around the page cache.
+//!
+//! Written through the page cache, every sstable a flush or compaction writes takes up cache space
+//! until the kernel writes it back and gets around to evicting it, pushing out the pages reads
+//! depend on. With `Options::use_direct_io_for_flush_and_compaction`, new sstables are opened with
+//! `O_DIRECT` instead, so their writes go from the tree's buffer straight to the disk.
+//!
+//! Direct writes have to start at offsets aligned to the disk's blocks, from memory aligned the same
+//! way, and be whole blocks long. A `DirectFile` collects what's written to it in an aligned buffer
+//! and writes it out in whole blocks once the buffer fills up. On `sync`, the last partial block is
+//! padded with zeros to a whole one, written, and cut off again by truncating the file to its length:
+//!
+//!   buffer:  [block 0][block 1][blo.....]     written at the aligned offset the buffer starts at
+//!   sync:    [blo.....|00000]  -> truncate to the length written
+//!
+//! The partial block stays in the buffer, and is written again, in full, once there's more of it.
+//! Filesystems that don't support direct I/O refuse to open files with `O_DIRECT`, and the file is
+//! opened normally instead, so the option never fails a tree, it just has no effect there. It's
+//! Linux only for the same reason, other platforms write through the cache as usual.
+
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::storage::WritableFile;
+
+// the alignment of direct writes, in offsets, lengths and memory. 4096 covers devices with 512
+// byte blocks as well.
+const ALIGNMENT: usize = 4096;
+// how much is collected before it's written out.
+const BUFFER_SIZE: usize = 64 * ALIGNMENT;
+
+// creates the file at `path` for direct writes, or for normal ones where direct I/O isn't supported.
+#[cfg(target_os = "linux")]
+pub(crate) fn create(path: &Path) -> io::Result<Box<dyn WritableFile>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use crate::storage::FsFile;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+        Ok(file) => Ok(Box::new(DirectFile::new(file))),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            Ok(Box::new(FsFile::new(options.open(path)?)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn create(path: &Path) -> io::Result<Box<dyn WritableFile>> {
+    use crate::storage::{FsStorage, Storage};
+
+    FsStorage.create(path)
+}
+
+// A file opened with `O_DIRECT`, written in whole aligned blocks, see `direct_io.rs`.
+pub(crate) struct DirectFile {
+    file: File,
+    // `BUFFER_SIZE` bytes of memory, `ALIGNMENT` aligned, at `start` within `memory`.
+    memory: Vec<u8>,
+    start: usize,
+    // how many bytes of the buffer are in use.
+    len: usize,
+    // the offset in the file the buffer is written at, always aligned.
+    offset: u64,
+}
+
+impl DirectFile {
+    pub(crate) fn new(file: File) -> Self {
+        let memory = vec![0; BUFFER_SIZE + ALIGNMENT];
+        let start = memory.as_ptr().align_offset(ALIGNMENT);
+        DirectFile {
+            file,
+            memory,
+            start,
+            len: 0,
+            offset: 0,
+        }
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        &mut self.memory[self.start..self.start + BUFFER_SIZE]
+    }
+
+    // writes the buffer out up to `len` rounded up to whole blocks, and drops the whole blocks from
+    // it, moving the partial one, if any, to the front.
+    fn write_out(&mut self) -> io::Result<()> {
+        let len = self.len;
+        let padded = len.next_multiple_of(ALIGNMENT);
+        let offset = self.offset;
+        let buffer = self.buffer();
+        buffer[len..padded].fill(0);
+        (&self.file).seek(SeekFrom::Start(offset))?;
+        (&self.file).write_all(&self.memory[self.start..self.start + padded])?;
+
+        let whole = len - len % ALIGNMENT;
+        self.buffer().copy_within(whole..len, 0);
+        self.len -= whole;
+        self.offset += whole as u64;
+        Ok(())
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.len == BUFFER_SIZE {
+            self.write_out()?;
+        }
+        let n = data.len().min(BUFFER_SIZE - self.len);
+        let len = self.len;
+        self.buffer()[len..len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        Ok(n)
+    }
+
+    // the buffer is only written out in full, or padded by `sync`.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for DirectFile {
+    fn sync(&mut self) -> io::Result<()> {
+        let end = self.offset + self.len as u64;
+        if self.len > 0 {
+            self.write_out()?;
+        }
+        // cut off the padding of the last block, and the space preallocated beyond it.
+        self.file.set_len(end)?;
+        self.file.sync_data()
+    }
+
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        crate::storage::allocate(&self.file, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Write, path::PathBuf};
+
+    use super::{ALIGNMENT, BUFFER_SIZE, DirectFile};
+    use crate::{LSMTree, Options, storage::WritableFile};
+
+    #[test]
+    fn test_direct_file_pads_and_truncates_partial_blocks() {
+        let dir = PathBuf::from("test_data").join("direct_io");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("1.sst");
+        // written through `DirectFile` whether or not the filesystem takes `O_DIRECT`, which only
+        // makes the alignment matter.
+        let mut file = DirectFile::new(File::create(&path).unwrap());
+        assert_eq!(file.buffer().as_ptr() as usize % ALIGNMENT, 0);
+        let data: Vec<u8> = (0..BUFFER_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        file.write_all(&data[..ALIGNMENT + 10]).unwrap();
+        file.sync().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), &data[..ALIGNMENT + 10]);
+
+        // the partial block is written again along with what follows it.
+        file.write_all(&data[ALIGNMENT + 10..]).unwrap();
+        file.sync().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        let mut file = super::create(&dir.join("2.sst")).unwrap();
+        file.write_all(&data[..100]).unwrap();
+        file.sync().unwrap();
+        assert_eq!(std::fs::read(dir.join("2.sst")).unwrap(), &data[..100]);
+    }
+
+    #[test]
+    fn test_tree_with_direct_io_for_flush_and_compaction() {
+        let dir = PathBuf::from("test_data").join("direct_io_tree");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        let options = Options {
+            memtable_limit: 10,
+            compaction_trigger: 2,
+            use_direct_io_for_flush_and_compaction: true,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(&dir, options.clone());
+        for i in 0..50 {
+            lsmtree
+                .put(&format!("key{:02}", i % 25), &i.to_string())
+                .unwrap();
+        }
+        drop(lsmtree);
+        let lsmtree = LSMTree::open_with_options(&dir, options);
+        assert_eq!(lsmtree.get("key03"), Some("28".to_string()));
+        assert_eq!(lsmtree.scan(..).count(), 25);
+        assert!(lsmtree.verify_checksums(None).corrupt_files.is_empty());
+    }
+}
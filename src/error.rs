@@ -0,0 +1,58 @@
+//! Errors for operations the tree refuses or gives up on, as opposed to the I/O errors of the
+//! storage underneath it.
+
+use std::{fmt, io};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // the key is longer than `Options::max_key_size`.
+    KeyTooLarge { size: usize, max: usize },
+    // the value is longer than `Options::max_value_size`.
+    ValueTooLarge { size: usize, max: usize },
+    // the deadline of the operation passed before it was done, see `Deadline`, or a transaction
+    // waited for a lock for longer than the lock timeout.
+    TimedOut,
+    // the operation was cancelled through a `CancellationToken`.
+    Cancelled,
+    // a transaction would wait for a lock forever, see `transaction.rs`.
+    Deadlock,
+    // the snapshot read from was released for being older than `Options::max_snapshot_age`.
+    SnapshotExpired,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::KeyTooLarge { size, max } => {
+                write!(f, "key of {} bytes exceeds max_key_size of {}", size, max)
+            }
+            Error::ValueTooLarge { size, max } => {
+                write!(
+                    f,
+                    "value of {} bytes exceeds max_value_size of {}",
+                    size, max
+                )
+            }
+            Error::TimedOut => write!(f, "deadline exceeded"),
+            Error::Cancelled => write!(f, "operation cancelled"),
+            Error::Deadlock => write!(f, "deadlock detected"),
+            Error::SnapshotExpired => write!(f, "snapshot expired"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// for the operations that return `io::Result`, e.g. `put_reader`.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::KeyTooLarge { .. } | Error::ValueTooLarge { .. } => io::ErrorKind::InvalidInput,
+            Error::TimedOut => io::ErrorKind::TimedOut,
+            Error::Cancelled => io::ErrorKind::Interrupted,
+            Error::Deadlock => io::ErrorKind::Deadlock,
+            Error::SnapshotExpired => io::ErrorKind::NotFound,
+        };
+        io::Error::new(kind, e)
+    }
+}
@@ -0,0 +1,255 @@
+//! Older versions of keys, kept for `LSMTree::get_history` when `Options::history_retention` is set.
+//!
+//! The tree only keeps the newest version of a key: the memtable replaces it in place, and
+//! compactions drop the versions that newer ones shadow. With a history retention set, every write
+//! is also recorded in a history tree, a complete LSM Tree of its own in the `history` dir under
+//! the data dir, under a key made of the written key and the sequence number of the write:
+//!
+//!   <key> \0 <u64::MAX - seq as 16 hex digits>  ->  <write time in ms since the epoch> [<value>]
+//!
+//! Inverting the sequence number sorts the versions of a key newest first, so that a prefix scan
+//! reads them in the order `get_history` returns them. A delete is recorded without a value.
+//! The versions of a write, or of a whole group of writes, go to the history tree in a single
+//! `write_batch`, so they cost one more sync of a log, and the versions of a group are recorded
+//! all or none.
+//!
+//! Writes don't look at the versions already recorded. Instead, when the memtable is flushed, the
+//! versions of each key written since the previous flush that the retention no longer allows are
+//! deleted from the history tree, in a single `write_batch` too, and its own compactions then
+//! reclaim their space. A key is pruned once per flush however often it was written, and until
+//! then `get_history` leaves out the versions past the retention as it reads them.
+//!
+//! Versions are recorded right after their writes are logged, so a crash in between may lose the
+//! record of those writes, but never records a write that didn't happen. Values streamed in with
+//! `put_reader` and entries added with `ingest` aren't recorded.
+//! 💡 RocksDB keeps older versions within its own sstables instead, with user defined timestamps:
+//! compactions keep every version newer than the `full_history_ts_low` timestamp.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{HistoryRetention, LSMTree, Record, Value, WriteBatch};
+
+// the dir under the data dir holding the history tree.
+pub(crate) const HISTORY_DIR: &str = "history";
+
+// A version of a key, as returned by `LSMTree::get_history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub seq: u64,
+    // when the version was written, by the tree's clock.
+    pub written: SystemTime,
+    // `None` for a delete.
+    pub value: Option<String>,
+}
+
+impl LSMTree {
+    // returns the versions of `key` that the history retention keeps, newest first and the
+    // current one included. Empty if the tree keeps no history, see `Options::history_retention`.
+    pub fn get_history(&self, key: &str) -> Vec<Version> {
+        let (Some(history), Some(retention)) = (&self.history, self.options.history_retention)
+        else {
+            return Vec::new();
+        };
+        // versions are only pruned when the memtable is flushed, and may have outlived the
+        // retention since.
+        let (kept, _) = retain(
+            versions(history, key),
+            retention,
+            self.sstable_mgr.clock.now(),
+        );
+        kept
+    }
+
+    // records `writes`, those of a single write or of a whole group, in the history tree if
+    // there's one, with a single write to it.
+    pub(crate) fn record_history(&mut self, writes: &[(&str, &Record)]) {
+        let Some(history) = &mut self.history else {
+            return;
+        };
+        let millis = (self.sstable_mgr.clock.now().duration_since(UNIX_EPOCH))
+            .unwrap_or_default()
+            .as_millis();
+        let mut batch = WriteBatch::new();
+        for (key, record) in writes {
+            let encoded = match &record.value {
+                Some(Value::Inline(value)) => format!("{} {}", millis, value),
+                Some(Value::Blob(_)) => continue,
+                None => millis.to_string(),
+            };
+            batch.put(&history_key(key, record.seq), &encoded);
+        }
+        (history.write_batch(batch)).expect("the history tree has no size limits");
+    }
+
+    // deletes the versions of `keys` that the retention no longer allows from the history tree, if
+    // there's one, with a single write to it. Called with the keys of the memtable as it's flushed.
+    pub(crate) fn prune_history<'a>(&mut self, keys: impl Iterator<Item = &'a str>) {
+        let (Some(history), Some(retention)) = (&mut self.history, self.options.history_retention)
+        else {
+            return;
+        };
+        let now = self.sstable_mgr.clock.now();
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            let (_, dropped) = retain(versions(history, key), retention, now);
+            for version in dropped {
+                batch.delete(&history_key(key, version.seq));
+            }
+        }
+        (history.write_batch(batch)).expect("the history tree has no size limits");
+    }
+}
+
+// splits `versions` of a key, newest first, into the ones `retention` keeps at `now` and the ones
+// it no longer allows. The newest version is always kept.
+fn retain(
+    versions: Vec<Version>,
+    retention: HistoryRetention,
+    now: SystemTime,
+) -> (Vec<Version>, Vec<Version>) {
+    let kept = |i: usize, v: &Version| match retention {
+        HistoryRetention::LastVersions(n) => i < n.max(1),
+        HistoryRetention::NewerThan(age) => {
+            i == 0
+                || now
+                    .checked_sub(age)
+                    .is_none_or(|cutoff| v.written >= cutoff)
+        }
+    };
+    let (kept, dropped): (Vec<_>, Vec<_>) =
+        (versions.into_iter().enumerate()).partition(|(i, v)| kept(*i, v));
+    let versions = |split: Vec<(usize, Version)>| split.into_iter().map(|(_, v)| v).collect();
+    (versions(kept), versions(dropped))
+}
+
+fn history_key(key: &str, seq: u64) -> String {
+    format!("{}\0{:016x}", key, u64::MAX - seq)
+}
+
+// reads the versions of `key` from the history tree `history`, newest first.
+fn versions(history: &LSMTree, key: &str) -> Vec<Version> {
+    let prefix = format!("{}\0", key);
+    let versions = history.scan_prefix(&prefix).filter_map(|(k, encoded)| {
+        // skips the versions of longer keys that start with `key` and a \0.
+        let (_, version) = decode_version(&k, &encoded).filter(|(written, _)| *written == key)?;
+        Some(version)
+    });
+    versions.collect()
+}
+
+// decodes an entry of the history tree into the key written and its version.
+pub(crate) fn decode_version(k: &str, encoded: &str) -> Option<(String, Version)> {
+    let (key, inverted) = k.split_at_checked(k.len().checked_sub(16)?)?;
+    let key = key.strip_suffix('\0')?;
+    let seq = u64::MAX - u64::from_str_radix(inverted, 16).ok()?;
+    let (millis, value) = match encoded.split_once(' ') {
+        Some((millis, value)) => (millis, Some(value.to_string())),
+        None => (encoded, None),
+    };
+    let written = UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?);
+    let version = Version {
+        seq,
+        written,
+        value,
+    };
+    Some((key.to_string(), version))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{HistoryRetention, LSMTree, Options, WriteBatch, sim::SimStorage};
+
+    fn options(storage: &SimStorage, retention: HistoryRetention) -> Options {
+        Options {
+            storage: Arc::new(storage.clone()),
+            history_retention: Some(retention),
+            ..Options::default()
+        }
+    }
+
+    fn values(lsmtree: &LSMTree, key: &str) -> Vec<Option<String>> {
+        let history = lsmtree.get_history(key);
+        history.into_iter().map(|v| v.value).collect()
+    }
+
+    #[test]
+    fn test_history_keeps_last_versions() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = options(&storage, HistoryRetention::LastVersions(3));
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        for i in 1..=4 {
+            lsmtree.put("key", &format!("v{}", i)).unwrap();
+            // enough writes of other keys in between for the versions to be compacted apart.
+            for j in 0..10 {
+                lsmtree.put(&format!("other{}", j), "x").unwrap();
+            }
+        }
+        lsmtree.delete("key");
+        // a longer key starting with the same bytes has a history of its own.
+        lsmtree.put("key\0suffix", "other").unwrap();
+
+        let expected = vec![None, Some("v4".to_string()), Some("v3".to_string())];
+        assert_eq!(values(&lsmtree, "key"), expected);
+        let history = lsmtree.get_history("key");
+        assert!(history.windows(2).all(|w| w[0].seq > w[1].seq));
+        assert_eq!(lsmtree.get("key"), None);
+        lsmtree.close().unwrap();
+
+        let lsmtree = LSMTree::open_with_options("data", options);
+        assert_eq!(values(&lsmtree, "key"), expected);
+        assert_eq!(values(&lsmtree, "missing"), Vec::<Option<String>>::new());
+    }
+
+    #[test]
+    fn test_history_records_groups_and_prunes_on_flush() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = options(&storage, HistoryRetention::LastVersions(2));
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        let ops = storage.ops();
+        lsmtree.put("key", "v1").unwrap();
+        let put_ops = storage.ops() - ops;
+        let mut batch = WriteBatch::new();
+        batch.put("key", "v2");
+        batch.put("other", "x");
+        batch.put("key", "v3");
+        let ops = storage.ops();
+        lsmtree.write_batch(batch).unwrap();
+
+        // the batch went to the history tree as a single write of all its versions, so it took no
+        // more writes to storage than the single put.
+        assert_eq!(storage.ops() - ops, put_ops);
+        let recorded = |lsmtree: &LSMTree| {
+            let history = lsmtree.history.as_ref().unwrap();
+            history.scan_prefix("key\0").count()
+        };
+        assert_eq!(recorded(&lsmtree), 3);
+        let expected = vec![Some("v3".to_string()), Some("v2".to_string())];
+        assert_eq!(values(&lsmtree, "key"), expected);
+
+        // versions past the retention are only deleted once the memtable is flushed.
+        lsmtree.flush_memtable();
+        assert_eq!(recorded(&lsmtree), 2);
+        assert_eq!(values(&lsmtree, "key"), expected);
+    }
+
+    #[test]
+    fn test_history_keeps_versions_newer_than() {
+        let storage = SimStorage::new(1, Default::default());
+        let retention = HistoryRetention::NewerThan(Duration::from_secs(3600));
+        let mut lsmtree = LSMTree::open_with_options("data", options(&storage, retention));
+        lsmtree.put("key", "v1").unwrap();
+        storage.advance_clock(Duration::from_secs(7200));
+        lsmtree.put("key", "v2").unwrap();
+        lsmtree.put("key", "v3").unwrap();
+        assert_eq!(
+            values(&lsmtree, "key"),
+            vec![Some("v3".to_string()), Some("v2".to_string())]
+        );
+
+        // the current version is kept however old it gets.
+        storage.advance_clock(Duration::from_secs(7200));
+        assert_eq!(values(&lsmtree, "key"), vec![Some("v3".to_string())]);
+    }
+}
@@ -0,0 +1,132 @@
+//! Bulk loading of sorted data straight into sstables.
+//!
+//! Loading a large dataset with `put` sends every key through the log and the memtable, and then
+//! through flushes and compactions that rewrite it again and again. When the data already comes
+//! sorted, e.g. from a table file of another database or an export, `LSMTree::ingest` writes it
+//! into a new sstable in one pass instead, like a flush of a memtable holding all of it:
+//!
+//!   sorted entries -> | new sstable (+ blob file) | -> manifest
+//!
+//! Every ingested entry gets the same sequence number, the next one of the tree, so the ingested
+//! data is newer than anything the tree held, and older than any later write.
+
+use std::io;
+
+use crate::{LSMTree, Level, Record, Value};
+
+impl LSMTree {
+    // adds `entries`, pairs of a key and its value or `None` for a deleted key, to the tree as a
+    // single new sstable, without going through the log and the memtable. Returns the number of
+    // entries ingested. The entries must be sorted by key, without duplicates; if they aren't, an
+    // error is returned and nothing is ingested.
+    pub fn ingest(
+        &mut self,
+        entries: impl IntoIterator<Item = (String, Option<String>)>,
+    ) -> io::Result<usize> {
+        // the memtable is always read first, flushing it lets the ingested entries take
+        // precedence over the writes in it.
+        self.flush_memtable();
+        let seq = self.last_seq + 1;
+        let mgr = &mut self.sstable_mgr;
+
+        let (sst_file, sst_id) = mgr.new_sstable(0);
+        let mut writer = mgr.sstable_writer(sst_file, Level::L0);
+        writer.set_created(mgr.clock.now());
+        let mut blob_writer = mgr.blob_writer(sst_id);
+        let mut last: Option<String> = None;
+        let mut count = 0;
+        for (key, value) in entries {
+            if last.as_ref().is_some_and(|last| *last >= key) {
+                // the sstable isn't in the manifest, so it's removed on the next open, like one
+                // left behind by a crash.
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("entries to ingest aren't sorted, at key `{}`", key),
+                ));
+            }
+            let record = Record {
+                seq,
+                value: value.map(Value::Inline),
+            };
+            match &mut blob_writer {
+                Some(blobs) => writer.add(&key, &blobs.separate(&key, &record)?),
+                None => writer.add(&key, &record),
+            }
+            mgr.count_shadowed(std::iter::once(&key));
+            last = Some(key);
+            count += 1;
+        }
+        if count == 0 {
+            drop(writer);
+            mgr.storage
+                .remove(&mgr.data_dir.join(format!("{}.sst", sst_id)))?;
+            return Ok(0);
+        }
+        let mut sst_file = writer.finish();
+
+        sst_file.sync()?;
+        if let Some(blobs) = blob_writer
+            && blobs.finish()?
+        {
+            mgr.add_blob_file(sst_id);
+        }
+
+        self.last_seq = seq;
+        mgr.last_seq = seq;
+        mgr.add_sstable(sst_id);
+        self.compact();
+        self.sstable_mgr.move_cold_sstables();
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{LSMTree, Options, sim::SimStorage};
+
+    #[test]
+    fn test_ingest_overrides_older_writes() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            blob_threshold: Some(16),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        lsmtree.put("key1", "old1").unwrap();
+        lsmtree.flush_memtable();
+        // still in the memtable when the entries are ingested.
+        lsmtree.put("key2", "old2").unwrap();
+        lsmtree.put("key4", "old4").unwrap();
+
+        let entries = vec![
+            ("key1".to_string(), Some("new1".to_string())),
+            ("key2".to_string(), None),
+            (
+                "key3".to_string(),
+                Some("a value kept in a blob file".to_string()),
+            ),
+        ];
+        assert_eq!(lsmtree.ingest(entries).unwrap(), 3);
+        lsmtree.put("key3", "newer3").unwrap();
+
+        let expected = vec![
+            ("key1".to_string(), "new1".to_string()),
+            ("key3".to_string(), "newer3".to_string()),
+            ("key4".to_string(), "old4".to_string()),
+        ];
+        assert_eq!(lsmtree.scan(..).collect::<Vec<_>>(), expected);
+        lsmtree.close().unwrap();
+
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        assert_eq!(lsmtree.scan(..).collect::<Vec<_>>(), expected);
+        let unsorted = vec![
+            ("key6".to_string(), Some("6".to_string())),
+            ("key5".to_string(), Some("5".to_string())),
+        ];
+        assert!(lsmtree.ingest(unsorted).is_err());
+        assert_eq!(lsmtree.get("key6"), None);
+    }
+}
@@ -16,469 +16,4534 @@
 //! compaction on our sstables, which is simply removing
 //! older values for keys in the sstable, and removing tombstone values of keys (older deleted values).
 
+mod audit;
+mod background_io;
+mod backup;
+mod batch;
+mod blob;
+mod block;
+mod bloom;
+mod cache;
+mod checksum;
+mod clock;
+mod codec;
+mod compression;
+mod deadline;
+mod delta;
+mod direct_io;
+mod error;
+mod history;
+mod ingest;
+mod manifest;
+mod memtable;
+#[cfg(all(feature = "opfs", target_arch = "wasm32"))]
+pub mod opfs;
+mod options;
+#[cfg(feature = "parquet")]
+mod parquet;
+mod partition;
+mod plan;
+mod progress;
+#[cfg(feature = "python")]
+mod python;
+mod queued;
+mod recovery;
+mod report;
+#[cfg(feature = "rocksdb")]
+mod rocksdb;
+mod sharded;
+mod sidecar;
+pub mod sim;
+mod snapshot;
+mod sstable;
+mod standby;
+pub mod storage;
+#[cfg(feature = "async")]
+mod stream;
+pub mod testkit;
+mod transaction;
+mod varint;
+mod wal;
+
 use std::{
-    collections::{BTreeMap, VecDeque},
-    fs::File,
-    io::{BufRead, BufReader, Write},
-    path::PathBuf,
+    collections::{BTreeMap, HashSet, VecDeque},
+    io::{self, BufWriter, Read, Write},
+    iter::Peekable,
+    ops::{Bound, Range, RangeBounds},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
-use std::fmt::Write as _;
+use audit::AuditLog;
+pub use audit::{AuditContext, AuditEntry, AuditOp};
+pub use batch::{BatchScanIter, WriteBatch, WriteBatchWithIndex};
+pub use blob::ValueReader;
+use blob::{BlobFile, BlobPointer, BlobWriter};
+pub use cache::BlockCache;
+use clock::StorageClock;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use codec::RecordFormat;
+pub use compression::Compression;
+pub use deadline::{CancellationToken, Deadline, DeadlineScanIter};
+pub use delta::{Change, DiffIter};
+pub use error::Error;
+pub use history::Version;
+use manifest::{Manifest, ManifestLog};
+use memtable::Memtable;
+pub use options::{
+    ColdTierOptions, CompactionSchedule, CompactionStrategy, HistoryRetention, IoPriority, Options,
+    PrefixExtractor, WalArchiveOptions, WalRecoveryMode,
+};
+pub use plan::CompactionPlan;
+pub use progress::{Job, JobProgress, ProgressCallback};
+use progress::{ProgressSlot, ProgressTracker, entry_size};
+pub use queued::QueuedLsmTree;
+use recovery::RecoveryReporter;
+pub use recovery::{RecoveryCallback, RecoveryPhase, RecoveryProgress};
+pub use report::{SSTableInfo, SpaceAmplification};
+pub use sharded::{ShardedLsmTree, ShardedScanIter};
+use sidecar::sidecar_path;
+pub use snapshot::Snapshot;
+use snapshot::SnapshotRegistry;
+pub use sstable::{CorruptFile, VerifyReport};
+use sstable::{SSTable, SSTableEntries, SSTableWriter, TableCache, TableOptions};
+pub use standby::Standby;
+use storage::{ReadOnlyStorage, Storage, WritableFile};
+#[cfg(feature = "async")]
+pub use stream::ScanStream;
+pub use transaction::{Transaction, TransactionalLsmTree};
+use wal::Wal;
+pub use wal::{WalOp, WalReader, WalRecord, WalRecords};
 
-// This is a byte marker used to denote a deletion in LSM Tree SSTable files.
-// 💡 Actual implementations use something different, like a 0x01 (in rocksdb and leveldb)
+// The value that stood for a deletion in sstables written before records had a kind, which made a
+// value of `🪦` indistinguishable from a delete. Records are now marked as deletions by their kind,
+// in the sstables and the log alike, so any value can be stored. The marker is only still read
+// from those older files, see `codec.rs`.
+// 💡 LevelDB and RocksDB tag every record with a type byte too, 0x0 for a deletion and 0x1 for a
+// value.
 const TOMBSTONE_MARKER: char = '🪦';
 
+// the file in the data dir listing the blocks that were cached when the tree was closed.
+const HOT_BLOCKS_FILE: &str = "HOT_BLOCKS";
+
+// A value stored for a key in the memtable or an sstable, along with the sequence number of the
+// write that produced it. Every write gets the next sequence number, so a higher sequence number
+// always means a newer version of the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    seq: u64,
+    // `None` denotes a deleted key.
+    value: Option<Value>,
+}
+
+// Where the value of a record is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Inline(String),
+    // a large value moved to a blob file when its record was flushed, see `blob.rs`.
+    Blob(BlobPointer),
+}
+
+// The outcome of looking up a key in the memtable or a single sstable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Lookup {
+    Found(Value),
+    // the newest record of the key is a tombstone, older sstables must not be consulted.
+    Deleted,
+    NotFound,
+}
+
+impl From<Option<&Record>> for Lookup {
+    fn from(record: Option<&Record>) -> Self {
+        match record.map(|r| &r.value) {
+            Some(Some(v)) => Lookup::Found(v.clone()),
+            Some(None) => Lookup::Deleted,
+            None => Lookup::NotFound,
+        }
+    }
+}
+
+// A sorted stream of keys and their records.
+type Entries = Box<dyn Iterator<Item = (String, Record)> + Send>;
+
 pub struct LSMTree {
-    memtable: BTreeMap<String, Option<String>>,
+    memtable: Memtable,
     memtable_limit: usize,
+    // the options the tree was opened with, as changed by `set_option` since.
+    options: Options,
+    // how long the latest flushes and compactions took, oldest first, see `report.rs`.
+    recent_flushes: VecDeque<Duration>,
+    recent_compactions: VecDeque<Duration>,
+    // sequence number of the latest write.
+    last_seq: u64,
+    // every write in the memtable is also in the log, so that it survives a crash. `None` for trees
+    // opened read-only.
+    wal: Option<Wal>,
     sstable_mgr: SSTableManager,
+    // the older versions of keys, when `Options::history_retention` is set, see `history.rs`.
+    history: Option<Box<LSMTree>>,
+    // where writes are audited, when `Options::audit_log` is set. `None` for trees opened
+    // read-only.
+    audit: Option<AuditLog>,
+    // the snapshots taken of the tree, see `snapshot.rs`.
+    snapshots: SnapshotRegistry,
+}
+
+impl Default for LSMTree {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LSMTree {
     // creates a new instance of LSM Tree
     pub fn new() -> Self {
-        let data_dir = PathBuf::from("data");
-        if !data_dir.exists() {
-            std::fs::create_dir(&data_dir).unwrap();
+        Self::open("data")
+    }
+
+    // creates a new instance of LSM Tree that keeps its sstables in the given `data_dir`
+    pub fn open(data_dir: impl AsRef<Path>) -> Self {
+        Self::open_with_options(data_dir, Options::default())
+    }
+
+    // creates a new instance of LSM Tree in `data_dir`, configured with the given `options`.
+    pub fn open_with_options(data_dir: impl AsRef<Path>, options: Options) -> Self {
+        Self::open_inner(data_dir.as_ref(), options, false)
+    }
+
+    // opens the tree in `data_dir` for reading only, like a checkpoint (see `checkpoint`) or the
+    // data dir of a tree that another process has open. Nothing in the data dir is modified: the
+    // log is replayed without repairing it, files left behind by crashes aren't removed and no
+    // compaction runs, while writes panic. The tree is read as of when it's opened, writes that
+    // another process makes afterwards aren't seen.
+    // Reading the data dir of a running tree only works as long as that tree doesn't remove the
+    // files it was opened with, so a checkpoint is the safe way to read a tree from elsewhere.
+    pub fn open_read_only(data_dir: impl AsRef<Path>, mut options: Options) -> Self {
+        options.storage = Arc::new(ReadOnlyStorage(options.storage));
+        if let Some(tier) = &mut options.cold_tier {
+            tier.storage = Arc::new(ReadOnlyStorage(Arc::clone(&tier.storage)));
+        }
+        Self::open_inner(data_dir.as_ref(), options, true)
+    }
+
+    fn open_inner(data_dir: &Path, options: Options, read_only: bool) -> Self {
+        let data_dir = data_dir.to_path_buf();
+        if !read_only && !options.storage.exists(&data_dir) {
+            options.storage.create_dir_all(&data_dir).unwrap();
+        }
+        if let Err(e) = options.check_compatible(&data_dir) {
+            panic!("{}", e);
+        }
+        if !read_only {
+            options.save(&data_dir).unwrap();
+        }
+        let opened_with = options.clone();
+        let recovery = RecoveryReporter::new(options.recovery_callback.clone());
+
+        let history_dir = data_dir.join(history::HISTORY_DIR);
+        let history = match &options.history_retention {
+            Some(_) if !read_only || options.storage.exists(&history_dir) => {
+                let history_options = Options {
+                    history_retention: None,
+                    audit_log: false,
+                    cold_tier: None,
+                    wal_archive: None,
+                    recovery_callback: None,
+                    // versions are stored under longer keys than the ones written.
+                    max_key_size: None,
+                    max_value_size: None,
+                    ..options.clone()
+                };
+                let history = Self::open_inner(&history_dir, history_options, read_only);
+                Some(Box::new(history))
+            }
+            _ => None,
+        };
+
+        let clock: Arc<dyn Clock> = match &options.clock {
+            Some(clock) => Arc::clone(clock),
+            None => Arc::new(StorageClock(Arc::clone(&options.storage))),
+        };
+        let (wal, memtable) = if read_only {
+            let storage = &*options.storage;
+            let mode = options.wal_recovery_mode;
+            let memtable = Wal::read(storage, &data_dir, mode, &recovery).unwrap();
+            (None, memtable)
+        } else {
+            let (wal, memtable) = Wal::open(
+                Arc::clone(&options.storage),
+                &data_dir,
+                options.wal_recovery_mode,
+                options.record_format.codec(),
+                options.wal_archive.clone(),
+                Arc::clone(&clock),
+                &recovery,
+            )
+            .unwrap();
+            (Some(wal), memtable)
+        };
+
+        let audit = match options.audit_log && !read_only {
+            true => {
+                let storage = Arc::clone(&options.storage);
+                Some(AuditLog::open(storage, Arc::clone(&clock), &data_dir).unwrap())
+            }
+            false => None,
+        };
+
+        if let Some(tier) = &options.cold_tier
+            && !read_only
+            && !tier.storage.exists(&tier.dir)
+        {
+            tier.storage.create_dir_all(&tier.dir).unwrap();
         }
 
-        let mut sstable_mgr = SSTableManager::new(&data_dir);
-        sstable_mgr.recover();
+        let mut sstable_mgr = SSTableManager::new(options.storage, &data_dir);
+        sstable_mgr.read_only = read_only;
+        sstable_mgr.clock = clock;
+        sstable_mgr.progress_callback = options.progress_callback;
+        sstable_mgr.compaction_trigger = options.compaction_trigger;
+        sstable_mgr.compaction_strategy = options.compaction_strategy;
+        sstable_mgr.intra_l0_compaction_trigger = options.intra_l0_compaction_trigger;
+        sstable_mgr.sstable_ttl = options.sstable_ttl;
+        sstable_mgr.compaction_schedule = options.compaction_schedule;
+        sstable_mgr.blob_threshold = options.blob_threshold;
+        sstable_mgr.cold_tier = options.cold_tier;
+        sstable_mgr.table_options = TableOptions {
+            block_size: options.block_size,
+            block_restart_interval: options.block_restart_interval,
+            bloom_bits_per_key: options.bloom_bits_per_key,
+            prefix_extractor: options.prefix_extractor,
+            compression: options.compression,
+            dictionary_size: 0,
+            codec: options.record_format.codec(),
+        };
+        sstable_mgr.compaction_compression = options.compaction_compression;
+        sstable_mgr.bottommost_compression = options.bottommost_compression;
+        sstable_mgr.compression_dictionary_size = options.compression_dictionary_size;
+        sstable_mgr.block_cache = options.block_cache;
+        sstable_mgr.readahead_size = options.readahead_size;
+        sstable_mgr.drop_compaction_inputs = options.drop_compaction_inputs_from_cache;
+        sstable_mgr.direct_io = options.use_direct_io_for_flush_and_compaction;
+        sstable_mgr.preallocate = options.preallocate_sstables;
+        sstable_mgr.write_buffer_size = options.sstable_write_buffer_size;
+        sstable_mgr.upgrade_text_sstables = options.upgrade_text_sstables_on_compaction;
+        sstable_mgr.recover(&recovery);
+        if options.preload_metadata {
+            sstable_mgr.preload_metadata();
+        }
+        sstable_mgr.load_hot_blocks(&recovery);
+        // continue numbering writes after the newest one persisted in the sstables or the log.
+        let memtable = Memtable::from(memtable);
+        let last_seq = sstable_mgr.last_seq.max(memtable.last_seq());
 
+        recovery.finish(RecoveryPhase::Done, 0);
         Self {
-            memtable: BTreeMap::new(),
-            memtable_limit: 10,
+            memtable,
+            memtable_limit: options.memtable_limit,
+            options: opened_with,
+            recent_flushes: VecDeque::new(),
+            recent_compactions: VecDeque::new(),
+            last_seq,
+            wal,
             sstable_mgr,
+            history,
+            audit,
+            snapshots: SnapshotRegistry::default(),
         }
     }
 
     // add k and v into the memtable
-    pub fn put(&mut self, k: &str, v: &str) {
-        self.memtable.insert(k.to_string(), Some(v.to_string()));
-        if self.memtable.len() == self.memtable_limit {
+    // Fails, leaving k as it was, if k or v is larger than `Options::max_key_size` or
+    // `Options::max_value_size` allow.
+    pub fn put(&mut self, k: &str, v: &str) -> Result<(), Error> {
+        self.put_audited(k, v, &AuditContext::default())
+    }
+
+    // adds k and v like `put`, recording `context` with the write in the audit log, see `audit.rs`.
+    pub fn put_audited(&mut self, k: &str, v: &str, context: &AuditContext) -> Result<(), Error> {
+        self.check_size(k, v.len())?;
+        self.write(k, Some(Value::Inline(v.to_string())), context);
+        if self.memtable.len() >= self.memtable_limit {
             self.flush_memtable();
         }
+        Ok(())
     }
 
-    // return the value associated with the given key
-    pub fn get(&self, k: &str) -> Option<String> {
-        match self.memtable.get(k) {
-            Some(Some(v)) => return Some(v.to_string()),
-            Some(None) => return None,
-            None => {
-                for i in self.sstable_mgr.sstables.iter().rev() {
-                    match self.sstable_mgr.get_sstable(*i, k) {
-                        Some(v) => return Some(v.clone()),
-                        None => {}
-                    }
-                }
-            }
+    // adds k with the value read from `value` until its end, without holding all of it in memory.
+    // The value is streamed into a blob file of its own, and only a pointer to it goes into the
+    // memtable. Meant for values of several megabytes, smaller ones are better off with `put`.
+    // Fails if reading from `value` fails or it isn't valid UTF-8, in which case k is left as it was.
+    // Also fails if k is larger than `Options::max_key_size`. The value goes to a blob file rather
+    // than an sstable, so `Options::max_value_size` doesn't apply.
+    pub fn put_reader(&mut self, k: &str, value: impl Read) -> io::Result<()> {
+        self.check_size(k, 0)?;
+        let pointer = self.sstable_mgr.write_blob(k, value)?;
+        self.write(k, Some(Value::Blob(pointer)), &AuditContext::default());
+        if self.memtable.len() >= self.memtable_limit {
+            self.flush_memtable();
         }
+        Ok(())
+    }
 
-        None
+    // checks `k` and a value of `value_size` bytes against `Options::max_key_size` and
+    // `Options::max_value_size`.
+    pub(crate) fn check_size(&self, k: &str, value_size: usize) -> Result<(), Error> {
+        if let Some(max) = self.options.max_key_size
+            && k.len() > max
+        {
+            return Err(Error::KeyTooLarge { size: k.len(), max });
+        }
+        if let Some(max) = self.options.max_value_size
+            && value_size > max
+        {
+            return Err(Error::ValueTooLarge {
+                size: value_size,
+                max,
+            });
+        }
+        Ok(())
     }
 
-    // deletes the value associated with the given key `k`
-    // NOTE: deletes are just a put in disguise in an LSM Tree, with None as the value in this case.
-    pub fn delete(&mut self, k: &str) {
-        self.memtable.insert(k.to_string(), None);
+    // logs and inserts a new version of key `k` into the memtable under the next sequence number.
+    fn write(&mut self, k: &str, value: Option<Value>, context: &AuditContext) {
+        let record = Record {
+            seq: self.last_seq + 1,
+            value,
+        };
+        if let Some(audit) = &mut self.audit {
+            audit.append(&[(k, &record, context)]).unwrap();
+        }
+        let wal = self.wal.as_mut().expect("the tree was opened read-only");
+        wal.append(k, &record).unwrap();
+        self.last_seq = record.seq;
+        self.record_history(&[(k, &record)]);
+        self.memtable.insert(k.to_string(), record);
     }
 
-    // flushes the memtable contents to a file
-    fn flush_memtable(&mut self) {
-        if self.memtable.is_empty() {
+    // logs and applies a group of writes, pairs of a key and its value or `None` for a delete, in
+    // order, atomically and with a single sync of the log for all of them. See `queued.rs` and
+    // `transaction.rs`.
+    pub(crate) fn write_group(&mut self, writes: Vec<(String, Option<String>)>) {
+        let records: Vec<(String, Record)> = (writes.into_iter())
+            .zip(self.last_seq + 1..)
+            .map(|((k, v), seq)| {
+                let value = v.map(Value::Inline);
+                (k, Record { seq, value })
+            })
+            .collect();
+        let Some((_, newest)) = records.last() else {
             return;
+        };
+        if let Some(audit) = &mut self.audit {
+            let context = AuditContext::default();
+            let group: Vec<_> = (records.iter())
+                .map(|(k, r)| (k.as_str(), r, &context))
+                .collect();
+            audit.append(&group).unwrap();
         }
+        let wal = self.wal.as_mut().expect("the tree was opened read-only");
+        let group: Vec<(&str, &Record)> = records.iter().map(|(k, r)| (k.as_str(), r)).collect();
+        wal.append_group(&group).unwrap();
+        self.last_seq = newest.seq;
+        self.record_history(&group);
+        for (k, record) in records {
+            self.memtable.insert(k, record);
+        }
+        if self.memtable.len() >= self.memtable_limit {
+            self.flush_memtable();
+        }
+    }
 
-        let (mut sst_file, sst_id) = self.sstable_mgr.new_sstable();
+    // return the value associated with the given key
+    // The newest record of the key wins, so we stop at the first source that has one, even if it's a
+    // tombstone. Otherwise a deleted key would come back with the value from an older sstable.
+    pub fn get(&self, k: &str) -> Option<String> {
+        match self.lookup(k) {
+            Lookup::Found(v) => Some(self.sstable_mgr.read_value(v)),
+            _ => None,
+        }
+    }
 
-        for (k, v) in &self.memtable {
-            let mut line = String::new();
-            match v {
-                Some(v) => {
-                    writeln!(&mut line, "{}:{}", k, v).unwrap();
-                    sst_file.write_all(line.as_bytes()).unwrap();
-                }
-                None => {
-                    writeln!(&mut line, "{}:{}", k, TOMBSTONE_MARKER).unwrap();
-                    sst_file.write_all(line.as_bytes()).unwrap();
-                }
+    // returns a reader over the value associated with the given key, which reads values kept in
+    // blob files from disk as it's consumed, instead of loading them into memory upfront.
+    pub fn get_stream(&self, k: &str) -> Option<ValueReader> {
+        match self.lookup(k) {
+            Lookup::Found(Value::Inline(v)) => Some(ValueReader::inline(v)),
+            Lookup::Found(Value::Blob(pointer)) => {
+                let blob_file = self.sstable_mgr.blob_file(&pointer);
+                Some(blob_file.open_value(&pointer).unwrap())
             }
+            _ => None,
         }
+    }
 
-        sst_file.sync_data().unwrap();
-
-        self.memtable.clear();
+    // copies the value associated with the given key into `out`, returning false if there's none.
+    pub fn get_writer(&self, k: &str, mut out: impl Write) -> io::Result<bool> {
+        match self.get_stream(k) {
+            Some(mut value) => io::copy(&mut value, &mut out).map(|_| true),
+            None => Ok(false),
+        }
+    }
 
-        self.sstable_mgr.add_sstable(sst_id);
-        self.compact();
+    // finds the newest record of the given key `k`, without reading its value from a blob file.
+    fn lookup(&self, k: &str) -> Lookup {
+        (self.lookup_until(k, &Deadline::default())).expect("lookups without a deadline can't fail")
     }
 
-    // Performs compaction of sstables if compaction condition is triggered.
-    fn compact(&mut self) {
-        if self.sstable_mgr.should_compact() {
-            self.sstable_mgr.compact_sstables();
+    // like `lookup`, giving up if `deadline` passes before it's done with the sstables.
+    fn lookup_until(&self, k: &str, deadline: &Deadline) -> Result<Lookup, Error> {
+        let memtable = Lookup::from(self.memtable.get(k));
+        if memtable != Lookup::NotFound {
+            return Ok(memtable);
+        }
+        for sst in self.sstable_mgr.sstables.iter().rev() {
+            deadline.check(&*self.sstable_mgr.clock)?;
+            let lookup = self.sstable_mgr.get_sstable(sst, k);
+            if lookup != Lookup::NotFound {
+                return Ok(lookup);
+            }
         }
+        Ok(Lookup::NotFound)
     }
 
-    // helper for tests, that performs compaction, regardless of trigger condition.
-    fn force_compact(&mut self) {
-        self.sstable_mgr.compact_sstables();
+    // returns an iterator over the live key value pairs within `range`, in sorted key order.
+    // The iterator holds a snapshot of the list of sstables, so a compaction running while the
+    // scan is in progress won't remove files from underneath it.
+    pub fn scan<'a>(&self, range: impl RangeBounds<&'a str>) -> ScanIter {
+        self.scan_sstables(KeyRange::from(range), self.sstable_mgr.sstables.iter())
     }
-}
-
-// A convenient wrapper struct that manages SSTables and issues new file ids to newly created SSTable files.
-struct SSTableManager {
-    // Directory where the sstables resides.
-    data_dir: PathBuf,
-    // a naive incrementing counter for file ids. 💡 Actual implementations use a combination of timestamp and unique identifiers.
-    next_sstable_id: usize,
-    // A list of sstables created in the past.
-    sstables: VecDeque<usize>,
-    // used to check if compaction can be triggered - it's simply max count of files in the data directory.
-    compaction_trigger: usize,
-}
 
-impl SSTableManager {
-    pub fn new(path_buf: &PathBuf) -> Self {
-        SSTableManager {
-            data_dir: path_buf.clone(),
-            next_sstable_id: 0,
-            sstables: VecDeque::new(),
-            compaction_trigger: 8,
-        }
+    // returns an iterator over the live key value pairs whose key starts with `prefix`.
+    // With a prefix extractor configured, sstables whose filters show no key with that prefix
+    // are skipped entirely.
+    pub fn scan_prefix(&self, prefix: &str) -> ScanIter {
+        let extractor = self.sstable_mgr.table_options.prefix_extractor.as_ref();
+        let sstables = self
+            .sstable_mgr
+            .sstables
+            .iter()
+            .filter(|sst| extractor.is_none_or(|e| sst.may_contain_prefix(e, prefix)));
+        self.scan_sstables(KeyRange::prefix(prefix), sstables)
     }
 
-    pub fn new_sstable(&mut self) -> (File, usize) {
-        self.next_sstable_id += 1;
+    // merges the memtable and the given `sstables`, ordered oldest first, over `range`.
+    fn scan_sstables<'a>(
+        &self,
+        range: KeyRange,
+        sstables: impl DoubleEndedIterator<Item = &'a Arc<SSTable>>,
+    ) -> ScanIter {
+        // the memtable is small, so we simply copy the entries within range for the iterator to own.
+        let memtable = self.memtable.range(&range);
 
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(self.data_dir.join(&format!("{}.sst", self.next_sstable_id)))
-            .unwrap();
+        let mut sources: Vec<Peekable<Entries>> =
+            vec![(Box::new(memtable.into_iter()) as Entries).peekable()];
+        // newest sstable first, so that on duplicate keys the source with the lowest index wins.
+        for sst in sstables.rev() {
+            let cache = self.sstable_mgr.table_cache(sst);
+            let readahead = self.sstable_mgr.readahead_size;
+            let entries = SSTableEntries::open(sst, &range, cache, readahead);
+            sources.push((Box::new(entries) as Entries).peekable());
+        }
 
-        (file, self.next_sstable_id)
+        ScanIter {
+            sources,
+            range,
+            _pinned: Arc::clone(&self.sstable_mgr.sstables),
+            blob_files: self.sstable_mgr.blob_files.clone(),
+            key_filters: Vec::new(),
+            value_filters: Vec::new(),
+        }
     }
 
-    // Adds the give sstable id to the queue of sstables.
-    pub fn add_sstable(&mut self, id: usize) {
-        self.sstables.push_back(id);
+    // returns an iterator over the live keys within `range`, in sorted key order. Unlike `scan`, it
+    // never reads the values that are kept in blob files.
+    pub fn keys<'a>(&self, range: impl RangeBounds<&'a str>) -> KeysIter {
+        KeysIter {
+            scan: self.scan(range),
+        }
     }
 
-    // retrieves the given key `k` from the list of sstables.
-    pub fn get_sstable(&self, sst_file_id: usize, key: &str) -> Option<String> {
-        let mut file = std::fs::OpenOptions::new()
-            .read(true)
-            .open(self.data_dir.join(&format!("{}.sst", sst_file_id)))
-            .unwrap();
+    // returns the number of live keys within `range`. Like `keys`, it merges the memtable and all
+    // sstables to skip deleted and overwritten keys, but never reads a value from a blob file.
+    pub fn count_range<'a>(&self, range: impl RangeBounds<&'a str>) -> usize {
+        self.keys(range).count()
+    }
 
-        let mut buf_reader = BufReader::new(file);
+    // estimates the number of live keys within `range` without reading any data block: the memtable
+    // is counted exactly, each sstable goes by its entry counts and the share of its blocks that
+    // overlap the range (see `SSTable::approximate_count`). Keys overwritten or deleted in a newer
+    // sstable are counted once per sstable they're in, so the estimate errs on the high side when
+    // compaction is behind.
+    // 💡 RocksDB's `GetApproximateSizes` and `GetApproximateMemTableStats` estimate the same way,
+    // from index blocks and table properties.
+    pub fn approximate_count_range<'a>(&self, range: impl RangeBounds<&'a str>) -> u64 {
+        let range = KeyRange::from(range);
+        let memtable = (self.memtable.range(&range).iter())
+            .filter(|(_, record)| record.value.is_some())
+            .count() as u64;
+        let sstables: u64 = self
+            .sstable_mgr
+            .sstables
+            .iter()
+            .map(|sst| sst.approximate_count(&range))
+            .sum();
+        memtable + sstables
+    }
 
-        for l in buf_reader.lines() {
-            let (k, v) = read_kv_line(&l);
-            if k == key {
-                if v == TOMBSTONE_MARKER.to_string() {
-                    return None;
-                } else {
-                    return Some(v.to_string());
-                }
-            }
+    // returns up to `n` keys within `range`, in order and roughly evenly spaced by the amount of
+    // data between them, without reading any data block. Sampled keys are the last keys of the
+    // sstables' data blocks, so each stands for about a block of entries and may since have been
+    // deleted. Meant for picking split points when dividing a range between shards or workers, and
+    // for estimating how far along a scan is. Fewer than `n` keys are returned when the range spans
+    // fewer blocks, none while all of its keys are still in the memtable.
+    // 💡 Bigtable and HBase pick split points from their sstables' block indexes the same way.
+    pub fn sample_keys<'a>(&self, range: impl RangeBounds<&'a str>, n: usize) -> Vec<String> {
+        let range = KeyRange::from(range);
+        let mut keys: Vec<String> = self
+            .sstable_mgr
+            .sstables
+            .iter()
+            .flat_map(|sst| sst.index_keys(&range))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        if keys.len() <= n {
+            return keys;
         }
+        // the key at the end of each of `n` equal slices of the candidates.
+        (1..=n)
+            .map(|i| keys[i * keys.len() / n - 1].clone())
+            .collect()
+    }
 
-        None
+    // returns an iterator over the live values of the keys within `range`, in the sorted order of
+    // their keys.
+    pub fn values<'a>(&self, range: impl RangeBounds<&'a str>) -> ValuesIter {
+        ValuesIter {
+            scan: self.scan(range),
+        }
     }
 
-    // recovers the ids of sstables from the data dir.
-    fn recover(&mut self) {
-        // We're using the helper function `files_with_extension` to get file list, else initializing
-        // with an empty vec.
-        let old_sst_ids = if let Ok(old_sst_files) = files_with_extension(&self.data_dir, "sst") {
-            let mut files: Vec<usize> = old_sst_files
-                .map(|p| {
-                    p.display()
-                        .to_string()
-                        .trim_end_matches(".sst")
-                        .trim_start_matches("data/")
-                        .parse()
-                        .unwrap()
-                })
-                .collect();
-            // smaller ids at first, being the oldest.
-            files.sort();
-            files
-        } else {
-            vec![]
+    // returns up to `limit` live key value pairs within `range`, in sorted key order, starting
+    // after `cursor` if given, along with a cursor to pass in for the next page, or `None` once the
+    // range is exhausted. Unlike `scan`, no iterator is held between pages, so an HTTP API can hand
+    // the cursor to its client (it converts to and from a string) and serve each page as a separate
+    // request. Pages are read independently: writes between two pages show up in the later one if
+    // they fall after the cursor.
+    pub fn scan_page<'a>(
+        &self,
+        range: impl RangeBounds<&'a str>,
+        limit: usize,
+        cursor: Option<Cursor>,
+    ) -> (Vec<(String, String)>, Option<Cursor>) {
+        let mut range = KeyRange::from(range);
+        if let Some(cursor) = cursor {
+            range.start = Bound::Excluded(cursor.after);
+        }
+        let mut scan = self.scan_sstables(range, self.sstable_mgr.sstables.iter());
+        let page: Vec<(String, String)> = scan.by_ref().take(limit).collect();
+        // check for another live key without reading its value.
+        let more = limit > 0 && (KeysIter { scan }).next().is_some();
+        let next = match page.last() {
+            Some((k, _)) if more => Some(Cursor { after: k.clone() }),
+            _ => None,
         };
-
-        self.sstables = old_sst_ids.into();
+        (page, next)
     }
 
-    fn should_compact(&mut self) -> bool {
-        self.sstables.len() == self.compaction_trigger
+    // calls `f` with every live key value pair within `range`, in sorted key order. Unlike `scan`,
+    // the pairs are lent to `f` rather than handed over, so callers that only aggregate over a range
+    // don't pay for copying them out.
+    pub fn for_each_in_range<'a>(
+        &self,
+        range: impl RangeBounds<&'a str>,
+        mut f: impl FnMut(&str, &str),
+    ) {
+        self.fold_range(range, (), |(), k, v| f(k, v))
     }
 
-    // Compacts sstables.
-    // In this toy implementation, we only take the oldest two sstables and attempt to merge duplicates or deletes from them one by one, using the merge
-    // algorithm from merge sort.
-    // once that is done, we rename the merged file to the 2nd oldest file, remove the oldest file from the data directory
-    // and pop remove the associated id of the file from the `sstables` queue
-    fn compact_sstables(&mut self) {
-        // bail early if we don't have enough required sstables to compact from.
-        if self.sstables.len() < 2 {
-            return;
+    // folds the live key value pairs within `range`, in sorted key order, into a single value,
+    // e.g. `tree.fold_range(.., 0, |n, _, v| n + v.len())` for the total size of the values.
+    pub fn fold_range<'a, B>(
+        &self,
+        range: impl RangeBounds<&'a str>,
+        init: B,
+        mut f: impl FnMut(B, &str, &str) -> B,
+    ) -> B {
+        let mut scan = self.scan(range);
+        let mut acc = init;
+        while let Some((key, record)) = scan.next_record() {
+            acc = match &record.value {
+                Some(Value::Inline(v)) => f(acc, &key, v),
+                Some(Value::Blob(pointer)) => {
+                    let blob_file = find_blob_file(&scan.blob_files, pointer);
+                    f(acc, &key, &blob_file.read(pointer).unwrap())
+                }
+                None => acc,
+            };
         }
+        acc
+    }
 
-        // 1. pick the oldest two sstable and create a BufReader from them.
-        let s1_path = self.data_dir.join(format!("{}.sst", self.sstables[0]));
-        let sstable = std::fs::OpenOptions::new()
-            .read(true)
-            .open(&s1_path)
-            .unwrap();
-        let s1_buf = BufReader::new(sstable);
+    // returns an iterator over the keys within `range` that were deleted, in key order, along with
+    // the sequence number of their delete. Meant for systems that mirror the tree, like a search
+    // index or a cache, to apply deletes without diffing full copies of the data: everything newer
+    // than the last sequence number a mirror synced up to is a delete it has yet to apply.
+    // Deletes are only found as long as their tombstones are in the tree, and tombstones go away
+    // once a compaction finds no older sstable that may still have their key. A mirror that falls
+    // further behind than that has to start over from a scan.
+    // 💡 Change data capture from the write-ahead log (see `WalReader`) sees every delete, but only
+    // as long as the log segments are archived.
+    pub fn deletions<'a>(&self, range: impl RangeBounds<&'a str>) -> DeletionIter {
+        DeletionIter {
+            scan: self.scan(range),
+        }
+    }
 
-        let s2_path = self.data_dir.join(format!("{}.sst", self.sstables[1]));
-        let sstable = std::fs::OpenOptions::new()
-            .read(true)
-            .open(&s2_path)
-            .unwrap();
-        let s2_buf = BufReader::new(sstable);
+    // re-reads the sstables holding keys within `range` (all of them if `None`) and validates every
+    // block checksum, without serving any data. Meant for scheduled integrity audits.
+    pub fn verify_checksums(&self, range: Option<KeyRange>) -> VerifyReport {
+        let range = range.unwrap_or_else(KeyRange::all);
+        let mut report = VerifyReport::default();
+        for sst in self.sstable_mgr.sstables.iter() {
+            report.files_checked += 1;
+            match sstable::verify(&*sst.storage, &sst.path, &range) {
+                Ok(blocks) => report.blocks_checked += blocks,
+                Err(reason) => report.corrupt_files.push(CorruptFile {
+                    path: sst.path.clone(),
+                    reason,
+                }),
+            }
+        }
+        report
+    }
 
-        // 2. create a lines iterator out of them
-        let mut s1_lines = s1_buf.lines();
-        let mut s2_lines = s2_buf.lines();
+    // returns the number of entries and files the tree holds at the moment.
+    pub fn stats(&self) -> Stats {
+        let sstables = &self.sstable_mgr.sstables;
+        let (snapshots, pinned_bytes) = self.snapshot_stats();
+        let data_sizes = sstables.iter().filter_map(|sst| match sst.data_sizes()? {
+            (stored, Some(raw)) => Some((stored, raw)),
+            _ => None,
+        });
+        let (data_bytes, raw_data_bytes) =
+            data_sizes.fold((0, 0), |(s, r), (stored, raw)| (s + stored, r + raw));
+        Stats {
+            memtable_entries: self.memtable.len(),
+            sstables: sstables.len(),
+            sstable_bytes: sstables
+                .iter()
+                .map(|sst| sst.storage.len(&sst.path).unwrap_or(0))
+                .sum(),
+            blob_files: self.sstable_mgr.blob_files.len(),
+            last_flush: self.sstable_mgr.flush_progress.lock().unwrap().clone(),
+            last_compaction: self.sstable_mgr.compaction_progress.lock().unwrap().clone(),
+            snapshots,
+            pinned_bytes,
+            data_bytes,
+            raw_data_bytes,
+            bytes_read: sstables.iter().map(|sst| sst.bytes_read()).sum(),
+        }
+    }
 
-        // 3. create two variable thar points to first line from both the sstable files.
-        let mut s1_next = s1_lines.next();
-        let mut s2_next = s2_lines.next();
+    // reads the data blocks that may hold keys within `range` into the block cache, so that the
+    // first reads of those keys don't have to go to disk. Returns the number of blocks read, which
+    // is 0 without a block cache.
+    pub fn warm_cache<'a>(&self, range: impl RangeBounds<&'a str>) -> usize {
+        let range = KeyRange::from(range);
+        self.sstable_mgr
+            .warm_cache(|_, prev_last_key, last_key, _| {
+                !range.is_before_start(last_key)
+                    && !prev_last_key.is_some_and(|p| range.is_past_end(p))
+            })
+    }
 
-        // 4. create a merged map that will store the merged key and values from the two files.
-        let mut merged_map: BTreeMap<String, String> = BTreeMap::new();
-        // 5. loop over the cursor for both files and do a match and merge them into a single sstable comparing the keys.
-        loop {
-            match (&s1_next, &s2_next) {
-                (Some(line_s1), Some(line_s2)) => {
-                    let (s1_k, s1_v) = read_kv_line(line_s1);
-                    let (s2_k, s2_v) = read_kv_line(line_s2);
-                    // TODO: compare the keys and push to `merged_map` accordingly and increment the respective line iterator.
-                    if s1_k <= s2_k {
-                        merged_map.insert(s1_k, s1_v);
-                        s1_next = s1_lines.next();
-                    } else {
-                        merged_map.insert(s2_k, s2_v);
-                        s2_next = s2_lines.next();
-                    }
-                }
-                (None, Some(line_s2)) => {
-                    let (s2_k, s2_v) = read_kv_line(line_s2);
-                    // TODO: insert s2_k into merged map and advance its iterator.
-                    merged_map.insert(s2_k, s2_v);
-                    s2_next = s2_lines.next();
-                }
-                (Some(line_s1), None) => {
-                    let (s1_k, s1_v) = read_kv_line(line_s1);
-                    // TODO: insert s1_k into merged map and advance its iterator.
-                    merged_map.insert(s1_k, s1_v);
-                    s1_next = s1_lines.next();
-                }
-                (None, None) => {
-                    // TODO: we have reached the end of both files, create a temp file ("temp.sst")
-                    let temp_file_path = self.data_dir.join("temp.sst");
-                    let mut temp_file = std::fs::OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .open(&temp_file_path)
-                        .unwrap();
-
-                    // TODO: write only the non deleted keys to this file from `merged_map`
-                    for (k, v) in merged_map {
-                        if v != TOMBSTONE_MARKER.to_string() {
-                            writeln!(temp_file, "{}:{}", k, v).unwrap();
-                        }
-                    }
+    // shuts the tree down, recording which of its blocks are in the block cache so that opening
+    // the tree again can read them back in, see `cache.rs`.
+    pub fn close(self) -> io::Result<()> {
+        if let Some(history) = self.history {
+            history.close()?;
+        }
+        if self.sstable_mgr.read_only {
+            return Ok(());
+        }
+        self.sstable_mgr.save_hot_blocks()
+    }
 
-                    // TODO: ensure file is synced to disk from file system buffers.
-                    temp_file.sync_data().unwrap();
+    // changes the option `name` of the open tree to `value`, e.g. `set_option("memtable_limit",
+    // "1000")`, and records it in the `OPTIONS` file. Options are named like the fields of
+    // `Options`, and the ones that can change while the tree is open are:
+    //
+    // - `memtable_limit`, `compaction_trigger` and `intra_l0_compaction_trigger`, which take effect
+    //   right away, flushing or compacting if the new limit is already reached.
+    // - `sstable_ttl` in seconds, or `none`.
+    // - `block_size`, `bloom_bits_per_key` and `readahead_size`, for the sstables written and read
+    //   from then on.
+    // - `block_cache_capacity` in bytes, which changes the capacity of the block cache for every
+    //   tree sharing it.
+    // - `max_key_size` and `max_value_size` in bytes, or `none`, for the writes from then on.
+    //
+    // Fails with `InvalidInput` for any other option, or a value that doesn't parse.
+    // 💡 RocksDB's `SetOptions` similarly takes options by name, as strings.
+    pub fn set_option(&mut self, name: &str, value: &str) -> io::Result<()> {
+        let mgr = &mut self.sstable_mgr;
+        let options = &mut self.options;
+        match name {
+            "memtable_limit" => {
+                self.memtable_limit = parse_option(name, value)?;
+                options.memtable_limit = self.memtable_limit;
+            }
+            "compaction_trigger" => {
+                mgr.compaction_trigger = parse_option(name, value)?;
+                options.compaction_trigger = mgr.compaction_trigger;
+            }
+            "intra_l0_compaction_trigger" => {
+                mgr.intra_l0_compaction_trigger = parse_optional(name, value)?;
+                options.intra_l0_compaction_trigger = mgr.intra_l0_compaction_trigger;
+            }
+            "sstable_ttl" => {
+                let ttl: Option<u64> = parse_optional(name, value)?;
+                mgr.sstable_ttl = ttl.map(Duration::from_secs);
+                options.sstable_ttl = mgr.sstable_ttl;
+            }
+            "block_size" => {
+                mgr.table_options.block_size = parse_option(name, value)?;
+                options.block_size = mgr.table_options.block_size;
+            }
+            "bloom_bits_per_key" => {
+                mgr.table_options.bloom_bits_per_key = parse_option(name, value)?;
+                options.bloom_bits_per_key = mgr.table_options.bloom_bits_per_key;
+            }
+            "readahead_size" => {
+                mgr.readahead_size = parse_option(name, value)?;
+                options.readahead_size = mgr.readahead_size;
+            }
+            "block_cache_capacity" => {
+                let Some(cache) = &mgr.block_cache else {
+                    return Err(invalid_option("the tree has no block cache"));
+                };
+                cache.set_capacity(parse_option(name, value)?);
+            }
+            "max_key_size" => options.max_key_size = parse_optional(name, value)?,
+            "max_value_size" => options.max_value_size = parse_optional(name, value)?,
+            _ => return Err(invalid_option(format!("`{}` can't be set", name))),
+        }
+        self.options.save(&self.sstable_mgr.data_dir)?;
 
-                    // TODO: remove the oldest files
-                    std::fs::remove_file(&s1_path).unwrap();
-                    std::fs::remove_file(&s2_path).unwrap();
+        if self.memtable.len() >= self.memtable_limit {
+            self.flush_memtable();
+        } else {
+            self.compact();
+        }
+        Ok(())
+    }
 
-                    // TODO: rename the temp file ("temp.sst") to the 2nd oldest file.
-                    std::fs::rename(&temp_file_path, s2_path).unwrap();
+    // rewrites the sstables written in an older on-disk format than the current one, e.g. the line
+    // based text sstables, in the current format. Returns the number of sstables rewritten.
+    pub fn upgrade_format(&mut self) -> usize {
+        self.sstable_mgr.upgrade_format()
+    }
 
-                    // TODO: pop remove the oldest file from front of sstables queue.
-                    self.sstables.pop_front();
+    // deletes the value associated with the given key `k`
+    // NOTE: deletes are just a put in disguise in an LSM Tree, with None as the value in this case.
+    pub fn delete(&mut self, k: &str) {
+        self.delete_audited(k, &AuditContext::default());
+    }
 
-                    // TODO: break from loop
-                    break;
-                }
-            }
+    // deletes k like `delete`, recording `context` with the delete in the audit log.
+    pub fn delete_audited(&mut self, k: &str, context: &AuditContext) {
+        self.write(k, None, context);
+        if self.memtable.len() >= self.memtable_limit {
+            self.flush_memtable();
         }
     }
-}
 
-// helper function to read a line of key value pair from the sstable.
-fn read_kv_line(l: &Result<String, std::io::Error>) -> (String, String) {
-    let line = l.as_ref().unwrap();
-    let mut kv = line.split(":");
-    let k = kv.next().unwrap();
-    let v = kv.next().unwrap();
-    (k.to_string(), v.to_string())
-}
+    // flushes the memtable contents to a file
+    fn flush_memtable(&mut self) {
+        if self.memtable.is_empty() {
+            return;
+        }
+        self.release_expired_snapshots();
+        let _priority = background_io::lower_priority(self.options.background_io_priority);
+        let started = self.sstable_mgr.clock.now();
 
-// returns an iterator of files in the given `dir_path` with the given `extension`
-pub fn files_with_extension(
-    dir_path: &PathBuf,
-    extension: &str,
-) -> std::io::Result<impl Iterator<Item = PathBuf>> {
-    // NOTE: read_dir doesn't guarantee same sorted order.
-    let entries = std::fs::read_dir(dir_path)?;
-    let ext = extension.to_string();
+        let entries = self.memtable.take();
+        let total = entries
+            .iter()
+            .map(|(k, record)| entry_size(k, record))
+            .sum();
+        let (sst_file, sst_id) = self.sstable_mgr.new_sstable(total);
 
-    let iter = entries.filter_map(move |entry| {
-        let entry = entry.ok()?;
-        let path = entry.path();
+        let mut writer = self.sstable_mgr.sstable_writer(sst_file, Level::L0);
+        writer.set_created(self.sstable_mgr.clock.now());
+        // large values go to a blob file with the same id, the sstable only points to them.
+        let mut blob_writer = self.sstable_mgr.blob_writer(sst_id);
+        let progress = self.sstable_mgr.track(Job::Flush, total);
+        for (k, record) in &entries {
+            match &mut blob_writer {
+                Some(blobs) => writer.add(k, &blobs.separate(k, record).unwrap()),
+                None => writer.add(k, record),
+            }
+            progress.advance(k, record);
+        }
+        let mut sst_file = writer.finish();
 
-        if path.is_file() && path.extension()?.to_str()? == ext {
-            Some(path)
-        } else {
-            None
+        // the syncs of a flush are coalesced: the sstable and blob file are synced, the log moves
+        // on to a new segment, and a single sync of the data dir makes all three files durable
+        // before the manifest lists the sstable. Only then do the flushed segments go.
+        sst_file.sync().unwrap();
+        if let Some(blobs) = blob_writer
+            && blobs.finish().unwrap()
+        {
+            self.sstable_mgr.add_blob_file(sst_id);
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.start_segment().unwrap();
         }
-    });
 
-    Ok(iter)
-}
+        self.sstable_mgr
+            .count_shadowed(entries.iter().map(|(k, _)| k));
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        io::{BufRead, BufReader},
-        path::PathBuf,
-    };
+        // the newest write was in the memtable, so it's the newest one in the sstables now.
+        self.sstable_mgr.last_seq = self.last_seq;
+        self.sstable_mgr.add_sstable(sst_id);
+        progress.finish();
+        // the flushed writes are safe in the sstable now, so their log can go.
+        if let Some(wal) = &mut self.wal {
+            wal.remove_flushed_segments().unwrap();
+        }
+        // the memtable held every key written since the previous flush.
+        self.prune_history(entries.iter().map(|(k, _)| k.as_str()));
+        let took = self.sstable_mgr.clock.now().duration_since(started);
+        report::record(&mut self.recent_flushes, took.unwrap_or_default());
+        self.compact();
+        self.sstable_mgr.move_cold_sstables();
+    }
 
-    use crate::LSMTree;
+    // Performs compaction of sstables if compaction condition is triggered, and the compaction
+    // schedule allows it.
+    fn compact(&mut self) {
+        let now = self.sstable_mgr.clock.now();
+        if !self.sstable_mgr.compaction_schedule.allows(now) {
+            return;
+        }
+        self.compact_now();
+    }
 
-    use super::{files_with_extension, read_kv_line};
+    // runs the compactions that are due right away, whatever the compaction schedule says, e.g. to
+    // catch up on the sstables that piled up outside the compaction windows during a quiet moment.
+    pub fn compact_now(&mut self) {
+        (self.compact_now_with_deadline(&Deadline::default()))
+            .expect("compactions without a deadline can't fail");
+    }
 
-    // a help function to reset `data`` directory for tests.
-    fn clear_data_dir() {
-        let data_dir = PathBuf::from("data");
-        if data_dir.exists() {
-            std::fs::remove_dir_all("data").unwrap();
+    // like `compact_now`, giving up on the compaction at hand once `deadline` passes. The
+    // compactions finished before that remain, see `deadline.rs`.
+    pub fn compact_now_with_deadline(&mut self, deadline: &Deadline) -> Result<(), Error> {
+        let _priority = background_io::lower_priority(self.options.background_io_priority);
+        let started = self.sstable_mgr.clock.now();
+        let ids = |mgr: &SSTableManager| mgr.sstables.iter().map(|sst| sst.id).collect::<Vec<_>>();
+        let before = ids(&self.sstable_mgr);
+        self.sstable_mgr.deadline = deadline.clone();
+        let compacted = (self.sstable_mgr.compact_l0(self.memtable_limit))
+            .and_then(|_| self.sstable_mgr.compact_below_trigger())
+            .and_then(|_| match ids(&self.sstable_mgr) != before {
+                true => self.sstable_mgr.upgrade_next_text_sstable(),
+                false => Ok(()),
+            });
+        self.sstable_mgr.deadline = Deadline::default();
+        if ids(&self.sstable_mgr) != before {
+            let took = self.sstable_mgr.clock.now().duration_since(started);
+            report::record(&mut self.recent_compactions, took.unwrap_or_default());
         }
+        compacted
     }
 
-    // helper to find the given key `k` in the sstable `path`
-    fn find_key_in_sstable(key: &str, path: &PathBuf) -> Option<String> {
-        let ids = files_with_extension(path, "sst").unwrap();
-        let mut ids: Vec<String> = ids
-            .map(|i: PathBuf| {
-                let a = i.file_name().unwrap().to_str().unwrap();
-                a.parse().unwrap()
-            })
-            .collect();
-        ids.sort();
-        let data_dir = PathBuf::from("data");
-        for f in ids.iter().rev() {
-            let sstable = std::fs::OpenOptions::new()
-                .read(true)
-                .open(data_dir.join(f))
-                .unwrap();
-            let s1_buf = BufReader::new(sstable);
-            let line = s1_buf.lines();
-            for l in line {
-                let (k, v) = read_kv_line(&l);
-                if key == k {
-                    return Some(v.to_string());
-                }
-            }
+    // flushes the memtable and merges every sstable into one, whatever the compaction strategy and
+    // trigger, dropping every tombstone and overwritten value along the way, e.g. to give back the
+    // space of a bulk delete, or before taking a backup that should be as small as it gets. Takes as
+    // long as reading and writing the whole tree does.
+    // 💡 RocksDB's `CompactRange` over the whole key range does the same, down to its last level.
+    pub fn compact_all(&mut self) {
+        self.flush_memtable();
+        let _priority = background_io::lower_priority(self.options.background_io_priority);
+        let started = self.sstable_mgr.clock.now();
+        let compacted =
+            (self.sstable_mgr.compact_all()).expect("compactions without a deadline can't fail");
+        if compacted {
+            let took = self.sstable_mgr.clock.now().duration_since(started);
+            report::record(&mut self.recent_compactions, took.unwrap_or_default());
         }
-
-        None
     }
 
-    // helper to find the given key `k` in a particular sstable file
-    fn find_key_in_sstable_file(key: &str, sst_file_name: &PathBuf) -> Option<String> {
-        let sstable = std::fs::OpenOptions::new()
-            .read(true)
-            .open(sst_file_name)
-            .unwrap();
-        let s1_buf = BufReader::new(sstable);
-        let line = s1_buf.lines();
-        for l in line {
-            let (k, v) = read_kv_line(&l);
-            if key == k {
-                return Some(v.to_string());
+    // rewrites the live values of every blob file in which at least `min_garbage_ratio` of the bytes
+    // belong to values that were overwritten or deleted since, and removes those files.
+    // Returns the number of blob files removed.
+    // The live values are written again, as if they were put anew, so they end up in a new blob file
+    // on the flush that follows, along with sstables pointing to them. Only once that flush is
+    // recorded in the manifest are the old files dropped from it.
+    pub fn collect_blob_garbage(&mut self, min_garbage_ratio: f64) -> usize {
+        let mut collected = Vec::new();
+        for blob_file in self.sstable_mgr.blob_files.clone() {
+            let records = blob_file.records().unwrap();
+            // a record is live if the newest record of its key still points to it.
+            let (live, dead): (Vec<_>, Vec<_>) = records
+                .into_iter()
+                .partition(|(k, pointer)| self.lookup(k) == Lookup::Found(Value::Blob(*pointer)));
+            let dead_size: u64 = dead.iter().map(|(_, pointer)| pointer.size).sum();
+            let live_size: u64 = live.iter().map(|(_, pointer)| pointer.size).sum();
+            if (dead_size as f64) < min_garbage_ratio * (dead_size + live_size) as f64 {
+                continue;
             }
+
+            // the rewrites show up in the audit log, so they're labeled as the tree's own.
+            let context = AuditContext {
+                actor: "lsmtree".to_string(),
+                reason: "blob garbage collection".to_string(),
+            };
+            for (k, pointer) in live {
+                let value = blob_file.read(&pointer).unwrap();
+                self.write(&k, Some(Value::Inline(value)), &context);
+            }
+            collected.push(blob_file.id);
         }
 
-        None
+        if collected.is_empty() {
+            return 0;
+        }
+        self.flush_memtable();
+        self.sstable_mgr.remove_blob_files(&collected);
+        collected.len()
     }
 
-    #[test]
-    fn test_lsm_basic_crud() {
-        let mut lsmtree = LSMTree::new();
-        lsmtree.put("hello", "world");
-        lsmtree.put("foo", "bar");
-        lsmtree.delete("hello");
-        assert!(lsmtree.get("foo").unwrap() == "bar");
-        assert!(lsmtree.get("hello").is_none());
+    // helper for tests, that performs compaction, regardless of trigger condition.
+    fn force_compact(&mut self) {
+        self.sstable_mgr.compact_sstables().unwrap();
     }
+}
 
-    #[test]
-    fn test_lsm_trigger_flush_basic() {
-        clear_data_dir();
-        let mut lsmtree = LSMTree::new();
-        lsmtree.put("a", "v1");
+// Where a paginated scan left off, returned by `LSMTree::scan_page` to resume from. It converts to
+// an opaque string token and back, for handing to clients of an API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    // the last key of the previous page.
+    after: String,
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // hex, so the token is safe to put in a URL whatever the key holds.
+        for b in self.after.bytes() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = io::Error;
+
+    fn from_str(token: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid cursor");
+        if !token.len().is_multiple_of(2) || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16).map_err(|_| invalid()))
+            .collect::<io::Result<Vec<u8>>>()?;
+        let after = String::from_utf8(bytes).map_err(|_| invalid())?;
+        Ok(Cursor { after })
+    }
+}
+
+// A compaction picked to run, see `SSTableManager::pick_next_compaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Compaction {
+    // merges the adjacent sstables within the range into one.
+    Merge(Range<usize>),
+    // merges the sstables at these positions, oldest first, into one. No other sstable has keys
+    // within their range.
+    MergeOverlapping(Vec<usize>),
+    // drops this many of the oldest sstables whole.
+    DropOldest(usize),
+}
+
+// Where a new sstable goes in the tree, which decides how it's compressed, see `compression.rs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Level {
+    // flushed from the memtable or ingested, the newest data in the tree.
+    L0,
+    // a compaction output with older sstables below it.
+    Middle,
+    // a compaction output taking the place of the oldest sstable, the oldest data in the tree.
+    Bottom,
+}
+
+impl Level {
+    // the level of a compaction output, `bottommost` if it takes the place of the oldest sstable.
+    fn compaction_output(bottommost: bool) -> Self {
+        if bottommost {
+            Level::Bottom
+        } else {
+            Level::Middle
+        }
+    }
+}
+
+// An sstable that's older than the newest input of a compaction without being one of its inputs,
+// with its smallest and largest key, see `SSTableManager::older_than`.
+type OlderSSTable = (Arc<SSTable>, Option<(String, String)>);
+
+// returns whether a compaction can drop a tombstone for `key`, i.e. whether none of the `older`
+// sstables may have the key, going by their fences first and their filters then. Otherwise the
+// tombstone has to be carried forward, or the value it deleted would show up again.
+fn can_drop_tombstone(older: &[OlderSSTable], key: &str) -> bool {
+    !older.iter().any(|(sst, fences)| {
+        fences
+            .as_ref()
+            .is_some_and(|(first, last)| first.as_str() <= key && key <= last.as_str())
+            && sst.may_contain(key)
+    })
+}
+
+// widens the key range `range`, if any, to cover `fence` too.
+fn widen(range: Option<(String, String)>, fence: &(String, String)) -> (String, String) {
+    match range {
+        Some((first, last)) => (first.min(fence.0.clone()), last.max(fence.1.clone())),
+        None => fence.clone(),
+    }
+}
+
+// A key range used by scans, with owned bounds so that iterators don't borrow from the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRange {
+    pub start: Bound<String>,
+    pub end: Bound<String>,
+}
+
+impl KeyRange {
+    // a range covering every key.
+    pub fn all() -> Self {
+        Self {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+
+    // a range covering every key that starts with `prefix`.
+    pub fn prefix(prefix: &str) -> Self {
+        // the end is the smallest string greater than every key with the prefix, found by
+        // incrementing the last character that isn't already the largest possible one.
+        let mut end: Vec<char> = prefix.chars().collect();
+        let end = loop {
+            match end.pop() {
+                Some(c) => {
+                    let next = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+                    if let Some(next) = next {
+                        end.push(next);
+                        break Bound::Excluded(end.into_iter().collect());
+                    }
+                }
+                None => break Bound::Unbounded,
+            }
+        };
+
+        Self {
+            start: Bound::Included(prefix.to_string()),
+            end,
+        }
+    }
+
+    // returns true if the given key `k` lies within this range.
+    pub fn contains(&self, k: &str) -> bool {
+        !self.is_before_start(k) && !self.is_past_end(k)
+    }
+
+    // returns true if the given key `k` sorts before the start of this range.
+    fn is_before_start(&self, k: &str) -> bool {
+        match &self.start {
+            Bound::Included(s) => k < s.as_str(),
+            Bound::Excluded(s) => k <= s.as_str(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    // returns true if the given key `k` sorts after the end of this range.
+    fn is_past_end(&self, k: &str) -> bool {
+        match &self.end {
+            Bound::Included(e) => k > e.as_str(),
+            Bound::Excluded(e) => k >= e.as_str(),
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl<'a, R: RangeBounds<&'a str>> From<R> for KeyRange {
+    fn from(range: R) -> Self {
+        let own = |b: Bound<&&str>| match b {
+            Bound::Included(s) => Bound::Included(s.to_string()),
+            Bound::Excluded(s) => Bound::Excluded(s.to_string()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Self {
+            start: own(range.start_bound()),
+            end: own(range.end_bound()),
+        }
+    }
+}
+
+// A snapshot of what an LSM Tree holds, returned by `LSMTree::stats`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    // entries in the memtable, not yet flushed to an sstable.
+    pub memtable_entries: usize,
+    pub sstables: usize,
+    // total size of the sstables on disk.
+    pub sstable_bytes: u64,
+    pub blob_files: usize,
+    // the progress last reported by the latest flush and compaction, see `progress.rs`.
+    pub last_flush: Option<JobProgress>,
+    pub last_compaction: Option<JobProgress>,
+    // live snapshots, and the size of the sstables they keep on disk that the tree is done with.
+    pub snapshots: usize,
+    pub pinned_bytes: u64,
+    // the size of the data blocks of the sstables whose raw size is known, on disk and before
+    // compression, see `LSMTree::sstables` for each sstable's.
+    pub data_bytes: u64,
+    pub raw_data_bytes: u64,
+    // bytes of data blocks read from disk since the tree was opened, by the current sstables.
+    pub bytes_read: u64,
+}
+
+impl std::iter::Sum for Stats {
+    fn sum<I: Iterator<Item = Stats>>(iter: I) -> Self {
+        iter.fold(Stats::default(), |total, s| Stats {
+            memtable_entries: total.memtable_entries + s.memtable_entries,
+            sstables: total.sstables + s.sstables,
+            sstable_bytes: total.sstable_bytes + s.sstable_bytes,
+            blob_files: total.blob_files + s.blob_files,
+            // the jobs of the first tree that ran any.
+            last_flush: total.last_flush.or(s.last_flush),
+            last_compaction: total.last_compaction.or(s.last_compaction),
+            snapshots: total.snapshots + s.snapshots,
+            pinned_bytes: total.pinned_bytes + s.pinned_bytes,
+            data_bytes: total.data_bytes + s.data_bytes,
+            raw_data_bytes: total.raw_data_bytes + s.raw_data_bytes,
+            bytes_read: total.bytes_read + s.bytes_read,
+        })
+    }
+}
+
+// Iterator returned by `LSMTree::scan`, merging the memtable and all sstables into a single sorted
+// stream of live key value pairs.
+// Each source is sorted, so at every step we pick the smallest key across sources. When several
+// sources have the same key, the newest source (lowest index) wins and the rest are skipped.
+pub struct ScanIter {
+    // sources ordered from newest (memtable) to oldest sstable.
+    sources: Vec<Peekable<Entries>>,
+    range: KeyRange,
+    // a snapshot of the list of sstables, which keeps the ones being read from alive until the
+    // iterator is dropped.
+    _pinned: Arc<VecDeque<Arc<SSTable>>>,
+    // the blob files large values are read from, also kept alive by the iterator.
+    blob_files: Vec<Arc<BlobFile>>,
+    // the predicates the keys and values handed out must satisfy, see `filter_keys`.
+    key_filters: Vec<ScanFilter>,
+    value_filters: Vec<ScanFilter>,
+}
+
+// A predicate on the keys or the values of a scan.
+type ScanFilter = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+impl ScanIter {
+    // keeps only the pairs whose key satisfies `filter`, e.g. a pattern it matches. Keys are
+    // checked as the sources are merged, before anything is done with their values, so the values
+    // of the keys left out are never read from blob files or handed out. Filters add up, a pair
+    // has to satisfy all of them.
+    // 💡 The same idea as predicate pushdown in query engines, and RocksDB's compaction filters,
+    // which look at a record before its value is merged or written out.
+    pub fn filter_keys(mut self, filter: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.key_filters.push(Box::new(filter));
+        self
+    }
+
+    // keeps only the pairs whose value satisfies `filter`. Inline values are checked as they are,
+    // large values once they're read from their blob file, which only happens for keys the key
+    // filters let through.
+    pub fn filter_values(mut self, filter: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.value_filters.push(Box::new(filter));
+        self
+    }
+
+    // returns the next key within range along with its newest record, which may be a tombstone.
+    fn next_record(&mut self) -> Option<(String, Record)> {
+        // compare the keys in place rather than cloning the smallest one seen so far.
+        let mut newest: Option<usize> = None;
+        for i in 0..self.sources.len() {
+            let (older, rest) = self.sources.split_at_mut(i);
+            let Some((k, _)) = rest[0].peek() else {
+                continue;
+            };
+            match newest {
+                Some(j) if older[j].peek().is_some_and(|(s, _)| s <= k) => {}
+                _ => newest = Some(i),
+            }
+        }
+
+        let newest = newest?;
+        let (key, record) = self.sources[newest].next().unwrap();
+        if self.range.is_past_end(&key) {
+            return None;
+        }
+
+        // skip older entries of the same key in other sources.
+        for source in self.sources.iter_mut().skip(newest + 1) {
+            source.next_if(|(k, _)| *k == key);
+        }
+        Some((key, record))
+    }
+}
+
+impl Iterator for ScanIter {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, record) = self.next_record()?;
+            if !self.key_filters.iter().all(|filter| filter(&key)) {
+                continue;
+            }
+            let value = match record.value {
+                Some(Value::Inline(v)) => v,
+                Some(Value::Blob(pointer)) => {
+                    let blob_file = find_blob_file(&self.blob_files, &pointer);
+                    blob_file.read(&pointer).unwrap()
+                }
+                // key was deleted, move on to the next one.
+                None => continue,
+            };
+            if self.value_filters.iter().all(|filter| filter(&value)) {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+// Iterator returned by `LSMTree::keys`. Data blocks hold each key next to its value, so inline
+// values are read all the same, but large values in blob files are left alone.
+pub struct KeysIter {
+    scan: ScanIter,
+}
+
+impl Iterator for KeysIter {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, record) = self.scan.next_record()?;
+            if record.value.is_some() {
+                return Some(key);
+            }
+        }
+    }
+}
+
+// Iterator returned by `LSMTree::values`.
+pub struct ValuesIter {
+    scan: ScanIter,
+}
+
+impl Iterator for ValuesIter {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.scan.next().map(|(_, v)| v)
+    }
+}
+
+// Iterator returned by `LSMTree::deletions`, yielding the keys whose newest record is a tombstone
+// and the sequence number it was written with.
+pub struct DeletionIter {
+    scan: ScanIter,
+}
+
+impl Iterator for DeletionIter {
+    type Item = (String, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, record) = self.scan.next_record()?;
+            if record.value.is_none() {
+                return Some((key, record.seq));
+            }
+        }
+    }
+}
+
+// A convenient wrapper struct that manages SSTables and issues new file ids to newly created SSTable files.
+struct SSTableManager {
+    // where all files are read from and written to.
+    storage: Arc<dyn Storage>,
+    // Directory where the sstables resides.
+    data_dir: PathBuf,
+    // where the time comes from, see `Options::clock`.
+    clock: Arc<dyn Clock>,
+    // an incrementing counter for file ids, holding the last id handed out. It's persisted in the manifest
+    // before a file with a new id is created, so an id is never used twice, not even after a crash.
+    // 💡 Some implementations use a combination of timestamp and unique identifiers instead.
+    next_sstable_id: usize,
+    // A list of sstables created in the past, oldest first.
+    // The list itself is never changed in place while a reader holds on to it: a flush or compaction
+    // changes a copy of it, through `sstables_mut`, and installs the copy in one go. Readers clone
+    // the `Arc` to take a snapshot of the list, and see all of a compaction's changes or none of them.
+    // 💡 RocksDB calls such a snapshot a `Version`, LevelDB and RocksDB keep reference counted
+    // versions for the same reason, so that iterators never see a half installed compaction.
+    sstables: Arc<VecDeque<Arc<SSTable>>>,
+    // the highest sequence number in any of the sstables, kept in the manifest so that opening the
+    // tree doesn't need to read every sstable to find it.
+    last_seq: u64,
+    // used to check if compaction can be triggered - it's simply max count of files in the data directory.
+    compaction_trigger: usize,
+    // which sstables to merge when compaction is triggered.
+    compaction_strategy: CompactionStrategy,
+    // number of small sstables after which they're merged with each other, see `Options`.
+    intra_l0_compaction_trigger: Option<usize>,
+    // age after which sstables are dropped whole, see `Options`.
+    sstable_ttl: Option<Duration>,
+    // when compactions triggered by flushes may run.
+    compaction_schedule: CompactionSchedule,
+    // block size, bloom filter and other settings for the sstables we write.
+    table_options: TableOptions,
+    // compression of compaction outputs, if different from `table_options`.
+    compaction_compression: Option<Compression>,
+    // compression of the compaction outputs taking the place of the oldest sstable, if different
+    // from `compaction_compression`.
+    bottommost_compression: Option<Compression>,
+    // size of the compression dictionaries of compaction outputs, 0 for none.
+    compression_dictionary_size: usize,
+    // cache of the data blocks read by lookups and scans, possibly shared with other trees.
+    block_cache: Option<BlockCache>,
+    // bytes scans and compactions read from an sstable at a time.
+    readahead_size: usize,
+    // whether compactions drop the sstables they read from the page cache, see `Options`.
+    drop_compaction_inputs: bool,
+    // whether new sstables are written around the page cache, see `Options`.
+    direct_io: bool,
+    // whether space is reserved for new sstables before writing them, see `Options`.
+    preallocate: bool,
+    // bytes new sstables are buffered in before they're written, see `Options`.
+    write_buffer_size: usize,
+    // whether compactions rewrite a text sstable each in the current format, see `Options`.
+    upgrade_text_sstables: bool,
+    // blob files holding the large values of the sstables, oldest first.
+    blob_files: Vec<Arc<BlobFile>>,
+    // values of at least this many bytes are moved to blob files when flushed, if set.
+    blob_threshold: Option<usize>,
+    // where sstables that turned cold are moved to, if anywhere.
+    cold_tier: Option<ColdTierOptions>,
+    // set for trees opened with `LSMTree::open_read_only`, which leave the data dir as it is.
+    read_only: bool,
+    // the manifest file new versions of the manifest are appended to, once the first is saved.
+    manifest_log: Option<ManifestLog>,
+    // called with the progress of flushes and compactions, see `Options`.
+    progress_callback: Option<ProgressCallback>,
+    // the progress last reported by a flush and a compaction.
+    flush_progress: ProgressSlot,
+    compaction_progress: ProgressSlot,
+    // when the manual compaction running gives up, see `LSMTree::compact_now_with_deadline`.
+    // Compactions run by flushes never do.
+    deadline: Deadline,
+}
+
+impl SSTableManager {
+    pub fn new(storage: Arc<dyn Storage>, path_buf: &Path) -> Self {
+        SSTableManager {
+            clock: Arc::new(StorageClock(Arc::clone(&storage))),
+            storage,
+            data_dir: path_buf.to_path_buf(),
+            next_sstable_id: 0,
+            sstables: Arc::default(),
+            last_seq: 0,
+            compaction_trigger: 8,
+            compaction_strategy: CompactionStrategy::default(),
+            intra_l0_compaction_trigger: None,
+            sstable_ttl: None,
+            compaction_schedule: CompactionSchedule::default(),
+            table_options: TableOptions::default(),
+            compaction_compression: None,
+            bottommost_compression: None,
+            compression_dictionary_size: 0,
+            block_cache: None,
+            readahead_size: 0,
+            drop_compaction_inputs: false,
+            direct_io: false,
+            preallocate: false,
+            write_buffer_size: 0,
+            upgrade_text_sstables: false,
+            blob_files: Vec::new(),
+            blob_threshold: None,
+            cold_tier: None,
+            read_only: false,
+            manifest_log: None,
+            progress_callback: None,
+            flush_progress: ProgressSlot::default(),
+            compaction_progress: ProgressSlot::default(),
+            deadline: Deadline::default(),
+        }
+    }
+
+    // creates the file of a new sstable, expected to take about `estimated_size` bytes, or 0 if
+    // there's no telling.
+    pub fn new_sstable(&mut self, estimated_size: u64) -> (Box<dyn WritableFile>, usize) {
+        let id = self.new_file_id();
+        let path = self.data_dir.join(format!("{}.sst", id));
+        (self.create_sstable_file(&path, estimated_size), id)
+    }
+
+    // like `new_sstable`, for a compaction output. It's written as `<id>.sst.tmp`, so that an
+    // output cut short by a crash is never mistaken for a whole sstable, and `install_output`
+    // gives it its sstable name once it's synced.
+    fn new_output(&mut self, estimated_size: u64) -> (Box<dyn WritableFile>, usize) {
+        let id = self.new_file_id();
+        let path = temp_path(&self.data_dir.join(format!("{}.sst", id)));
+        (self.create_sstable_file(&path, estimated_size), id)
+    }
+
+    // renames the synced output with the given id, see `new_output`, and opens it. The data dir
+    // must be synced before the manifest lists it.
+    fn install_output(&self, id: usize) -> SSTable {
+        let sst = SSTable::new(&self.storage, &self.data_dir, id);
+        self.storage
+            .rename(&temp_path(&sst.path), &sst.path)
+            .unwrap();
+        sst
+    }
+
+    fn create_sstable_file(&self, path: &Path, estimated_size: u64) -> Box<dyn WritableFile> {
+        let file = match self.direct_io {
+            true => self.storage.create_direct(path),
+            false => self.storage.create(path),
+        };
+        let mut file = file.unwrap();
+        if self.preallocate && estimated_size > 0 {
+            // only a hint, the file grows as needed without it.
+            let _ = file.preallocate(estimated_size);
+        }
+        // direct I/O has a buffer of its own.
+        if self.write_buffer_size > 0 && !self.direct_io {
+            file = Box::new(BufWriter::with_capacity(self.write_buffer_size, file));
+        }
+        file
+    }
+
+    // hands out the next id for an sstable or a blob file.
+    fn new_file_id(&mut self) -> usize {
+        self.next_sstable_id += 1;
+        // record the id as taken first, a file left behind by a crash is removed on recovery, but
+        // caches and backups that saw its name must never see it reused for different contents.
+        self.save_manifest();
+        self.next_sstable_id
+    }
+
+    // streams `value` into a new blob file and adds it to the tree, returning a pointer to the value.
+    // The file is listed in the manifest before the pointer is logged, so that it's never removed as
+    // a leftover while the log refers to it.
+    fn write_blob(&mut self, key: &str, value: impl Read) -> io::Result<BlobPointer> {
+        let id = self.new_file_id();
+        let path = blob::blob_path(&self.data_dir, id);
+        let pointer = match blob::write_stream(&*self.storage, &path, id, key, value) {
+            Ok(pointer) => pointer,
+            Err(e) => {
+                let _ = self.storage.remove(&path);
+                return Err(e);
+            }
+        };
+        self.storage.sync_dir(&self.data_dir).unwrap();
+        self.add_blob_file(id);
+        self.save_manifest();
+        Ok(pointer)
+    }
+
+    // wraps the given sstable file in a writer, configured with this manager's table options and
+    // the codec for the `level` the sstable goes to, see `compression.rs`.
+    fn sstable_writer(
+        &self,
+        file: Box<dyn WritableFile>,
+        level: Level,
+    ) -> SSTableWriter<Box<dyn WritableFile>> {
+        let mut options = self.table_options.clone();
+        let middle = self.compaction_compression.unwrap_or(options.compression);
+        match level {
+            Level::L0 => {}
+            Level::Middle => options.compression = middle,
+            Level::Bottom => {
+                options.compression = self.bottommost_compression.unwrap_or(middle);
+                options.dictionary_size = self.compression_dictionary_size;
+            }
+        }
+        SSTableWriter::new(file, &options)
+    }
+
+    // creates a writer for the large values of the sstable with the given id, if values are separated.
+    fn blob_writer(&self, id: usize) -> Option<BlobWriter> {
+        let threshold = self.blob_threshold?;
+        Some(BlobWriter::new(
+            &self.storage,
+            &self.data_dir,
+            id,
+            threshold,
+        ))
+    }
+
+    // adds the blob file with the given id to the tree. It must already be synced, and becomes part of
+    // the tree once the manifest is saved, e.g. along with the sstable pointing to it in `add_sstable`.
+    fn add_blob_file(&mut self, id: usize) {
+        let blob_file = BlobFile::new(&self.storage, &self.data_dir, id);
+        self.blob_files.push(Arc::new(blob_file));
+    }
+
+    // drops the blob files with the given ids from the tree, they are removed from disk once no
+    // iterator reads from them anymore.
+    fn remove_blob_files(&mut self, ids: &[usize]) {
+        let (removed, kept) = self
+            .blob_files
+            .drain(..)
+            .partition(|blob_file| ids.contains(&blob_file.id));
+        self.blob_files = kept;
+        self.save_manifest();
+        for blob_file in removed {
+            blob_file.mark_obsolete();
+        }
+    }
+
+    // returns the given value, reading it from its blob file if it's not inline.
+    fn read_value(&self, value: Value) -> String {
+        match value {
+            Value::Inline(v) => v,
+            Value::Blob(pointer) => self.blob_file(&pointer).read(&pointer).unwrap(),
+        }
+    }
+
+    // returns the blob file the given `pointer` points into.
+    fn blob_file(&self, pointer: &BlobPointer) -> &Arc<BlobFile> {
+        find_blob_file(&self.blob_files, pointer)
+    }
+
+    // Adds the give sstable id to the queue of sstables.
+    // The sstable must already be synced, it becomes part of the tree once the manifest is saved.
+    pub fn add_sstable(&mut self, id: usize) {
+        // make sure the new file itself can be found after a crash, before the manifest refers to it,
+        // along with the other files created in the data dir since the last sync, e.g. a flush's
+        // blob file and log segment.
+        self.storage.sync_dir(&self.data_dir).unwrap();
+        let sst = Arc::new(SSTable::new(&self.storage, &self.data_dir, id));
+        self.sstables_mut().push_back(sst);
+        self.save_manifest();
+    }
+
+    // the list of sstables to change, which is a copy if a reader holds a snapshot of it.
+    fn sstables_mut(&mut self) -> &mut VecDeque<Arc<SSTable>> {
+        Arc::make_mut(&mut self.sstables)
+    }
+
+    // durably records the current list of sstables and the id allocator in the manifest, starting a
+    // new manifest file on the first save and once the current one is full.
+    fn save_manifest(&mut self) {
+        let manifest = self.manifest();
+        match &mut self.manifest_log {
+            Some(log) if !log.is_full() => log.append(&manifest).unwrap(),
+            _ => {
+                let log = ManifestLog::create(&*self.storage, &self.data_dir, &manifest).unwrap();
+                self.manifest_log = Some(log);
+            }
+        }
+    }
+
+    // starts tracking the progress of a job processing about `bytes_total` bytes of keys and values.
+    fn track(&self, job: Job, bytes_total: u64) -> ProgressTracker {
+        let slot = match job {
+            Job::Flush => &self.flush_progress,
+            Job::Compaction => &self.compaction_progress,
+        };
+        let callback = self.progress_callback.clone();
+        ProgressTracker::start(job, bytes_total, callback, Arc::clone(slot))
+    }
+
+    // tracks the progress of a compaction of `inputs`, estimating its total from their sizes.
+    fn track_compaction(&self, inputs: &[&SSTable]) -> ProgressTracker {
+        let size = |sst: &&SSTable| sst.storage.len(&sst.path).unwrap_or(0);
+        self.track(Job::Compaction, inputs.iter().map(size).sum())
+    }
+
+    // the manifest listing the current sstables and blob files.
+    fn manifest(&self) -> Manifest {
+        Manifest {
+            last_sstable_id: self.next_sstable_id,
+            sstables: self.sstables.iter().map(|sst| sst.id).collect(),
+            cold_sstables: self
+                .sstables
+                .iter()
+                .filter(|s| s.cold)
+                .map(|s| s.id)
+                .collect(),
+            blob_files: self.blob_files.iter().map(|b| b.id).collect(),
+            last_seq: Some(self.last_seq),
+            shadowed: (self.sstables.iter())
+                .filter(|sst| sst.shadowed() > 0)
+                .map(|sst| (sst.id, sst.shadowed()))
+                .collect(),
+        }
+    }
+
+    // returns the cache to read the blocks of the given sstable through, if there's a block cache.
+    fn table_cache(&self, sst: &SSTable) -> Option<TableCache> {
+        let cache = self.block_cache.clone()?;
+        Some(TableCache {
+            cache,
+            cache_id: sst.cache_id,
+        })
+    }
+
+    // reads the data blocks `wanted` picks into the block cache, returning how many were picked.
+    // `wanted` is given the id of the sstable along with the block, see `sstable::warm`.
+    fn warm_cache(&self, mut wanted: impl FnMut(usize, Option<&str>, &str, u64) -> bool) -> usize {
+        let mut read = 0;
+        for sst in self.sstables.iter() {
+            let Some(cache) = self.table_cache(sst) else {
+                return 0;
+            };
+            read += sst.warm(cache, |prev, last, offset| {
+                wanted(sst.id, prev, last, offset)
+            });
+        }
+        read
+    }
+
+    // writes the ids and offsets of this tree's cached blocks to the hot blocks file, least
+    // recently used first.
+    fn save_hot_blocks(&self) -> io::Result<()> {
+        let Some(cache) = &self.block_cache else {
+            return Ok(());
+        };
+        let path = self.data_dir.join(HOT_BLOCKS_FILE);
+        let mut file = self.storage.create(&path)?;
+        for (cache_id, offset) in cache.keys() {
+            if let Some(sst) = self.sstables.iter().find(|sst| sst.cache_id == cache_id) {
+                writeln!(file, "{} {}", sst.id, offset)?;
+            }
+        }
+        file.sync()
+    }
+
+    // reads the blocks listed in the hot blocks file back into the block cache. The file is only a
+    // hint, so one that's missing or can't be parsed is ignored.
+    fn load_hot_blocks(&self, recovery: &RecoveryReporter) {
+        let path = self.data_dir.join(HOT_BLOCKS_FILE);
+        if self.block_cache.is_none() || !self.storage.exists(&path) {
+            recovery.finish(RecoveryPhase::WarmCache, 0);
+            return;
+        }
+        let mut hints = String::new();
+        if self
+            .storage
+            .open(&path)
+            .and_then(|mut f| f.read_to_string(&mut hints))
+            .is_err()
+        {
+            recovery.finish(RecoveryPhase::WarmCache, 0);
+            return;
+        }
+
+        let blocks: HashSet<(usize, u64)> = hints
+            .lines()
+            .filter_map(|line| {
+                let (id, offset) = line.split_once(' ')?;
+                Some((id.parse().ok()?, offset.parse().ok()?))
+            })
+            .collect();
+        let total = blocks.len() as u64;
+        recovery.advance(RecoveryPhase::WarmCache, 0, total);
+        self.warm_cache(|id, _, _, offset| blocks.contains(&(id, offset)));
+        recovery.finish(RecoveryPhase::WarmCache, total);
+    }
+
+    // looks up the given key `k` in the given sstable.
+    fn get_sstable(&self, sst: &SSTable, key: &str) -> Lookup {
+        let record = sst.get(key, self.table_cache(sst));
+        Lookup::from(record.as_ref())
+    }
+
+    // reads the metadata of every sstable in a background thread, see `Options::preload_metadata`.
+    fn preload_metadata(&self) {
+        let sstables: Vec<Arc<SSTable>> = self.sstables.iter().cloned().collect();
+        std::thread::spawn(move || {
+            for sst in sstables {
+                sst.load();
+            }
+        });
+    }
+
+    // recovers the ids of sstables from the manifest in the data dir.
+    fn recover(&mut self, recovery: &RecoveryReporter) {
+        recovery.advance(RecoveryPhase::LoadManifest, 0, 0);
+        let manifest = match Manifest::load(&*self.storage, &self.data_dir).unwrap() {
+            Some(manifest) => manifest,
+            // cold tiers and blob files came after the manifest, so there are none to recover.
+            None => Manifest {
+                sstables: self.recover_without_manifest(),
+                ..Manifest::default()
+            },
+        };
+        self.next_sstable_id = manifest.last_sstable_id;
+        let last_seq = manifest.last_seq;
+        let old_sst_ids = manifest.sstables;
+        let blob_ids = manifest.blob_files;
+        // continue numbering after every id in use, so new sstables never overwrite existing ones.
+        let max_id = old_sst_ids.iter().copied().max().unwrap_or(0);
+        self.next_sstable_id = self.next_sstable_id.max(max_id);
+
+        let sstables = old_sst_ids
+            .into_iter()
+            .map(|id| match &self.cold_tier {
+                _ if !manifest.cold_sstables.contains(&id) => {
+                    SSTable::new(&self.storage, &self.data_dir, id)
+                }
+                Some(tier) => SSTable::new_cold(&tier.storage, &tier.dir, id),
+                None => panic!("sstable {} is on a cold tier, but none is configured", id),
+            })
+            .map(Arc::new)
+            .collect();
+        self.sstables = Arc::new(sstables);
+        for (id, count) in manifest.shadowed {
+            if let Some(sst) = self.sstables.iter().find(|sst| sst.id == id) {
+                sst.set_shadowed(count);
+            }
+        }
+        self.blob_files = blob_ids
+            .into_iter()
+            .map(|id| Arc::new(BlobFile::new(&self.storage, &self.data_dir, id)))
+            .collect();
+        // manifests written before they recorded it leave finding it to the sstables themselves.
+        self.last_seq = last_seq.unwrap_or_else(|| {
+            let max_seqs = self.sstables.iter().map(|sst| sst.max_seq());
+            max_seqs.max().unwrap_or(0)
+        });
+        if !self.read_only {
+            self.save_manifest();
+            self.remove_orphans();
+        }
+        recovery.finish(RecoveryPhase::LoadManifest, self.sstables.len() as u64);
+    }
+
+    // recovers the ids of sstables from a data dir written before the tree kept a manifest.
+    fn recover_without_manifest(&self) -> Vec<usize> {
+        // We're listing the files in the data dir through the storage, else initializing with an empty vec.
+        if let Ok(old_files) = self.storage.list(&self.data_dir) {
+            let mut files: Vec<usize> = old_files
+                .iter()
+                .filter(|p| p.extension().is_some_and(|ext| ext == "sst"))
+                // the id is the file name without its extension. Files not named after an id, like
+                // the leftover output of a compaction that didn't finish (`temp.sst`), are skipped.
+                .filter_map(|p| p.file_stem()?.to_str()?.parse().ok())
+                .collect();
+            // smaller ids at first, being the oldest.
+            files.sort();
+            files
+        } else {
+            vec![]
+        }
+    }
+
+    // removes the files left behind by flushes and compactions that were interrupted by a crash,
+    // i.e. sstables, their sidecars and blob files that aren't part of the tree, like the
+    // `temp.sst` of older versions, and temporary files, like unfinished manifests and compaction
+    // outputs.
+    // On the cold tier, those are copies of sstables whose move didn't take effect.
+    fn remove_orphans(&self) {
+        let cold_tier = self.cold_tier.iter().map(|tier| (&tier.storage, &tier.dir));
+        for (storage, dir) in std::iter::once((&self.storage, &self.data_dir)).chain(cold_tier) {
+            let Ok(files) = storage.list(dir) else {
+                continue;
+            };
+            let mut removed = false;
+            for path in files {
+                let is_table = path
+                    .extension()
+                    .is_some_and(|ext| ext == "sst" || ext == "idx" || ext == "blob");
+                let is_live = self.sstables.iter().any(|sst| sst.path == path)
+                    || self
+                        .sstables
+                        .iter()
+                        .any(|sst| sidecar_path(&sst.path) == path)
+                    || self.blob_files.iter().any(|b| b.path == path);
+                let is_temp = path.extension().is_some_and(|ext| ext == "tmp");
+                if (is_table && !is_live) || is_temp {
+                    removed |= storage.remove(&path).is_ok();
+                }
+            }
+            if removed {
+                let _ = storage.sync_dir(dir);
+            }
+        }
+    }
+
+    // moves the sstables in the data dir created longer ago than the cold tier's `cold_after`, see
+    // `SSTable::created`, to the cold tier. Each one is copied over and synced first, and only replaced by its copy in the
+    // manifest after that, so a crash leaves one of the two copies in the tree, and the other one
+    // behind for recovery to remove. Iterators reading the old copy keep it alive until they're done.
+    fn move_cold_sstables(&mut self) {
+        let Some(tier) = self.cold_tier.clone() else {
+            return;
+        };
+        let Some(cutoff) = self.clock.now().checked_sub(tier.cold_after) else {
+            return;
+        };
+
+        let mut sstables = (*self.sstables).clone();
+        let mut moved = Vec::new();
+        for sst in sstables.iter_mut() {
+            // a file whose age can't be told stays where it is, and is tried again after the next
+            // flush.
+            let old_enough = sst.try_created().is_ok_and(|created| created <= cutoff);
+            if sst.cold || !old_enough {
+                continue;
+            }
+            let cold = SSTable::new_cold(&tier.storage, &tier.dir, sst.id);
+            cold.set_shadowed(sst.shadowed());
+            copy_file(&*self.storage, &sst.path, &*tier.storage, &cold.path).unwrap();
+            moved.push(std::mem::replace(sst, Arc::new(cold)));
+        }
+        if moved.is_empty() {
+            return;
+        }
+
+        tier.storage.sync_dir(&tier.dir).unwrap();
+        self.sstables = Arc::new(sstables);
+        self.save_manifest();
+        for sst in moved {
+            sst.mark_obsolete();
+        }
+    }
+
+    // picks the two adjacent sstables to compact, returning the index of the older one.
+    // Compaction costs reading and writing both files in full, so the pair expected to reclaim the
+    // largest share of its entries wins. That's the entries of the older file shadowed by newer
+    // versions of their keys, see `count_shadowed`, which mostly sit in the file right after it,
+    // and merging the oldest two drops their tombstones too. Without any garbage to go by, the
+    // oldest two are picked.
+    // 💡 RocksDB similarly marks files with many tombstones for compaction, and weighs files by
+    // their tombstones when picking what to compact next.
+    fn pick_compaction(&self) -> usize {
+        let mut picked = (0, 0.0);
+        for (i, pair) in self
+            .sstables
+            .iter()
+            .collect::<Vec<_>>()
+            .windows(2)
+            .enumerate()
+        {
+            let (Some(older), Some(newer)) = (pair[0].properties(), pair[1].properties()) else {
+                continue;
+            };
+            let mut reclaimed = pair[0].shadowed().min(older.entries);
+            if i == 0 {
+                reclaimed += older.deletions + newer.deletions;
+            }
+            let ratio = reclaimed as f64 / (older.entries + newer.entries).max(1) as f64;
+            if ratio > picked.1 {
+                picked = (i, ratio);
+            }
+        }
+        picked.0
+    }
+
+    // estimates how many of the entries of each sstable are garbage: every tombstone is, and so is
+    // every entry shadowed by a newer version of its key, see `count_shadowed`. Sstables without
+    // table properties are assumed to have no garbage.
+    fn stale_entries(&self) -> Vec<u64> {
+        (self.sstables.iter())
+            .map(|sst| match sst.properties() {
+                Some(properties) => (properties.deletions + sst.shadowed()).min(properties.entries),
+                None => 0,
+            })
+            .collect()
+    }
+
+    // counts the entries of the sstables that the given keys, about to be written to a new sstable,
+    // shadow. For each key, that's the newest sstable with an older version of it, going by their
+    // filters, so the odd false positive counts an entry that isn't there. Older versions in older
+    // sstables yet were counted when that newest one was written.
+    // Compaction outputs take over the counts of their inputs, see `carry_shadowed`, so that the
+    // estimates stay up to date without reading any sstable.
+    // 💡 RocksDB has no such estimate per file, only the tombstone counts. Estimating shadowed
+    // entries when a file is written is closer to what some key value separated stores do for their
+    // value logs.
+    fn count_shadowed<'a>(&self, keys: impl Iterator<Item = &'a String>) {
+        for key in keys {
+            let newest = self.sstables.iter().rev().find(|sst| sst.may_contain(key));
+            if let Some(sst) = newest {
+                sst.add_shadowed();
+            }
+        }
+    }
+
+    // sets the shadowed entry count of `output`, the compaction output of `inputs`. Entries shadowed
+    // within the inputs are dropped by the compaction, the rest stay shadowed in the output.
+    // Inputs without entry counts, like text sstables, count as empty.
+    fn carry_shadowed(inputs: &[Arc<SSTable>], output: &SSTable) {
+        let shadowed: u64 = inputs.iter().map(|sst| sst.shadowed()).sum();
+        let entries = |sst: &SSTable| sst.properties().map_or(0, |p| p.entries);
+        let input_entries = inputs.iter().map(|sst| entries(sst)).sum::<u64>();
+        let dropped = input_entries.saturating_sub(entries(output));
+        output.set_shadowed(shadowed.saturating_sub(dropped));
+    }
+
+    // merges the newest sstables into one if there are at least `intra_l0_compaction_trigger` of
+    // them with at most `max_entries` entries each, like the files flushed from the memtable. It's
+    // the equivalent of an intra-L0 compaction in a leveled tree: flushed files pile up in level 0,
+    // and merging them with each other is much cheaper than into the far larger levels below.
+    // Tombstones are kept for the keys older sstables may have, like in any merge.
+    // 💡 RocksDB picks an intra-L0 compaction when L0 files can't be compacted into L1 because an
+    // L0 -> L1 compaction is already running.
+    fn compact_l0(&mut self, max_entries: usize) -> Result<(), Error> {
+        match self.pick_l0_run(max_entries) {
+            Some(run) => self.merge_sstables(run),
+            None => Ok(()),
+        }
+    }
+
+    // picks the run of small sstables `compact_l0` merges, if there are enough of them.
+    fn pick_l0_run(&self, max_entries: usize) -> Option<Range<usize>> {
+        let trigger = self.intra_l0_compaction_trigger?;
+        let is_small = |sst: &Arc<SSTable>| {
+            sst.properties()
+                .is_some_and(|p| p.entries <= max_entries as u64)
+        };
+        let small = self.sstables.iter().rev().take_while(|sst| is_small(sst));
+        let count = small.count();
+        let len = self.sstables.len();
+        (count >= trigger.max(2)).then(|| len - count..len)
+    }
+
+    // merges the adjacent sstables within `range` into one that takes their place. Takes effect
+    // once it's in the manifest, like any compaction.
+    fn merge_sstables(&mut self, range: Range<usize>) -> Result<(), Error> {
+        self.merge_picked(&range.collect::<Vec<_>>())
+    }
+
+    // merges every sstable into one, see `LSMTree::compact_all`. A single sstable is only rewritten
+    // if it may have tombstones to drop. Returns whether anything was merged.
+    fn compact_all(&mut self) -> Result<bool, Error> {
+        let len = self.sstables.len();
+        let has_deletions = |sst: &SSTable| sst.properties().is_none_or(|p| p.deletions > 0);
+        if len == 0 || len == 1 && !has_deletions(&self.sstables[0]) {
+            return Ok(false);
+        }
+        self.merge_picked(&(0..len).collect::<Vec<_>>())?;
+        Ok(true)
+    }
+
+    // merges the sstables at the `picked` positions, oldest first, into one that takes the place
+    // of the oldest, dropping the tombstones for keys no older sstable may have. The sstables in
+    // between that aren't picked must not have any of the keys of the picked ones, for the newer of
+    // those to move past them.
+    fn merge_picked(&mut self, picked: &[usize]) -> Result<(), Error> {
+        self.check_deadline()?;
+        let older = self.older_than(picked);
+        let inputs: Vec<Arc<SSTable>> = picked
+            .iter()
+            .map(|&i| Arc::clone(&self.sstables[i]))
+            .collect();
+        let progress = self.track_compaction(&inputs.iter().map(|sst| &**sst).collect::<Vec<_>>());
+        // newer records replace older ones.
+        let mut merged: BTreeMap<String, Record> = BTreeMap::new();
+        for sst in &inputs {
+            let entries = SSTableEntries::open(sst, &KeyRange::all(), None, self.readahead_size);
+            for (k, record) in entries {
+                self.check_deadline()?;
+                progress.advance(&k, &record);
+                merged.insert(k, record);
+            }
+        }
+        self.drop_inputs_from_cache(&inputs);
+        let entries = merged
+            .into_iter()
+            .filter(|(k, record)| record.value.is_some() || !can_drop_tombstone(&older, k));
+        let created = inputs.iter().map(|sst| sst.created()).max().unwrap();
+        let estimated_size = inputs.iter().map(|sst| sst.size()).sum();
+        // the output takes the place of the oldest input, and holds the oldest data if that's the
+        // oldest sstable.
+        let bottommost = picked[0] == 0;
+        let level = Level::compaction_output(bottommost);
+        let output = self.write_sstable(entries, created, estimated_size, level);
+        Self::carry_shadowed(&inputs, &output);
+        self.storage.sync_dir(&self.data_dir).unwrap();
+        let sstables = self.sstables_mut();
+        for &i in picked[1..].iter().rev() {
+            sstables.remove(i);
+        }
+        sstables[picked[0]] = output;
+        self.save_manifest();
+        progress.finish();
+        for sst in inputs {
+            sst.mark_obsolete();
+        }
+        Ok(())
+    }
+
+    // returns the sstables older than the newest of the `picked` positions that aren't picked
+    // themselves, oldest first, with their fences: the ones the tombstones of a compaction of the
+    // picked sstables may still have to delete keys in. The sstables left out in between have none
+    // of the picked ones' keys, but counting them too keeps it safe whatever picked them.
+    // 💡 RocksDB checks the same before dropping a tombstone, with `KeyNotExistsBeyondOutputLevel`,
+    // going by the key ranges of the files in the levels below the compaction's output level.
+    fn older_than(&self, picked: &[usize]) -> Vec<OlderSSTable> {
+        let newest = picked.last().copied().unwrap_or(0);
+        (0..newest)
+            .filter(|i| !picked.contains(i))
+            .map(|i| {
+                let sst = Arc::clone(&self.sstables[i]);
+                let fences = sst.fences();
+                (sst, fences)
+            })
+            .collect()
+    }
+
+    // tells the OS the compaction is done reading `inputs`, if `Options::drop_compaction_inputs_from_cache`
+    // is set, see `background_io.rs`.
+    fn drop_inputs_from_cache(&self, inputs: &[Arc<SSTable>]) {
+        if self.drop_compaction_inputs {
+            for sst in inputs {
+                let _ = sst.storage.drop_from_cache(&sst.path);
+            }
+        }
+    }
+
+    // fails if the deadline of the manual compaction running has passed.
+    fn check_deadline(&self) -> Result<(), Error> {
+        self.deadline.check(&*self.clock)
+    }
+
+    // merges sstables the way the compaction strategy picks them, unless there are sstables past
+    // their TTL to drop instead, which takes no reading or writing at all.
+    // 💡 RocksDB's `ttl` option similarly picks files older than it for compaction first, although
+    // it still has to rewrite them unless the compaction style is FIFO.
+    fn run_compaction(&mut self) -> Result<(), Error> {
+        match self.pick_next_compaction() {
+            Some(Compaction::DropOldest(count)) => {
+                self.drop_oldest_sstables(count);
+                Ok(())
+            }
+            // the pairwise compaction picks the same pair by itself.
+            Some(Compaction::Merge(_))
+                if self.compaction_strategy == CompactionStrategy::Pairwise =>
+            {
+                self.compact_sstables()
+            }
+            Some(Compaction::Merge(run)) => self.merge_sstables(run),
+            Some(Compaction::MergeOverlapping(picked)) => self.merge_picked(&picked),
+            None => Ok(()),
+        }
+    }
+
+    // picks the compaction `run_compaction` runs next, if there's anything to compact.
+    fn pick_next_compaction(&self) -> Option<Compaction> {
+        let expired = self.count_oldest_to_drop(None, self.sstable_ttl);
+        if self.sstable_ttl.is_some() && expired > 0 {
+            return Some(Compaction::DropOldest(expired));
+        }
+        match self.compaction_strategy {
+            CompactionStrategy::Pairwise => (self.sstables.len() >= 2).then(|| {
+                let older = self.pick_compaction();
+                Compaction::Merge(older..older + 2)
+            }),
+            CompactionStrategy::Overlapping => (self.sstables.len() >= 2)
+                .then(|| Compaction::MergeOverlapping(self.pick_overlapping())),
+            CompactionStrategy::Universal {
+                size_ratio,
+                min_merge_width,
+            } => {
+                let run = self.pick_universal_run(size_ratio, min_merge_width);
+                (!run.is_empty()).then_some(Compaction::Merge(run))
+            }
+            CompactionStrategy::Fifo { max_size, max_age } => {
+                let count = self.count_oldest_to_drop(max_size, max_age);
+                (count > 0).then_some(Compaction::DropOldest(count))
+            }
+        }
+    }
+
+    // drops the `count` oldest sstables, without reading them. Only ever dropping the oldest keeps
+    // older values from showing up again in place of the dropped ones.
+    fn drop_oldest_sstables(&mut self, count: usize) {
+        let dropped: Vec<Arc<SSTable>> = self.sstables_mut().drain(..count).collect();
+        self.save_manifest();
+        for sst in dropped {
+            sst.mark_obsolete();
+        }
+    }
+
+    // returns how many of the oldest sstables to drop for the rest of them to take no more than
+    // `max_size` bytes together, with the newest record in the oldest one written no longer ago
+    // than `max_age`.
+    fn count_oldest_to_drop(&self, max_size: Option<u64>, max_age: Option<Duration>) -> usize {
+        let now = self.clock.now();
+        let size = |sst: &SSTable| sst.storage.len(&sst.path).unwrap_or(0);
+        let mut total: u64 = self.sstables.iter().map(|sst| size(sst)).sum();
+        let mut dropped = 0;
+        for sst in self.sstables.iter() {
+            let age = now.duration_since(sst.created()).unwrap_or_default();
+            let too_old = max_age.is_some_and(|max| age > max);
+            if !too_old && max_size.is_none_or(|max| total <= max) {
+                break;
+            }
+            total -= size(sst);
+            dropped += 1;
+        }
+        dropped
+    }
+
+    // picks the sstables for `CompactionStrategy::Overlapping` to merge: the pair `pick_compaction`
+    // picks, or else the oldest two that overlap, and every sstable that overlaps the range of the
+    // ones picked so far, until there are none left. Returns their positions, oldest first.
+    fn pick_overlapping(&self) -> Vec<usize> {
+        let fences: Vec<Option<(String, String)>> =
+            self.sstables.iter().map(|sst| sst.fences()).collect();
+        let overlaps = |range: &(String, String), i: usize| {
+            fences[i]
+                .as_ref()
+                .is_some_and(|(first, last)| *first <= range.1 && range.0 <= *last)
+        };
+        let len = fences.len();
+        let older = self.pick_compaction();
+        let pairs = (0..len).flat_map(|i| (i + 1..len).map(move |j| (i, j)));
+        let (i, j) = std::iter::once((older, older + 1))
+            .chain(pairs)
+            .find(|&(i, j)| fences[i].as_ref().is_some_and(|range| overlaps(range, j)))
+            .unwrap_or((older, older + 1));
+
+        let mut picked = vec![i, j];
+        let mut range: Option<(String, String)> = None;
+        for fence in [&fences[i], &fences[j]].into_iter().flatten() {
+            range = Some(widen(range, fence));
+        }
+        while let Some(current) = &range {
+            let Some(k) = (0..len).find(|k| !picked.contains(k) && overlaps(current, *k)) else {
+                break;
+            };
+            picked.push(k);
+            range = Some(widen(range, fences[k].as_ref().unwrap()));
+        }
+        picked.sort();
+        picked
+    }
+
+    // picks the newest run of adjacent sstables in which each sstable is at most `size_ratio`
+    // percent larger than all the newer ones in the run together, with at least `min_merge_width`
+    // sstables in it. Falls back to the newest two if there's no such run.
+    fn pick_universal_run(&self, size_ratio: u32, min_merge_width: usize) -> Range<usize> {
+        let sizes: Vec<u64> = (self.sstables.iter())
+            .map(|sst| sst.storage.len(&sst.path).unwrap_or(0))
+            .collect();
+        let len = sizes.len();
+        for end in (2..=len).rev() {
+            let mut run_size = sizes[end - 1];
+            let mut start = end - 1;
+            while start > 0 && sizes[start - 1] * 100 <= run_size * (100 + size_ratio as u64) {
+                start -= 1;
+                run_size += sizes[start];
+            }
+            if end - start >= min_merge_width.max(2) {
+                return start..end;
+            }
+        }
+        len.saturating_sub(2)..len
+    }
+
+    fn should_compact(&self) -> bool {
+        matches!(self.compaction_strategy, CompactionStrategy::Fifo { .. })
+            || self.sstables.len() >= self.compaction_trigger
+    }
+
+    // compacts the sstables until they're back under the trigger. Usually a flush brings them to the
+    // trigger and a single compaction does, but adding several at once, or flushing several times
+    // outside the compaction windows, leaves more to catch up on.
+    fn compact_below_trigger(&mut self) -> Result<(), Error> {
+        while self.should_compact() {
+            let len = self.sstables.len();
+            self.run_compaction()?;
+            // FIFO compaction only drops sstables that are over its limits.
+            if self.sstables.len() == len {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Compacts sstables.
+    // In this toy implementation, we only take two adjacent sstables, picked by `pick_compaction`, and attempt to merge duplicates or deletes from them one by one, using the merge
+    // algorithm from merge sort.
+    // once that is done, we write the merged entries to a new sstable, replace the two files with it in
+    // the `sstables` queue and the manifest, and only then remove the two files from the data directory.
+    // A crash at any point leaves either the two inputs or the merged file listed in the manifest, never both.
+    fn compact_sstables(&mut self) -> Result<(), Error> {
+        // bail early if we don't have enough required sstables to compact from.
+        if self.sstables.len() < 2 {
+            return Ok(());
+        }
+        self.check_deadline()?;
+
+        // 1. pick the two sstables and create an entries iterator from them.
+        let older = self.pick_compaction();
+        // tombstones can only be dropped for keys no older sstable may have, for deleted values not
+        // to show up again.
+        let older_ssts = self.older_than(&[older, older + 1]);
+        let bottommost = older == 0;
+        let s1 = Arc::clone(&self.sstables[older]);
+        // compactions read every block once, caching them would only evict the blocks reads need.
+        let open = |sst: &SSTable| {
+            let range = KeyRange::all();
+            SSTableEntries::open(sst, &range, None, self.readahead_size)
+        };
+        let s2 = Arc::clone(&self.sstables[older + 1]);
+        let progress = self.track_compaction(&[&s1, &s2]);
+        let advance = |(k, record): &(String, Record)| progress.advance(k, record);
+        let mut s1_entries = open(&s1).inspect(advance);
+        let mut s2_entries = open(&s2).inspect(advance);
+
+        // 2. create two variable thar points to first entry from both the sstable files.
+        let mut s1_next = s1_entries.next();
+        let mut s2_next = s2_entries.next();
+
+        // 3. create a merged map that will store the merged key and values from the two files.
+        let mut merged_map: BTreeMap<String, Record> = BTreeMap::new();
+        // 4. loop over the cursor for both files and do a match and merge them into a single sstable comparing the keys.
+        loop {
+            // the output isn't created until both inputs are read, so there's nothing to undo.
+            self.check_deadline()?;
+            match (s1_next.take(), s2_next.take()) {
+                (Some((s1_k, s1_v)), Some((s2_k, s2_v))) => {
+                    // TODO: compare the keys and push to `merged_map` accordingly and increment the respective iterator.
+                    if s1_k <= s2_k {
+                        merged_map.insert(s1_k, s1_v);
+                        s1_next = s1_entries.next();
+                        s2_next = Some((s2_k, s2_v));
+                    } else {
+                        merged_map.insert(s2_k, s2_v);
+                        s2_next = s2_entries.next();
+                        s1_next = Some((s1_k, s1_v));
+                    }
+                }
+                (None, Some((s2_k, s2_v))) => {
+                    // TODO: insert s2_k into merged map and advance its iterator.
+                    merged_map.insert(s2_k, s2_v);
+                    s2_next = s2_entries.next();
+                }
+                (Some((s1_k, s1_v)), None) => {
+                    // TODO: insert s1_k into merged map and advance its iterator.
+                    merged_map.insert(s1_k, s1_v);
+                    s1_next = s1_entries.next();
+                }
+                (None, None) => {
+                    self.drop_inputs_from_cache(&[Arc::clone(&s1), Arc::clone(&s2)]);
+                    // TODO: we have reached the end of both files, create a new sstable for the output.
+                    // Until the manifest lists it, recovery treats it as a leftover and removes it.
+                    let (merged_file, merged_id) = self.new_output(s1.size() + s2.size());
+
+                    // TODO: write only the non deleted keys to this file from `merged_map`, along with
+                    // the tombstones older sstables may still have the keys of.
+                    // the merged file of the oldest two holds the oldest data in the tree, like the
+                    // bottom level of a leveled tree, so it's compressed with the settings meant for that.
+                    let level = Level::compaction_output(bottommost);
+                    let mut writer = self.sstable_writer(merged_file, level);
+                    writer.set_created(s1.created().max(s2.created()));
+                    for (k, record) in &merged_map {
+                        if record.value.is_some() || !can_drop_tombstone(&older_ssts, k) {
+                            writer.add(k, record);
+                        }
+                    }
+                    let mut merged_file = writer.finish();
+
+                    // TODO: ensure file is synced to disk from file system buffers, and that its
+                    // directory entry is too.
+                    merged_file.sync().unwrap();
+                    let merged = self.install_output(merged_id);
+                    self.storage.sync_dir(&self.data_dir).unwrap();
+
+                    // TODO: replace the two sstables with the merged one, and atomically
+                    // record that in the manifest. This is the point where the compaction takes effect.
+                    Self::carry_shadowed(&[Arc::clone(&s1), Arc::clone(&s2)], &merged);
+                    let sstables = self.sstables_mut();
+                    sstables.remove(older + 1);
+                    sstables[older] = Arc::new(merged);
+                    self.save_manifest();
+                    progress.finish();
+
+                    // TODO: remove the inputs, they get removed from disk once no iterator references
+                    // them anymore. A crash before that leaves them behind for recovery to clean up.
+                    s1.mark_obsolete();
+                    s2.mark_obsolete();
+
+                    // TODO: break from loop
+                    break Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl SSTableManager {
+    // replaces every sstable in an older format with a copy in the current one, at the same place
+    // in the list. Each copy takes effect once it's recorded in the manifest, like a compaction
+    // output, so a crash part way leaves a tree with some sstables upgraded and the rest not.
+    fn upgrade_format(&mut self) -> usize {
+        let mut upgraded = 0;
+        for i in 0..self.sstables.len() {
+            if self.sstables[i].format_version() != sstable::FORMAT_VERSION {
+                self.upgrade_sstable(i);
+                upgraded += 1;
+            }
+        }
+        upgraded
+    }
+
+    // rewrites the oldest text sstable in the current format, if there's one left and
+    // `Options::upgrade_text_sstables_on_compaction` is set. Run after every compaction that did
+    // something, one sstable at a time, so that converting an old data dir takes a little longer
+    // on each of them rather than all at once. Only text sstables are converted: they're read
+    // through sidecar indexes, line by line, while older block based ones only lack some of the
+    // metadata in the footer and read as fast as current ones.
+    fn upgrade_next_text_sstable(&mut self) -> Result<(), Error> {
+        if !self.upgrade_text_sstables {
+            return Ok(());
+        }
+        let is_text = |sst: &Arc<SSTable>| sst.format_version() == sstable::FORMAT_TEXT;
+        if let Some(i) = self.sstables.iter().position(is_text) {
+            self.check_deadline()?;
+            self.upgrade_sstable(i);
+        }
+        Ok(())
+    }
+
+    // replaces the sstable at `i` with a copy in the current format.
+    fn upgrade_sstable(&mut self, i: usize) {
+        let old = Arc::clone(&self.sstables[i]);
+        let range = KeyRange::all();
+        let entries = SSTableEntries::open(&old, &range, None, self.readahead_size);
+        let level = Level::compaction_output(i == 0);
+        let upgraded = self.write_sstable(entries, old.created(), old.size(), level);
+        upgraded.set_shadowed(old.shadowed());
+        self.sstables_mut()[i] = upgraded;
+        self.storage.sync_dir(&self.data_dir).unwrap();
+        self.save_manifest();
+        old.mark_obsolete();
+    }
+
+    // writes `entries` to a new sstable created at `created`, see `SSTable::created`, and syncs
+    // it. Like a compaction output, it's only part of the tree once it's listed in the manifest.
+    // It's compressed for the `level` it goes to, see `sstable_writer`.
+    fn write_sstable(
+        &mut self,
+        entries: impl Iterator<Item = (String, Record)>,
+        created: SystemTime,
+        estimated_size: u64,
+        level: Level,
+    ) -> Arc<SSTable> {
+        let (file, id) = self.new_output(estimated_size);
+        let mut writer = self.sstable_writer(file, level);
+        writer.set_created(created);
+        for (k, record) in entries {
+            writer.add(&k, &record);
+        }
+        writer.finish().sync().unwrap();
+        Arc::new(self.install_output(id))
+    }
+}
+
+// parses the `value` given to `LSMTree::set_option` for the option `name`.
+fn parse_option<T: std::str::FromStr>(name: &str, value: &str) -> io::Result<T> {
+    (value.parse()).map_err(|_| invalid_option(format!("invalid value `{}` for `{}`", value, name)))
+}
+
+// like `parse_option`, for options that can be unset with `none`.
+fn parse_optional<T: std::str::FromStr>(name: &str, value: &str) -> io::Result<Option<T>> {
+    match value {
+        "none" => Ok(None),
+        value => parse_option(name, value).map(Some),
+    }
+}
+
+fn invalid_option(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+// the path a file at `path` is written under until it's complete.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    PathBuf::from(temp)
+}
+
+// copies the file at `from` on storage `from_storage` to `to` on `to_storage`, and syncs the copy.
+fn copy_file(
+    from_storage: &dyn Storage,
+    from: &Path,
+    to_storage: &dyn Storage,
+    to: &Path,
+) -> io::Result<()> {
+    let mut reader = from_storage.open(from)?;
+    let mut writer = to_storage.create(to)?;
+    io::copy(&mut reader, &mut writer)?;
+    writer.sync()
+}
+
+// returns the one of `blob_files` that `pointer` points into.
+fn find_blob_file<'a>(blob_files: &'a [Arc<BlobFile>], pointer: &BlobPointer) -> &'a Arc<BlobFile> {
+    blob_files
+        .iter()
+        .find(|b| b.id == pointer.file_id)
+        .expect("a live record points to a blob file that isn't part of the tree")
+}
+
+// returns an iterator of files in the given `dir_path` with the given `extension`
+pub fn files_with_extension(
+    dir_path: &Path,
+    extension: &str,
+) -> std::io::Result<impl Iterator<Item = PathBuf>> {
+    // NOTE: read_dir doesn't guarantee same sorted order.
+    let entries = std::fs::read_dir(dir_path)?;
+    let ext = extension.to_string();
+
+    let iter = entries.filter_map(move |entry| {
+        let entry = entry.ok()?;
+        let path = entry.path();
+
+        if path.is_file() && path.extension()?.to_str()? == ext {
+            Some(path)
+        } else {
+            None
+        }
+    });
+
+    Ok(iter)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Read, Write},
+        ops::Range,
+        panic::{AssertUnwindSafe, catch_unwind},
+        path::{Path, PathBuf},
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use crate::{
+        AuditContext, BlockCache, Clock, ColdTierOptions, CompactionSchedule, CompactionStrategy,
+        Compression, Cursor, Error, KeyRange, LSMTree, MockClock, Options, PrefixExtractor,
+        RecordFormat, SSTable, SSTableEntries, TOMBSTONE_MARKER, Value, WalOp, WalReader,
+        WalRecord,
+        sim::{self, SimStorage},
+        sstable,
+        storage::{FsStorage, Storage},
+    };
+
+    // a help function to reset `data`` directory for tests.
+    fn clear_data_dir() {
+        let data_dir = PathBuf::from("data");
+        if data_dir.exists() {
+            std::fs::remove_dir_all(&data_dir).unwrap();
+        }
+    }
+
+    // a helper to create an empty data directory, private to the given test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from("test_data").join(name);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        dir
+    }
+
+    // helper to find the given key `k` in a particular sstable file
+    fn find_key_in_sstable_file(key: &str, sst_file_name: &Path) -> Option<String> {
+        let storage: Arc<dyn Storage> = Arc::new(FsStorage);
+        let sst = SSTable::at(&storage, sst_file_name.to_path_buf(), 0);
+        SSTableEntries::open(&sst, &KeyRange::all(), None, 0)
+            .find(|(k, _)| k == key)
+            .map(|(_, r)| match r.value {
+                Some(Value::Inline(v)) => v,
+                Some(blob) => format!("{:?}", blob),
+                None => TOMBSTONE_MARKER.to_string(),
+            })
+    }
+
+    #[test]
+    fn test_lsm_basic_crud() {
+        let mut lsmtree = LSMTree::new();
+        lsmtree.put("hello", "world").unwrap();
+        lsmtree.put("foo", "bar").unwrap();
+        lsmtree.delete("hello");
+        assert!(lsmtree.get("foo").unwrap() == "bar");
+        assert!(lsmtree.get("hello").is_none());
+    }
+
+    #[test]
+    fn test_lsm_trigger_flush_basic() {
+        clear_data_dir();
+        let mut lsmtree = LSMTree::new();
+        lsmtree.put("a", "v1").unwrap();
+        lsmtree.flush_memtable();
+        assert!(std::fs::exists(Path::new("data").join("1.sst")).unwrap());
+    }
+
+    #[test]
+    fn test_lsm_reads_from_sstable() {
+        let mut lsmtree = LSMTree::new();
+        lsmtree.put("hello", "world").unwrap();
+        lsmtree.put("foo", "bar").unwrap();
+        lsmtree.delete("hello");
+        // force flush memtable so reads can happen from sstable.
+        lsmtree.flush_memtable();
+        assert!(lsmtree.get("hello").is_none());
+        assert!(lsmtree.get("foo").unwrap() == "bar");
+    }
+
+    #[test]
+    fn test_lsm_recovers_and_reads_older_sstables() {
+        let mut lsmtree = LSMTree::new();
+        lsmtree.put("hello", "world").unwrap();
+        lsmtree.put("foo", "bar").unwrap();
+        lsmtree.delete("hello");
+        lsmtree.flush_memtable();
+        drop(lsmtree);
+        // re-initialize another LSMTree instance.
+        let lsmtree = LSMTree::new();
+        // confirm that memtable is empty on a new instance.
+        assert!(lsmtree.memtable.is_empty());
+        assert!(lsmtree.get("hello").is_none());
+        assert!(lsmtree.get("foo").unwrap() == "bar");
+    }
+
+    // TODO: make this test pass
+    #[test]
+    fn test_lsm_flush_triggers_compaction() {
+        clear_data_dir();
+        let mut lsmtree = LSMTree::new();
+        lsmtree.memtable_limit = 1;
+        lsmtree.sstable_mgr.compaction_trigger = 3;
+
+        lsmtree.put("a", "v1").unwrap();
+        lsmtree.put("b", "v2").unwrap();
+        lsmtree.put("c", "v3").unwrap();
+
+        // the two oldest sstables were merged into a new one, which takes their place.
+        let merged = lsmtree.sstable_mgr.sstables[0].path.clone();
+        assert!(find_key_in_sstable_file("a", &merged).is_some());
+        assert!(find_key_in_sstable_file("b", &merged).is_some());
+        assert!(find_key_in_sstable_file("c", &merged).is_none());
+        assert!(!Path::new("data").join("1.sst").exists());
+        assert!(!Path::new("data").join("2.sst").exists());
+    }
+
+    #[test]
+    fn test_lsm_flush_after_restart_keeps_older_sstables() {
+        let dir = test_dir("flush_after_restart");
+        let mut lsmtree = LSMTree::open(&dir);
+        lsmtree.put("a", "v1").unwrap();
+        lsmtree.flush_memtable();
+        drop(lsmtree);
+
+        // the first flush after a restart used to reuse id 1 and overwrite the older sstable.
+        let mut lsmtree = LSMTree::open(&dir);
+        lsmtree.put("b", "v1").unwrap();
+        lsmtree.flush_memtable();
+        assert!(dir.join("1.sst").exists());
+        assert!(dir.join("2.sst").exists());
+        assert_eq!(lsmtree.get("a"), Some("v1".to_string()));
+        assert_eq!(lsmtree.get("b"), Some("v1".to_string()));
+    }
+
+    #[test]
+    fn test_lsm_preallocated_space_is_given_back_on_sync() {
+        let dir = test_dir("preallocate");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("1.sst");
+        let mut file = FsStorage.create(&path).unwrap();
+        let preallocated = file.preallocate(1 << 20).is_ok();
+        file.write_all(&[1; 100]).unwrap();
+        // the space is reserved, the length only covers what's written.
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 100);
+        file.sync().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), [1; 100]);
+        #[cfg(unix)]
+        if preallocated {
+            use std::os::unix::fs::MetadataExt;
+            assert!(std::fs::metadata(&path).unwrap().blocks() * 512 < 1 << 20);
+        }
+
+        // and sstables written with it read back like any other.
+        let mut lsmtree = LSMTree::open(dir.join("tree"));
+        for i in 0..30 {
+            lsmtree.put(&format!("key{:02}", i), "v1").unwrap();
+        }
+        let sst = &lsmtree.sstable_mgr.sstables[0];
+        assert!(sst.size() > 0);
+        assert!(lsmtree.verify_checksums(None).corrupt_files.is_empty());
+        assert_eq!(lsmtree.scan(..).count(), 30);
+    }
+
+    #[test]
+    fn test_lsm_buffered_sstable_writes_match_unbuffered_ones() {
+        let write = |buffer_size: usize| {
+            let storage = SimStorage::new(1, Default::default());
+            let options = Options {
+                storage: Arc::new(storage.clone()),
+                sstable_write_buffer_size: buffer_size,
+                ..Options::default()
+            };
+            let mut lsmtree = LSMTree::open_with_options("data", options);
+            for i in 0..50 {
+                lsmtree
+                    .put(&format!("key{:02}", i % 30), &"v".repeat(i * 100))
+                    .unwrap();
+            }
+            let mut contents = Vec::new();
+            for sst in lsmtree.sstable_mgr.sstables.iter() {
+                storage
+                    .open(&sst.path)
+                    .unwrap()
+                    .read_to_end(&mut contents)
+                    .unwrap();
+            }
+            contents
+        };
+        let unbuffered = write(0);
+        assert!(!unbuffered.is_empty());
+        assert_eq!(write(100), unbuffered);
+        assert_eq!(write(1024 * 1024), unbuffered);
+    }
+
+    #[test]
+    fn test_lsm_sstable_ids_are_not_reused_after_crash() {
+        sim::silence_fault_panics();
+        for crash_after in 1.. {
+            let storage = SimStorage::new(crash_after, Default::default());
+            let options = Options {
+                storage: Arc::new(storage.clone()),
+                ..Options::default()
+            };
+            let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+            lsmtree.put("a", "v1").unwrap();
+            lsmtree.flush_memtable();
+            lsmtree.put("b", "v1").unwrap();
+
+            // crash part way through a flush, possibly leaving a file with a new id behind.
+            storage.crash_after(crash_after);
+            let flushed = catch_unwind(AssertUnwindSafe(|| lsmtree.flush_memtable())).is_ok();
+            // the highest id a file was created with, before the crash cleans it up.
+            let files = storage.list(Path::new("data")).unwrap();
+            let handed_out = files
+                .iter()
+                .filter(|p| p.extension().is_some_and(|e| e == "sst"))
+                .filter_map(|p| p.file_stem()?.to_str()?.parse::<usize>().ok())
+                .max()
+                .unwrap();
+            drop(lsmtree);
+            storage.crash();
+
+            let mut lsmtree = LSMTree::open_with_options("data", options);
+            lsmtree.put("c", "v1").unwrap();
+            lsmtree.flush_memtable();
+            let newest = lsmtree.sstable_mgr.sstables.back().unwrap();
+            assert!(newest.id > handed_out);
+
+            if flushed {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_lsm_recovers_sstables_without_manifest_in_any_dir() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let dir = Path::new("nested").join("tree");
+        let mut lsmtree = LSMTree::open_with_options(&dir, options.clone());
+        lsmtree.put("a", "v1").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.put("b", "v1").unwrap();
+        lsmtree.flush_memtable();
+        drop(lsmtree);
+
+        // a directory from before the tree kept a manifest, with stray files next to the sstables.
+        storage.remove(&dir.join("MANIFEST")).unwrap();
+        storage.create(&dir.join("temp.sst")).unwrap();
+        storage.create(&dir.join("notes.txt")).unwrap();
+
+        let lsmtree = LSMTree::open_with_options(&dir, options);
+        let ids: Vec<usize> = lsmtree.sstable_mgr.sstables.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(lsmtree.get("a"), Some("v1".to_string()));
+        assert!(!storage.exists(&dir.join("temp.sst")));
+        assert!(storage.exists(&dir.join("notes.txt")));
+    }
+
+    #[test]
+    fn test_lsm_get_tombstone_shadows_older_sstables() {
+        let mut lsmtree = LSMTree::open(test_dir("tombstone_shadows"));
+        lsmtree.put("a", "v1").unwrap();
+        lsmtree.put("b", "v1").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.delete("a");
+        lsmtree.flush_memtable();
+
+        // the tombstone in the newer sstable hides the value in the older one.
+        assert_eq!(lsmtree.get("a"), None);
+        assert_eq!(lsmtree.get("b"), Some("v1".to_string()));
+        lsmtree.put("a", "v2").unwrap();
+        assert_eq!(lsmtree.get("a"), Some("v2".to_string()));
+    }
+
+    #[test]
+    fn test_lsm_scan_merges_memtable_and_sstables() {
+        let mut lsmtree = LSMTree::open(test_dir("scan_merges"));
+        lsmtree.put("a", "v1").unwrap();
+        lsmtree.put("b", "v1").unwrap();
+        lsmtree.put("c", "v1").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.put("b", "v2").unwrap();
+        lsmtree.delete("c");
+        lsmtree.put("d", "v1").unwrap();
+
+        let all: Vec<(String, String)> = lsmtree.scan(..).collect();
+        let expected = vec![
+            ("a".to_string(), "v1".to_string()),
+            ("b".to_string(), "v2".to_string()),
+            ("d".to_string(), "v1".to_string()),
+        ];
+        assert_eq!(all, expected);
+
+        let keys: Vec<String> = lsmtree.scan("b".."d").map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn test_lsm_deletions_yields_tombstones_still_in_the_tree() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..10 {
+            lsmtree.put(&format!("key{}", i), "value").unwrap();
+        }
+        lsmtree.delete("key3");
+        lsmtree.delete("key7");
+        lsmtree.delete("key8");
+        // deleted, then written again.
+        lsmtree.put("key8", "value").unwrap();
+        lsmtree.delete("key9");
+        lsmtree.flush_memtable();
+        lsmtree.delete("key1");
+
+        let deletions: Vec<(String, u64)> = lsmtree.deletions(..).collect();
+        let expected = [("key1", 16), ("key3", 11), ("key7", 12), ("key9", 15)];
+        let expected = expected.map(|(k, seq)| (k.to_string(), seq));
+        assert_eq!(deletions, expected);
+        let in_range: Vec<String> = lsmtree.deletions("key2".."key8").map(|(k, _)| k).collect();
+        assert_eq!(in_range, ["key3", "key7"]);
+
+        // compacting the tombstones into the oldest sstable drops them.
+        lsmtree.flush_memtable();
+        lsmtree.force_compact();
+        lsmtree.force_compact();
+        assert_eq!(lsmtree.deletions(..).count(), 0);
+    }
+
+    #[test]
+    fn test_lsm_scan_pins_sstables_during_compaction() {
+        let dir = test_dir("scan_pins");
+        let mut lsmtree = LSMTree::open(&dir);
+        lsmtree.put("a", "v1").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.put("b", "v1").unwrap();
+        lsmtree.flush_memtable();
+
+        let mut iter = lsmtree.scan(..);
+        assert_eq!(iter.next(), Some(("a".to_string(), "v1".to_string())));
+
+        // compaction drops the oldest file from the tree, but the iterator still references it.
+        lsmtree.force_compact();
+        assert!(dir.join("1.sst").exists());
+        // the compaction installed a new list of sstables, the iterator reads the one it started with.
+        assert!(!Arc::ptr_eq(&iter._pinned, &lsmtree.sstable_mgr.sstables));
+        assert_eq!(iter._pinned.len(), 2);
+        assert_eq!(iter.next(), Some(("b".to_string(), "v1".to_string())));
+        assert_eq!(iter.next(), None);
+
+        drop(iter);
+        assert!(!dir.join("1.sst").exists());
+    }
+
+    #[test]
+    fn test_lsm_scan_prefix_skips_sstables_without_prefix() {
+        let options = Options {
+            prefix_extractor: Some(PrefixExtractor::UpToDelimiter(':', 1)),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(test_dir("scan_prefix"), options);
+        lsmtree.put("user:1", "alice").unwrap();
+        lsmtree.put("user:2", "bob").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.put("order:1", "book").unwrap();
+        lsmtree.put("users", "not a user").unwrap();
+        lsmtree.flush_memtable();
+
+        let users: Vec<(String, String)> = lsmtree.scan_prefix("user:").collect();
+        assert_eq!(
+            users,
+            vec![
+                ("user:1".to_string(), "alice".to_string()),
+                ("user:2".to_string(), "bob".to_string()),
+            ]
+        );
+        // only the first sstable has keys under `user:`, the second one is never opened.
+        assert_eq!(lsmtree.scan_prefix("user:").sources.len(), 2);
+        assert_eq!(lsmtree.scan_prefix("user").count(), 3);
+    }
+
+    #[test]
+    fn test_lsm_verify_checksums_reports_corrupt_blocks() {
+        let dir = test_dir("verify_checksums");
+        let mut lsmtree = LSMTree::open(&dir);
+        lsmtree.sstable_mgr.table_options.block_size = 64;
+        for i in 0..9 {
+            lsmtree
+                .put(&format!("key{}", i), "some value to fill blocks")
+                .unwrap();
+        }
+        lsmtree.flush_memtable();
+
+        let report = lsmtree.verify_checksums(None);
+        assert_eq!(report.files_checked, 1);
+        assert!(report.blocks_checked > 3);
+        assert!(report.corrupt_files.is_empty());
+
+        // flip a byte within the first data block, holding the smallest keys.
+        let path = dir.join("1.sst");
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[10] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let report = lsmtree.verify_checksums(None);
+        assert_eq!(report.corrupt_files.len(), 1);
+        assert!(report.corrupt_files[0].reason.contains("checksum mismatch"));
+
+        // the damaged block isn't checked when auditing a range of keys it can't hold.
+        let report = lsmtree.verify_checksums(Some(KeyRange::from("key5"..)));
+        assert!(report.corrupt_files.is_empty());
+    }
+
+    #[test]
+    fn test_lsm_separates_large_values_into_blob_files() {
+        let dir = test_dir("blob_separation");
+        let options = Options {
+            blob_threshold: Some(8),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(&dir, options.clone());
+        lsmtree.put("a", "small").unwrap();
+        lsmtree.put("b", "a large value").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.put("c", "another large value").unwrap();
+        lsmtree.flush_memtable();
+
+        // only the large values went to the blob files, the sstables point to them.
+        assert_eq!(
+            find_key_in_sstable_file("a", &dir.join("1.sst")).unwrap(),
+            "small"
+        );
+        let b = find_key_in_sstable_file("b", &dir.join("1.sst")).unwrap();
+        assert!(b.starts_with("Blob("));
+        assert!(dir.join("1.blob").exists() && dir.join("2.blob").exists());
+
+        // compaction copies the pointers, leaving the values where they are.
+        lsmtree.force_compact();
+        assert_eq!(lsmtree.sstable_mgr.blob_files.len(), 2);
+        assert_eq!(lsmtree.get("b"), Some("a large value".to_string()));
+
+        drop(lsmtree);
+        let lsmtree = LSMTree::open_with_options(&dir, options);
+        let all: Vec<(String, String)> = lsmtree.scan(..).collect();
+        let expected = vec![
+            ("a".to_string(), "small".to_string()),
+            ("b".to_string(), "a large value".to_string()),
+            ("c".to_string(), "another large value".to_string()),
+        ];
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn test_lsm_reopens_a_tree_written_in_the_text_record_format() {
+        let dir = test_dir("text_record_format");
+        let options = Options {
+            record_format: RecordFormat::Text,
+            blob_threshold: Some(8),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(&dir, options.clone());
+        lsmtree.put("a", "small").unwrap();
+        lsmtree.put("b", "a large value").unwrap();
+        lsmtree.put("c", "v1").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.put("key:with:colons", "value:with:colons").unwrap();
+        lsmtree.delete("c");
+        lsmtree.flush_memtable();
+        lsmtree.force_compact();
+        let compacted = lsmtree.sstable_mgr.sstables[0].path.clone();
+        // left in the log only.
+        lsmtree.put("d", "another large value").unwrap();
+        lsmtree.delete("a");
+        drop(lsmtree);
+
+        // the compacted sstable and the log segments record their codec, the one of `TextCodec`.
+        let sst = std::fs::read(&compacted).unwrap();
+        assert_eq!(sst[sst.len() - 16], 2);
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "log") {
+                assert_eq!(std::fs::read(&path).unwrap()[8], 2);
+            }
+        }
+
+        // files are read with the codec they were written with, whatever the tree writes now.
+        for record_format in [RecordFormat::Text, RecordFormat::Binary] {
+            let options = Options {
+                record_format,
+                ..options.clone()
+            };
+            let lsmtree = LSMTree::open_with_options(&dir, options);
+            assert_eq!(lsmtree.last_seq, 7);
+            let all: Vec<(String, String)> = lsmtree.scan(..).collect();
+            let expected = vec![
+                ("b".to_string(), "a large value".to_string()),
+                ("d".to_string(), "another large value".to_string()),
+                (
+                    "key:with:colons".to_string(),
+                    "value:with:colons".to_string(),
+                ),
+            ];
+            assert_eq!(all, expected);
+            let deletions: Vec<(String, u64)> = lsmtree.deletions(..).collect();
+            assert_eq!(deletions, vec![("a".to_string(), 7)]);
+        }
+    }
+
+    #[test]
+    fn test_lsm_keys_skip_blob_values() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            blob_threshold: Some(8),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        lsmtree.put("a", "small").unwrap();
+        lsmtree.put("b", "a large value").unwrap();
+        lsmtree.put("c", "small").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.delete("c");
+        lsmtree.put("d", "another large value").unwrap();
+
+        assert_eq!(lsmtree.keys(..).collect::<Vec<_>>(), ["a", "b", "d"]);
+        let values: Vec<String> = lsmtree.values("b"..).collect();
+        assert_eq!(values, ["a large value", "another large value"]);
+
+        // listing the keys doesn't need the blob file.
+        storage.remove(Path::new("data/1.blob")).unwrap();
+        assert_eq!(lsmtree.keys(..).count(), 3);
+    }
+
+    #[test]
+    fn test_lsm_fold_range_visits_live_pairs_in_order() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            blob_threshold: Some(8),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        lsmtree.put("a", "1").unwrap();
+        lsmtree.put("b", "a large value").unwrap();
+        lsmtree.put("c", "22").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.put("a", "333").unwrap();
+        lsmtree.delete("c");
+        lsmtree.put("d", "4444").unwrap();
+
+        let total = lsmtree.fold_range(.., 0, |n, _, v| n + v.len());
+        assert_eq!(total, 3 + 13 + 4);
+
+        let mut seen = Vec::new();
+        lsmtree.for_each_in_range("b".., |k, v| seen.push(format!("{}={}", k, v)));
+        assert_eq!(seen, ["b=a large value", "d=4444"]);
+        assert_eq!(lsmtree.fold_range("e".., 0, |n, _, _| n + 1), 0);
+    }
+
+    #[test]
+    fn test_lsm_scan_page_resumes_from_cursor() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..10 {
+            lsmtree
+                .put(&format!("key{}", i), &format!("value{}", i))
+                .unwrap();
+        }
+        lsmtree.flush_memtable();
+        lsmtree.delete("key4");
+
+        let (page, cursor) = lsmtree.scan_page("key1"..="key8", 3, None);
+        let keys: Vec<&str> = page.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["key1", "key2", "key3"]);
+
+        // the cursor survives a round trip through a string, like it would through an HTTP API.
+        let token = cursor.unwrap().to_string();
+        lsmtree.put("key3a", "written between pages").unwrap();
+        let cursor: Cursor = token.parse().unwrap();
+        let (page, cursor) = lsmtree.scan_page("key1"..="key8", 3, Some(cursor));
+        let keys: Vec<&str> = page.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["key3a", "key5", "key6"]);
+
+        let (page, cursor) = lsmtree.scan_page("key1"..="key8", 3, cursor);
+        let keys: Vec<&str> = page.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["key7", "key8"]);
+        assert_eq!(cursor, None);
+
+        assert!("not hex".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn test_lsm_count_range() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 1000,
+            block_size: 256,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..1000 {
+            lsmtree
+                .put(&format!("key{:04}", i), &format!("value{}", i))
+                .unwrap();
+        }
+        lsmtree.flush_memtable();
+        for i in 0..10 {
+            lsmtree.delete(&format!("key{:04}", i * 100));
+        }
+
+        assert_eq!(lsmtree.count_range("key0100".."key0300"), 198);
+        assert_eq!(lsmtree.count_range(..), 990);
+        assert_eq!(lsmtree.count_range("zzz"..), 0);
+
+        // the memtable holds only tombstones, so the estimate goes by the sstable's blocks.
+        let estimate = lsmtree.approximate_count_range("key0100".."key0300");
+        assert!((180..=240).contains(&estimate), "{}", estimate);
+        assert_eq!(lsmtree.approximate_count_range(..), 1000);
+    }
+
+    #[test]
+    fn test_lsm_sample_keys_spreads_over_range() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 1000,
+            block_size: 256,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        assert!(lsmtree.sample_keys(.., 4).is_empty());
+        for i in 0..1000 {
+            lsmtree
+                .put(&format!("key{:04}", i), &format!("value{}", i))
+                .unwrap();
+        }
+        lsmtree.flush_memtable();
+
+        let samples = lsmtree.sample_keys("key0200".."key0600", 4);
+        assert_eq!(samples.len(), 4);
+        assert!(samples.is_sorted());
+        // each sample closes roughly a quarter of the range.
+        for (i, key) in samples.iter().enumerate() {
+            let k: usize = key["key".len()..].parse().unwrap();
+            let expected = 200 + (i + 1) * 100;
+            assert!(k.abs_diff(expected) <= 25, "{} for {}", key, expected);
+        }
+
+        let all = lsmtree.sample_keys(.., 10_000);
+        assert!(all.len() < 1000);
+        assert_eq!(all.last().unwrap(), "key0999");
+    }
+
+    #[test]
+    fn test_lsm_collect_blob_garbage_rewrites_live_values() {
+        let dir = test_dir("blob_garbage");
+        let options = Options {
+            blob_threshold: Some(8),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(&dir, options.clone());
+        lsmtree.put("a", "large value a1").unwrap();
+        lsmtree.put("b", "large value b1").unwrap();
+        lsmtree.put("c", "large value c1").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.put("a", "large value a2").unwrap();
+        lsmtree.delete("b");
+        lsmtree.flush_memtable();
+
+        // two thirds of the first blob file are dead, not enough to bother yet.
+        assert_eq!(lsmtree.collect_blob_garbage(0.9), 0);
+        assert_eq!(lsmtree.collect_blob_garbage(0.5), 1);
+        assert!(!dir.join("1.blob").exists());
+        let ids: Vec<usize> = lsmtree
+            .sstable_mgr
+            .blob_files
+            .iter()
+            .map(|b| b.id)
+            .collect();
+        assert_eq!(ids, vec![2, 3]);
+
+        drop(lsmtree);
+        let lsmtree = LSMTree::open_with_options(&dir, options);
+        assert_eq!(lsmtree.get("a"), Some("large value a2".to_string()));
+        assert_eq!(lsmtree.get("b"), None);
+        assert_eq!(lsmtree.get("c"), Some("large value c1".to_string()));
+    }
+
+    #[test]
+    fn test_lsm_streams_large_values_through_blob_files() {
+        let dir = test_dir("stream_values");
+        let mut lsmtree = LSMTree::open(&dir);
+        // larger than a streaming chunk, with a character split between two chunks.
+        let large = "aé".repeat(40_000);
+        lsmtree.put_reader("large", large.as_bytes()).unwrap();
+        lsmtree.put("small", "v1").unwrap();
+
+        let mut streamed = String::new();
+        let mut reader = lsmtree.get_stream("large").unwrap();
+        reader.read_to_string(&mut streamed).unwrap();
+        assert_eq!(streamed, large);
+        let mut out = Vec::new();
+        assert!(lsmtree.get_writer("small", &mut out).unwrap());
+        assert_eq!(out, b"v1");
+        assert!(!lsmtree.get_writer("missing", &mut out).unwrap());
+
+        // values that aren't valid UTF-8 are rejected, without a trace.
+        let invalid: &[u8] = &[b'a', 0xff];
+        assert!(lsmtree.put_reader("large", invalid).is_err());
+        assert_eq!(lsmtree.sstable_mgr.blob_files.len(), 1);
+
+        // the log points to the streamed value until the memtable is flushed.
+        drop(reader);
+        drop(lsmtree);
+        let records: Vec<WalRecord> = WalReader::open(&dir)
+            .unwrap()
+            .records_since(0)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records[0].op, WalOp::Put(large.clone()));
+        let mut lsmtree = LSMTree::open(&dir);
+        assert_eq!(lsmtree.get("large"), Some(large.clone()));
+        lsmtree.flush_memtable();
+        drop(lsmtree);
+
+        let lsmtree = LSMTree::open(&dir);
+        assert_eq!(lsmtree.get("large"), Some(large));
+        assert_eq!(lsmtree.scan(..).count(), 2);
+    }
+
+    #[test]
+    fn test_lsm_moves_cold_sstables_to_cold_tier() {
+        let hot = SimStorage::new(1, Default::default());
+        let cold = SimStorage::new(2, Default::default());
+        let tier = ColdTierOptions {
+            dir: PathBuf::from("cold"),
+            storage: Arc::new(cold.clone()),
+            cold_after: Duration::from_secs(3600),
+        };
+        // sstables turn cold by the injected clock, the storages' clocks stand still.
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let options = Options {
+            storage: Arc::new(hot.clone()),
+            cold_tier: Some(tier),
+            clock: Some(Arc::new(clock.clone())),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        lsmtree.put("a", "v1").unwrap();
+        lsmtree.flush_memtable();
+        clock.advance(Duration::from_secs(7200));
+        lsmtree.put("b", "v1").unwrap();
+        let mut iter = lsmtree.scan(..);
+
+        // the first sstable turned cold by the time the second one is flushed.
+        lsmtree.flush_memtable();
+        let tiers: Vec<bool> = lsmtree
+            .sstable_mgr
+            .sstables
+            .iter()
+            .map(|s| s.cold)
+            .collect();
+        assert_eq!(tiers, vec![true, false]);
+        assert!(cold.exists(Path::new("cold/1.sst")));
+        assert_eq!(lsmtree.get("a"), Some("v1".to_string()));
+
+        // the hot copy is only removed once the iterator reading it is done.
+        assert!(hot.exists(Path::new("data/1.sst")));
+        assert_eq!(iter.next(), Some(("a".to_string(), "v1".to_string())));
+        drop(iter);
+        assert!(!hot.exists(Path::new("data/1.sst")));
+
+        drop(lsmtree);
+        let lsmtree = LSMTree::open_with_options("data", options);
+        assert!(lsmtree.sstable_mgr.sstables[0].cold);
+        assert_eq!(lsmtree.scan(..).count(), 2);
+    }
+
+    #[test]
+    fn test_lsm_compresses_compaction_outputs() {
+        let dir = test_dir("bottommost_compression");
+        let options = Options {
+            bottommost_compression: Some(Compression::Lz4),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(&dir, options);
+        for _ in 0..2 {
+            for i in 0..9 {
+                lsmtree
+                    .put(&format!("key{}", i), &"a compressible value ".repeat(8))
+                    .unwrap();
+            }
+            lsmtree.flush_memtable();
+        }
+
+        // the merged file holds the same entries as either input, compressed.
+        let flushed = std::fs::metadata(dir.join("2.sst")).unwrap().len();
+        lsmtree.force_compact();
+        let merged = std::fs::metadata(&lsmtree.sstable_mgr.sstables[0].path).unwrap();
+        assert!(merged.len() < flushed / 2);
+        assert_eq!(lsmtree.get("key3"), Some("a compressible value ".repeat(8)));
+        assert_eq!(lsmtree.scan(..).count(), 9);
+    }
+
+    #[test]
+    fn test_lsm_compaction_trains_compression_dictionary() {
+        let record = |i: usize| {
+            format!(
+                "{{\"id\": {}, \"name\": \"user{}\", \"email\": \"user{}@example.com\"}}",
+                i, i, i
+            )
+        };
+        // the size of the compacted sstable, with a dictionary of the given size.
+        let compacted_size = |dictionary_size: usize| {
+            let storage = SimStorage::new(1, Default::default());
+            let options = Options {
+                storage: Arc::new(storage.clone()),
+                // blocks of a couple of records each, without filters to take up space.
+                block_size: 128,
+                bloom_bits_per_key: 0,
+                bottommost_compression: Some(Compression::Lz4),
+                compression_dictionary_size: dictionary_size,
+                ..Options::default()
+            };
+            let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+            for i in 0..200 {
+                lsmtree.put(&format!("key{:03}", i), &record(i)).unwrap();
+                if i % 100 == 99 {
+                    lsmtree.flush_memtable();
+                }
+            }
+            lsmtree.force_compact();
+            let merged = &lsmtree.sstable_mgr.sstables[0];
+            let size = storage.len(&merged.path).unwrap();
+            drop(lsmtree);
+
+            let lsmtree = LSMTree::open_with_options("data", options);
+            assert_eq!(lsmtree.get("key042"), Some(record(42)));
+            assert_eq!(lsmtree.scan(..).count(), 200);
+            assert!(lsmtree.verify_checksums(None).corrupt_files.is_empty());
+            size
+        };
+        // the dictionary is stored in the file too, and pays for itself many times over.
+        assert!(compacted_size(1024) < compacted_size(0) * 5 / 6);
+    }
+
+    #[test]
+    fn test_lsm_trees_share_block_cache() {
+        let cache = BlockCache::new(4096);
+        let open = |value: &str| {
+            // both trees have their files at the same paths, on different storages.
+            let options = Options {
+                storage: Arc::new(SimStorage::new(1, Default::default())),
+                block_cache: Some(cache.clone()),
+                block_size: 64,
+                ..Options::default()
+            };
+            let mut lsmtree = LSMTree::open_with_options("data", options);
+            for i in 0..9 {
+                lsmtree.put(&format!("key{}", i), value).unwrap();
+            }
+            lsmtree.flush_memtable();
+            lsmtree
+        };
+        let first = open("first");
+        let second = open("second");
+
+        for _ in 0..2 {
+            assert_eq!(first.get("key1"), Some("first".to_string()));
+            assert_eq!(second.get("key1"), Some("second".to_string()));
+        }
+        assert!(cache.usage() > 0);
+        assert_eq!(second.scan(..).count(), 9);
+        assert!(cache.usage() <= cache.capacity());
+    }
+
+    #[test]
+    fn test_lsm_warms_block_cache_after_restart() {
+        let storage = SimStorage::new(1, Default::default());
+        let open = |cache: &BlockCache| {
+            let options = Options {
+                storage: Arc::new(storage.clone()),
+                block_cache: Some(cache.clone()),
+                block_size: 64,
+                ..Options::default()
+            };
+            LSMTree::open_with_options("data", options)
+        };
+
+        let cache = BlockCache::new(1 << 20);
+        let mut lsmtree = open(&cache);
+        for i in 0..30 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        lsmtree.flush_memtable();
+        // warming a range only reads the blocks that may hold keys within it.
+        let blocks = lsmtree.warm_cache(..);
+        assert!(blocks > 2);
+        assert!((1..blocks).contains(&lsmtree.warm_cache("key10".."key11")));
+
+        // only the blocks of the last lookups are hot when the tree is closed.
+        let cache = BlockCache::new(1 << 20);
+        let lsmtree = open(&cache);
+        assert_eq!(cache.usage(), 0);
+        lsmtree.get("key01");
+        lsmtree.get("key29");
+        let usage = cache.usage();
+        lsmtree.close().unwrap();
+
+        let cache = BlockCache::new(1 << 20);
+        let lsmtree = open(&cache);
+        assert_eq!(cache.usage(), usage);
+        assert_eq!(cache.keys().len(), 2);
+        assert_eq!(lsmtree.get("key29"), Some("value".to_string()));
+    }
+
+    #[test]
+    fn test_lsm_open_reads_sstables_lazily() {
+        let storage = SimStorage::new(1, Default::default());
+        let open = |preload_metadata| {
+            let options = Options {
+                storage: Arc::new(storage.clone()),
+                compaction_trigger: 100,
+                preload_metadata,
+                ..Options::default()
+            };
+            LSMTree::open_with_options("data", options)
+        };
+
+        let mut lsmtree = open(false);
+        for i in 0..30 {
+            lsmtree
+                .put(&format!("key{:02}", i), &format!("v{}", i))
+                .unwrap();
+        }
+        drop(lsmtree);
+
+        // the sequence numbers continue from the manifest, without reading any sstable.
+        let mut lsmtree = open(false);
+        let sstables = || lsmtree.sstable_mgr.sstables.iter();
+        assert_eq!(sstables().len(), 3);
+        assert!(sstables().all(|sst| !sst.is_loaded()));
+        assert_eq!(lsmtree.last_seq, 30);
+        // the newest sstable holds the key, the older ones are never touched.
+        assert_eq!(lsmtree.get("key25"), Some("v25".to_string()));
+        let loaded: Vec<bool> = sstables().map(|sst| sst.is_loaded()).collect();
+        assert_eq!(loaded, [false, false, true]);
+        lsmtree.put("key00", "new").unwrap();
+        assert_eq!(lsmtree.memtable.get("key00").unwrap().seq, 31);
+        drop(lsmtree);
+
+        let lsmtree = open(true);
+        let sstables = || lsmtree.sstable_mgr.sstables.iter();
+        while !sstables().all(|sst| sst.is_loaded()) {
+            std::thread::yield_now();
+        }
+        assert_eq!(lsmtree.get("key00"), Some("new".to_string()));
+    }
+
+    #[test]
+    fn test_lsm_compacts_sstables_with_most_garbage() {
+        let options = Options {
+            storage: Arc::new(SimStorage::new(1, Default::default())),
+            memtable_limit: 100,
+            compaction_trigger: 100,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        // an older version of one of the keys deleted below, flushed along with the first sstable.
+        lsmtree.put("b5", "old").unwrap();
+        let mut flush = |keys: &str, delete: bool| {
+            for i in 0..10 {
+                let k = format!("{}{}", keys, i);
+                if delete {
+                    lsmtree.delete(&k);
+                } else {
+                    lsmtree.put(&k, "value").unwrap();
+                }
+            }
+            lsmtree.flush_memtable();
+        };
+        flush("a", false);
+        flush("b", false);
+        flush("b", true);
+        flush("c", false);
+        let ids: Vec<usize> = lsmtree.sstable_mgr.sstables.iter().map(|s| s.id).collect();
+
+        // the tombstones of the third sstable delete all of the second one.
+        assert_eq!(lsmtree.sstable_mgr.pick_compaction(), 1);
+        lsmtree.force_compact();
+        let sstables = &lsmtree.sstable_mgr.sstables;
+        assert_eq!(sstables.len(), 3);
+        assert_eq!((sstables[0].id, sstables[2].id), (ids[0], ids[3]));
+        // only the tombstone for the key the oldest sstable has is kept.
+        let properties = sstables[1].properties().unwrap();
+        assert_eq!((properties.entries, properties.deletions), (1, 1));
+        assert_eq!(lsmtree.get("b1"), None);
+        assert_eq!(lsmtree.get("b5"), None);
+        assert_eq!(lsmtree.scan(..).count(), 20);
+
+        // merged into the oldest sstable, the tombstones are dropped too.
+        assert_eq!(lsmtree.sstable_mgr.pick_compaction(), 0);
+        lsmtree.force_compact();
+        // nothing left to reclaim, so the oldest two go.
+        lsmtree.force_compact();
+        let properties = lsmtree.sstable_mgr.sstables[0].properties().unwrap();
+        assert_eq!((properties.entries, properties.deletions), (20, 0));
+    }
+
+    #[test]
+    fn test_lsm_tracks_shadowed_entries_per_sstable() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        for i in 0..20 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        // overwrites 4 keys of the first sstable, and deletes 3 of the second one.
+        for i in 0..4 {
+            lsmtree.put(&format!("key{:02}", i), "new value").unwrap();
+        }
+        for i in 10..13 {
+            lsmtree.delete(&format!("key{:02}", i));
+        }
+        lsmtree.put("key20", "value").unwrap();
+        lsmtree.put("key21", "value").unwrap();
+        lsmtree.put("key22", "value").unwrap();
+        let shadowed = |lsmtree: &LSMTree| -> Vec<u64> {
+            lsmtree.sstables().iter().map(|sst| sst.shadowed).collect()
+        };
+        assert_eq!(shadowed(&lsmtree), [4, 3, 0]);
+        drop(lsmtree);
+
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        assert_eq!(shadowed(&lsmtree), [4, 3, 0]);
+        // merging the newest two drops the deleted entries, the overwritten ones remain shadowed.
+        lsmtree.sstable_mgr.merge_sstables(1..3).unwrap();
+        assert_eq!(shadowed(&lsmtree), [4, 0]);
+        lsmtree.force_compact();
+        assert_eq!(shadowed(&lsmtree), [0]);
+    }
+
+    #[test]
+    fn test_lsm_merges_small_sstables_with_each_other() {
+        let options = Options {
+            storage: Arc::new(SimStorage::new(1, Default::default())),
+            compaction_trigger: 100,
+            intra_l0_compaction_trigger: Some(4),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..40 {
+            lsmtree.put(&format!("key{:02}", i), "old").unwrap();
+        }
+        // the first four flushes are merged into one large sstable.
+        let sstables = || lsmtree.sstable_mgr.sstables.iter();
+        let base: Vec<usize> = sstables().map(|sst| sst.id).collect();
+        assert_eq!(base.len(), 1);
+
+        lsmtree.delete("key00");
+        for i in 1..30 {
+            lsmtree.put(&format!("key{:02}", i), "new").unwrap();
+        }
+        assert_eq!(lsmtree.sstable_mgr.sstables.len(), 4);
+        for i in 30..40 {
+            lsmtree.put(&format!("key{:02}", i), "new").unwrap();
+        }
+        // the next four are merged with each other, and the large one is left alone.
+        let sstables = &lsmtree.sstable_mgr.sstables;
+        assert_eq!(sstables.len(), 2);
+        assert_eq!(sstables[0].id, base[0]);
+        assert_eq!(sstables[1].properties().unwrap().deletions, 1);
+        assert_eq!(lsmtree.get("key00"), None);
+        assert!(lsmtree.scan(..).all(|(_, v)| v == "new"));
+    }
+
+    #[test]
+    fn test_lsm_overlapping_compaction_merges_only_sstables_that_overlap() {
+        let options = Options {
+            storage: Arc::new(SimStorage::new(1, Default::default())),
+            compaction_trigger: 4,
+            compaction_strategy: CompactionStrategy::Overlapping,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for prefix in ["a", "b", "c"] {
+            for i in 0..10 {
+                lsmtree.put(&format!("{}{}", prefix, i), "value").unwrap();
+            }
+        }
+        let ids: Vec<usize> = lsmtree.sstable_mgr.sstables.iter().map(|s| s.id).collect();
+        for i in 0..5 {
+            lsmtree.delete(&format!("b{}", i));
+        }
+        for i in 5..10 {
+            lsmtree.put(&format!("b{}", i), "new").unwrap();
+        }
+
+        // the newest sstable only overlaps the one with the older `b` keys, and the two are merged,
+        // moving past the sstable in between, which is left alone like the oldest one.
+        let sstables = &lsmtree.sstable_mgr.sstables;
+        assert_eq!(sstables.len(), 3);
+        assert_eq!((sstables[0].id, sstables[2].id), (ids[0], ids[2]));
+        // no sstable left out has `b` keys, so the tombstones are dropped in the middle too.
+        let properties = sstables[1].properties().unwrap();
+        assert_eq!((properties.entries, properties.deletions), (5, 0));
+        assert_eq!(lsmtree.get("b1"), None);
+        assert_eq!(lsmtree.get("b7"), Some("new".to_string()));
+        assert_eq!(lsmtree.get("c1"), Some("value".to_string()));
+        assert_eq!(lsmtree.scan(..).count(), 25);
+
+        // a flush that spans all of them has them all merged.
+        lsmtree.put("a5", "new").unwrap();
+        for i in 0..9 {
+            lsmtree.put(&format!("c{}", i), "new").unwrap();
+        }
+        let sstables = &lsmtree.sstable_mgr.sstables;
+        assert_eq!(sstables.len(), 1);
+        assert_eq!(sstables[0].properties().unwrap().entries, 25);
+        assert_eq!(lsmtree.get("a5"), Some("new".to_string()));
+    }
+
+    #[test]
+    fn test_lsm_universal_compaction_merges_sstables_of_similar_size() {
+        let options = Options {
+            storage: Arc::new(SimStorage::new(1, Default::default())),
+            compaction_trigger: 4,
+            compaction_strategy: CompactionStrategy::universal(),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        let put = |lsmtree: &mut LSMTree, keys: Range<usize>, value: &str| {
+            for i in keys {
+                lsmtree.put(&format!("key{:02}", i), value).unwrap();
+            }
+        };
+        // four flushes of the same size are merged all at once.
+        put(&mut lsmtree, 0..40, &"large value ".repeat(10));
+        let sstables = || lsmtree.sstable_mgr.sstables.iter();
+        let base: Vec<usize> = sstables().map(|sst| sst.id).collect();
+        assert_eq!(base.len(), 1);
+
+        // three more are no match for the large one, they're merged on their own.
+        put(&mut lsmtree, 40..70, "value");
+        let sstables = &lsmtree.sstable_mgr.sstables;
+        assert_eq!(sstables.len(), 2);
+        assert_eq!(sstables[0].id, base[0]);
+        assert_eq!(sstables[1].properties().unwrap().entries, 30);
+        assert_eq!(lsmtree.scan(..).count(), 70);
+    }
+
+    #[test]
+    fn test_lsm_fifo_compaction_drops_oldest_sstables() {
+        let storage = SimStorage::new(1, Default::default());
+        let open = |strategy| {
+            let options = Options {
+                storage: Arc::new(storage.clone()),
+                compaction_trigger: 2,
+                compaction_strategy: strategy,
+                ..Options::default()
+            };
+            LSMTree::open_with_options("data", options)
+        };
+        let mut lsmtree = open(CompactionStrategy::Fifo {
+            max_size: None,
+            max_age: Some(Duration::from_secs(60)),
+        });
+        for i in 0..30 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+            storage.advance_clock(Duration::from_secs(4));
+        }
+        // never merged, the sstables flushed over a minute ago are dropped as a whole.
+        let sstables = &lsmtree.sstable_mgr.sstables;
+        assert_eq!(sstables.len(), 2);
+        assert!(
+            sstables
+                .iter()
+                .all(|sst| sst.properties().unwrap().entries == 10)
+        );
+        assert_eq!(lsmtree.get("key09"), None);
+        assert_eq!(lsmtree.scan(..).count(), 20);
+        drop(lsmtree);
+
+        let sst_size = storage.len(Path::new("data/3.sst")).unwrap();
+        let mut lsmtree = open(CompactionStrategy::Fifo {
+            max_size: Some(sst_size * 3 / 2),
+            max_age: None,
+        });
+        lsmtree.put("key99", "value").unwrap();
         lsmtree.flush_memtable();
-        assert!(std::fs::exists("data/1.sst").unwrap());
+        assert_eq!(lsmtree.sstable_mgr.sstables.len(), 1);
+        assert_eq!(lsmtree.scan(..).count(), 1);
     }
 
     #[test]
-    fn test_lsm_reads_from_sstable() {
-        let mut lsmtree = LSMTree::new();
-        lsmtree.put("hello", "world");
-        lsmtree.put("foo", "bar");
-        lsmtree.delete("hello");
-        // force flush memtable so reads can happen from sstable.
+    fn test_lsm_drops_sstables_past_their_ttl() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            compaction_trigger: 3,
+            sstable_ttl: Some(Duration::from_secs(60)),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..20 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        storage.advance_clock(Duration::from_secs(120));
+        for i in 20..30 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        // the two expired sstables are dropped instead of being merged.
+        let sstables = &lsmtree.sstable_mgr.sstables;
+        assert_eq!(sstables.len(), 1);
+        assert_eq!(sstables[0].created(), storage.now());
+        assert_eq!(lsmtree.get("key05"), None);
+        assert_eq!(lsmtree.scan(..).count(), 10);
+    }
+
+    #[test]
+    fn test_lsm_takes_time_from_injected_clock() {
+        let storage = SimStorage::new(1, Default::default());
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            clock: Some(Arc::new(clock.clone())),
+            compaction_trigger: 3,
+            sstable_ttl: Some(Duration::from_secs(60)),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..20 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        // the storage's clock stands still, only the injected one moves.
+        clock.advance(Duration::from_secs(120));
+        for i in 20..30 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        let sstables = &lsmtree.sstable_mgr.sstables;
+        assert_eq!(sstables.len(), 1);
+        assert_eq!(sstables[0].created(), clock.now());
+        assert_eq!(storage.now(), UNIX_EPOCH);
+        assert_eq!(lsmtree.scan(..).count(), 10);
+    }
+
+    #[test]
+    fn test_lsm_compacts_only_within_compaction_windows() {
+        let storage = SimStorage::new(1, Default::default());
+        let busy = Arc::new(AtomicBool::new(false));
+        let is_busy = Arc::clone(&busy);
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            compaction_trigger: 3,
+            compaction_schedule: CompactionSchedule {
+                is_busy: Some(Arc::new(move || is_busy.load(Ordering::Relaxed))),
+                ..CompactionSchedule::between_hours(2, 6)
+            },
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        let mut written = 0;
+        // writes `n` memtables worth of keys.
+        let mut put = |lsmtree: &mut LSMTree, n: usize| {
+            for _ in 0..n * 10 {
+                lsmtree.put(&format!("key{:03}", written), "value").unwrap();
+                written += 1;
+            }
+        };
+        // the clock starts at midnight, flushes go on without compacting.
+        put(&mut lsmtree, 4);
+        assert_eq!(lsmtree.sstable_mgr.sstables.len(), 4);
+
+        storage.advance_clock(Duration::from_secs(3 * 3600));
+        busy.store(true, Ordering::Relaxed);
+        put(&mut lsmtree, 1);
+        assert_eq!(lsmtree.sstable_mgr.sstables.len(), 5);
+
+        // within the window and not busy, the next flush catches up.
+        busy.store(false, Ordering::Relaxed);
+        put(&mut lsmtree, 1);
+        assert_eq!(lsmtree.sstable_mgr.sstables.len(), 2);
+
+        storage.advance_clock(Duration::from_secs(4 * 3600));
+        put(&mut lsmtree, 2);
+        assert_eq!(lsmtree.sstable_mgr.sstables.len(), 4);
+        lsmtree.compact_now();
+        assert_eq!(lsmtree.sstable_mgr.sstables.len(), 2);
+        assert_eq!(lsmtree.scan(..).count(), 80);
+    }
+
+    #[test]
+    fn test_lsm_set_option_takes_effect_while_open() {
+        let storage = SimStorage::new(1, Default::default());
+        let cache = BlockCache::new(1 << 20);
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            block_cache: Some(cache.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        for i in 0..25 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        assert_eq!(lsmtree.stats().memtable_entries, 5);
+        lsmtree.set_option("memtable_limit", "4").unwrap();
+        assert_eq!(lsmtree.stats().memtable_entries, 0);
+        assert_eq!(lsmtree.stats().sstables, 3);
+
+        lsmtree.set_option("compaction_trigger", "3").unwrap();
+        assert_eq!(lsmtree.stats().sstables, 2);
+        for i in 25..29 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        assert_eq!(lsmtree.stats().sstables, 2);
+
+        assert_eq!(lsmtree.scan(..).count(), 29);
+        assert!(cache.usage() > 0);
+        lsmtree.set_option("block_cache_capacity", "0").unwrap();
+        assert_eq!(cache.usage(), 0);
+
+        let err = lsmtree.set_option("memtable_limit", "lots").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(lsmtree.set_option("storage", "s3").is_err());
+        drop(lsmtree);
+
+        let mut contents = String::new();
+        let mut file = storage.open(Path::new("data/OPTIONS")).unwrap();
+        file.read_to_string(&mut contents).unwrap();
+        assert!(contents.contains("memtable_limit 4\n"));
+        assert!(contents.contains("compaction_trigger 3\n"));
+    }
+
+    #[test]
+    fn test_lsm_flushes_a_memtable_recovered_over_the_limit_on_the_next_write() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        for i in 0..5 {
+            lsmtree.put(&format!("key{}", i), "value").unwrap();
+        }
+        drop(lsmtree);
+
+        // every kind of write flushes the memtable once it's past the limit, not just at it.
+        options.memtable_limit = 2;
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        assert_eq!(lsmtree.stats().memtable_entries, 5);
+        lsmtree
+            .put_audited("key5", "value", &AuditContext::default())
+            .unwrap();
+        assert_eq!(lsmtree.stats().memtable_entries, 0);
+        drop(lsmtree);
+
+        options.memtable_limit = 1000;
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        for i in 6..11 {
+            lsmtree.put(&format!("key{}", i), "value").unwrap();
+        }
+        drop(lsmtree);
+        options.memtable_limit = 2;
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        assert_eq!(lsmtree.stats().memtable_entries, 5);
+        lsmtree.put_reader("key11", "value".as_bytes()).unwrap();
+        assert_eq!(lsmtree.stats().memtable_entries, 0);
+        drop(lsmtree);
+
+        options.memtable_limit = 1000;
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        for i in 0..5 {
+            lsmtree.delete(&format!("key{}", i));
+        }
+        drop(lsmtree);
+        options.memtable_limit = 2;
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        assert_eq!(lsmtree.stats().memtable_entries, 5);
+        lsmtree.delete("key5");
+        assert_eq!(lsmtree.stats().memtable_entries, 0);
+        assert_eq!(lsmtree.scan(..).count(), 6);
+    }
+
+    #[test]
+    fn test_lsm_refuses_keys_and_values_over_max_size() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            max_key_size: Some(8),
+            max_value_size: Some(16),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        assert_eq!(lsmtree.put("key", &"v".repeat(16)), Ok(()));
+        assert_eq!(
+            lsmtree.put("key", &"v".repeat(17)),
+            Err(Error::ValueTooLarge { size: 17, max: 16 })
+        );
+        assert_eq!(lsmtree.put("long key", "v"), Ok(()));
+        assert_eq!(
+            lsmtree.put("longer key", "v"),
+            Err(Error::KeyTooLarge { size: 10, max: 8 })
+        );
+        let err = lsmtree
+            .put_reader("longer key", "v".as_bytes())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(lsmtree.get("key"), Some("v".repeat(16)));
+        assert_eq!(lsmtree.get("longer key"), None);
+
+        lsmtree.set_option("max_value_size", "none").unwrap();
+        assert_eq!(lsmtree.put("key", &"v".repeat(17)), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "written with the `reverse` comparator")]
+    fn test_lsm_rejects_data_dir_with_different_comparator() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            compression: Compression::Lz4,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        lsmtree.put("key", "value").unwrap();
+        drop(lsmtree);
+
+        let mut contents = String::new();
+        let mut file = storage.open(Path::new("data/OPTIONS")).unwrap();
+        file.read_to_string(&mut contents).unwrap();
+        assert!(contents.contains("comparator bytewise\n"));
+        assert!(contents.contains("compression Lz4\n"));
+
+        // as if written by a tree ordering its keys differently.
+        let contents = contents.replace("comparator bytewise", "comparator reverse");
+        let mut file = storage.create(Path::new("data/OPTIONS")).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file.sync().unwrap();
+        LSMTree::open_with_options("data", options);
+    }
+
+    #[test]
+    fn test_lsm_upgrades_text_sstables() {
+        let dir = test_dir("upgrade_format");
+        std::fs::create_dir_all(&dir).unwrap();
+        // a data dir written before sstables had blocks, or the tree a manifest.
+        std::fs::write(dir.join("1.sst"), "a:1\nb:2\nc:3\n").unwrap();
+        std::fs::write(dir.join("2.sst"), "b:🪦\nc:30\n").unwrap();
+
+        let mut lsmtree = LSMTree::open(&dir);
+        let versions = |lsmtree: &LSMTree| -> Vec<u32> {
+            let sstables = lsmtree.sstable_mgr.sstables.iter();
+            sstables.map(|sst| sst.format_version()).collect()
+        };
+        assert_eq!(versions(&lsmtree), [1, 1]);
+        assert_eq!(lsmtree.upgrade_format(), 2);
+        assert_eq!(lsmtree.upgrade_format(), 0);
+        drop(lsmtree);
+
+        let lsmtree = LSMTree::open(&dir);
+        assert_eq!(versions(&lsmtree), [8, 8]);
+        assert!(!dir.join("1.sst").exists() && !dir.join("2.sst").exists());
+        let entries: Vec<(String, String)> = lsmtree.scan(..).collect();
+        assert_eq!(
+            entries,
+            [("a".into(), "1".into()), ("c".into(), "30".into())]
+        );
+        assert_eq!(lsmtree.get("b"), None);
+    }
+
+    #[test]
+    fn test_lsm_compactions_upgrade_text_sstables_one_at_a_time() {
+        for upgrade in [true, false] {
+            let dir = test_dir(&format!("upgrade_on_compaction_{}", upgrade));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("1.sst"), "a:1\nb:2\n").unwrap();
+            std::fs::write(dir.join("2.sst"), "b:🪦\nc:3\n").unwrap();
+            std::fs::write(dir.join("3.sst"), "d:4\n").unwrap();
+            let options = Options {
+                memtable_limit: 2,
+                compaction_trigger: 5,
+                upgrade_text_sstables_on_compaction: upgrade,
+                ..Options::default()
+            };
+            let mut lsmtree = LSMTree::open_with_options(&dir, options);
+            let text_sstables = |lsmtree: &LSMTree| {
+                let sstables = lsmtree.sstable_mgr.sstables.iter();
+                sstables.filter(|sst| sst.format_version() == 1).count()
+            };
+            lsmtree.put("e", "5").unwrap();
+            lsmtree.put("f", "6").unwrap();
+            assert_eq!(text_sstables(&lsmtree), 3);
+
+            // the compaction merges the oldest two, and the third is upgraded after it.
+            lsmtree.put("g", "7").unwrap();
+            lsmtree.put("h", "8").unwrap();
+            assert_eq!(lsmtree.sstable_mgr.sstables.len(), 4);
+            assert_eq!(text_sstables(&lsmtree), if upgrade { 0 } else { 1 });
+            assert!(!upgrade || !dir.join("3.sst").exists());
+            assert_eq!(lsmtree.get("b"), None);
+            assert_eq!(lsmtree.get("d"), Some("4".to_string()));
+            assert_eq!(lsmtree.scan(..).count(), 7);
+        }
+    }
+
+    #[test]
+    fn test_lsm_stores_the_old_tombstone_marker_as_a_value() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 2,
+            compaction_trigger: 2,
+            ..Options::default()
+        };
+        let marker = TOMBSTONE_MARKER.to_string();
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        lsmtree.put("a", &marker).unwrap();
+        lsmtree.put("b", "1").unwrap();
+        lsmtree.put("c", &marker).unwrap();
+        lsmtree.delete("b");
+        lsmtree.put("d", &marker).unwrap();
+        assert_eq!(lsmtree.stats().sstables, 1);
+        drop(lsmtree);
+
+        // from the log, the memtable and a compacted sstable alike.
+        let lsmtree = LSMTree::open_with_options("data", options);
+        let entries: Vec<(String, String)> = lsmtree.scan(..).collect();
+        let expected = ["a", "c", "d"].map(|k| (k.to_string(), marker.clone()));
+        assert_eq!(entries, expected);
+        assert_eq!(lsmtree.get("a"), Some(marker));
+        assert_eq!(lsmtree.get("b"), None);
+    }
+
+    #[test]
+    fn test_lsm_flush_survives_crash_at_any_point() {
+        sim::silence_fault_panics();
+        let faults = sim::FaultConfig {
+            strict_directory_sync: true,
+            ..Default::default()
+        };
+        for crash_after in 1.. {
+            let storage = SimStorage::new(crash_after, faults.clone());
+            let options = Options {
+                storage: Arc::new(storage.clone()),
+                memtable_limit: 100,
+                blob_threshold: Some(20),
+                ..Options::default()
+            };
+            let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+            lsmtree.put("a", "v1").unwrap();
+            lsmtree.put("b", &"v".repeat(30)).unwrap();
+
+            storage.crash_after(crash_after);
+            let flushed = catch_unwind(AssertUnwindSafe(|| lsmtree.flush_memtable())).is_ok();
+            drop(lsmtree);
+            storage.crash();
+
+            // whether the flush took effect or not, the writes are in the sstable or the log.
+            let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+            assert_eq!(lsmtree.get("a"), Some("v1".to_string()));
+            assert_eq!(lsmtree.get("b"), Some("v".repeat(30)));
+            // and writes made after it survive another crash.
+            lsmtree.put("c", "v1").unwrap();
+            drop(lsmtree);
+            storage.crash();
+            let lsmtree = LSMTree::open_with_options("data", options);
+            assert_eq!(lsmtree.get("c"), Some("v1".to_string()));
+
+            if flushed {
+                assert_eq!(lsmtree.sstable_mgr.sstables.len(), 1);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_lsm_compaction_survives_crash_at_any_point() {
+        sim::silence_fault_panics();
+        // crash after every possible number of storage operations into the compaction, until it
+        // finishes without crashing.
+        for crash_after in 1.. {
+            let storage = SimStorage::new(crash_after, Default::default());
+            let options = Options {
+                storage: Arc::new(storage.clone()),
+                ..Options::default()
+            };
+            let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+            lsmtree.put("a", "v1").unwrap();
+            lsmtree.put("b", "v1").unwrap();
+            lsmtree.flush_memtable();
+            lsmtree.delete("a");
+            lsmtree.flush_memtable();
+            lsmtree.put("c", "v1").unwrap();
+            lsmtree.flush_memtable();
+
+            storage.crash_after(crash_after);
+            let compacted = catch_unwind(AssertUnwindSafe(|| lsmtree.force_compact())).is_ok();
+            drop(lsmtree);
+            storage.crash();
+            // an output cut short is only ever found under its temporary name, every sstable has
+            // its footer.
+            let files = storage.list(Path::new("data")).unwrap();
+            let shared: Arc<dyn Storage> = Arc::new(storage.clone());
+            for path in files
+                .iter()
+                .filter(|p| p.extension().is_some_and(|e| e == "sst"))
+            {
+                let sst = SSTable::at(&shared, path.clone(), 0);
+                assert_eq!(sst.format_version(), sstable::FORMAT_VERSION, "{:?}", path);
+            }
+
+            // whether the compaction took effect or not, the deleted key must stay deleted.
+            let lsmtree = LSMTree::open_with_options("data", options);
+            let keys: Vec<String> = lsmtree.scan(..).map(|(k, _)| k).collect();
+            assert_eq!(keys, vec!["b".to_string(), "c".to_string()]);
+            let sstables = lsmtree.sstable_mgr.sstables.len();
+            let files = storage.list(Path::new("data")).unwrap();
+            let sst_files = files
+                .iter()
+                .filter(|p| p.extension().is_some_and(|e| e == "sst"));
+            // leftover sstables and outputs were cleaned up.
+            assert_eq!(sst_files.count(), sstables);
+            assert!(
+                !files
+                    .iter()
+                    .any(|p| p.extension().is_some_and(|e| e == "tmp"))
+            );
+
+            if compacted {
+                assert_eq!(sstables, 2);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_lsm_removed_files_stay_removed_after_crash() {
+        let faults = sim::FaultConfig {
+            strict_directory_sync: true,
+            ..Default::default()
+        };
+        let storage = SimStorage::new(1, faults);
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 10,
+            compaction_trigger: 2,
+            blob_threshold: Some(20),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..50 {
+            lsmtree
+                .put(&format!("key{:02}", i % 25), &"v".repeat(30 + i))
+                .unwrap();
+        }
+        let mut live: Vec<PathBuf> = (lsmtree.sstable_mgr.sstables.iter())
+            .map(|sst| sst.path.clone())
+            .chain(
+                lsmtree
+                    .sstable_mgr
+                    .blob_files
+                    .iter()
+                    .map(|b| b.path.clone()),
+            )
+            .collect();
+        drop(lsmtree);
+        storage.crash();
+
+        // the compaction inputs and flushed log segments are gone without recovery removing them.
+        let mut files: Vec<PathBuf> = (storage.list(Path::new("data")).unwrap().into_iter())
+            .filter(|p| p.extension().is_some_and(|e| e == "sst" || e == "blob"))
+            .collect();
+        files.sort();
+        live.sort();
+        assert_eq!(files, live);
+        let segments = (storage.list(Path::new("data")).unwrap().iter())
+            .filter(|p| p.extension().is_some_and(|e| e == "log"))
+            .count();
+        assert_eq!(segments, 1);
+    }
+
+    #[test]
+    fn test_lsm_recovery_removes_temporary_files() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        lsmtree.put("a", "v1").unwrap();
         lsmtree.flush_memtable();
-        assert!(lsmtree.get("hello").is_none());
-        assert!(lsmtree.get("foo").unwrap() == "bar");
+        drop(lsmtree);
+        let leftovers = ["MANIFEST.tmp", "OPTIONS.tmp", "temp.sst"];
+        for name in leftovers {
+            let mut file = storage.create(&Path::new("data").join(name)).unwrap();
+            file.write_all(b"partial").unwrap();
+            file.sync().unwrap();
+        }
+
+        let lsmtree = LSMTree::open_with_options("data", options);
+        assert_eq!(lsmtree.get("a"), Some("v1".to_string()));
+        for name in leftovers {
+            assert!(!storage.exists(&Path::new("data").join(name)), "{name}");
+        }
     }
 
     #[test]
-    fn test_lsm_recovers_and_reads_older_sstables() {
-        let mut lsmtree = LSMTree::new();
-        lsmtree.put("hello", "world");
-        lsmtree.put("foo", "bar");
-        lsmtree.delete("hello");
+    fn test_lsm_compaction_output_gets_a_fresh_id() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        lsmtree.put("a", "v1").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.put("b", "v1").unwrap();
+        lsmtree.flush_memtable();
+        let inputs: Vec<(usize, PathBuf)> = (lsmtree.sstable_mgr.sstables.iter())
+            .map(|sst| (sst.id, sst.path.clone()))
+            .collect();
+
+        lsmtree.force_compact();
+        // the output never takes the name of an input, which caches and iterators may still hold.
+        let ids: Vec<usize> = lsmtree.sstable_mgr.sstables.iter().map(|s| s.id).collect();
+        assert_eq!(ids.len(), 1);
+        assert!(inputs.iter().all(|(id, _)| ids[0] > *id));
+        assert!(inputs.iter().all(|(_, path)| !storage.exists(path)));
+        assert_eq!(lsmtree.get("a"), Some("v1".to_string()));
+    }
+
+    #[test]
+    fn test_lsm_scan_filters_keys_before_reading_values() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            blob_threshold: Some(20),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..10 {
+            lsmtree
+                .put(&format!("key{:02}", i), &i.to_string())
+                .unwrap();
+        }
+        lsmtree.put("large", &"v".repeat(30)).unwrap();
+        lsmtree.put("key03", "33").unwrap();
+        lsmtree.delete("key04");
         lsmtree.flush_memtable();
+        // the blob file can't be read anymore, so a scan that read its value would panic.
+        let blob_file = &lsmtree.sstable_mgr.blob_files[0];
+        storage.remove(&blob_file.path).unwrap();
+
+        let odd = |key: &str| key.starts_with("key") && key.ends_with(['1', '3', '5', '7', '9']);
+        let keys: Vec<String> = lsmtree.scan(..).filter_keys(odd).map(|(k, _)| k).collect();
+        assert_eq!(keys, ["key01", "key03", "key05", "key07", "key09"]);
+
+        let entries: Vec<(String, String)> = (lsmtree.scan("key"..))
+            .filter_keys(|key| key != "large")
+            .filter_values(|value| value.len() == 1)
+            .filter_values(|value| value != "0")
+            .collect();
+        let expected: Vec<(String, String)> = [1, 2, 5, 6, 7, 8, 9]
+            .map(|i| (format!("key{:02}", i), i.to_string()))
+            .into();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_lsm_compact_all_merges_every_sstable_and_drops_tombstones() {
+        let dir = test_dir("compact_all");
+        let options = Options {
+            compaction_trigger: 100,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(&dir, options.clone());
+        for i in 0..50 {
+            lsmtree
+                .put(&format!("key{:02}", i), &i.to_string())
+                .unwrap();
+        }
+        // only puts fill the memtable up, so every ninth delete is followed by one.
+        for i in 0..36 {
+            lsmtree.delete(&format!("key{:02}", i));
+            if i % 9 == 8 {
+                lsmtree.put("key45", &format!("overwritten {}", i)).unwrap();
+            }
+        }
+        // still in the memtable, which is flushed first.
+        lsmtree.delete("key36");
+        let sstables = lsmtree.sstables();
+        assert_eq!(sstables.len(), 9);
+        assert_eq!(sstables[8].deletions, Some(9));
+
+        lsmtree.compact_all();
+        let sstables = lsmtree.sstables();
+        assert_eq!(sstables.len(), 1);
+        assert_eq!(sstables[0].entries, Some(13));
+        assert_eq!(sstables[0].deletions, Some(0));
+        assert_eq!(lsmtree.get("key05"), None);
+        assert_eq!(lsmtree.get("key36"), None);
+        assert_eq!(lsmtree.get("key45"), Some("overwritten 35".to_string()));
+        assert_eq!(lsmtree.scan(..).count(), 13);
+
+        // a single sstable without tombstones is left as it is.
+        let id = lsmtree.sstables()[0].id;
+        lsmtree.compact_all();
+        assert_eq!(lsmtree.sstables()[0].id, id);
+
         drop(lsmtree);
-        // re-initialize another LSMTree instance.
-        let mut lsmtree = LSMTree::new();
-        // confirm that memtable is empty on a new instance.
-        assert!(lsmtree.memtable.is_empty());
-        assert!(lsmtree.get("hello").is_none());
-        assert!(lsmtree.get("foo").unwrap() == "bar");
+        let lsmtree = LSMTree::open_with_options(&dir, options);
+        assert_eq!(lsmtree.stats().sstables, 1);
+        assert_eq!(lsmtree.scan(..).count(), 13);
     }
 
-    // TODO: make this test pass
     #[test]
-    fn test_lsm_flush_triggers_compaction() {
-        clear_data_dir();
-        let mut lsmtree = LSMTree::new();
-        lsmtree.memtable_limit = 1;
-        lsmtree.sstable_mgr.compaction_trigger = 3;
+    fn test_lsm_compaction_keeps_only_tombstones_older_sstables_may_need() {
+        let options = Options {
+            storage: Arc::new(SimStorage::new(1, Default::default())),
+            compaction_trigger: 100,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for prefix in ["a", "b"] {
+            for i in 0..10 {
+                lsmtree
+                    .put(&format!("{}{:02}", prefix, i), "value")
+                    .unwrap();
+            }
+        }
+        for i in 0..5 {
+            lsmtree.delete(&format!("b{:02}", i));
+        }
+        lsmtree.delete("a05");
+        for i in 0..4 {
+            lsmtree.put(&format!("c{:02}", i), "value").unwrap();
+        }
+        let deletions = |lsmtree: &LSMTree| -> Vec<Option<u64>> {
+            lsmtree.sstables().iter().map(|sst| sst.deletions).collect()
+        };
+        assert_eq!(deletions(&lsmtree), [Some(0), Some(0), Some(6)]);
+
+        // the oldest sstable is left out, and only the tombstone of a key within its range stays.
+        lsmtree.sstable_mgr.merge_sstables(1..3).unwrap();
+        assert_eq!(deletions(&lsmtree), [Some(0), Some(1)]);
+        assert_eq!(lsmtree.get("a05"), None);
+        assert_eq!(lsmtree.get("b00"), None);
+        assert_eq!(lsmtree.scan(..).count(), 18);
+
+        lsmtree.force_compact();
+        assert_eq!(deletions(&lsmtree), [Some(0)]);
+        assert_eq!(lsmtree.get("a05"), None);
+        assert_eq!(lsmtree.scan(..).count(), 18);
+    }
+
+    #[test]
+    fn test_lsm_merges_into_the_oldest_sstable_use_bottommost_compression() {
+        let options = Options {
+            storage: Arc::new(SimStorage::new(1, Default::default())),
+            compaction_trigger: 100,
+            bottommost_compression: Some(Compression::Lz4),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..40 {
+            lsmtree
+                .put(&format!("key{:02}", i), &"a compressible value ".repeat(8))
+                .unwrap();
+        }
+        let compressed = |lsmtree: &LSMTree| -> Vec<bool> {
+            let sstables = lsmtree.sstables();
+            sstables
+                .iter()
+                .map(|sst| sst.compression_ratio().unwrap() > 2.0)
+                .collect()
+        };
+        assert_eq!(compressed(&lsmtree), [false; 4]);
+
+        // a merge of the newest sstables leaves older ones below it.
+        lsmtree.sstable_mgr.merge_sstables(2..4).unwrap();
+        assert_eq!(compressed(&lsmtree), [false; 3]);
+        lsmtree.sstable_mgr.merge_sstables(0..2).unwrap();
+        assert_eq!(compressed(&lsmtree), [true, false]);
+        assert_eq!(lsmtree.scan(..).count(), 40);
+    }
+
+    #[test]
+    fn test_lsm_compact_all_output_uses_bottommost_compression() {
+        let options = Options {
+            storage: Arc::new(SimStorage::new(1, Default::default())),
+            compaction_trigger: 100,
+            bottommost_compression: Some(Compression::Lz4),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..35 {
+            lsmtree
+                .put(&format!("key{:02}", i), &"a compressible value ".repeat(8))
+                .unwrap();
+        }
+        let sstables = lsmtree.sstables();
+        assert!(
+            sstables
+                .iter()
+                .all(|sst| sst.compression_ratio() == Some(1.0))
+        );
+
+        lsmtree.compact_all();
+        let sstables = lsmtree.sstables();
+        assert_eq!(sstables.len(), 1);
+        assert!(sstables[0].compression_ratio().unwrap() > 2.0);
+        assert_eq!(lsmtree.scan(..).count(), 35);
+    }
+
+    #[test]
+    fn test_lsm_compresses_sstables_with_the_codec_of_their_level() {
+        let options = Options {
+            storage: Arc::new(SimStorage::new(1, Default::default())),
+            compaction_trigger: 100,
+            compaction_compression: Some(Compression::Lz4),
+            bottommost_compression: Some(Compression::Zstd { level: 19 }),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..40 {
+            let value = format!("value {} of key{:02} ", i * 7919 % 1000, i).repeat(8);
+            lsmtree.put(&format!("key{:02}", i), &value).unwrap();
+        }
+        let ratios = |lsmtree: &LSMTree| -> Vec<f64> {
+            let sstables = lsmtree.sstables();
+            sstables
+                .iter()
+                .map(|sst| sst.compression_ratio().unwrap())
+                .collect()
+        };
+        // flushes are left uncompressed.
+        assert_eq!(ratios(&lsmtree), [1.0; 4]);
+
+        lsmtree.sstable_mgr.merge_sstables(2..4).unwrap();
+        let middle = ratios(&lsmtree)[2];
+        assert!(middle > 2.0);
+        lsmtree.sstable_mgr.merge_sstables(0..2).unwrap();
+        let bottom = ratios(&lsmtree)[0];
+        assert!(bottom > middle);
+        assert_eq!(lsmtree.scan(..).count(), 40);
+        assert_eq!(lsmtree.get("key07"), Some("value 433 of key07 ".repeat(8)));
+    }
 
-        lsmtree.put("a", "v1");
-        lsmtree.put("b", "v2");
-        lsmtree.put("c", "v3");
+    #[test]
+    fn test_lsm_bottommost_zstd_dictionary_compresses_small_values() {
+        let record = |i: usize| {
+            format!(
+                "{{\"id\": {}, \"name\": \"user{}\", \"email\": \"user{}@example.com\"}}",
+                i, i, i
+            )
+        };
+        // the size of the bottommost sstable once `compact` is done, with a dictionary of the given
+        // size.
+        let compacted_size = |dictionary_size: usize, compact: fn(&mut LSMTree)| {
+            let storage = SimStorage::new(1, Default::default());
+            let options = Options {
+                storage: Arc::new(storage.clone()),
+                memtable_limit: 250,
+                compaction_trigger: 100,
+                // blocks of a couple of records each, without filters to take up space.
+                block_size: 128,
+                bloom_bits_per_key: 0,
+                bottommost_compression: Some(Compression::Zstd { level: 3 }),
+                compression_dictionary_size: dictionary_size,
+                ..Options::default()
+            };
+            let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+            for i in 0..1000 {
+                lsmtree.put(&format!("key{:04}", i), &record(i)).unwrap();
+            }
+            assert_eq!(lsmtree.sstables().len(), 4);
+            compact(&mut lsmtree);
+            let bottom = &lsmtree.sstable_mgr.sstables[0];
+            let size = storage.len(&bottom.path).unwrap();
+            drop(lsmtree);
 
-        assert!(find_key_in_sstable_file("a", &PathBuf::from("data/2.sst")).is_some());
-        assert!(find_key_in_sstable_file("b", &PathBuf::from("data/2.sst")).is_some());
-        assert!(find_key_in_sstable_file("c", &PathBuf::from("data/2.sst")).is_none());
+            let lsmtree = LSMTree::open_with_options("data", options);
+            assert_eq!(lsmtree.get("key0042"), Some(record(42)));
+            assert_eq!(lsmtree.scan(..).count(), 1000);
+            assert!(lsmtree.verify_checksums(None).corrupt_files.is_empty());
+            size
+        };
+        // a merge into the oldest sstable, and a full compaction.
+        let merge_oldest: fn(&mut LSMTree) =
+            |lsmtree| lsmtree.sstable_mgr.merge_sstables(0..2).unwrap();
+        let compact_all: fn(&mut LSMTree) = |lsmtree| lsmtree.compact_all();
+        for compact in [merge_oldest, compact_all] {
+            assert!(compacted_size(4096, compact) < compacted_size(0, compact) * 5 / 6);
+        }
     }
 }
@@ -0,0 +1,369 @@
+//! The manifest, recording which sstables make up the tree.
+//!
+//! Without it, recovery would have to trust whatever `.sst` files it finds in the data directory,
+//! including half written flushes and compaction outputs, or inputs that a compaction was about to
+//! delete. Instead, a flush or compaction only takes effect once the manifest listing its result
+//! is durable, and files that aren't listed are leftovers of an interrupted operation.
+//!
+//! The manifest is a small text file with the version of the on-disk format of the tree that wrote
+//! it, the highest id given to an sstable so far and the highest sequence number in the sstables,
+//! followed by one line per live sstable id, oldest first, one per sstable that was moved to the
+//! cold tier, one per live blob file (see `blob.rs`) and one per sstable with entries shadowed by
+//! newer sstables, with their estimated number:
+//!
+//!   format_version 5
+//!   last_sstable_id 8
+//!   last_seq 120
+//!   sst 4
+//!   sst 7
+//!   cold 4
+//!   blob 7
+//!   shadowed 4 12
+//!
+//! A tree refuses to open a manifest with a newer format version than its own, as it may not be
+//! able to read the files. Older versions are fine: every format the tree ever wrote can be read,
+//! and `LSMTree::upgrade_format` rewrites sstables in older formats.
+//!
+//! Every flush and compaction changes the manifest. Rather than replacing the file every time, the
+//! tree appends the new version of the manifest to it, preceded by a line with the checksum of the
+//! version's lines and their number:
+//!
+//!   checksum 1c2f9a3b 8
+//!   format_version 6
+//!   ...
+//!   checksum 90e1d4c2 9
+//!   ...
+//!
+//! The last version whose checksum matches is the current one, so a version torn by a crash in the
+//! middle of appending it, or damaged later on, leaves the one before it in effect. Once the file
+//! holds `MAX_VERSIONS` versions, it's rewritten with just the newest one: the current file is
+//! renamed to `MANIFEST.old`, the previous generation, and a new `MANIFEST` is started. If no
+//! version of `MANIFEST` checks out, the tree falls back to the newest version in `MANIFEST.old`.
+//! Manifests written before they had checksums hold a single version without the checksum line.
+//! 💡 LevelDB and RocksDB append version edits, only what changed, to their manifest log instead,
+//! and start a new one on restart or when it grows past `max_manifest_file_size`.
+
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    checksum::crc32,
+    sstable::FORMAT_VERSION,
+    storage::{Storage, WritableFile},
+};
+
+pub(crate) const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TEMP_FILE: &str = "MANIFEST.tmp";
+// the previous generation of the manifest, see the module docs.
+const PREVIOUS_MANIFEST_FILE: &str = "MANIFEST.old";
+// number of versions the manifest holds before it's rewritten.
+const MAX_VERSIONS: usize = 100;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    // the highest id given to an sstable so far, so that ids aren't reused after a restart or crash.
+    pub last_sstable_id: usize,
+    // ids of the live sstables, oldest first.
+    pub sstables: Vec<usize>,
+    // ids of the sstables kept on the cold tier rather than in the data dir.
+    pub cold_sstables: Vec<usize>,
+    // ids of the blob files holding large values, oldest first.
+    pub blob_files: Vec<usize>,
+    // the highest sequence number in the sstables, missing from older manifests.
+    pub last_seq: Option<u64>,
+    // (sstable id, estimated number of its entries shadowed by newer sstables), for the sstables
+    // that have any.
+    pub shadowed: Vec<(usize, u64)>,
+}
+
+impl Manifest {
+    // reads the current version of the manifest in `dir`, falling back to the previous generation
+    // if none of the current one's versions checks out. Returns `None` if the directory doesn't
+    // have a manifest yet.
+    pub fn load(storage: &dyn Storage, dir: &Path) -> io::Result<Option<Manifest>> {
+        let path = dir.join(MANIFEST_FILE);
+        let previous = dir.join(PREVIOUS_MANIFEST_FILE);
+        if !storage.exists(&path) && !storage.exists(&previous) {
+            return Ok(None);
+        }
+
+        for path in [path, previous] {
+            if let Some(lines) = last_version(storage, &path)? {
+                return Manifest::parse(&lines).map(Some);
+            }
+        }
+        Err(invalid(format!("no intact manifest in {}", dir.display())))
+    }
+
+    fn parse(lines: &[String]) -> io::Result<Manifest> {
+        let mut manifest = Manifest::default();
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("format_version", version)) => {
+                    let version: u32 = version.parse().map_err(invalid)?;
+                    if version > FORMAT_VERSION {
+                        return Err(invalid(format!(
+                            "format version {} is newer than the supported {}",
+                            version, FORMAT_VERSION
+                        )));
+                    }
+                }
+                Some(("last_sstable_id", id)) => {
+                    manifest.last_sstable_id = id.parse().map_err(invalid)?
+                }
+                Some(("last_seq", seq)) => manifest.last_seq = Some(seq.parse().map_err(invalid)?),
+                Some(("sst", id)) => manifest.sstables.push(id.parse().map_err(invalid)?),
+                Some(("cold", id)) => manifest.cold_sstables.push(id.parse().map_err(invalid)?),
+                Some(("blob", id)) => manifest.blob_files.push(id.parse().map_err(invalid)?),
+                Some(("shadowed", counts)) => {
+                    let (id, count) = counts.split_once(' ').ok_or_else(|| invalid(line))?;
+                    let count = (
+                        id.parse().map_err(invalid)?,
+                        count.parse().map_err(invalid)?,
+                    );
+                    manifest.shadowed.push(count);
+                }
+                _ => return Err(invalid(format!("unknown manifest line `{}`", line))),
+            }
+        }
+        Ok(manifest)
+    }
+
+    // atomically replaces the manifest in `dir` with one holding just this version.
+    pub fn save(&self, storage: &dyn Storage, dir: &Path) -> io::Result<()> {
+        let temp_path = dir.join(MANIFEST_TEMP_FILE);
+        let mut file = storage.create(&temp_path)?;
+        self.write_to(&mut file)?;
+        file.sync()?;
+
+        storage.rename(&temp_path, &dir.join(MANIFEST_FILE))?;
+        storage.sync_dir(dir)
+    }
+
+    // writes the manifest to `file` as a single version, preceded by its checksum line.
+    pub fn write_to(&self, mut file: impl Write) -> io::Result<()> {
+        let mut lines = vec![
+            format!("format_version {}", FORMAT_VERSION),
+            format!("last_sstable_id {}", self.last_sstable_id),
+        ];
+        if let Some(seq) = self.last_seq {
+            lines.push(format!("last_seq {}", seq));
+        }
+        lines.extend(self.sstables.iter().map(|id| format!("sst {}", id)));
+        lines.extend(self.cold_sstables.iter().map(|id| format!("cold {}", id)));
+        lines.extend(self.blob_files.iter().map(|id| format!("blob {}", id)));
+        lines
+            .extend((self.shadowed.iter()).map(|(id, count)| format!("shadowed {} {}", id, count)));
+
+        let body: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        let checksum = crc32(body.as_bytes());
+        write!(file, "checksum {:08x} {}\n{}", checksum, lines.len(), body)
+    }
+}
+
+// The manifest file a tree appends new versions of the manifest to.
+pub(crate) struct ManifestLog {
+    file: Box<dyn WritableFile>,
+    // number of versions in the file.
+    versions: usize,
+}
+
+impl ManifestLog {
+    // starts a new manifest in `dir` holding just `manifest`, keeping the current one as the
+    // previous generation if any of its versions checks out.
+    pub fn create(storage: &dyn Storage, dir: &Path, manifest: &Manifest) -> io::Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        if last_version(storage, &path)?.is_some() {
+            storage.rename(&path, &dir.join(PREVIOUS_MANIFEST_FILE))?;
+        }
+        let file = storage.create(&path)?;
+        let mut log = ManifestLog { file, versions: 0 };
+        log.append(manifest)?;
+        storage.sync_dir(dir)?;
+        Ok(log)
+    }
+
+    // durably appends `manifest` as the new current version.
+    pub fn append(&mut self, manifest: &Manifest) -> io::Result<()> {
+        let mut version = Vec::new();
+        manifest.write_to(&mut version)?;
+        self.file.write_all(&version)?;
+        self.file.sync()?;
+        self.versions += 1;
+        Ok(())
+    }
+
+    // whether the file holds enough versions to be rewritten.
+    pub fn is_full(&self) -> bool {
+        self.versions >= MAX_VERSIONS
+    }
+}
+
+// returns the lines of the last version in the manifest file at `path` whose checksum matches, or
+// `None` if there's no such version or no file.
+fn last_version(storage: &dyn Storage, path: &Path) -> io::Result<Option<Vec<String>>> {
+    if !storage.exists(path) {
+        return Ok(None);
+    }
+    let mut contents = String::new();
+    storage.open(path)?.read_to_string(&mut contents)?;
+    let lines: Vec<&str> = contents.lines().collect();
+    match lines.first() {
+        None => return Ok(None),
+        // a manifest from before versions had checksums.
+        Some(line) if !line.starts_with("checksum ") => {
+            return Ok(Some(lines.iter().map(|l| l.to_string()).collect()));
+        }
+        _ => {}
+    }
+
+    let mut current = None;
+    let mut i = 0;
+    while i < lines.len() {
+        let header = lines[i]
+            .strip_prefix("checksum ")
+            .and_then(|h| h.split_once(' '));
+        i += 1;
+        let Some((checksum, len)) = header else {
+            continue;
+        };
+        let (Ok(checksum), Ok(len)) = (u32::from_str_radix(checksum, 16), len.parse::<usize>())
+        else {
+            continue;
+        };
+        let Some(version) = lines.get(i..i + len) else {
+            break;
+        };
+        let body: String = version.iter().map(|line| format!("{}\n", line)).collect();
+        if crc32(body.as_bytes()) == checksum {
+            current = Some(version.iter().map(|l| l.to_string()).collect());
+            i += len;
+        }
+    }
+    Ok(current)
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        path::Path,
+    };
+
+    use super::{MAX_VERSIONS, Manifest, ManifestLog};
+    use crate::{sim::SimStorage, storage::Storage};
+
+    fn manifest(last_sstable_id: usize) -> Manifest {
+        Manifest {
+            last_sstable_id,
+            sstables: (1..=last_sstable_id).collect(),
+            ..Manifest::default()
+        }
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let storage = SimStorage::new(1, Default::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+        assert_eq!(Manifest::load(&storage, dir).unwrap(), None);
+
+        let manifest = Manifest {
+            last_sstable_id: 8,
+            sstables: vec![4, 7],
+            cold_sstables: vec![4],
+            blob_files: vec![7],
+            last_seq: Some(120),
+            shadowed: vec![(4, 12)],
+        };
+        manifest.save(&storage, dir).unwrap();
+        assert_eq!(Manifest::load(&storage, dir).unwrap(), Some(manifest));
+        assert_eq!(storage.list(dir).unwrap(), vec![dir.join("MANIFEST")]);
+
+        let mut file = storage.create(&dir.join("MANIFEST")).unwrap();
+        writeln!(file, "format_version 99").unwrap();
+        assert!(Manifest::load(&storage, dir).is_err());
+    }
+
+    #[test]
+    fn test_manifest_log_falls_back_to_last_intact_version() {
+        let storage = SimStorage::new(1, Default::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+        let mut log = ManifestLog::create(&storage, dir, &manifest(1)).unwrap();
+        log.append(&manifest(2)).unwrap();
+        assert_eq!(Manifest::load(&storage, dir).unwrap(), Some(manifest(2)));
+
+        // a version torn by a crash while it was appended.
+        let mut torn = Vec::new();
+        manifest(3).write_to(&mut torn).unwrap();
+        torn.truncate(torn.len() - 4);
+        log.file.write_all(&torn).unwrap();
+        assert_eq!(Manifest::load(&storage, dir).unwrap(), Some(manifest(2)));
+
+        // a version whose lines were damaged after it was written.
+        let path = dir.join("MANIFEST");
+        let mut contents = String::new();
+        storage
+            .open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        let damaged = contents.replacen("sst 2", "sst 9", 1);
+        storage
+            .create(&path)
+            .unwrap()
+            .write_all(damaged.as_bytes())
+            .unwrap();
+        assert_eq!(Manifest::load(&storage, dir).unwrap(), Some(manifest(1)));
+    }
+
+    #[test]
+    fn test_manifest_log_rewrites_and_falls_back_to_previous_generation() {
+        let storage = SimStorage::new(1, Default::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+        let mut log = ManifestLog::create(&storage, dir, &manifest(1)).unwrap();
+        for id in 2..=MAX_VERSIONS {
+            log.append(&manifest(id)).unwrap();
+        }
+        assert!(log.is_full());
+        let size = storage.len(&dir.join("MANIFEST")).unwrap();
+
+        // the next version starts a new file, the full one becomes the previous generation.
+        ManifestLog::create(&storage, dir, &manifest(MAX_VERSIONS + 1)).unwrap();
+        assert_eq!(storage.len(&dir.join("MANIFEST.old")).unwrap(), size);
+        assert!(storage.len(&dir.join("MANIFEST")).unwrap() < size / 10);
+        let latest = Some(manifest(MAX_VERSIONS + 1));
+        assert_eq!(Manifest::load(&storage, dir).unwrap(), latest);
+
+        // a current manifest without any intact version.
+        let mut file = storage.create(&dir.join("MANIFEST")).unwrap();
+        writeln!(file, "checksum 00000000 1\nsst 1").unwrap();
+        let previous = Some(manifest(MAX_VERSIONS));
+        assert_eq!(Manifest::load(&storage, dir).unwrap(), previous);
+        // which isn't kept as a previous generation when the next one starts.
+        ManifestLog::create(&storage, dir, &manifest(MAX_VERSIONS + 2)).unwrap();
+        assert_eq!(storage.len(&dir.join("MANIFEST.old")).unwrap(), size);
+
+        storage.create(&dir.join("MANIFEST.old")).unwrap();
+        storage.create(&dir.join("MANIFEST")).unwrap();
+        assert!(Manifest::load(&storage, dir).is_err());
+    }
+
+    #[test]
+    fn test_manifest_without_checksums() {
+        let storage = SimStorage::new(1, Default::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+        let mut file = storage.create(&dir.join("MANIFEST")).unwrap();
+        writeln!(file, "format_version 5\nlast_sstable_id 1\nsst 1").unwrap();
+        assert_eq!(Manifest::load(&storage, dir).unwrap(), Some(manifest(1)));
+    }
+}
@@ -0,0 +1,96 @@
+//! The memtable, which holds the newest record of every key written since the last flush.
+//!
+//! It's a single sorted map, written through the tree's `&mut` methods only: every write takes
+//! the lock of the log anyway, to append and sync its record before it's inserted, so writers
+//! sharing a tree (see `queued.rs` and `transaction.rs`) are serialized before they get here, and
+//! a lock per part of the map would only add locking to every insert and a merge to every scan.
+//! Scans and flushes get the keys in order straight from the map.
+
+use std::collections::BTreeMap;
+
+use crate::{KeyRange, Record};
+
+#[derive(Default)]
+pub(crate) struct Memtable {
+    records: BTreeMap<String, Record>,
+}
+
+impl From<BTreeMap<String, Record>> for Memtable {
+    fn from(records: BTreeMap<String, Record>) -> Self {
+        Memtable { records }
+    }
+}
+
+impl Memtable {
+    // inserts `record` as the newest record of `key`.
+    pub fn insert(&mut self, key: String, record: Record) {
+        self.records.insert(key, record);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Record> {
+        self.records.get(key)
+    }
+
+    // number of keys in the memtable.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // the newest sequence number in the memtable, 0 when it's empty.
+    pub fn last_seq(&self) -> u64 {
+        self.records.values().map(|r| r.seq).max().unwrap_or(0)
+    }
+
+    // copies the keys within `range` and their records, in key order.
+    pub fn range(&self, range: &KeyRange) -> Vec<(String, Record)> {
+        (self.records.iter())
+            .filter(|(k, _)| range.contains(k))
+            .map(|(k, record)| (k.clone(), record.clone()))
+            .collect()
+    }
+
+    // removes every key along with its record, returning them in key order.
+    pub fn take(&mut self) -> Vec<(String, Record)> {
+        std::mem::take(&mut self.records).into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Memtable;
+    use crate::{KeyRange, Record, Value};
+
+    fn record(seq: u64) -> Record {
+        Record {
+            seq,
+            value: Some(Value::Inline(seq.to_string())),
+        }
+    }
+
+    #[test]
+    fn test_memtable_keeps_the_newest_record_of_each_key_in_key_order() {
+        let mut memtable = Memtable::default();
+        for i in (0..100).rev() {
+            memtable.insert(format!("key{:02}", i), record(i));
+        }
+        memtable.insert("key42".to_string(), record(100));
+        assert_eq!(memtable.len(), 100);
+        assert_eq!(memtable.get("key42"), Some(&record(100)));
+        assert_eq!(memtable.last_seq(), 100);
+
+        let keys: Vec<String> = (memtable.range(&KeyRange::prefix("key1")).into_iter())
+            .map(|(k, _)| k)
+            .collect();
+        let expected: Vec<String> = (10..20).map(|i| format!("key{:02}", i)).collect();
+        assert_eq!(keys, expected);
+
+        let entries = memtable.take();
+        assert!(entries.is_sorted_by(|(a, _), (b, _)| a < b));
+        assert_eq!(entries.len(), 100);
+        assert!(memtable.is_empty());
+    }
+}
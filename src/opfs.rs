@@ -0,0 +1,401 @@
+//! Storage in the browser's origin private file system (OPFS), so that the tree can run client-side
+//! in a web app. Built for wasm32 with the `opfs` feature.
+//!
+//! The `Storage` trait is synchronous, while the OPFS API is asynchronous: files can only be opened,
+//! written and removed through promises, even from a worker. So `OpfsStorage` keeps every file in
+//! memory, read from OPFS when it's opened, and persists changes in the background, one at a time
+//! and in the order they were made:
+//!
+//!   - syncing a file writes its contents to OPFS.
+//!   - renaming a file writes it under its new name, then removes the old one.
+//!   - removing a file removes it from OPFS.
+//!
+//! OPFS thus always holds the files as they were after some prefix of these changes, the same as
+//! a disk that crashed at some point, which the tree recovers from like from any crash. Call
+//! `OpfsStorage::flush` to wait for every change made so far to be persisted, e.g. before a page
+//! is closed.
+//! 💡 Syncing rewrites the whole file. That's fine for the small files of an in-browser store, but
+//! a long write-ahead log would rather append through a sync access handle, only found in workers.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    future::Future,
+    io::{self, Cursor, Write},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    time::{Duration, SystemTime},
+};
+
+use js_sys::{Array, Reflect, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::{JsFuture, spawn_local};
+use web_sys::{
+    File, FileSystemDirectoryHandle, FileSystemFileHandle, FileSystemGetDirectoryOptions,
+    FileSystemGetFileOptions, FileSystemHandleKind, FileSystemWritableFileStream, Navigator,
+};
+
+use crate::storage::{ReadableFile, Storage, WritableFile};
+
+#[derive(Default)]
+struct OpfsState {
+    files: BTreeMap<PathBuf, FileData>,
+    dirs: BTreeSet<PathBuf>,
+    // changes yet to be persisted, oldest first.
+    pending: VecDeque<Change>,
+    // whether a task is persisting the pending changes.
+    persisting: bool,
+    // tasks waiting in `flush` for the pending changes to be persisted.
+    waiters: Vec<Waker>,
+    // the first change that failed to persist, reported by `flush`.
+    error: Option<String>,
+}
+
+struct FileData {
+    data: Vec<u8>,
+    modified: SystemTime,
+}
+
+enum Change {
+    Write(PathBuf, Vec<u8>),
+    Remove(PathBuf),
+}
+
+// Storage keeping its files in memory and persisting them to the origin private file system.
+// Clones share the same files.
+#[derive(Clone)]
+pub struct OpfsStorage {
+    state: Arc<Mutex<OpfsState>>,
+}
+
+impl std::fmt::Debug for OpfsStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpfsStorage").finish_non_exhaustive()
+    }
+}
+
+impl OpfsStorage {
+    // reads every file in the origin private file system into memory.
+    pub async fn open() -> io::Result<Self> {
+        let mut state = OpfsState::default();
+        let root = root().await.map_err(js_error)?;
+        load_dir(&root, PathBuf::new(), &mut state)
+            .await
+            .map_err(js_error)?;
+        Ok(OpfsStorage {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    // waits until every change made so far is persisted, returning the first one that couldn't be.
+    pub async fn flush(&self) -> io::Result<()> {
+        Persisted {
+            state: Arc::clone(&self.state),
+        }
+        .await;
+        match self.state().error.take() {
+            Some(e) => Err(io::Error::other(e)),
+            None => Ok(()),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, OpfsState> {
+        self.state.lock().unwrap()
+    }
+
+    // queues `change` to be persisted after the ones before it.
+    fn persist(&self, change: Change) {
+        let mut state = self.state();
+        state.pending.push_back(change);
+        if state.persisting {
+            return;
+        }
+        state.persisting = true;
+
+        let state = Arc::clone(&self.state);
+        spawn_local(async move {
+            loop {
+                let Some(change) = state.lock().unwrap().pending.pop_front() else {
+                    break;
+                };
+                if let Err(e) = apply(change).await {
+                    let mut state = state.lock().unwrap();
+                    state.error.get_or_insert(js_error(e).to_string());
+                }
+            }
+            let mut state = state.lock().unwrap();
+            state.persisting = false;
+            state.waiters.drain(..).for_each(Waker::wake);
+        });
+    }
+
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, path.display().to_string())
+    }
+}
+
+// Resolves once no changes are pending.
+struct Persisted {
+    state: Arc<Mutex<OpfsState>>,
+}
+
+impl Future for Persisted {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.persisting && state.pending.is_empty() {
+            return Poll::Ready(());
+        }
+        state.waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Storage for OpfsStorage {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let now = self.now();
+        self.state().files.insert(
+            path.to_path_buf(),
+            FileData {
+                data: Vec::new(),
+                modified: now,
+            },
+        );
+        Ok(Box::new(OpfsFile {
+            storage: self.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>> {
+        let state = self.state();
+        let file = state.files.get(path).ok_or_else(|| Self::not_found(path))?;
+        Ok(Box::new(Cursor::new(file.data.clone())))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.state()
+            .files
+            .remove(path)
+            .ok_or_else(|| Self::not_found(path))?;
+        self.persist(Change::Remove(path.to_path_buf()));
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let data = {
+            let mut state = self.state();
+            let file = state
+                .files
+                .get_mut(path)
+                .ok_or_else(|| Self::not_found(path))?;
+            file.data.truncate(len as usize);
+            file.data.clone()
+        };
+        self.persist(Change::Write(path.to_path_buf(), data));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let data = {
+            let mut state = self.state();
+            let file = state
+                .files
+                .remove(from)
+                .ok_or_else(|| Self::not_found(from))?;
+            let data = file.data.clone();
+            state.files.insert(to.to_path_buf(), file);
+            data
+        };
+        self.persist(Change::Write(to.to_path_buf(), data));
+        self.persist(Change::Remove(from.to_path_buf()));
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state();
+        if !state.dirs.contains(dir) {
+            return Err(Self::not_found(dir));
+        }
+        let files = state.files.keys().filter(|p| p.parent() == Some(dir));
+        Ok(files.cloned().collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state();
+        state.files.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        let state = self.state();
+        let file = state.files.get(path).ok_or_else(|| Self::not_found(path))?;
+        Ok(file.data.len() as u64)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        let state = self.state();
+        let file = state.files.get(path).ok_or_else(|| Self::not_found(path))?;
+        Ok(file.modified)
+    }
+
+    // `SystemTime::now` isn't available on wasm32, the time comes from JavaScript instead.
+    fn now(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(js_sys::Date::now() as u64)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        // directories are created in OPFS along with the first file written to them.
+        let mut state = self.state();
+        state.dirs.extend(dir.ancestors().map(Path::to_path_buf));
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        // every change to a directory is persisted in order anyway.
+        Ok(())
+    }
+}
+
+// A file being written to an `OpfsStorage`.
+struct OpfsFile {
+    storage: OpfsStorage,
+    path: PathBuf,
+}
+
+impl Write for OpfsFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = self.storage.now();
+        let mut state = self.storage.state();
+        let file = state
+            .files
+            .get_mut(&self.path)
+            .ok_or_else(|| OpfsStorage::not_found(&self.path))?;
+        file.data.extend_from_slice(buf);
+        file.modified = now;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for OpfsFile {
+    fn sync(&mut self) -> io::Result<()> {
+        let data = {
+            let state = self.storage.state();
+            let file = state
+                .files
+                .get(&self.path)
+                .ok_or_else(|| OpfsStorage::not_found(&self.path))?;
+            file.data.clone()
+        };
+        self.storage.persist(Change::Write(self.path.clone(), data));
+        Ok(())
+    }
+}
+
+// the root of the origin private file system, from a window or a worker alike.
+async fn root() -> Result<FileSystemDirectoryHandle, JsValue> {
+    let navigator: Navigator =
+        Reflect::get(&js_sys::global(), &"navigator".into())?.unchecked_into();
+    Ok(JsFuture::from(navigator.storage().get_directory())
+        .await?
+        .unchecked_into())
+}
+
+// reads the files in `dir`, found at `path` within the root, and its subdirectories into `state`.
+async fn load_dir(
+    dir: &FileSystemDirectoryHandle,
+    path: PathBuf,
+    state: &mut OpfsState,
+) -> Result<(), JsValue> {
+    state.dirs.insert(path.clone());
+    let entries = dir.entries();
+    loop {
+        let next = JsFuture::from(entries.next()?).await?;
+        if Reflect::get(&next, &"done".into())?.is_truthy() {
+            return Ok(());
+        }
+        // each entry is a `[name, handle]` pair.
+        let entry: Array = Reflect::get(&next, &"value".into())?.unchecked_into();
+        let name = entry.get(0).as_string().unwrap_or_default();
+        let handle = entry.get(1);
+        let kind: web_sys::FileSystemHandle = handle.clone().unchecked_into();
+        if kind.kind() == FileSystemHandleKind::Directory {
+            let subdir: FileSystemDirectoryHandle = handle.unchecked_into();
+            Box::pin(load_dir(&subdir, path.join(&name), state)).await?;
+            continue;
+        }
+
+        let file_handle: FileSystemFileHandle = handle.unchecked_into();
+        let file: File = JsFuture::from(file_handle.get_file())
+            .await?
+            .unchecked_into();
+        let buffer = JsFuture::from(file.array_buffer()).await?;
+        let modified = Duration::from_millis(file.last_modified() as u64);
+        state.files.insert(
+            path.join(&name),
+            FileData {
+                data: Uint8Array::new(&buffer).to_vec(),
+                modified: SystemTime::UNIX_EPOCH + modified,
+            },
+        );
+    }
+}
+
+// returns the directory holding `path`, creating the directories on the way if `create` is set,
+// along with the file name.
+async fn parent_dir(
+    path: &Path,
+    create: bool,
+) -> Result<(FileSystemDirectoryHandle, String), JsValue> {
+    let mut dir = root().await?;
+    let options = FileSystemGetDirectoryOptions::new();
+    options.set_create(create);
+    let components: Vec<String> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+    let (name, dirs) = components
+        .split_last()
+        .ok_or_else(|| JsValue::from_str("empty path"))?;
+    for d in dirs {
+        let handle = dir.get_directory_handle_with_options(d, &options);
+        dir = JsFuture::from(handle).await?.unchecked_into();
+    }
+    Ok((dir, name.clone()))
+}
+
+async fn apply(change: Change) -> Result<(), JsValue> {
+    match change {
+        Change::Write(path, data) => {
+            let (dir, name) = parent_dir(&path, true).await?;
+            let options = FileSystemGetFileOptions::new();
+            options.set_create(true);
+            let handle = dir.get_file_handle_with_options(&name, &options);
+            let file: FileSystemFileHandle = JsFuture::from(handle).await?.unchecked_into();
+            let stream: FileSystemWritableFileStream = JsFuture::from(file.create_writable())
+                .await?
+                .unchecked_into();
+            JsFuture::from(stream.write_with_u8_array(&data)?).await?;
+            JsFuture::from(stream.close()).await?;
+        }
+        Change::Remove(path) => {
+            // a file that was never synced isn't in OPFS to begin with.
+            if let Ok((dir, name)) = parent_dir(&path, false).await {
+                let _ = JsFuture::from(dir.remove_entry(&name)).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn js_error(e: JsValue) -> io::Error {
+    io::Error::other(format!("{:?}", e))
+}
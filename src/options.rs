@@ -0,0 +1,581 @@
+//! Options to configure an LSM Tree with, passed to `LSMTree::open_with_options`.
+//!
+//! The options a tree is opened with are written to an `OPTIONS` text file in the data dir, one
+//! setting per line, so that operators can tell how the files in there were written:
+//!
+//!   format_version 5
+//!   comparator bytewise
+//!   compression Lz4
+//!   compaction_strategy Universal { size_ratio: 1, min_merge_width: 2 }
+//!   ...
+//!
+//! Most settings can change from one open to the next, e.g. sstables record the compression of
+//! each of their blocks, and the prefix extractor their filters were built with. Those that
+//! can't, like the order of the keys, are checked against the file on open, and a tree refuses to
+//! open a data dir written with different ones rather than misreading it.
+
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    cache::BlockCache,
+    clock::Clock,
+    codec::RecordFormat,
+    compression::Compression,
+    progress::ProgressCallback,
+    recovery::RecoveryCallback,
+    sstable::FORMAT_VERSION,
+    storage::{FsStorage, Storage},
+};
+
+pub(crate) const OPTIONS_FILE: &str = "OPTIONS";
+const OPTIONS_TEMP_FILE: &str = "OPTIONS.tmp";
+
+// name of the order keys are kept in: the byte order of their UTF-8 encoding, which is the order
+// of Rust strings. Every sorted structure in the tree depends on it, from the memtable to the
+// index blocks, so files written in any other order can't be read.
+pub(crate) const COMPARATOR: &str = "bytewise";
+
+// Settings of an LSM Tree instance. `Options::default()` gives the same tree as `LSMTree::new`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    // number of keys in the memtable after which it's flushed to an sstable.
+    pub memtable_limit: usize,
+    // number of sstables after which compaction is triggered.
+    pub compaction_trigger: usize,
+    // which sstables compaction merges once triggered.
+    pub compaction_strategy: CompactionStrategy,
+    // when set, once this many of the newest sstables are small, i.e. hold no more entries than a
+    // flushed memtable, they're merged into a single larger sstable, leaving the older sstables
+    // alone. Under heavy writes, this keeps the number of files a read may go through down far
+    // more cheaply than compacting them into the large sstables below would.
+    pub intra_l0_compaction_trigger: Option<usize>,
+    // when set, compaction first drops the oldest sstables whose newest record was written longer
+    // ago than this, whole and without reading them, and only merges sstables if there were none.
+    // For data that's all kept for the same retention period, which then expires a file at a time
+    // instead of being rewritten by compactions until its records are finally deleted.
+    pub sstable_ttl: Option<Duration>,
+    // when automatic compactions may run. Flushes go on regardless, see `CompactionSchedule`.
+    pub compaction_schedule: CompactionSchedule,
+    // target size in bytes of the data blocks within an sstable.
+    pub block_size: usize,
+    // number of keys between restart points in a data block.
+    pub block_restart_interval: usize,
+    // bits used per key in the bloom filters, 0 disables filters.
+    pub bloom_bits_per_key: usize,
+    // when set, sstable filters also include the prefix of every key, so that `scan_prefix` can
+    // skip sstables with no keys under the prefix.
+    pub prefix_extractor: Option<PrefixExtractor>,
+    // codec the data blocks of flushed memtables and ingested sstables are compressed with.
+    pub compression: Compression,
+    // codec for the data blocks of compaction outputs, `compression` if not set. They're rewritten
+    // less often than flushes, and worth compressing even when flushes are left uncompressed to
+    // keep them fast.
+    pub compaction_compression: Option<Compression>,
+    // codec for the data blocks of the compaction outputs taking the place of the oldest sstable,
+    // `compaction_compression` if not set. These files hold most of the data and are read far more
+    // often than they are rewritten, e.g. zstd at a high level pays off there. See `compression.rs`.
+    pub bottommost_compression: Option<Compression>,
+    // when not 0, bottom level sstables compressed with a codec get a compression dictionary of up
+    // to this many bytes, trained on their first data blocks. Blocks of many small records compress
+    // far better with one, see `compression.rs`. A few KiB to 16 KiB is usually enough.
+    pub compression_dictionary_size: usize,
+    // encoding of the records written to new sstables and log segments. Each file records the codec
+    // it was written with, so existing files stay readable after this changes, see `codec.rs`.
+    pub record_format: RecordFormat,
+    // when set, data blocks read from sstables are kept in this cache. Pass clones of the same cache
+    // to several trees to bound the memory they use for blocks together.
+    pub block_cache: Option<BlockCache>,
+    // bytes read from an sstable at once by scans and compactions, which go through its data blocks
+    // in order. Reading well past the block at hand turns a full scan into a few large reads instead
+    // of one per block. 0 reads one block at a time, like point lookups do.
+    pub readahead_size: usize,
+    // sstables are opened without reading any of them, their index and filters are read the first
+    // time a read needs them. When set, they're also read in a background thread right after open,
+    // so that the first reads don't have to.
+    pub preload_metadata: bool,
+    // where the tree keeps its files, the local filesystem by default.
+    pub storage: Arc<dyn Storage>,
+    // called with the progress of flushes and compactions while they run, and once they're done.
+    // See `progress.rs`.
+    pub progress_callback: Option<ProgressCallback>,
+    // called with the progress of opening the tree, phase by phase. See `recovery.rs`.
+    pub recovery_callback: Option<RecoveryCallback>,
+    // where the tree takes the time from, e.g. for TTLs and compaction schedules. `None` takes it
+    // from the storage, i.e. the system clock for the local filesystem. See `clock.rs`.
+    pub clock: Option<Arc<dyn Clock>>,
+    // how to treat damaged records found while replaying the write-ahead log on open.
+    pub wal_recovery_mode: WalRecoveryMode,
+    // when set, write-ahead log segments are moved to an archive once their memtable is flushed,
+    // instead of being removed.
+    pub wal_archive: Option<WalArchiveOptions>,
+    // when set, values of at least this many bytes are kept in blob files instead of the sstables,
+    // which keeps compactions from rewriting them over and over. See `LSMTree::collect_blob_garbage`.
+    pub blob_threshold: Option<usize>,
+    // when set, sstables that haven't been rewritten for a while are moved to a second, slower and
+    // cheaper location. Reads find them there transparently.
+    pub cold_tier: Option<ColdTierOptions>,
+    // when set, the versions of a key that newer writes replace are kept around, as much of them as
+    // the retention allows, for `LSMTree::get_history`. See `history.rs`.
+    pub history_retention: Option<HistoryRetention>,
+    // when set, every write is recorded in the audit log along with who made it and when, for
+    // `LSMTree::audit_entries`. See `audit.rs`.
+    pub audit_log: bool,
+    // when set, `put` and `write_batch` refuse keys or values longer than this many bytes with an
+    // `Error`, rather than writing records too large for reads and compactions to handle well.
+    pub max_key_size: Option<usize>,
+    pub max_value_size: Option<usize>,
+    // when set, snapshots older than this are released when the tree flushes or takes a new
+    // snapshot, so that a forgotten one doesn't keep the files it reads from forever. See
+    // `snapshot.rs`.
+    pub max_snapshot_age: Option<Duration>,
+    // the I/O priority flushes and compactions run with, so that they take the disk time foreground
+    // reads leave over. See `background_io.rs`.
+    pub background_io_priority: IoPriority,
+    // when set, compactions tell the OS they're done with the sstables they read, so that it drops
+    // them from the page cache instead of the pages foreground reads depend on.
+    pub drop_compaction_inputs_from_cache: bool,
+    // when set, the sstables flushes and compactions write bypass the page cache, where the storage
+    // supports it. See `direct_io.rs`.
+    pub use_direct_io_for_flush_and_compaction: bool,
+    // when set, flushes and compactions reserve the space they expect their sstables to take before
+    // writing them, giving back what's left over once done, so that the filesystem can lay them out
+    // in one piece.
+    pub preallocate_sstables: bool,
+    // bytes flushes and compactions collect before writing them to an sstable, rather than writing
+    // every block on its own. 0 to write them unbuffered.
+    pub sstable_write_buffer_size: usize,
+    // when set, every compaction that runs also rewrites the oldest of the line based text
+    // sstables left in the tree in the current format, so that a data dir written before sstables
+    // had blocks is converted over time, without an `LSMTree::upgrade_format` to rewrite it all at
+    // once. Text sstables are read all the same in the meantime, just slower.
+    pub upgrade_text_sstables_on_compaction: bool,
+}
+
+impl Options {
+    // atomically replaces the `OPTIONS` file in `dir` with these options.
+    pub(crate) fn save(&self, dir: &Path) -> io::Result<()> {
+        let storage = &self.storage;
+        let temp_path = dir.join(OPTIONS_TEMP_FILE);
+        let mut file = storage.create(&temp_path)?;
+        writeln!(file, "format_version {}", FORMAT_VERSION)?;
+        writeln!(file, "comparator {}", COMPARATOR)?;
+        writeln!(file, "memtable_limit {}", self.memtable_limit)?;
+        writeln!(file, "compaction_trigger {}", self.compaction_trigger)?;
+        writeln!(file, "compaction_strategy {:?}", self.compaction_strategy)?;
+        writeln!(
+            file,
+            "intra_l0_compaction_trigger {:?}",
+            self.intra_l0_compaction_trigger
+        )?;
+        writeln!(file, "sstable_ttl {:?}", self.sstable_ttl)?;
+        writeln!(file, "compaction_schedule {:?}", self.compaction_schedule)?;
+        writeln!(file, "block_size {}", self.block_size)?;
+        writeln!(
+            file,
+            "block_restart_interval {}",
+            self.block_restart_interval
+        )?;
+        writeln!(file, "bloom_bits_per_key {}", self.bloom_bits_per_key)?;
+        writeln!(file, "prefix_extractor {:?}", self.prefix_extractor)?;
+        writeln!(file, "compression {:?}", self.compression)?;
+        writeln!(
+            file,
+            "compaction_compression {:?}",
+            self.compaction_compression
+        )?;
+        writeln!(
+            file,
+            "bottommost_compression {:?}",
+            self.bottommost_compression
+        )?;
+        writeln!(
+            file,
+            "compression_dictionary_size {}",
+            self.compression_dictionary_size
+        )?;
+        writeln!(file, "record_format {:?}", self.record_format)?;
+        writeln!(file, "readahead_size {}", self.readahead_size)?;
+        writeln!(file, "blob_threshold {:?}", self.blob_threshold)?;
+        writeln!(file, "history_retention {:?}", self.history_retention)?;
+        writeln!(file, "audit_log {}", self.audit_log)?;
+        writeln!(file, "max_key_size {:?}", self.max_key_size)?;
+        writeln!(file, "max_value_size {:?}", self.max_value_size)?;
+        writeln!(
+            file,
+            "background_io_priority {:?}",
+            self.background_io_priority
+        )?;
+        writeln!(
+            file,
+            "drop_compaction_inputs_from_cache {}",
+            self.drop_compaction_inputs_from_cache
+        )?;
+        writeln!(
+            file,
+            "use_direct_io_for_flush_and_compaction {}",
+            self.use_direct_io_for_flush_and_compaction
+        )?;
+        writeln!(file, "preallocate_sstables {}", self.preallocate_sstables)?;
+        writeln!(
+            file,
+            "sstable_write_buffer_size {}",
+            self.sstable_write_buffer_size
+        )?;
+        writeln!(
+            file,
+            "upgrade_text_sstables_on_compaction {}",
+            self.upgrade_text_sstables_on_compaction
+        )?;
+        file.sync()?;
+
+        storage.rename(&temp_path, &dir.join(OPTIONS_FILE))?;
+        storage.sync_dir(dir)
+    }
+
+    // checks that the data dir `dir` was written with settings these options are compatible with,
+    // according to its `OPTIONS` file if it has one.
+    pub(crate) fn check_compatible(&self, dir: &Path) -> io::Result<()> {
+        let path = dir.join(OPTIONS_FILE);
+        if !self.storage.exists(&path) {
+            return Ok(());
+        }
+
+        for line in BufReader::new(self.storage.open(&path)?).lines() {
+            let line = line?;
+            match line.split_once(' ') {
+                Some(("format_version", version)) => {
+                    let version: u32 = version.parse().map_err(incompatible)?;
+                    if version > FORMAT_VERSION {
+                        return Err(incompatible(format!(
+                            "{} was written in format version {}, newer than the supported {}",
+                            dir.display(),
+                            version,
+                            FORMAT_VERSION
+                        )));
+                    }
+                }
+                Some(("comparator", comparator)) if comparator != COMPARATOR => {
+                    return Err(incompatible(format!(
+                        "{} was written with the `{}` comparator, this tree orders keys with `{}`",
+                        dir.display(),
+                        comparator,
+                        COMPARATOR
+                    )));
+                }
+                // the other settings may change between opens.
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn incompatible(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            memtable_limit: 10,
+            compaction_trigger: 8,
+            compaction_strategy: CompactionStrategy::default(),
+            intra_l0_compaction_trigger: None,
+            sstable_ttl: None,
+            compaction_schedule: CompactionSchedule::default(),
+            block_size: 4096,
+            block_restart_interval: 16,
+            bloom_bits_per_key: 10,
+            prefix_extractor: None,
+            compression: Compression::None,
+            compaction_compression: None,
+            bottommost_compression: None,
+            compression_dictionary_size: 0,
+            record_format: RecordFormat::Binary,
+            block_cache: None,
+            readahead_size: 256 * 1024,
+            preload_metadata: false,
+            storage: Arc::new(FsStorage),
+            progress_callback: None,
+            recovery_callback: None,
+            clock: None,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            wal_archive: None,
+            blob_threshold: None,
+            cold_tier: None,
+            history_retention: None,
+            audit_log: false,
+            max_key_size: None,
+            max_value_size: None,
+            max_snapshot_age: None,
+            background_io_priority: IoPriority::default(),
+            drop_compaction_inputs_from_cache: false,
+            use_direct_io_for_flush_and_compaction: false,
+            preallocate_sstables: true,
+            sstable_write_buffer_size: 1024 * 1024,
+            upgrade_text_sstables_on_compaction: true,
+        }
+    }
+}
+
+// How compaction picks the sstables to merge, once there are `Options::compaction_trigger` of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompactionStrategy {
+    // merges two adjacent sstables, the pair expected to reclaim the most space or else the oldest
+    // two. Most data ends up rewritten into one large sstable at the bottom over and over, which
+    // keeps reads and space usage low at the cost of writing the same data many times.
+    #[default]
+    Pairwise,
+    // merges the pair `Pairwise` would pick along with every other sstable whose key range overlaps
+    // theirs, going by the smallest and largest key of each, until none of the sstables left out
+    // overlaps the merged ones. The merged sstable then holds the only version of every key within
+    // its range, so its tombstones are dropped wherever it sits. The pair is swapped for the oldest
+    // two sstables that overlap if it doesn't. Suits keys that grow over time, like timestamps,
+    // for which few sstables overlap and a compaction leaves the rest alone.
+    // 💡 LevelDB widens the inputs of its compactions the same way, adding every file of the next
+    // level that overlaps them, and then files of their own level that fit the wider range.
+    Overlapping,
+    // size-tiered: merges a run of adjacent sstables of similar size, starting from the newest.
+    // Each sstable is only rewritten when enough data of its size has piled up on top of it, so
+    // data is written far fewer times, in exchange for more sstables for reads to go through and
+    // more space taken by overwritten values. Suits write-heavy workloads with few scans.
+    // 💡 Named after RocksDB's universal compaction, which picks runs the same way. Cassandra's
+    // size-tiered compaction buckets files by size instead.
+    Universal {
+        // an sstable joins the run if it's at most this many percent larger than the run so far.
+        size_ratio: u32,
+        // the fewest sstables merged at once. Without such a run, the newest two are merged.
+        min_merge_width: usize,
+    },
+    // first in, first out: sstables are never merged, the oldest ones are dropped instead, data
+    // and all, once the sstables get too large or too old. Meant for data that's only appended and
+    // ages out as a whole, like metrics or logs keyed by timestamp, for which rewriting sstables
+    // would be wasted work. `compaction_trigger` doesn't apply, the limits are checked on every
+    // flush.
+    // 💡 RocksDB's FIFO compaction works the same, with the same two limits.
+    Fifo {
+        // once all sstables together take more than this many bytes, the oldest ones are dropped.
+        max_size: Option<u64>,
+        // sstables whose newest record was written longer ago than this are dropped.
+        max_age: Option<Duration>,
+    },
+}
+
+impl CompactionStrategy {
+    // universal compaction with RocksDB's default settings.
+    pub fn universal() -> Self {
+        CompactionStrategy::Universal {
+            size_ratio: 1,
+            min_merge_width: 2,
+        }
+    }
+}
+
+// When the compactions that flushes trigger may run, to keep their I/O away from the hours or the
+// moments the system is busiest. Outside of those, memtables are still flushed, and the sstables
+// pile up until compaction is allowed again, or `LSMTree::compact_now` is called. Reads go through
+// more sstables in the meantime. The default allows compaction at any time.
+// 💡 Cassandra operators get the same effect by pausing compactions with `nodetool
+// disableautocompaction`, and scheduling them with cron.
+#[derive(Clone, Default)]
+pub struct CompactionSchedule {
+    // times of day, as offsets from midnight UTC, during which compaction may run, e.g. 2h..6h.
+    // A window that starts after it ends wraps around midnight. Empty allows any time of day.
+    pub windows: Vec<Range<Duration>>,
+    // when set, compaction is put off while this returns true, e.g. while the system serves a
+    // burst of requests.
+    pub is_busy: Option<Arc<dyn Fn() -> bool + Send + Sync>>,
+}
+
+impl CompactionSchedule {
+    // a schedule that allows compaction between `start` and `end` hours of the day, UTC.
+    pub fn between_hours(start: u64, end: u64) -> Self {
+        CompactionSchedule {
+            windows: vec![Duration::from_secs(start * 3600)..Duration::from_secs(end * 3600)],
+            is_busy: None,
+        }
+    }
+
+    // whether compaction may run at `now`.
+    pub fn allows(&self, now: SystemTime) -> bool {
+        let day = 24 * 3600;
+        let since_epoch = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let time_of_day = Duration::new(since_epoch.as_secs() % day, since_epoch.subsec_nanos());
+        let in_window = self.windows.is_empty()
+            || self.windows.iter().any(|window| {
+                if window.start <= window.end {
+                    window.contains(&time_of_day)
+                } else {
+                    time_of_day >= window.start || time_of_day < window.end
+                }
+            });
+        in_window && !self.is_busy.as_ref().is_some_and(|is_busy| is_busy())
+    }
+}
+
+impl fmt::Debug for CompactionSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompactionSchedule")
+            .field("windows", &self.windows)
+            .field("is_busy", &self.is_busy.is_some())
+            .finish()
+    }
+}
+
+// What to do when the write-ahead log ends in a record that's incomplete or fails its checksum.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalRecoveryMode {
+    // assume the record was being appended when the process crashed. It was never acknowledged,
+    // so it's dropped along with anything after it.
+    #[default]
+    TolerateCorruptedTail,
+    // refuse to open, for setups that would rather investigate than lose any record.
+    Strict,
+}
+
+// The I/O priority of flushes and compactions, see `Options::background_io_priority`. Only Linux
+// has per thread I/O priorities, elsewhere background work always runs with the priority of the
+// thread it runs on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IoPriority {
+    // the priority of the thread writing to the tree.
+    #[default]
+    Normal,
+    // the lowest priority of the best effort class, which still gets disk time when foreground
+    // I/O keeps the disk busy, just less of it.
+    Low,
+    // the idle class, which only gets disk time when no one else wants it.
+    Idle,
+}
+
+// How many of the older versions of a key to keep, see `Options::history_retention`. The newest
+// version of a key is always kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryRetention {
+    // the newest this many versions, the current one included.
+    LastVersions(usize),
+    // the versions written within this long.
+    NewerThan(Duration),
+}
+
+// Where to archive flushed write-ahead log segments, and how long to keep them.
+// Archived segments hold every write made to the tree, in order, which is what point in time
+// recovery and consumers of the change stream need.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalArchiveOptions {
+    pub dir: PathBuf,
+    // archived segments archived longer ago than this, by `Options::clock`, are removed.
+    pub max_age: Option<Duration>,
+    // once the archive grows beyond this many bytes, its oldest segments are removed.
+    pub max_size: Option<u64>,
+}
+
+impl WalArchiveOptions {
+    // an archive in `dir` that keeps every segment.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        WalArchiveOptions {
+            dir: dir.into(),
+            max_age: None,
+            max_size: None,
+        }
+    }
+}
+
+// Where to keep cold sstables, and when an sstable turns cold.
+// Fresh sstables, like flushed memtables and compaction outputs, are the ones read and rewritten
+// the most, so they stay in the data dir. Once the newest record of an sstable is older than
+// `cold_after`, by `Options::clock`, it's moved to `dir` on `storage` the next time the memtable is
+// flushed, e.g. from an NVMe drive to a spinning disk, or to object storage.
+#[derive(Debug, Clone)]
+pub struct ColdTierOptions {
+    pub dir: PathBuf,
+    pub storage: Arc<dyn Storage>,
+    pub cold_after: Duration,
+}
+
+impl ColdTierOptions {
+    // a cold tier in `dir` on the local filesystem.
+    pub fn new(dir: impl Into<PathBuf>, cold_after: Duration) -> Self {
+        ColdTierOptions {
+            dir: dir.into(),
+            storage: Arc::new(FsStorage),
+            cold_after,
+        }
+    }
+}
+
+// Extracts the prefix of a key, for keys that share a structure like `user:42:email`.
+// Keys that are too short to have a prefix are left out of the prefix filters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrefixExtractor {
+    // the first `n` bytes of the key.
+    FixedLength(usize),
+    // the key up to and including the `n`th occurrence of `delimiter`,
+    // e.g. `UpToDelimiter(':', 2)` extracts `user:42:` from `user:42:email`.
+    UpToDelimiter(char, usize),
+}
+
+impl PrefixExtractor {
+    // returns the prefix of `key`, or `None` if the key has no prefix.
+    pub fn extract<'a>(&self, key: &'a str) -> Option<&'a str> {
+        match self {
+            PrefixExtractor::FixedLength(n) => key.get(..*n),
+            PrefixExtractor::UpToDelimiter(delimiter, n) => {
+                let (i, _) = key.match_indices(*delimiter).nth(n.checked_sub(1)?)?;
+                Some(&key[..i + delimiter.len_utf8()])
+            }
+        }
+    }
+
+    // a stable name for the extractor, stored in sstables to know which extractor built their filters.
+    pub fn name(&self) -> String {
+        match self {
+            PrefixExtractor::FixedLength(n) => format!("fixed:{}", n),
+            PrefixExtractor::UpToDelimiter(delimiter, n) => {
+                format!("delimiter:{}:{}", delimiter, n)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{CompactionSchedule, PrefixExtractor};
+
+    #[test]
+    fn test_prefix_extractor() {
+        let fixed = PrefixExtractor::FixedLength(4);
+        assert_eq!(fixed.extract("user:42"), Some("user"));
+        assert_eq!(fixed.extract("usr"), None);
+
+        let delimited = PrefixExtractor::UpToDelimiter(':', 2);
+        assert_eq!(delimited.extract("user:42:email"), Some("user:42:"));
+        assert_eq!(delimited.extract("user:42"), None);
+    }
+
+    #[test]
+    fn test_compaction_schedule_windows() {
+        let at = |hour: u64| SystemTime::UNIX_EPOCH + Duration::from_secs((24 * 7 + hour) * 3600);
+        let schedule = CompactionSchedule::between_hours(2, 6);
+        assert!(schedule.allows(at(2)));
+        assert!(!schedule.allows(at(6)));
+        assert!(!schedule.allows(at(13)));
+
+        let overnight = CompactionSchedule::between_hours(22, 3);
+        assert!(overnight.allows(at(23)));
+        assert!(overnight.allows(at(1)));
+        assert!(!overnight.allows(at(12)));
+        assert!(CompactionSchedule::default().allows(at(12)));
+    }
+}
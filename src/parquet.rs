@@ -0,0 +1,183 @@
+//! Exports of the tree to Parquet files, built with the `parquet` feature.
+//!
+//! Analytics tools like DuckDB, Spark or pandas can't read sstables, but they all read Parquet.
+//! `LSMTree::export_parquet` writes the tree as of when it's called to a single Parquet file, with
+//! one row per key:
+//!
+//!   key         string, required
+//!   value       string, null for deleted keys
+//!   seqno       int64, the sequence number of the key's newest write
+//!   tombstone   boolean, whether the key's newest write was a delete
+//!
+//! so that e.g. `SELECT count(*) FROM 'export.parquet' WHERE NOT tombstone` counts the live keys.
+//! Rows are in key order and written in row groups of `ROW_GROUP_SIZE` rows, so that only one row
+//! group is held in memory at a time. Deleted keys are only there as long as their tombstones are
+//! in the tree, and older versions of a key are left out.
+//! 💡 Parquet stores each column of a row group contiguously, which is what makes scanning a few
+//! columns of a large dataset fast, and the opposite of an sstable, which stores whole entries.
+
+use std::{io, path::Path, sync::Arc};
+
+use ::parquet::{
+    data_type::{BoolType, ByteArray, ByteArrayType, Int64Type},
+    errors::ParquetError,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+
+use crate::{LSMTree, Value, find_blob_file, storage::WritableFile};
+
+const SCHEMA: &str = "
+    message entry {
+        required binary key (UTF8);
+        optional binary value (UTF8);
+        required int64 seqno;
+        required boolean tombstone;
+    }
+";
+
+// number of rows buffered before they're written out as a row group.
+const ROW_GROUP_SIZE: usize = 64 * 1024;
+
+// The rows of a row group, column by column.
+#[derive(Default)]
+struct RowGroup {
+    keys: Vec<ByteArray>,
+    // values of the keys that aren't deleted, and for every key whether it has one.
+    values: Vec<ByteArray>,
+    has_value: Vec<i16>,
+    seqnos: Vec<i64>,
+    tombstones: Vec<bool>,
+}
+
+impl LSMTree {
+    // writes the newest version of every key in the tree, deleted ones included, to a Parquet file
+    // at `path` on the tree's storage, and returns the number of rows written. The export is of the
+    // tree as of when it's called, like a scan.
+    pub fn export_parquet(&self, path: impl AsRef<Path>) -> io::Result<u64> {
+        let schema = Arc::new(parse_message_type(SCHEMA).map_err(io::Error::other)?);
+        let properties = Arc::new(WriterProperties::builder().build());
+        let file = self.sstable_mgr.storage.create(path.as_ref())?;
+        let mut writer =
+            SerializedFileWriter::new(file, schema, properties).map_err(io::Error::other)?;
+
+        let mut scan = self.scan(..);
+        let mut rows = RowGroup::default();
+        let mut written = 0;
+        while let Some((key, record)) = scan.next_record() {
+            let value = match &record.value {
+                Some(Value::Inline(v)) => Some(v.clone()),
+                Some(Value::Blob(pointer)) => {
+                    Some(find_blob_file(&scan.blob_files, pointer).read(pointer)?)
+                }
+                None => None,
+            };
+            rows.keys.push(key.into_bytes().into());
+            rows.has_value.push(value.is_some() as i16);
+            rows.tombstones.push(value.is_none());
+            if let Some(value) = value {
+                rows.values.push(value.into_bytes().into());
+            }
+            rows.seqnos.push(record.seq as i64);
+
+            if rows.keys.len() == ROW_GROUP_SIZE {
+                let rows = std::mem::take(&mut rows);
+                written += write_row_group(&mut writer, rows).map_err(io::Error::other)?;
+            }
+        }
+        if !rows.keys.is_empty() {
+            written += write_row_group(&mut writer, rows).map_err(io::Error::other)?;
+        }
+
+        let mut file = writer.into_inner().map_err(io::Error::other)?;
+        file.sync()?;
+        Ok(written)
+    }
+}
+
+// writes `rows` to `writer` as a row group of their own, returning the number of rows.
+fn write_row_group(
+    writer: &mut SerializedFileWriter<Box<dyn WritableFile>>,
+    rows: RowGroup,
+) -> Result<u64, ParquetError> {
+    let mut row_group = writer.next_row_group()?;
+    // the columns come in the order of the schema.
+    let mut column = row_group.next_column()?.unwrap();
+    (column.typed::<ByteArrayType>()).write_batch(&rows.keys, None, None)?;
+    column.close()?;
+    let mut column = row_group.next_column()?.unwrap();
+    let typed = column.typed::<ByteArrayType>();
+    typed.write_batch(&rows.values, Some(&rows.has_value), None)?;
+    column.close()?;
+    let mut column = row_group.next_column()?.unwrap();
+    (column.typed::<Int64Type>()).write_batch(&rows.seqnos, None, None)?;
+    column.close()?;
+    let mut column = row_group.next_column()?.unwrap();
+    (column.typed::<BoolType>()).write_batch(&rows.tombstones, None, None)?;
+    column.close()?;
+    row_group.close()?;
+    Ok(rows.keys.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, path::PathBuf};
+
+    use ::parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::RowAccessor,
+    };
+
+    use crate::{LSMTree, Options};
+
+    #[test]
+    fn test_export_parquet() {
+        let dir = PathBuf::from("test_data").join("export_parquet");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        let options = Options {
+            blob_threshold: Some(16),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options(&dir, options);
+        lsmtree.put("a", "1").unwrap();
+        lsmtree.put("b", "a value kept in a blob file").unwrap();
+        lsmtree.put("c", "3").unwrap();
+        lsmtree.flush_memtable();
+        lsmtree.delete("c");
+        lsmtree.put("a", "updated").unwrap();
+
+        let path = dir.join("export.parquet");
+        assert_eq!(lsmtree.export_parquet(&path).unwrap(), 3);
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let rows: Vec<_> = (reader.get_row_iter(None).unwrap())
+            .map(|row| row.unwrap())
+            .collect();
+        let columns = |i: usize| -> (String, Option<String>, i64, bool) {
+            let row = &rows[i];
+            let value = row.get_string(1).ok().cloned();
+            (
+                row.get_string(0).unwrap().clone(),
+                value,
+                row.get_long(2).unwrap(),
+                row.get_bool(3).unwrap(),
+            )
+        };
+        assert_eq!(
+            columns(0),
+            ("a".to_string(), Some("updated".to_string()), 5, false)
+        );
+        assert_eq!(
+            columns(1),
+            (
+                "b".to_string(),
+                Some("a value kept in a blob file".to_string()),
+                2,
+                false
+            )
+        );
+        assert_eq!(columns(2), ("c".to_string(), None, 4, true));
+    }
+}
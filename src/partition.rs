@@ -0,0 +1,394 @@
+//! Splitting a tree in two at a key, and merging two trees back into one.
+//!
+//! A deployment that partitions its keyspace by range across several trees grows by splitting a
+//! tree that got too big, and shrinks by merging two neighbouring ones. Both work on whole
+//! sstables where they can: an sstable's fences, its smallest and largest key, tell which side of
+//! the boundary it's on, and only the ones whose fences cross it are read and rewritten in two
+//! halves. The rest are copied over as they are:
+//!
+//!   boundary:                     |
+//!   sstables:   [a ... f]  [e ......... m]  [p ... z]
+//!   lower tree: [a ... f]  [e . j]
+//!   upper tree:                    [k . m]  [p ... z]
+//!
+//! Either operation first flushes the memtables, so that all data is in sstables. The tree that
+//! gains sstables records them in its manifest before the other one drops them, so a crash in
+//! between leaves keys in both trees rather than in neither.
+//!
+//! `LSMTree::absorb` consolidates trees whose key ranges do overlap, e.g. datasets produced by
+//! separate jobs, by taking copies of all sstables of another tree, ordered among its own by their
+//! sequence numbers.
+//! 💡 Bigtable and HBase split tablets (regions) without copying anything: both halves keep
+//! referring to the parent's files, and the parent's files are only rewritten by later compactions.
+
+use std::{collections::VecDeque, io, ops::Bound, path::Path, sync::Arc};
+
+use crate::{
+    KeyRange, LSMTree, Level, Options, Record, SSTableManager, Value,
+    blob::{self, BlobFile},
+    copy_file, find_blob_file,
+    manifest::{MANIFEST_FILE, Manifest},
+    sstable::{SSTable, SSTableEntries},
+};
+
+impl LSMTree {
+    // moves the keys >= `boundary` out of this tree, into a new tree in `data_dir` that's
+    // configured with `options`, and returns the new tree. `data_dir` must not hold a tree yet.
+    pub fn split_off(
+        &mut self,
+        boundary: &str,
+        data_dir: impl AsRef<Path>,
+        options: Options,
+    ) -> io::Result<LSMTree> {
+        let data_dir = data_dir.as_ref();
+        if options.storage.exists(&data_dir.join(MANIFEST_FILE)) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a tree", data_dir.display()),
+            ));
+        }
+        self.flush_memtable();
+        let mut upper = LSMTree::open_with_options(data_dir, options);
+
+        let mgr = &mut self.sstable_mgr;
+        let upper_mgr = &mut upper.sstable_mgr;
+        // sstables are copied with their ids, and so are all blob files, so that the pointers to the
+        // blob files in the copies stay valid. New ids in the upper tree continue after them.
+        upper_mgr.next_sstable_id = mgr.next_sstable_id;
+        for blob_file in &mgr.blob_files {
+            let path = blob::blob_path(&upper_mgr.data_dir, blob_file.id);
+            copy_file(&*mgr.storage, &blob_file.path, &*upper_mgr.storage, &path)?;
+            upper_mgr.add_blob_file(blob_file.id);
+        }
+
+        let below = KeyRange {
+            start: Bound::Unbounded,
+            end: Bound::Excluded(boundary.to_string()),
+        };
+        let above = KeyRange {
+            start: Bound::Included(boundary.to_string()),
+            end: Bound::Unbounded,
+        };
+        let readahead = mgr.readahead_size;
+        let mut kept = VecDeque::new();
+        let mut moved = Vec::new();
+        for sst in Arc::clone(&mgr.sstables).iter().cloned() {
+            let fences = sst.fences();
+            let Some((first, _)) = fences.filter(|(_, last)| last.as_str() >= boundary) else {
+                kept.push_back(sst);
+                continue;
+            };
+            if first.as_str() >= boundary {
+                let copy = SSTable::new(&upper_mgr.storage, &upper_mgr.data_dir, sst.id);
+                copy_file(&*sst.storage, &sst.path, &*upper_mgr.storage, &copy.path)?;
+                upper_mgr.sstables_mut().push_back(Arc::new(copy));
+            } else {
+                // the sstable crosses the boundary, each tree gets the half on its side.
+                let entries = SSTableEntries::open(&sst, &above, None, readahead);
+                let level = Level::compaction_output(upper_mgr.sstables.is_empty());
+                let upper_half = upper_mgr.write_sstable(entries, sst.created(), sst.size(), level);
+                upper_mgr.sstables_mut().push_back(upper_half);
+                let entries = SSTableEntries::open(&sst, &below, None, readahead);
+                let level = Level::compaction_output(kept.is_empty());
+                kept.push_back(mgr.write_sstable(entries, sst.created(), sst.size(), level));
+            }
+            moved.push(sst);
+        }
+
+        upper_mgr.storage.sync_dir(&upper_mgr.data_dir)?;
+        upper_mgr.last_seq = mgr.last_seq;
+        upper.last_seq = self.last_seq;
+        upper_mgr.save_manifest();
+
+        mgr.storage.sync_dir(&mgr.data_dir)?;
+        mgr.sstables = Arc::new(kept);
+        mgr.save_manifest();
+        for sst in moved {
+            sst.mark_obsolete();
+        }
+        Ok(upper)
+    }
+
+    // moves all keys of `other` into this tree, leaving `other` empty. The key ranges of the trees
+    // must not overlap, as there'd be no telling which tree has the newer version of a key.
+    pub fn merge(&mut self, mut other: LSMTree) -> io::Result<()> {
+        self.flush_memtable();
+        other.flush_memtable();
+        if let (Some((first, last)), Some((other_first, other_last))) =
+            (self.fences(), other.fences())
+            && first <= other_last
+            && other_first <= last
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the key ranges of the trees overlap",
+            ));
+        }
+
+        let mgr = &mut self.sstable_mgr;
+        let other_mgr = &mut other.sstable_mgr;
+        let moved: Vec<Arc<SSTable>> = other_mgr.sstables_mut().drain(..).collect();
+        for sst in &moved {
+            let copy = mgr.import_sstable(sst, &other_mgr.blob_files)?;
+            mgr.sstables_mut().push_back(copy);
+        }
+
+        mgr.storage.sync_dir(&mgr.data_dir)?;
+        mgr.last_seq = mgr.last_seq.max(other_mgr.last_seq);
+        self.last_seq = self.last_seq.max(other.last_seq);
+        mgr.save_manifest();
+        mgr.compact_below_trigger()?;
+
+        let blob_ids: Vec<usize> = other_mgr.blob_files.iter().map(|b| b.id).collect();
+        // also records that the other tree has no sstables left.
+        other_mgr.remove_blob_files(&blob_ids);
+        for sst in moved {
+            sst.mark_obsolete();
+        }
+        Ok(())
+    }
+
+    // adds the live sstables of the tree in `other_dir` to this one, leaving that tree as it is.
+    // Returns the number of sstables added. Writes still in the other tree's log aren't, close
+    // that tree first if it may have some.
+    // Unlike `merge`, the key ranges of the trees may overlap. A key found in both then takes the
+    // value of the newer write, by sequence number: the sstables of both trees are ordered by the
+    // highest sequence number in them, and reads go from the newest sstable to the oldest.
+    // Sequence numbers of separate trees are only comparable as far as their writes are, e.g.
+    // from jobs that ran one after the other. Later writes to this tree are newer than anything
+    // absorbed.
+    pub fn absorb(&mut self, other_dir: impl AsRef<Path>) -> io::Result<usize> {
+        let other_dir = other_dir.as_ref();
+        let storage = Arc::clone(&self.sstable_mgr.storage);
+        let Some(manifest) = Manifest::load(&*storage, other_dir)? else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} doesn't hold a tree", other_dir.display()),
+            ));
+        };
+        if !manifest.cold_sstables.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the tree has sstables on a cold tier",
+            ));
+        }
+        // the memtable is always read first, flushing it lets absorbed sstables with newer writes
+        // take precedence over it.
+        self.flush_memtable();
+
+        let mgr = &mut self.sstable_mgr;
+        let blob_files: Vec<Arc<BlobFile>> = (manifest.blob_files.iter())
+            .map(|id| Arc::new(BlobFile::new(&storage, other_dir, *id)))
+            .collect();
+        let mut absorbed = VecDeque::new();
+        for id in &manifest.sstables {
+            let sst = SSTable::new(&storage, other_dir, *id);
+            absorbed.push_back(mgr.import_sstable(&sst, &blob_files)?);
+        }
+        let count = absorbed.len();
+
+        // each tree's sstables are ordered oldest first already, so the two lists are merged like
+        // in a merge sort. On a tie this tree's sstable counts as the newer one.
+        let mut ours = (*mgr.sstables).clone();
+        let mut sstables = VecDeque::new();
+        while let (Some(a), Some(b)) = (ours.front(), absorbed.front()) {
+            let next = if b.max_seq() <= a.max_seq() {
+                absorbed.pop_front()
+            } else {
+                ours.pop_front()
+            };
+            sstables.extend(next);
+        }
+        sstables.extend(ours.into_iter().chain(absorbed));
+        mgr.sstables = Arc::new(sstables);
+
+        mgr.storage.sync_dir(&mgr.data_dir)?;
+        let other_seq = manifest.last_seq.unwrap_or_else(|| {
+            let max_seqs = mgr.sstables.iter().map(|sst| sst.max_seq());
+            max_seqs.max().unwrap_or(0)
+        });
+        mgr.last_seq = mgr.last_seq.max(other_seq);
+        self.last_seq = self.last_seq.max(other_seq);
+        mgr.save_manifest();
+        mgr.compact_below_trigger()?;
+        Ok(count)
+    }
+
+    // the smallest and largest key in the sstables, see `SSTable::fences`.
+    fn fences(&self) -> Option<(String, String)> {
+        let fences = self
+            .sstable_mgr
+            .sstables
+            .iter()
+            .filter_map(|sst| sst.fences());
+        fences.reduce(|(first, last), (f, l)| (first.min(f), last.max(l)))
+    }
+}
+
+impl SSTableManager {
+    // copies the sstable `sst` of another tree into this one under a new id, and syncs it. It's
+    // only part of this tree once it's listed in the manifest.
+    // The ids of the other tree's `blob_files` may be taken here, so if it has any, the values
+    // they hold are brought along inline instead, in a rewritten copy.
+    fn import_sstable(
+        &mut self,
+        sst: &SSTable,
+        blob_files: &[Arc<BlobFile>],
+    ) -> io::Result<Arc<SSTable>> {
+        if blob_files.is_empty() {
+            let id = self.new_file_id();
+            let copy = SSTable::new(&self.storage, &self.data_dir, id);
+            copy_file(&*sst.storage, &sst.path, &*self.storage, &copy.path)?;
+            return Ok(Arc::new(copy));
+        }
+
+        let entries = SSTableEntries::open(sst, &KeyRange::all(), None, self.readahead_size);
+        let inlined = entries.map(|(k, record)| {
+            let value = record.value.map(|v| match v {
+                Value::Blob(pointer) => {
+                    let blob_file = find_blob_file(blob_files, &pointer);
+                    Value::Inline(blob_file.read(&pointer).unwrap())
+                }
+                inline => inline,
+            });
+            (k, Record { value, ..record })
+        });
+        // an imported copy isn't a compaction output, it's compressed like an ingested sstable.
+        Ok(self.write_sstable(inlined, sst.created(), sst.size(), Level::L0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{LSMTree, Options, sim::SimStorage};
+
+    fn options(storage: &SimStorage) -> Options {
+        Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 1000,
+            ..Options::default()
+        }
+    }
+
+    // a tree with an sstable on each side of `key050`, and one crossing it.
+    fn open(storage: &SimStorage) -> LSMTree {
+        let mut lsmtree = LSMTree::open_with_options("data/low", options(storage));
+        for batch in [0..40, 60..100, 40..70] {
+            for i in batch {
+                lsmtree
+                    .put(&format!("key{:03}", i), &format!("value{}", i))
+                    .unwrap();
+            }
+            lsmtree.flush_memtable();
+        }
+        lsmtree.delete("key010");
+        lsmtree.delete("key080");
+        lsmtree
+    }
+
+    #[test]
+    fn test_lsm_split_off_and_merge() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lower = open(&storage);
+        let ids: Vec<usize> = lower.sstable_mgr.sstables.iter().map(|s| s.id).collect();
+
+        let upper = lower
+            .split_off("key050", "data/high", options(&storage))
+            .unwrap();
+        // the sstables on one side are copied as they are, only the crossing one is rewritten.
+        let lower_ids: Vec<usize> = lower.sstable_mgr.sstables.iter().map(|s| s.id).collect();
+        let upper_ids: Vec<usize> = upper.sstable_mgr.sstables.iter().map(|s| s.id).collect();
+        assert_eq!(lower_ids.len(), 3);
+        assert_eq!(lower_ids[0], ids[0]);
+        assert_eq!(upper_ids.len(), 3);
+        assert_eq!(upper_ids[0], ids[1]);
+
+        assert_eq!(lower.scan(..).count(), 49);
+        assert_eq!(lower.get("key049"), Some("value49".to_string()));
+        assert_eq!(lower.get("key050"), None);
+        assert_eq!(upper.scan(..).count(), 49);
+        assert_eq!(upper.get("key050"), Some("value50".to_string()));
+        assert_eq!(upper.get("key080"), None);
+        assert_eq!(upper.get("key049"), None);
+
+        let upper = LSMTree::open_with_options("data/high", options(&storage));
+        assert!(lower.merge(upper).is_ok());
+        let keys: Vec<String> = lower.scan(..).map(|(k, _)| k).collect();
+        let expected: Vec<String> = (0..100)
+            .filter(|i| *i != 10 && *i != 80)
+            .map(|i| format!("key{:03}", i))
+            .collect();
+        assert_eq!(keys, expected);
+        drop(lower);
+
+        let lower = LSMTree::open_with_options("data/low", options(&storage));
+        assert_eq!(lower.scan(..).count(), 98);
+        let upper = LSMTree::open_with_options("data/high", options(&storage));
+        assert_eq!(upper.scan(..).count(), 0);
+    }
+
+    #[test]
+    fn test_lsm_absorbs_newer_writes() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lsmtree = LSMTree::open_with_options("data/a", options(&storage));
+        for k in ["k1", "k2", "k3"] {
+            lsmtree.put(k, "a").unwrap();
+        }
+        lsmtree.flush_memtable();
+
+        let other_options = Options {
+            blob_threshold: Some(16),
+            ..options(&storage)
+        };
+        let mut other = LSMTree::open_with_options("data/b", other_options.clone());
+        // older than the writes in the first tree.
+        other.put("k2", "b").unwrap();
+        other.flush_memtable();
+        for i in 0..10 {
+            other.put(&format!("k{}", i + 10), "b").unwrap();
+        }
+        // newer.
+        other.put("k3", "b").unwrap();
+        other.put("k9", "a value moved to a blob file").unwrap();
+        other.flush_memtable();
+        drop(other);
+
+        assert_eq!(lsmtree.absorb("data/b").unwrap(), 2);
+        assert_eq!(lsmtree.get("k1"), Some("a".to_string()));
+        assert_eq!(lsmtree.get("k2"), Some("a".to_string()));
+        assert_eq!(lsmtree.get("k3"), Some("b".to_string()));
+        let value = Some("a value moved to a blob file".to_string());
+        assert_eq!(lsmtree.get("k9"), value);
+        assert_eq!(lsmtree.scan(..).count(), 14);
+        // writes after absorbing are newer than anything absorbed.
+        lsmtree.put("k3", "c").unwrap();
+        lsmtree.flush_memtable();
+        drop(lsmtree);
+
+        let mut lsmtree = LSMTree::open_with_options("data/a", options(&storage));
+        assert_eq!(lsmtree.get("k3"), Some("c".to_string()));
+        assert_eq!(lsmtree.get("k9"), value);
+        // the absorbed tree is left as it was.
+        let other = LSMTree::open_with_options("data/b", other_options);
+        assert_eq!(other.get("k3"), Some("b".to_string()));
+        assert!(lsmtree.absorb("data/missing").is_err());
+    }
+
+    #[test]
+    fn test_lsm_merge_rejects_overlapping_trees() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lower = open(&storage);
+        assert!(
+            lower
+                .split_off("key050", "data/low", options(&storage))
+                .is_err()
+        );
+
+        let mut other = LSMTree::open_with_options("data/other", options(&storage));
+        other.put("key042", "other").unwrap();
+        assert!(lower.merge(other).is_err());
+        assert_eq!(lower.get("key042"), Some("value42".to_string()));
+    }
+}
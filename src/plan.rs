@@ -0,0 +1,176 @@
+//! Dry runs of compactions.
+//!
+//! A compaction reads and rewrites whole sstables, which on a large tree means minutes of I/O
+//! competing with reads and writes. `LSMTree::plan_compaction` tells what the next compaction would
+//! do without doing it: which sstables it would merge or drop, how much it would write, and how
+//! much space it would give back, so that operators can judge whether a `compact_now` is worth
+//! running at the moment.
+//!
+//! The sizes are estimates, based on the tombstones and shadowed entries recorded for each sstable
+//! (see `SSTableManager::count_shadowed`). Merged inputs are assumed to shrink by their garbage,
+//! except for the newest input, whose shadowed entries live on in newer sstables. Tombstones are
+//! counted as garbage only if no older sstable left out overlaps the inputs, while the compaction
+//! itself also drops those for keys the overlapping ones turn out not to have.
+
+use crate::{Compaction, LSMTree, SSTable, SSTableManager, widen};
+
+// What the next compaction would do, returned by `LSMTree::plan_compaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionPlan {
+    // ids of the sstables the compaction would merge or drop, oldest first.
+    pub inputs: Vec<usize>,
+    // whether the inputs would be dropped whole, with all their data, rather than merged.
+    pub drops_inputs: bool,
+    // total size of the inputs.
+    pub input_bytes: u64,
+    // estimated size of the merged sstable, 0 if the inputs are dropped.
+    pub output_bytes: u64,
+    // estimated space given back once the inputs are removed.
+    pub reclaimed_bytes: u64,
+}
+
+impl LSMTree {
+    // returns the compaction `compact_now` would run first, or `None` if none is due.
+    // Compactions due outside the compaction schedule's windows are planned all the same.
+    pub fn plan_compaction(&self) -> Option<CompactionPlan> {
+        let mgr = &self.sstable_mgr;
+        let compaction = match mgr.pick_l0_run(self.memtable_limit) {
+            Some(run) => Compaction::Merge(run),
+            None if mgr.should_compact() => mgr.pick_next_compaction()?,
+            None => return None,
+        };
+        Some(mgr.plan(&compaction))
+    }
+}
+
+impl SSTableManager {
+    fn plan(&self, compaction: &Compaction) -> CompactionPlan {
+        let picked: Vec<usize> = match compaction {
+            Compaction::Merge(range) => range.clone().collect(),
+            Compaction::MergeOverlapping(picked) => picked.clone(),
+            Compaction::DropOldest(count) => (0..*count).collect(),
+        };
+        let inputs: Vec<_> = picked.iter().map(|&i| &self.sstables[i]).collect();
+        // whether the tombstones of the inputs are all dropped, with no older sstable left out that
+        // overlaps them.
+        let mut range: Option<(String, String)> = None;
+        for fences in inputs.iter().filter_map(|sst| sst.fences()) {
+            range = Some(widen(range, &fences));
+        }
+        let overlaps = |fences: &(String, String)| {
+            range
+                .as_ref()
+                .is_some_and(|(first, last)| fences.0 <= *last && *first <= fences.1)
+        };
+        let older = self.older_than(&picked);
+        let bottommost = older
+            .iter()
+            .all(|(_, fences)| !fences.as_ref().is_some_and(overlaps));
+        let size = |sst: &SSTable| sst.storage.len(&sst.path).unwrap_or(0);
+        let input_bytes = inputs.iter().map(|sst| size(sst)).sum();
+
+        let mut output_bytes = 0;
+        if !matches!(compaction, Compaction::DropOldest(_)) {
+            for (i, sst) in inputs.iter().enumerate() {
+                let Some(properties) = sst.properties() else {
+                    output_bytes += size(sst);
+                    continue;
+                };
+                let mut garbage = 0;
+                if i + 1 < inputs.len() {
+                    garbage += sst.shadowed();
+                }
+                // tombstones are dropped when there are no older sstables left for them to delete in.
+                if bottommost {
+                    garbage += properties.deletions;
+                }
+                let entries = properties.entries.max(1);
+                let live = entries.saturating_sub(garbage) as f64 / entries as f64;
+                output_bytes += (size(sst) as f64 * live) as u64;
+            }
+        }
+
+        CompactionPlan {
+            inputs: inputs.iter().map(|sst| sst.id).collect(),
+            drops_inputs: matches!(compaction, Compaction::DropOldest(_)),
+            input_bytes,
+            output_bytes,
+            reclaimed_bytes: input_bytes - output_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{CompactionSchedule, LSMTree, Options, sim::SimStorage};
+
+    #[test]
+    fn test_plan_compaction_previews_compact_now() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            compaction_trigger: 3,
+            compaction_schedule: CompactionSchedule::between_hours(2, 6),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..20 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        assert_eq!(lsmtree.plan_compaction(), None);
+
+        // deletes half of the first sstable.
+        for i in 0..5 {
+            lsmtree.delete(&format!("key{:02}", i));
+        }
+        for i in 20..25 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        let plan = lsmtree.plan_compaction().unwrap();
+        let ids: Vec<usize> = lsmtree.sstables().iter().map(|sst| sst.id).collect();
+        assert_eq!(plan.inputs, ids[..2]);
+        assert!(!plan.drops_inputs);
+        let sizes: Vec<u64> = lsmtree.sstables().iter().map(|sst| sst.size).collect();
+        assert_eq!(plan.input_bytes, sizes[0] + sizes[1]);
+        assert!(plan.output_bytes.abs_diff(sizes[0] / 2 + sizes[1]) <= 1);
+        assert_eq!(plan.reclaimed_bytes, plan.input_bytes - plan.output_bytes);
+
+        // planning doesn't compact anything, `compact_now` runs the plan.
+        assert_eq!(lsmtree.sstables().len(), 3);
+        lsmtree.compact_now();
+        let sstables = lsmtree.sstables();
+        assert_eq!(sstables.len(), 2);
+        assert!(!ids.contains(&sstables[0].id));
+        assert!(sstables[0].size < plan.input_bytes);
+        assert_eq!(lsmtree.plan_compaction(), None);
+    }
+
+    #[test]
+    fn test_plan_compaction_drops_expired_sstables() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            compaction_trigger: 2,
+            sstable_ttl: Some(Duration::from_secs(60)),
+            compaction_schedule: CompactionSchedule::between_hours(2, 6),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..10 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        storage.advance_clock(Duration::from_secs(120));
+        for i in 10..20 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+
+        let plan = lsmtree.plan_compaction().unwrap();
+        let first = &lsmtree.sstables()[0];
+        assert_eq!(plan.inputs, [first.id]);
+        assert!(plan.drops_inputs);
+        assert_eq!(plan.output_bytes, 0);
+        assert_eq!(plan.reclaimed_bytes, first.size);
+    }
+}
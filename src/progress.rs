@@ -0,0 +1,197 @@
+//! Progress of flushes and compactions, for telling a job that's slow but moving from one that's
+//! stuck.
+//!
+//! A flush or compaction goes through its input entries in key order. Every `REPORT_INTERVAL`
+//! bytes of keys and values, and once more when it's done, it calls `Options::progress_callback`
+//! with the bytes processed so far, an estimate of the total, and the key it's at:
+//!
+//!   flush       memtable        -> 64 KiB, key0153 -> 128 KiB, key0307 -> ... -> done
+//!   compaction  4.sst + 5.sst   -> 64 KiB, apple   -> ...                     -> done
+//!
+//! The total of a flush is exact, the size of the memtable's keys and values. That of a
+//! compaction is the size of its input files, which compressed inputs can fall short of, so the
+//! processed bytes may pass it. `LSMTree::stats` keeps the last numbers reported by each kind of
+//! job, which are those of a job that never finished if it failed part way.
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    mem::size_of,
+    sync::{Arc, Mutex},
+};
+
+use crate::{Record, Value, blob::BlobPointer};
+
+// bytes of keys and values processed between two reports.
+const REPORT_INTERVAL: u64 = 64 * 1024;
+
+// where the last progress reported by a kind of job is kept for `LSMTree::stats`.
+pub(crate) type ProgressSlot = Arc<Mutex<Option<JobProgress>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Job {
+    Flush,
+    Compaction,
+}
+
+// How far a flush or compaction got, as reported to `Options::progress_callback`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobProgress {
+    pub job: Job,
+    // bytes of the keys and values processed so far. Large values in blob files count with the
+    // size of their pointer, which is all that a compaction copies of them.
+    pub bytes_processed: u64,
+    // estimate of the bytes the job processes in all, see the module docs.
+    pub bytes_total: u64,
+    // the key processed last, empty until the first one.
+    pub current_key: String,
+    pub finished: bool,
+}
+
+// Called with the progress of flushes and compactions while they run, see `progress.rs`.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(&JobProgress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new(callback: impl Fn(&JobProgress) + Send + Sync + 'static) -> Self {
+        ProgressCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+// Tracks the progress of a running job and reports it. Takes `&self` to advance, so that the
+// readers of several inputs of a compaction can share it.
+pub(crate) struct ProgressTracker {
+    progress: RefCell<JobProgress>,
+    callback: Option<ProgressCallback>,
+    slot: ProgressSlot,
+    next_report: Cell<u64>,
+}
+
+impl ProgressTracker {
+    // starts tracking a job, reporting to `callback` and keeping the progress in `slot`.
+    pub fn start(
+        job: Job,
+        bytes_total: u64,
+        callback: Option<ProgressCallback>,
+        slot: ProgressSlot,
+    ) -> Self {
+        let progress = JobProgress {
+            job,
+            bytes_processed: 0,
+            bytes_total,
+            current_key: String::new(),
+            finished: false,
+        };
+        *slot.lock().unwrap() = Some(progress.clone());
+        ProgressTracker {
+            progress: RefCell::new(progress),
+            callback,
+            slot,
+            next_report: Cell::new(REPORT_INTERVAL),
+        }
+    }
+
+    // counts the entry of `key` as processed, reporting the progress if it's time to.
+    pub fn advance(&self, key: &str, record: &Record) {
+        let mut progress = self.progress.borrow_mut();
+        progress.bytes_processed += entry_size(key, record);
+        progress.current_key.clear();
+        progress.current_key.push_str(key);
+        if progress.bytes_processed < self.next_report.get() {
+            return;
+        }
+        self.next_report
+            .set(progress.bytes_processed + REPORT_INTERVAL);
+        self.report(&progress);
+    }
+
+    pub fn finish(self) {
+        let mut progress = self.progress.borrow_mut();
+        progress.finished = true;
+        self.report(&progress);
+    }
+
+    fn report(&self, progress: &JobProgress) {
+        *self.slot.lock().unwrap() = Some(progress.clone());
+        if let Some(callback) = &self.callback {
+            (callback.0)(progress);
+        }
+    }
+}
+
+// bytes of the key and value of an entry, see `JobProgress::bytes_processed`.
+pub(crate) fn entry_size(key: &str, record: &Record) -> u64 {
+    let value = match &record.value {
+        Some(Value::Inline(v)) => v.len(),
+        Some(Value::Blob(_)) => size_of::<BlobPointer>(),
+        None => 0,
+    };
+    (key.len() + value) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{Job, JobProgress, ProgressCallback};
+    use crate::{LSMTree, Options, sim::SimStorage};
+
+    #[test]
+    fn test_flush_and_compaction_report_progress() {
+        let storage = SimStorage::new(1, Default::default());
+        let reports: Arc<Mutex<Vec<JobProgress>>> = Arc::default();
+        let reported = Arc::clone(&reports);
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 100,
+            compaction_trigger: 2,
+            progress_callback: Some(ProgressCallback::new(move |progress| {
+                reported.lock().unwrap().push(progress.clone());
+            })),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        assert_eq!(lsmtree.stats().last_flush, None);
+        let value = "v".repeat(1000);
+        for i in 0..200 {
+            lsmtree.put(&format!("key{:03}", i), &value).unwrap();
+        }
+
+        let reports = reports.lock().unwrap();
+        let flushes: Vec<&JobProgress> = reports.iter().filter(|p| p.job == Job::Flush).collect();
+        // 100 entries of about 1 KiB each, reported every 64 KiB and at the end, twice over.
+        assert_eq!(flushes.len(), 4);
+        assert!(!flushes[0].finished);
+        assert!(flushes[0].bytes_processed >= 64 * 1024);
+        assert!(flushes[0].current_key.as_str() > "key060");
+        let last = flushes[3];
+        assert!(last.finished);
+        assert_eq!(last.bytes_processed, last.bytes_total);
+        assert_eq!(last.current_key, "key199");
+
+        // the compaction of both sstables reads both in key order, and the total is an estimate.
+        let compactions: Vec<&JobProgress> = (reports.iter())
+            .filter(|p| p.job == Job::Compaction)
+            .collect();
+        assert!(compactions.len() > 2);
+        assert!(
+            compactions
+                .windows(2)
+                .all(|w| w[0].bytes_processed < w[1].bytes_processed)
+        );
+        let last = *compactions.last().unwrap();
+        assert!(last.finished);
+        assert_eq!(last.bytes_processed, 2 * flushes[3].bytes_total);
+        assert!(last.bytes_total > last.bytes_processed);
+
+        let stats = lsmtree.stats();
+        assert_eq!(stats.last_flush.as_ref(), Some(flushes[3]));
+        assert_eq!(stats.last_compaction.as_ref(), Some(last));
+    }
+}
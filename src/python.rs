@@ -0,0 +1,106 @@
+//! Python bindings, built with the `python` feature (e.g. `maturin develop` with `pyproject.toml`).
+//!
+//! `lsmtree.LsmTree` wraps a tree in a data dir and behaves like a dict of strings, kept in key
+//! order:
+//!
+//! ```python
+//! >>> import lsmtree
+//! >>> db = lsmtree.LsmTree("data")
+//! >>> db["name"] = "ferris"
+//! >>> db["name"]
+//! 'ferris'
+//! >>> del db["name"]
+//! >>> "name" in db
+//! False
+//! ```
+//!
+//! Iterating over the tree yields its keys, `items()` its key value pairs, both from a scan taken
+//! when the iteration starts.
+
+use std::sync::Mutex;
+
+use pyo3::{
+    exceptions::{PyKeyError, PyValueError},
+    prelude::*,
+};
+
+use crate::LSMTree;
+
+#[pyclass(name = "LsmTree")]
+struct PyLsmTree {
+    // Python objects can be shared between threads, the tree can't.
+    tree: Mutex<LSMTree>,
+}
+
+#[pymethods]
+impl PyLsmTree {
+    #[new]
+    fn new(data_dir: &str) -> Self {
+        PyLsmTree {
+            tree: Mutex::new(LSMTree::open(data_dir)),
+        }
+    }
+
+    fn __getitem__(&self, key: &str) -> PyResult<String> {
+        let value = self.tree.lock().unwrap().get(key);
+        value.ok_or_else(|| PyKeyError::new_err(key.to_string()))
+    }
+
+    fn __setitem__(&self, key: &str, value: &str) -> PyResult<()> {
+        let result = self.tree.lock().unwrap().put(key, value);
+        result.map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    fn __delitem__(&self, key: &str) -> PyResult<()> {
+        let mut tree = self.tree.lock().unwrap();
+        if tree.get(key).is_none() {
+            return Err(PyKeyError::new_err(key.to_string()));
+        }
+        tree.delete(key);
+        Ok(())
+    }
+
+    fn __contains__(&self, key: &str) -> bool {
+        self.tree.lock().unwrap().get(key).is_some()
+    }
+
+    // returns the value of `key`, or `default` if there's none, like `dict.get`.
+    #[pyo3(signature = (key, default = None))]
+    fn get(&self, key: &str, default: Option<String>) -> Option<String> {
+        self.tree.lock().unwrap().get(key).or(default)
+    }
+
+    fn __iter__(&self) -> KeyIter {
+        let tree = self.tree.lock().unwrap();
+        let keys: Vec<String> = tree.keys(..).collect();
+        KeyIter {
+            keys: keys.into_iter(),
+        }
+    }
+
+    fn items(&self) -> Vec<(String, String)> {
+        self.tree.lock().unwrap().scan(..).collect()
+    }
+}
+
+// Iterator over the keys of a tree, as of when the iteration started.
+#[pyclass]
+struct KeyIter {
+    keys: std::vec::IntoIter<String>,
+}
+
+#[pymethods]
+impl KeyIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self) -> Option<String> {
+        self.keys.next()
+    }
+}
+
+#[pymodule]
+fn lsmtree(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyLsmTree>()
+}
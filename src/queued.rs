@@ -0,0 +1,182 @@
+//! An LSM Tree whose writes all go through a single writer thread.
+//!
+//! Every write to an `LSMTree` appends to the log and syncs it before returning, so writers that
+//! share a tree behind a mutex each wait for their own sync, one after the other. A
+//! `QueuedLsmTree` sends writes over a channel to a thread that owns all writing instead, and
+//! which commits whatever queued up while it was busy as one group, with a single sync:
+//!
+//!   put, put, delete -> | channel | -> writer thread -> group of 3 -> log (1 sync) -> memtable
+//!
+//! The more writers there are, the larger the groups get and the fewer syncs each write costs.
+//! Writers don't contend on a lock, they only wait for their group to be committed, so a write is
+//! durable once `put` or `delete` returns, as with `LSMTree`. Reads go to the tree directly, behind
+//! a read-write lock that the writer thread only takes to apply a group, never while it waits for
+//! writes or takes them off the channel.
+
+use std::{
+    io,
+    ops::RangeBounds,
+    sync::{
+        Arc, RwLock,
+        mpsc::{Receiver, Sender, SyncSender, channel, sync_channel},
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{Error, LSMTree, ScanIter};
+
+// the largest number of writes committed as one group, so that a steady stream of writes can't
+// hold back the ones at the front of the queue for too long.
+const MAX_GROUP_SIZE: usize = 1024;
+
+// A write waiting to be committed by the writer thread, along with where to report back once it is.
+struct QueuedWrite {
+    key: String,
+    // `None` for a delete.
+    value: Option<String>,
+    done: SyncSender<()>,
+}
+
+pub struct QueuedLsmTree {
+    tree: Arc<RwLock<LSMTree>>,
+    sender: Sender<QueuedWrite>,
+    writer: JoinHandle<()>,
+}
+
+impl QueuedLsmTree {
+    // takes over `tree`, starting the thread that commits writes to it.
+    pub fn new(tree: LSMTree) -> Self {
+        let tree = Arc::new(RwLock::new(tree));
+        let (sender, receiver) = channel();
+        let shared = Arc::clone(&tree);
+        let writer = thread::spawn(move || commit_writes(&shared, receiver));
+        QueuedLsmTree {
+            tree,
+            sender,
+            writer,
+        }
+    }
+
+    // adds k and v, returning once the write is durable. Fails if either is larger than the tree's
+    // options allow, like `LSMTree::put`.
+    pub fn put(&self, k: &str, v: &str) -> Result<(), Error> {
+        self.tree.read().unwrap().check_size(k, v.len())?;
+        self.enqueue(k, Some(v.to_string()));
+        Ok(())
+    }
+
+    // deletes k, returning once the delete is durable.
+    pub fn delete(&self, k: &str) {
+        self.enqueue(k, None);
+    }
+
+    fn enqueue(&self, k: &str, value: Option<String>) {
+        let (done, committed) = sync_channel(1);
+        let write = QueuedWrite {
+            key: k.to_string(),
+            value,
+            done,
+        };
+        self.sender.send(write).unwrap();
+        committed
+            .recv()
+            .expect("the writer thread failed to commit the write");
+    }
+
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.tree.read().unwrap().get(k)
+    }
+
+    // returns an iterator over the live key value pairs within `range`, see `LSMTree::scan`.
+    pub fn scan<'a>(&self, range: impl RangeBounds<&'a str>) -> ScanIter {
+        self.tree.read().unwrap().scan(range)
+    }
+
+    // commits the writes still queued, stops the writer thread and closes the tree, see
+    // `LSMTree::close`.
+    pub fn close(self) -> io::Result<()> {
+        drop(self.sender);
+        self.writer.join().expect("the writer thread panicked");
+        let tree = Arc::into_inner(self.tree).unwrap();
+        tree.into_inner().unwrap().close()
+    }
+}
+
+// the writer thread: commits the queued writes in groups, until every sender is gone.
+fn commit_writes(tree: &RwLock<LSMTree>, receiver: Receiver<QueuedWrite>) {
+    while let Ok(first) = receiver.recv() {
+        // everything that queued up while the previous group was being committed goes in this one.
+        let mut group = vec![first];
+        group.extend(receiver.try_iter().take(MAX_GROUP_SIZE - 1));
+
+        let writes = (group.iter())
+            .map(|write| (write.key.clone(), write.value.clone()))
+            .collect();
+        tree.write().unwrap().write_group(writes);
+        for write in group {
+            // the writer may have given up waiting, e.g. if its thread panicked.
+            let _ = write.done.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::QueuedLsmTree;
+    use crate::{Error, LSMTree, Options, sim::SimStorage};
+
+    #[test]
+    fn test_queued_tree_commits_concurrent_writes() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 100,
+            ..Options::default()
+        };
+        let tree = QueuedLsmTree::new(LSMTree::open_with_options("data", options.clone()));
+        thread::scope(|s| {
+            for t in 0..4 {
+                let tree = &tree;
+                s.spawn(move || {
+                    for i in (t..400).step_by(4) {
+                        tree.put(&format!("key{:03}", i), &format!("value{}", i))
+                            .unwrap();
+                        // every write is visible to reads once it returns.
+                        assert_eq!(
+                            tree.get(&format!("key{:03}", i)),
+                            Some(format!("value{}", i))
+                        );
+                    }
+                });
+            }
+        });
+        tree.delete("key007");
+        assert_eq!(tree.scan(..).count(), 399);
+        tree.close().unwrap();
+
+        let lsmtree = LSMTree::open_with_options("data", options);
+        assert_eq!(lsmtree.get("key007"), None);
+        assert_eq!(lsmtree.get("key399"), Some("value399".to_string()));
+        assert_eq!(lsmtree.scan(..).count(), 399);
+    }
+
+    #[test]
+    fn test_queued_tree_refuses_values_over_max_size() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            max_value_size: Some(4),
+            ..Options::default()
+        };
+        let tree = QueuedLsmTree::new(LSMTree::open_with_options("data", options));
+        assert_eq!(
+            tree.put("key", "value"),
+            Err(Error::ValueTooLarge { size: 5, max: 4 })
+        );
+        tree.put("key", "v").unwrap();
+        assert_eq!(tree.get("key"), Some("v".to_string()));
+        tree.close().unwrap();
+    }
+}
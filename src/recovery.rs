@@ -0,0 +1,143 @@
+//! Progress of opening a tree, for telling a service that's still recovering from one that hangs.
+//!
+//! Opening a tree goes through a fixed sequence of phases, each of which calls
+//! `Options::recovery_callback` as it goes and once more when it's done:
+//!
+//!   ReplayWal     bytes of the log segments replayed into the memtable
+//!   LoadManifest  sstables listed in the manifest, known once it's read
+//!   WarmCache     blocks read back into the block cache, see `cache.rs`
+//!   Done          the tree is open and serves reads and writes
+//!
+//! Every phase reports its end, even one with nothing to do, so the last report is always `Done`.
+//! The callback runs on the thread opening the tree: a readiness probe served by another one can
+//! keep the last report in a shared variable, or send them down a channel.
+//! 💡 RocksDB only writes the log files it recovers to its info `LOG`, as `Recovering log #<n>`.
+
+use std::{fmt, sync::Arc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RecoveryPhase {
+    ReplayWal,
+    LoadManifest,
+    WarmCache,
+    Done,
+}
+
+// How far opening a tree got, as reported to `Options::recovery_callback`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    pub phase: RecoveryPhase,
+    // what's done of the phase and all there is to do, in the units of the phase, see the
+    // module docs.
+    pub completed: u64,
+    pub total: u64,
+    pub finished: bool,
+}
+
+impl RecoveryProgress {
+    // how far along the phase is, from 0 to 100.
+    pub fn percent(&self) -> u8 {
+        match self.total {
+            _ if self.finished => 100,
+            0 => 0,
+            total => (self.completed.min(total) * 100 / total) as u8,
+        }
+    }
+}
+
+// Called with the progress of opening a tree, see `recovery.rs`.
+#[derive(Clone)]
+pub struct RecoveryCallback(Arc<dyn Fn(&RecoveryProgress) + Send + Sync>);
+
+impl RecoveryCallback {
+    pub fn new(callback: impl Fn(&RecoveryProgress) + Send + Sync + 'static) -> Self {
+        RecoveryCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for RecoveryCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecoveryCallback")
+    }
+}
+
+// Reports the progress of opening a tree to the callback, if there's one.
+#[derive(Default)]
+pub(crate) struct RecoveryReporter(Option<RecoveryCallback>);
+
+impl RecoveryReporter {
+    pub fn new(callback: Option<RecoveryCallback>) -> Self {
+        RecoveryReporter(callback)
+    }
+
+    pub fn advance(&self, phase: RecoveryPhase, completed: u64, total: u64) {
+        self.report(phase, completed, total, false);
+    }
+
+    pub fn finish(&self, phase: RecoveryPhase, total: u64) {
+        self.report(phase, total, total, true);
+    }
+
+    fn report(&self, phase: RecoveryPhase, completed: u64, total: u64, finished: bool) {
+        if let Some(callback) = &self.0 {
+            (callback.0)(&RecoveryProgress {
+                phase,
+                completed,
+                total,
+                finished,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{RecoveryCallback, RecoveryPhase, RecoveryProgress};
+    use crate::{LSMTree, Options, cache::BlockCache, sim::SimStorage};
+
+    #[test]
+    fn test_open_reports_recovery_phases() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 10,
+            block_cache: Some(BlockCache::new(1 << 20)),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options.clone());
+        for i in 0..25 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        assert_eq!(lsmtree.get("key03"), Some("value".to_string()));
+        lsmtree.close().unwrap();
+
+        let reports: Arc<Mutex<Vec<RecoveryProgress>>> = Arc::default();
+        let reported = Arc::clone(&reports);
+        let options = Options {
+            recovery_callback: Some(RecoveryCallback::new(move |progress| {
+                reported.lock().unwrap().push(progress.clone());
+            })),
+            ..options
+        };
+        let lsmtree = LSMTree::open_with_options("data", options);
+        assert_eq!(lsmtree.scan(..).count(), 25);
+
+        let reports = reports.lock().unwrap();
+        assert!(reports.windows(2).all(|w| w[0].phase <= w[1].phase));
+        let finished: Vec<&RecoveryProgress> = reports.iter().filter(|p| p.finished).collect();
+        let phases: Vec<RecoveryPhase> = finished.iter().map(|p| p.phase).collect();
+        use RecoveryPhase::*;
+        assert_eq!(phases, [ReplayWal, LoadManifest, WarmCache, Done]);
+        assert!(finished.iter().all(|p| p.percent() == 100));
+
+        // the log holds the 5 writes since the last flush, the manifest 2 sstables, and the cache
+        // the block read before closing.
+        let replayed = reports.iter().find(|p| p.phase == ReplayWal).unwrap();
+        assert_eq!((replayed.completed, replayed.percent()), (0, 0));
+        assert!(finished[0].total > 0);
+        assert_eq!(finished[1].total, 2);
+        assert_eq!(finished[2].total, 1);
+    }
+}
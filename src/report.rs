@@ -0,0 +1,511 @@
+//! A human readable report of what a tree holds and how it's doing, for operators to look at or
+//! log every so often:
+//!
+//!   memtable: 7 of 10 entries
+//!   sstables: 3, 41.2 KiB, compaction trigger 8, backlog 0
+//!     id  size      entries  deletions  shadowed  format  tier  compression  read
+//!     4   20.1 KiB  1000     12         250       7       hot   2.6x         8.0 KiB
+//!     ...
+//!   compression: 2.4x, 39.8 KiB of data blocks, 95.5 KiB raw
+//!   space amplification: 1.4 (41.2 KiB on disk, 29.4 KiB live)
+//!   blob files: 0
+//!   block cache: 36.0 KiB of 1.0 MiB, 520 hits, 40 misses (92.9% hits)
+//!   recent flushes: 2ms 3ms 2ms
+//!   recent compactions: 15ms
+//!
+//! The compression ratio of an sstable is the size of its data blocks before compression over their
+//! size on disk, which tells whether the codec pays off for the data, and `read` is how much of
+//! the file was read from disk since the tree was opened, by reads that missed the block cache as
+//! well as by compactions. Files written before the raw size was recorded show `?`.
+//!
+//! The backlog is the number of sstables compaction has yet to merge or drop to get back under the
+//! trigger, which grows while compaction is held off by the compaction schedule, or falls behind.
+//!
+//! Space amplification compares the size of the sstables to an estimate of how much of it is live
+//! data, judged by the tombstones and shadowed entries in each sstable. It tells whether the tree
+//! grows because there's more data, or because compaction hasn't caught up with the garbage yet.
+//!
+//! `LSMTree::get_property` serves the same numbers one at a time, by name, for dashboards.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    time::{Duration, SystemTime},
+};
+
+use crate::LSMTree;
+
+// What's known about one of the sstables of a tree, returned by `LSMTree::sstables`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SSTableInfo {
+    pub id: usize,
+    // size of the file in bytes.
+    pub size: u64,
+    // the number of entries and tombstones among them, unknown for files written before they
+    // were recorded.
+    pub entries: Option<u64>,
+    pub deletions: Option<u64>,
+    // estimated number of entries shadowed by newer versions of their keys in newer sstables,
+    // which compactions will drop.
+    pub shadowed: u64,
+    pub format_version: u32,
+    // when the newest record in the file was written.
+    pub created: SystemTime,
+    // whether the file was moved to the cold tier.
+    pub cold: bool,
+    // the size of the data blocks on disk, and before compression, unknown for text files and for
+    // files written before the raw size was recorded.
+    pub data_bytes: Option<u64>,
+    pub raw_data_bytes: Option<u64>,
+    // bytes of data blocks read from the file since the tree was opened, see `report.rs`.
+    pub bytes_read: u64,
+}
+
+impl SSTableInfo {
+    // the size of the data blocks before compression over their size on disk, if both are known.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        compression_ratio(self.data_bytes?, self.raw_data_bytes?)
+    }
+}
+
+// The size of the sstables on disk, and how much of that is estimated to be live data, returned by
+// `LSMTree::space_amplification`. Blob files have their own garbage, see
+// `LSMTree::collect_blob_garbage`, and aren't included.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceAmplification {
+    pub disk_bytes: u64,
+    pub live_bytes: u64,
+}
+
+impl SpaceAmplification {
+    // bytes on disk per byte of live data, 1.0 with no garbage at all.
+    pub fn ratio(&self) -> f64 {
+        if self.disk_bytes == 0 {
+            return 1.0;
+        }
+        self.disk_bytes as f64 / self.live_bytes as f64
+    }
+}
+
+// the number of flush and compaction durations kept for the report.
+const RECENT_JOBS: usize = 10;
+
+impl LSMTree {
+    // describes the sstables of the tree, oldest first.
+    pub fn sstables(&self) -> Vec<SSTableInfo> {
+        (self.sstable_mgr.sstables.iter())
+            .map(|sst| {
+                let properties = sst.properties();
+                let data_sizes = sst.data_sizes();
+                SSTableInfo {
+                    id: sst.id,
+                    size: sst.storage.len(&sst.path).unwrap_or(0),
+                    entries: properties.map(|p| p.entries),
+                    deletions: properties.map(|p| p.deletions),
+                    shadowed: sst.shadowed(),
+                    format_version: sst.format_version(),
+                    created: sst.created(),
+                    cold: sst.cold,
+                    data_bytes: data_sizes.map(|(stored, _)| stored),
+                    raw_data_bytes: data_sizes.and_then(|(_, raw)| raw),
+                    bytes_read: sst.bytes_read(),
+                }
+            })
+            .collect()
+    }
+
+    // returns the value of the property `name` as a string, or `None` if there's no such property,
+    // for dashboards and tools that go by names rather than the methods behind them:
+    //
+    // - `lsm.num-sstables`, `lsm.sstable-bytes` and `lsm.num-blob-files`
+    // - `lsm.memtable-entries`
+    // - `lsm.pending-compactions`, the compaction backlog, see `report.rs`
+    // - `lsm.estimated-live-bytes` and `lsm.space-amplification`, see `space_amplification`
+    // - `lsm.block-cache-usage` and `lsm.block-cache-capacity`, in bytes, if there's a block cache
+    // - `lsm.last-seq`, the sequence number of the latest write
+    // - `lsm.compression-ratio`, of the sstables whose raw size is known, see `report.rs`
+    // - `lsm.bytes-read`, from the data blocks of the sstables since the tree was opened
+    // - `lsm.stats`, the whole `stats_report`
+    //
+    // Names may be added over time, but never change meaning.
+    // 💡 RocksDB's `GetProperty` works the same, with names like `rocksdb.num-files-at-level0`.
+    pub fn get_property(&self, name: &str) -> Option<String> {
+        let stats = self.stats();
+        let cache = self.sstable_mgr.block_cache.as_ref();
+        let value = match name {
+            "lsm.num-sstables" => stats.sstables.to_string(),
+            "lsm.sstable-bytes" => stats.sstable_bytes.to_string(),
+            "lsm.num-blob-files" => stats.blob_files.to_string(),
+            "lsm.memtable-entries" => stats.memtable_entries.to_string(),
+            "lsm.pending-compactions" => self.compaction_backlog().to_string(),
+            "lsm.estimated-live-bytes" => self.space_amplification().live_bytes.to_string(),
+            "lsm.space-amplification" => format!("{:.2}", self.space_amplification().ratio()),
+            "lsm.block-cache-usage" => cache?.usage().to_string(),
+            "lsm.block-cache-capacity" => cache?.capacity().to_string(),
+            "lsm.last-seq" => self.last_seq.to_string(),
+            "lsm.compression-ratio" => {
+                format!(
+                    "{:.2}",
+                    compression_ratio(stats.data_bytes, stats.raw_data_bytes)?
+                )
+            }
+            "lsm.bytes-read" => stats.bytes_read.to_string(),
+            "lsm.stats" => self.stats_report(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // the number of sstables compaction has yet to merge or drop to get back under the trigger.
+    fn compaction_backlog(&self) -> usize {
+        let mgr = &self.sstable_mgr;
+        if !mgr.should_compact() {
+            return 0;
+        }
+        (mgr.sstables.len() + 1).saturating_sub(mgr.compaction_trigger)
+    }
+
+    // estimates how much of the space the sstables take is live data. Each sstable counts as live
+    // in proportion to the share of its entries that aren't garbage, see
+    // `SSTableManager::stale_entries`.
+    // 💡 RocksDB estimates live data similarly, from the counts in its table properties, for the
+    // `rocksdb.estimate-live-data-size` property.
+    pub fn space_amplification(&self) -> SpaceAmplification {
+        let mgr = &self.sstable_mgr;
+        let mut amplification = SpaceAmplification::default();
+        for (sst, stale) in mgr.sstables.iter().zip(mgr.stale_entries()) {
+            let size = sst.storage.len(&sst.path).unwrap_or(0);
+            let entries = sst.properties().map_or(0, |p| p.entries);
+            let live = if entries == 0 {
+                size
+            } else {
+                (size as f64 * (entries - stale) as f64 / entries as f64) as u64
+            };
+            amplification.disk_bytes += size;
+            amplification.live_bytes += live;
+        }
+        amplification
+    }
+
+    // returns a report of the memtable, sstables, block cache and recent flushes and compactions
+    // of the tree, see `report.rs`.
+    pub fn stats_report(&self) -> String {
+        let mgr = &self.sstable_mgr;
+        let mut report = String::new();
+        let out = &mut report;
+        writeln!(
+            out,
+            "memtable: {} of {} entries",
+            self.memtable.len(),
+            self.memtable_limit
+        )
+        .unwrap();
+
+        let sstables = self.sstables();
+        writeln!(
+            out,
+            "sstables: {}, {}, compaction trigger {}, backlog {}",
+            sstables.len(),
+            human_bytes(sstables.iter().map(|sst| sst.size).sum()),
+            mgr.compaction_trigger,
+            self.compaction_backlog()
+        )
+        .unwrap();
+        if !sstables.is_empty() {
+            let header = [
+                "id",
+                "size",
+                "entries",
+                "deletions",
+                "shadowed",
+                "format",
+                "tier",
+                "compression",
+                "read",
+            ];
+            let mut rows = vec![header.map(String::from).to_vec()];
+            for sst in &sstables {
+                let count = |n: Option<u64>| n.map_or("?".to_string(), |n| n.to_string());
+                rows.push(vec![
+                    sst.id.to_string(),
+                    human_bytes(sst.size),
+                    count(sst.entries),
+                    count(sst.deletions),
+                    sst.shadowed.to_string(),
+                    sst.format_version.to_string(),
+                    (if sst.cold { "cold" } else { "hot" }).to_string(),
+                    (sst.compression_ratio()).map_or("?".to_string(), |r| format!("{:.1}x", r)),
+                    human_bytes(sst.bytes_read),
+                ]);
+            }
+            write_table(out, &rows);
+        }
+        let stats = self.stats();
+        if let Some(ratio) = compression_ratio(stats.data_bytes, stats.raw_data_bytes) {
+            writeln!(
+                out,
+                "compression: {:.1}x, {} of data blocks, {} raw",
+                ratio,
+                human_bytes(stats.data_bytes),
+                human_bytes(stats.raw_data_bytes)
+            )
+            .unwrap();
+        }
+        let amplification = self.space_amplification();
+        writeln!(
+            out,
+            "space amplification: {:.1} ({} on disk, {} live)",
+            amplification.ratio(),
+            human_bytes(amplification.disk_bytes),
+            human_bytes(amplification.live_bytes)
+        )
+        .unwrap();
+        writeln!(out, "blob files: {}", mgr.blob_files.len()).unwrap();
+
+        if let Some(cache) = &mgr.block_cache {
+            let (hits, misses) = cache.hits_and_misses();
+            let ratio = hits as f64 * 100.0 / (hits + misses).max(1) as f64;
+            writeln!(
+                out,
+                "block cache: {} of {}, {} hits, {} misses ({:.1}% hits)",
+                human_bytes(cache.usage() as u64),
+                human_bytes(cache.capacity() as u64),
+                hits,
+                misses,
+                ratio
+            )
+            .unwrap();
+        }
+        writeln!(out, "recent flushes:{}", durations(&self.recent_flushes)).unwrap();
+        writeln!(
+            out,
+            "recent compactions:{}",
+            durations(&self.recent_compactions)
+        )
+        .unwrap();
+        report
+    }
+}
+
+// adds `took` to the durations of recent flushes or compactions, forgetting the oldest one if
+// there are too many.
+pub(crate) fn record(recent: &mut VecDeque<Duration>, took: Duration) {
+    if recent.len() == RECENT_JOBS {
+        recent.pop_front();
+    }
+    recent.push_back(took);
+}
+
+// the ratio of `raw` bytes to the `stored` bytes they were compressed to, `None` if there are none.
+fn compression_ratio(stored: u64, raw: u64) -> Option<f64> {
+    (stored > 0).then(|| raw as f64 / stored as f64)
+}
+
+fn durations(recent: &VecDeque<Duration>) -> String {
+    recent.iter().map(|took| format!(" {:?}", took)).collect()
+}
+
+// writes `rows` indented, with their columns aligned.
+fn write_table(out: &mut String, rows: &[Vec<String>]) {
+    let columns = rows[0].len();
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap())
+        .collect();
+    for row in rows {
+        let cells: Vec<String> = (row.iter().zip(&widths))
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        writeln!(out, "  {}", cells.join("  ").trim_end()).unwrap();
+    }
+}
+
+// formats a size in bytes with a binary unit, e.g. `41.2 KiB`.
+fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::human_bytes;
+    use crate::{BlockCache, CompactionSchedule, Compression, LSMTree, Options, sim::SimStorage};
+
+    #[test]
+    fn test_stats_report() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            block_cache: Some(BlockCache::new(1 << 20)),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..23 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        lsmtree.delete("key03");
+        assert_eq!(lsmtree.get("key05"), Some("value".to_string()));
+        assert_eq!(lsmtree.get("key05"), Some("value".to_string()));
+
+        let report = lsmtree.stats_report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "memtable: 4 of 10 entries");
+        assert!(lines[1].starts_with("sstables: 2, "));
+        assert!(lines[1].ends_with(", compaction trigger 8, backlog 0"));
+        assert_eq!(
+            lines[2].split_whitespace().collect::<Vec<_>>(),
+            [
+                "id",
+                "size",
+                "entries",
+                "deletions",
+                "shadowed",
+                "format",
+                "tier",
+                "compression",
+                "read"
+            ]
+        );
+        let row: Vec<&str> = lines[3].split_whitespace().collect();
+        assert_eq!(row[0], "1");
+        assert_eq!(row[3..9], ["10", "0", "0", "8", "hot", "1.0x"]);
+        // the block of `key05` was read once, then served by the cache.
+        assert_ne!(row[9], "0");
+        let row: Vec<&str> = lines[4].split_whitespace().collect();
+        assert_eq!(row[9..], ["0", "B"]);
+        assert!(lines[5].starts_with("compression: 1.0x, "));
+        assert!(lines[6].starts_with("space amplification: 1.0 "));
+        assert_eq!(lines[7], "blob files: 0");
+        assert!(lines[8].ends_with(", 1 hits, 1 misses (50.0% hits)"));
+        assert_eq!(lines[9], "recent flushes: 0ns 0ns");
+        assert_eq!(lines[10], "recent compactions:");
+    }
+
+    #[test]
+    fn test_sstables_report_compression_ratio_and_reads() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            compression: Compression::Lz4,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..20 {
+            lsmtree
+                .put(&format!("key{:02}", i), &"value".repeat(20))
+                .unwrap();
+        }
+        let sstables = lsmtree.sstables();
+        assert_eq!(sstables[0].bytes_read, 0);
+        let raw = sstables[0].raw_data_bytes.unwrap();
+        assert!(raw > sstables[0].data_bytes.unwrap());
+        assert!(sstables[0].compression_ratio().unwrap() > 2.0);
+
+        assert_eq!(lsmtree.get("key05"), Some("value".repeat(20)));
+        let sstables = lsmtree.sstables();
+        assert_eq!(sstables[0].bytes_read, sstables[0].data_bytes.unwrap());
+        assert_eq!(sstables[1].bytes_read, 0);
+        let stats = lsmtree.stats();
+        assert_eq!(
+            stats.raw_data_bytes,
+            raw + sstables[1].raw_data_bytes.unwrap()
+        );
+        assert_eq!(stats.bytes_read, sstables[0].bytes_read);
+        let ratio: f64 = lsmtree
+            .get_property("lsm.compression-ratio")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(ratio > 2.0);
+        let bytes_read = lsmtree.get_property("lsm.bytes-read").unwrap();
+        assert_eq!(bytes_read, stats.bytes_read.to_string());
+    }
+
+    #[test]
+    fn test_space_amplification_counts_deleted_entries() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..20 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        let amplification = lsmtree.space_amplification();
+        assert_eq!(amplification.live_bytes, amplification.disk_bytes);
+        assert_eq!(amplification.ratio(), 1.0);
+
+        // the third sstable is half tombstones, shadowing half of the first one.
+        for i in 0..5 {
+            lsmtree.delete(&format!("key{:02}", i));
+        }
+        for i in 20..25 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        assert_eq!(lsmtree.sstable_mgr.stale_entries(), [5, 0, 5]);
+        let amplification = lsmtree.space_amplification();
+        let sizes: Vec<u64> = (lsmtree.sstable_mgr.sstables.iter())
+            .map(|sst| sst.storage.len(&sst.path).unwrap())
+            .collect();
+        assert_eq!(amplification.disk_bytes, sizes.iter().sum::<u64>());
+        let live = sizes[0] / 2 + sizes[1] + sizes[2] / 2;
+        assert!(amplification.live_bytes.abs_diff(live) <= 1);
+        assert!(amplification.ratio() > 1.2);
+    }
+
+    #[test]
+    fn test_get_property() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            compaction_trigger: 3,
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..25 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        let property = |name| lsmtree.get_property(name);
+        assert_eq!(property("lsm.num-sstables").as_deref(), Some("2"));
+        assert_eq!(property("lsm.memtable-entries").as_deref(), Some("5"));
+        assert_eq!(property("lsm.pending-compactions").as_deref(), Some("0"));
+        assert_eq!(property("lsm.last-seq").as_deref(), Some("25"));
+        assert_eq!(property("lsm.space-amplification").as_deref(), Some("1.00"));
+        let live: u64 = property("lsm.estimated-live-bytes")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(live, lsmtree.stats().sstable_bytes);
+        assert_eq!(property("lsm.stats"), Some(lsmtree.stats_report()));
+        assert_eq!(property("lsm.block-cache-usage"), None);
+        assert_eq!(property("rocksdb.num-files-at-level0"), None);
+
+        // compaction is held off outside its window, so the sstables pile up.
+        lsmtree.sstable_mgr.compaction_schedule = CompactionSchedule::between_hours(2, 6);
+        for i in 25..45 {
+            lsmtree.put(&format!("key{:02}", i), "value").unwrap();
+        }
+        let property = |name| lsmtree.get_property(name);
+        assert_eq!(property("lsm.num-sstables").as_deref(), Some("4"));
+        assert_eq!(property("lsm.pending-compactions").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(42_188), "41.2 KiB");
+        assert_eq!(human_bytes(3 << 30), "3.0 GiB");
+    }
+}
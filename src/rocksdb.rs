@@ -0,0 +1,639 @@
+//! Ingestion of table files written by RocksDB or LevelDB, built with the `rocksdb` feature.
+//!
+//! Data moving off RocksDB is in its sstables already. Rather than replaying every key through
+//! `put`, `LSMTree::ingest_rocksdb_sst` reads such a table file directly and hands its entries to
+//! `LSMTree::ingest`. Both databases write the same "block-based table" format:
+//!
+//!   | data block 1 | ... | data block N | meta blocks | metaindex block | index block | footer |
+//!
+//! The footer at the end of the file points to the index block, listing the data blocks, and to
+//! the metaindex block, listing the meta blocks such as the table properties. Each block is
+//! followed by a trailer with the compression of the block and a CRC32C checksum:
+//!
+//!   block:  | entries | restarts: u32 each | restart count: u32 | compression: u8 | checksum: u32 |
+//!   entry:  | shared: varint | non shared: varint | value length: varint | key suffix | value |
+//!
+//! Keys are prefix compressed: an entry only stores the part of its key that differs from the key
+//! before it, except at the restart points. The keys of data blocks are "internal keys", the user
+//! key followed by 8 bytes with the sequence number and the type of the write, a put or a delete.
+//! A key has an entry per version in the table, newest first, and only the newest one is ingested.
+//!
+//! Tables with uncompressed, Snappy or LZ4 compressed blocks can be read, and partitioned indexes
+//! too. Merge operands, range deletions, values in blob files, user defined timestamps, custom
+//! comparators and tables in RocksDB's `format_version` 6 or later are reported as `Unsupported`.
+//! Block checksums are verified when they're CRC32C, the default, and skipped when they're xxHash.
+
+use std::{
+    fmt::Display,
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::{
+    LSMTree, checksum::crc32c, compression::Compression, storage::Storage, varint::Decoder,
+};
+
+// the magic number ending tables written by LevelDB, or by RocksDB in `format_version` 0.
+const LEGACY_MAGIC: u64 = 0xdb47_7524_8b80_fb57;
+// the magic number ending tables written by RocksDB in later format versions.
+const BLOCK_BASED_MAGIC: u64 = 0x88e2_41b7_85f4_cff7;
+// footers are padded to fit two block handles of the longest encoding, 40 bytes. The legacy one
+// is followed by the magic number, the other one starts with the checksum type and ends with the
+// format version and the magic number.
+const LEGACY_FOOTER_LEN: usize = 48;
+const FOOTER_LEN: usize = 53;
+// the compression type and checksum following every block.
+const TRAILER_LEN: u64 = 5;
+
+// types of writes, in the last byte of an internal key.
+const TYPE_DELETION: u8 = 0x0;
+const TYPE_VALUE: u8 = 0x1;
+const TYPE_MERGE: u8 = 0x2;
+const TYPE_SINGLE_DELETION: u8 = 0x7;
+
+const CHECKSUM_CRC32C: u8 = 1;
+
+const NO_COMPRESSION: u8 = 0;
+const SNAPPY: u8 = 1;
+const LZ4: u8 = 4;
+const LZ4HC: u8 = 5;
+
+// index types, in the table properties.
+const TWO_LEVEL_INDEX: u32 = 2;
+const BINARY_SEARCH_WITH_FIRST_KEY: u32 = 3;
+
+impl LSMTree {
+    // ingests the table file at `path` on the tree's storage, written by RocksDB or LevelDB, see
+    // `LSMTree::ingest`. Returns the number of keys ingested, deleted ones included.
+    pub fn ingest_rocksdb_sst(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let entries = read_table(&*self.sstable_mgr.storage, path.as_ref())?;
+        self.ingest(entries)
+    }
+}
+
+// reads the newest version of every key in the table at `path`, in key order.
+fn read_table(storage: &dyn Storage, path: &Path) -> io::Result<Vec<(String, Option<String>)>> {
+    let mut data = Vec::new();
+    storage.open(path)?.read_to_end(&mut data)?;
+    let table = Table::open(data)?;
+
+    // LevelDB tables have no properties, the defaults are what LevelDB writes.
+    let mut properties = Vec::new();
+    for (name, handle) in entries(&table.read_block(table.metaindex)?)? {
+        let handle = BlockHandle::decode(&mut Decoder::new(&handle)).ok_or_else(malformed)?;
+        match name.as_slice() {
+            b"rocksdb.properties" => properties = entries(&table.read_block(handle)?)?,
+            b"rocksdb.range_del" if !entries(&table.read_block(handle)?)?.is_empty() => {
+                return Err(unsupported("range deletions"));
+            }
+            _ => {}
+        }
+    }
+    let property = |name: &str| {
+        let found = properties.iter().find(|(n, _)| n == name.as_bytes());
+        found.map(|(_, value)| value.as_slice())
+    };
+    if let Some(comparator) = property("rocksdb.comparator")
+        && comparator != b"leveldb.BytewiseComparator"
+    {
+        let comparator = String::from_utf8_lossy(comparator);
+        return Err(unsupported(format!("keys ordered by `{}`", comparator)));
+    }
+    let delta_encoded = property("rocksdb.index.value.is.delta.encoded")
+        .and_then(|value| Decoder::new(value).varint())
+        .is_some_and(|delta_encoded| delta_encoded != 0);
+    let index_type = property("rocksdb.block.based.table.index.type")
+        .and_then(|value| Some(u32::from_le_bytes(value.try_into().ok()?)))
+        .unwrap_or(0);
+
+    let first_key = index_type == BINARY_SEARCH_WITH_FIRST_KEY;
+    let mut handles = index_handles(&table.read_block(table.index)?, delta_encoded, first_key)?;
+    if index_type == TWO_LEVEL_INDEX {
+        // the top level index points to the partitions of the index, which point to data blocks.
+        let mut data_handles = Vec::new();
+        for partition in handles {
+            let partition = table.read_block(partition)?;
+            data_handles.extend(index_handles(&partition, delta_encoded, false)?);
+        }
+        handles = data_handles;
+    }
+
+    let mut out: Vec<(String, Option<String>)> = Vec::new();
+    for handle in handles {
+        for (key, value) in entries(&table.read_block(handle)?)? {
+            let split = key.len().checked_sub(8).ok_or_else(malformed)?;
+            let (user_key, trailer) = key.split_at(split);
+            // older versions of a key come right after its newest one.
+            if out
+                .last()
+                .is_some_and(|(last, _)| last.as_bytes() == user_key)
+            {
+                continue;
+            }
+            let value = match trailer[0] {
+                TYPE_VALUE => Some(utf8(value)?),
+                TYPE_DELETION | TYPE_SINGLE_DELETION => None,
+                TYPE_MERGE => return Err(unsupported("merge operands")),
+                other => return Err(unsupported(format!("entries of type {:#x}", other))),
+            };
+            out.push((utf8(user_key.to_vec())?, value));
+        }
+    }
+    Ok(out)
+}
+
+// Location of a block in a table file, the size excluding the block trailer:
+// | offset: varint | size: varint |
+#[derive(Debug, Clone, Copy)]
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+}
+
+impl BlockHandle {
+    fn decode(decoder: &mut Decoder) -> Option<Self> {
+        Some(BlockHandle {
+            offset: decoder.varint()?,
+            size: decoder.varint()?,
+        })
+    }
+}
+
+// A table file read into memory, with what its footer says about reading its blocks.
+struct Table {
+    data: Vec<u8>,
+    checksum: u8,
+    format_version: u32,
+    metaindex: BlockHandle,
+    index: BlockHandle,
+}
+
+impl Table {
+    fn open(data: Vec<u8>) -> io::Result<Self> {
+        let len = data.len();
+        if len < LEGACY_FOOTER_LEN {
+            return Err(corrupt("the file is too short to be a table"));
+        }
+        let magic = u64::from_le_bytes(data[len - 8..].try_into().unwrap());
+        let (checksum, handles, format_version) = match magic {
+            LEGACY_MAGIC => (CHECKSUM_CRC32C, &data[len - LEGACY_FOOTER_LEN..], 0),
+            BLOCK_BASED_MAGIC if len >= FOOTER_LEN => {
+                let footer = &data[len - FOOTER_LEN..];
+                let format_version = u32::from_le_bytes(footer[41..45].try_into().unwrap());
+                (footer[0], &footer[1..], format_version)
+            }
+            _ => return Err(corrupt("the file isn't a block-based table")),
+        };
+        // format version 6 moved the index handle out of the footer.
+        if format_version >= 6 {
+            let what = format!("tables in format_version {}", format_version);
+            return Err(unsupported(what));
+        }
+
+        let mut decoder = Decoder::new(handles);
+        let metaindex = BlockHandle::decode(&mut decoder).ok_or_else(malformed)?;
+        let index = BlockHandle::decode(&mut decoder).ok_or_else(malformed)?;
+        Ok(Table {
+            data,
+            checksum,
+            format_version,
+            metaindex,
+            index,
+        })
+    }
+
+    // reads the block at `handle`, verifying its checksum and decompressing it.
+    fn read_block(&self, handle: BlockHandle) -> io::Result<Vec<u8>> {
+        let end = (handle.offset.checked_add(handle.size))
+            .and_then(|end| end.checked_add(TRAILER_LEN))
+            .filter(|end| *end <= self.data.len() as u64)
+            .ok_or_else(|| corrupt("a block lies past the end of the file"))?;
+        let block = &self.data[handle.offset as usize..end as usize];
+        let (contents, trailer) = block.split_at(handle.size as usize);
+        let compression = trailer[0];
+        let checksum = u32::from_le_bytes(trailer[1..].try_into().unwrap());
+        if self.checksum == CHECKSUM_CRC32C && mask(crc32c(&[contents, &trailer[..1]])) != checksum
+        {
+            return Err(corrupt("block checksum mismatch"));
+        }
+
+        let decompressed = match compression {
+            NO_COMPRESSION => Some(contents.to_vec()),
+            SNAPPY => snappy_decompress(contents),
+            // since format version 2, the LZ4 block is preceded by its uncompressed size.
+            LZ4 | LZ4HC if self.format_version >= 2 => {
+                let mut decoder = Decoder::new(contents);
+                decoder.varint().and_then(|len| {
+                    let data = Compression::Lz4.decompress(decoder.remaining().to_vec(), &[])?;
+                    (data.len() as u64 == len).then_some(data)
+                })
+            }
+            other => {
+                return Err(unsupported(format!(
+                    "blocks compressed with type {}",
+                    other
+                )));
+            }
+        };
+        decompressed.ok_or_else(|| corrupt("malformed compressed block"))
+    }
+}
+
+// the CRC32C checksums of blocks are stored masked, as checksums of data holding checksums
+// themselves would otherwise be prone to collisions.
+fn mask(crc: u32) -> u32 {
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+// the entries of `block`, without the restart points at its end, which only serve lookups.
+fn block_entries(block: &[u8]) -> io::Result<&[u8]> {
+    let mut end = block.len().checked_sub(4).ok_or_else(malformed)?;
+    let packed = u32::from_le_bytes(block[end..].try_into().unwrap());
+    // the high bit of the restart count marks data blocks with a hash index, a byte per bucket
+    // followed by the number of buckets as a u16, between the entries and the restarts.
+    if packed >> 31 == 1 {
+        end = end.checked_sub(2).ok_or_else(malformed)?;
+        let buckets = u16::from_le_bytes(block[end..end + 2].try_into().unwrap());
+        end = end.checked_sub(buckets as usize).ok_or_else(malformed)?;
+    }
+    let restarts = (packed & !(1 << 31)) as usize;
+    end = end.checked_sub(restarts * 4).ok_or_else(malformed)?;
+    Ok(&block[..end])
+}
+
+// reads the header and key of the next entry of a block into `key`, which holds the key of the
+// entry before it. Returns how many bytes of that key were shared, and if `value_len`, the length
+// of the value that follows.
+fn read_key(decoder: &mut Decoder, key: &mut Vec<u8>, value_len: bool) -> Option<(usize, usize)> {
+    let shared = decoder.varint()? as usize;
+    let non_shared = decoder.varint()? as usize;
+    let len = if value_len {
+        decoder.varint()? as usize
+    } else {
+        0
+    };
+    if shared > key.len() {
+        return None;
+    }
+    key.truncate(shared);
+    key.extend_from_slice(decoder.bytes(non_shared)?);
+    Some((shared, len))
+}
+
+// the key value pairs of `block`.
+fn entries(block: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut decoder = Decoder::new(block_entries(block)?);
+    let mut key = Vec::new();
+    let mut out = Vec::new();
+    while !decoder.is_empty() {
+        let (_, len) = read_key(&mut decoder, &mut key, true).ok_or_else(malformed)?;
+        let value = decoder.bytes(len).ok_or_else(malformed)?;
+        out.push((key.clone(), value.to_vec()));
+    }
+    Ok(out)
+}
+
+// the handles in the index block `block`, in order. When they're `delta_encoded`, entries have no
+// value length, and only those storing their whole key store a whole handle. The others store how
+// much larger their block is than the one before, which it comes right after. With `first_key`,
+// every handle is followed by the first key in its block.
+fn index_handles(
+    block: &[u8],
+    delta_encoded: bool,
+    first_key: bool,
+) -> io::Result<Vec<BlockHandle>> {
+    let mut decoder = Decoder::new(block_entries(block)?);
+    let mut key = Vec::new();
+    let mut handles: Vec<BlockHandle> = Vec::new();
+    while !decoder.is_empty() {
+        let handle = match read_key(&mut decoder, &mut key, !delta_encoded) {
+            Some((_, len)) if !delta_encoded => {
+                (decoder.bytes(len)).and_then(|value| BlockHandle::decode(&mut Decoder::new(value)))
+            }
+            Some((0, _)) => BlockHandle::decode(&mut decoder),
+            Some(_) => handles.last().zip(decoder.varint()).map(|(before, delta)| {
+                // the difference is zigzag encoded, to keep small negative ones short.
+                let delta = (delta >> 1) as i64 ^ -((delta & 1) as i64);
+                BlockHandle {
+                    offset: before.offset + before.size + TRAILER_LEN,
+                    size: before.size.wrapping_add_signed(delta),
+                }
+            }),
+            None => None,
+        };
+        handles.push(handle.ok_or_else(malformed)?);
+        if delta_encoded && first_key {
+            decoder.length_prefixed().ok_or_else(malformed)?;
+        }
+    }
+    Ok(handles)
+}
+
+// Decompresses a Snappy block: its uncompressed length as a varint, then elements that are either
+// literals or copies of earlier output, told apart by the low 2 bits of their tag byte, like LZ4.
+fn snappy_decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut decoder = Decoder::new(input);
+    let len = decoder.varint()? as usize;
+    let input = decoder.remaining();
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let tag = input[i];
+        i += 1;
+        let (offset, copy_len) = match tag & 3 {
+            0 => {
+                // lengths of 60 and up are stored in the 1 to 4 bytes after the tag.
+                let mut literal_len = (tag >> 2) as usize;
+                if literal_len >= 60 {
+                    let bytes = literal_len - 59;
+                    literal_len = (input.get(i..i + bytes)?.iter().rev())
+                        .fold(0, |len, b| (len << 8) | *b as usize);
+                    i += bytes;
+                }
+                let literal_len = literal_len + 1;
+                out.extend_from_slice(input.get(i..i.checked_add(literal_len)?)?);
+                i += literal_len;
+                continue;
+            }
+            1 => {
+                let offset = ((tag as usize >> 5) << 8) | *input.get(i)? as usize;
+                i += 1;
+                (offset, 4 + ((tag >> 2) & 7) as usize)
+            }
+            2 => {
+                let offset = u16::from_le_bytes(input.get(i..i + 2)?.try_into().unwrap());
+                i += 2;
+                (offset as usize, 1 + (tag >> 2) as usize)
+            }
+            _ => {
+                let offset = u32::from_le_bytes(input.get(i..i + 4)?.try_into().unwrap());
+                i += 4;
+                (offset as usize, 1 + (tag >> 2) as usize)
+            }
+        };
+        let start = out.len().checked_sub(offset).filter(|_| offset > 0)?;
+        // the copy may overlap the bytes it produces, e.g. to repeat a run, so copy byte by byte.
+        for k in start..start + copy_len {
+            out.push(out[k]);
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+fn utf8(bytes: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(bytes).map_err(|_| corrupt("keys and values must be UTF-8"))
+}
+
+fn malformed() -> io::Error {
+    corrupt("malformed block")
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad table: {}", what))
+}
+
+fn unsupported(what: impl Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} can't be ingested", what),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::Arc};
+
+    use super::{
+        BLOCK_BASED_MAGIC, LEGACY_MAGIC, LZ4, NO_COMPRESSION, SNAPPY, TYPE_DELETION, TYPE_VALUE,
+        mask, snappy_decompress,
+    };
+    use crate::{
+        Compression, LSMTree, Options, checksum::crc32c, sim::SimStorage, storage::Storage,
+        varint::put_varint,
+    };
+
+    // builds a block of `entries`, with a restart point every `interval` entries. Without
+    // `value_len`, values are stored as they are, the way delta encoded index entries are.
+    fn block(entries: &[(Vec<u8>, Vec<u8>)], interval: usize, value_len: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut restarts = Vec::new();
+        let mut before: &[u8] = &[];
+        for (i, (key, value)) in entries.iter().enumerate() {
+            let mut shared = 0;
+            if i % interval == 0 {
+                restarts.push(out.len() as u32);
+            } else {
+                let common = key.iter().zip(before).take_while(|(a, b)| a == b);
+                shared = common.count();
+            }
+            put_varint(&mut out, shared as u64);
+            put_varint(&mut out, (key.len() - shared) as u64);
+            if value_len {
+                put_varint(&mut out, value.len() as u64);
+            }
+            out.extend_from_slice(&key[shared..]);
+            out.extend_from_slice(value);
+            before = key;
+        }
+        if restarts.is_empty() {
+            restarts.push(0);
+        }
+        for restart in &restarts {
+            out.extend_from_slice(&restart.to_le_bytes());
+        }
+        out.extend_from_slice(&(restarts.len() as u32).to_le_bytes());
+        out
+    }
+
+    fn internal_key(key: &str, seq: u64, kind: u8) -> Vec<u8> {
+        let mut out = key.as_bytes().to_vec();
+        out.extend_from_slice(&((seq << 8) | kind as u64).to_le_bytes());
+        out
+    }
+
+    fn handle((offset, size): (u64, u64)) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_varint(&mut out, offset);
+        put_varint(&mut out, size);
+        out
+    }
+
+    // appends a block with its trailer to `file`, returning its offset and size.
+    fn append_block(file: &mut Vec<u8>, contents: &[u8], compression: u8) -> (u64, u64) {
+        let at = (file.len() as u64, contents.len() as u64);
+        file.extend_from_slice(contents);
+        file.push(compression);
+        let checksum = mask(crc32c(&[contents, &[compression]]));
+        file.extend_from_slice(&checksum.to_le_bytes());
+        at
+    }
+
+    fn write_file(storage: &SimStorage, path: &str, data: &[u8]) {
+        let mut file = storage.create(path.as_ref()).unwrap();
+        file.write_all(data).unwrap();
+        file.sync().unwrap();
+    }
+
+    #[test]
+    fn test_ingest_rocksdb_table() {
+        let mut file = Vec::new();
+        let data_blocks = [
+            vec![
+                (internal_key("key01", 9, TYPE_VALUE), b"new".to_vec()),
+                (internal_key("key01", 3, TYPE_VALUE), b"old".to_vec()),
+                (internal_key("key02", 8, TYPE_DELETION), vec![]),
+                (internal_key("key03", 7, TYPE_VALUE), b"3".to_vec()),
+            ],
+            vec![
+                (internal_key("key04", 6, TYPE_VALUE), b"4".to_vec()),
+                (internal_key("key05", 5, TYPE_VALUE), b"five".repeat(20)),
+            ],
+            vec![(internal_key("key06", 4, TYPE_VALUE), b"6".to_vec())],
+        ];
+        let mut handles = Vec::new();
+        for (i, entries) in data_blocks.iter().enumerate() {
+            let contents = block(entries, 16, true);
+            // the second block is LZ4 compressed, preceded by its uncompressed size.
+            let handle = if i == 1 {
+                let (compressed, codec) = Compression::Lz4.compress(&contents, &[]);
+                assert_eq!(codec, Compression::Lz4);
+                let mut lz4 = Vec::new();
+                put_varint(&mut lz4, contents.len() as u64);
+                lz4.extend_from_slice(&compressed);
+                append_block(&mut file, &lz4, LZ4)
+            } else {
+                append_block(&mut file, &contents, NO_COMPRESSION)
+            };
+            handles.push(handle);
+        }
+
+        let properties = block(
+            &[
+                (
+                    b"rocksdb.block.based.table.index.type".to_vec(),
+                    0u32.to_le_bytes().to_vec(),
+                ),
+                (
+                    b"rocksdb.comparator".to_vec(),
+                    b"leveldb.BytewiseComparator".to_vec(),
+                ),
+                (b"rocksdb.index.value.is.delta.encoded".to_vec(), vec![1]),
+            ],
+            1,
+            true,
+        );
+        let properties = append_block(&mut file, &properties, NO_COMPRESSION);
+        let metaindex = block(
+            &[(b"rocksdb.properties".to_vec(), handle(properties))],
+            1,
+            true,
+        );
+        let metaindex = append_block(&mut file, &metaindex, NO_COMPRESSION);
+        // the second entry shares part of its key with the first one, so it only stores the
+        // difference of its size to the size of the first block, zigzag encoded.
+        let size_delta = (handles[1].1 as i64 - handles[0].1 as i64) * 2;
+        let mut delta = Vec::new();
+        put_varint(
+            &mut delta,
+            if size_delta < 0 {
+                -size_delta - 1
+            } else {
+                size_delta
+            } as u64,
+        );
+        let index = block(
+            &[
+                (b"key03".to_vec(), handle(handles[0])),
+                (b"key05".to_vec(), delta),
+                (b"key06".to_vec(), handle(handles[2])),
+            ],
+            2,
+            false,
+        );
+        let index = append_block(&mut file, &index, NO_COMPRESSION);
+
+        let mut footer = vec![1];
+        footer.extend(handle(metaindex));
+        footer.extend(handle(index));
+        footer.resize(41, 0);
+        footer.extend_from_slice(&5u32.to_le_bytes());
+        footer.extend_from_slice(&BLOCK_BASED_MAGIC.to_le_bytes());
+        file.extend(footer);
+
+        let storage = SimStorage::new(1, Default::default());
+        write_file(&storage, "export.sst", &file);
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        lsmtree.put("key02", "old2").unwrap();
+        lsmtree.put("key07", "7").unwrap();
+        assert_eq!(lsmtree.ingest_rocksdb_sst("export.sst").unwrap(), 6);
+
+        let keys: Vec<(String, String)> = lsmtree.scan(..).collect();
+        let expected = [
+            ("key01", "new".to_string()),
+            ("key03", "3".to_string()),
+            ("key04", "4".to_string()),
+            ("key05", "five".repeat(20)),
+            ("key06", "6".to_string()),
+            ("key07", "7".to_string()),
+        ];
+        let expected: Vec<(String, String)> = (expected.into_iter())
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_ingest_leveldb_table() {
+        let entries = [
+            (internal_key("a", 2, TYPE_VALUE), b"apple".to_vec()),
+            (internal_key("b", 1, TYPE_VALUE), b"banana".to_vec()),
+        ];
+        let contents = block(&entries, 16, true);
+        // a Snappy block holding a single literal.
+        let mut snappy = Vec::new();
+        put_varint(&mut snappy, contents.len() as u64);
+        snappy.push(((contents.len() - 1) as u8) << 2);
+        snappy.extend_from_slice(&contents);
+
+        let mut file = Vec::new();
+        let data = append_block(&mut file, &snappy, SNAPPY);
+        let metaindex = append_block(&mut file, &block(&[], 1, true), NO_COMPRESSION);
+        let index = block(&[(b"b".to_vec(), handle(data))], 1, true);
+        let index = append_block(&mut file, &index, NO_COMPRESSION);
+        let mut footer = handle(metaindex);
+        footer.extend(handle(index));
+        footer.resize(40, 0);
+        footer.extend_from_slice(&LEGACY_MAGIC.to_le_bytes());
+        file.extend(footer);
+
+        let storage = SimStorage::new(1, Default::default());
+        write_file(&storage, "000005.ldb", &file);
+        // a flipped bit in the data block.
+        file[10] ^= 1;
+        write_file(&storage, "corrupt.ldb", &file);
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+
+        let err = lsmtree.ingest_rocksdb_sst("corrupt.ldb").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(lsmtree.ingest_rocksdb_sst("000005.ldb").unwrap(), 2);
+        assert_eq!(lsmtree.get("a"), Some("apple".to_string()));
+        assert_eq!(lsmtree.get("b"), Some("banana".to_string()));
+    }
+
+    #[test]
+    fn test_snappy_decompress() {
+        // the literal "abc", then a copy of 9 bytes from 3 bytes back.
+        let compressed = [12, 2 << 2, b'a', b'b', b'c', 1 | (5 << 2), 3];
+        assert_eq!(snappy_decompress(&compressed).unwrap(), b"abcabcabcabc");
+        // a copy reaching before the start of the output.
+        assert_eq!(snappy_decompress(&[4, 1, 1]), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! A set of LSM Trees splitting the keyspace between them by key hash.
+//!
+//! A single `LSMTree` takes one write at a time: every put appends to the same log and memtable,
+//! and a writer that fills the memtable holds up the rest while it's flushed and compacted.
+//! `ShardedLsmTree` spreads keys over N independent trees, the shards, each in its own directory
+//! under the data dir with its own log, sstables and compactions:
+//!
+//!   data/SHARDS       the number of shards
+//!   data/shard-0/     a complete LSM Tree
+//!   data/shard-1/
+//!   ...
+//!
+//! A key always goes to the same shard, picked by the crc32 of the key, so writes and lookups of
+//! different keys proceed in parallel on different shards. Scans read every shard and merge their
+//! (disjoint) results back into key order.
+//!
+//! The number of shards can't change once the tree is created, since that would move most keys to
+//! a different shard. It's recorded in the `SHARDS` file and checked on open.
+//! 💡 Systems that need to grow split the keyspace by key range instead (e.g. TiKV's regions,
+//! CockroachDB's ranges), so that a shard can be split in two without touching the others.
+
+use std::{
+    io::{self, Read, Write},
+    iter::Peekable,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::{Error, LSMTree, Options, ScanIter, Stats, checksum::crc32};
+
+// the file in the data dir holding the number of shards.
+const SHARDS_FILE: &str = "SHARDS";
+
+pub struct ShardedLsmTree {
+    shards: Vec<Mutex<LSMTree>>,
+}
+
+impl ShardedLsmTree {
+    // creates or opens a tree in `data_dir`, split into `shards` shards.
+    pub fn open(data_dir: impl AsRef<Path>, shards: usize) -> Self {
+        Self::open_with_options(data_dir, shards, Options::default())
+    }
+
+    // creates or opens a tree in `data_dir`, split into `shards` shards that are each configured
+    // with `options`. A block cache in the options is shared by all shards.
+    pub fn open_with_options(data_dir: impl AsRef<Path>, shards: usize, options: Options) -> Self {
+        assert!(shards > 0, "a sharded tree needs at least one shard");
+        let data_dir = data_dir.as_ref();
+        let storage = &options.storage;
+        if !storage.exists(data_dir) {
+            storage.create_dir_all(data_dir).unwrap();
+        }
+
+        let shards_file = data_dir.join(SHARDS_FILE);
+        if storage.exists(&shards_file) {
+            let mut contents = String::new();
+            let mut file = storage.open(&shards_file).unwrap();
+            file.read_to_string(&mut contents).unwrap();
+            let existing: usize = contents.trim().parse().unwrap();
+            assert_eq!(
+                existing,
+                shards,
+                "{} was created with {} shards",
+                data_dir.display(),
+                existing
+            );
+        } else {
+            let mut file = storage.create(&shards_file).unwrap();
+            writeln!(file, "{}", shards).unwrap();
+            file.sync().unwrap();
+            storage.sync_dir(data_dir).unwrap();
+        }
+
+        let shards = (0..shards)
+            .map(|i| {
+                let mut options = options.clone();
+                // shards number their sstables independently, so they can't share a cold tier dir.
+                if let Some(tier) = &mut options.cold_tier {
+                    tier.dir = shard_dir(&tier.dir, i);
+                }
+                Mutex::new(LSMTree::open_with_options(shard_dir(data_dir, i), options))
+            })
+            .collect();
+        ShardedLsmTree { shards }
+    }
+
+    // the shard holding `k`.
+    fn shard(&self, k: &str) -> MutexGuard<'_, LSMTree> {
+        let i = crc32(k.as_bytes()) as usize % self.shards.len();
+        self.shards[i].lock().unwrap()
+    }
+
+    pub fn put(&self, k: &str, v: &str) -> Result<(), Error> {
+        self.shard(k).put(k, v)
+    }
+
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.shard(k).get(k)
+    }
+
+    pub fn delete(&self, k: &str) {
+        self.shard(k).delete(k);
+    }
+
+    // returns an iterator over the live key value pairs within `range`, in sorted key order, across
+    // all shards. Each shard's part of the scan is as of when the scan starts.
+    pub fn scan<'a>(&self, range: impl RangeBounds<&'a str> + Clone) -> ShardedScanIter {
+        self.merge(|tree| tree.scan(range.clone()))
+    }
+
+    // returns an iterator over the live key value pairs whose key starts with `prefix`.
+    pub fn scan_prefix(&self, prefix: &str) -> ShardedScanIter {
+        self.merge(|tree| tree.scan_prefix(prefix))
+    }
+
+    fn merge(&self, scan: impl Fn(&LSMTree) -> ScanIter) -> ShardedScanIter {
+        let sources = self
+            .shards
+            .iter()
+            .map(|shard| scan(&shard.lock().unwrap()).peekable())
+            .collect();
+        ShardedScanIter { sources }
+    }
+
+    // changes an option of every shard, see `LSMTree::set_option`.
+    pub fn set_option(&self, name: &str, value: &str) -> io::Result<()> {
+        for shard in &self.shards {
+            shard.lock().unwrap().set_option(name, value)?;
+        }
+        Ok(())
+    }
+
+    // the number of entries and files across all shards.
+    pub fn stats(&self) -> Stats {
+        self.shards.iter().map(|s| s.lock().unwrap().stats()).sum()
+    }
+
+    // the number of entries and files in each shard.
+    pub fn shard_stats(&self) -> Vec<Stats> {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().stats())
+            .collect()
+    }
+
+    // shuts every shard down, see `LSMTree::close`.
+    pub fn close(self) -> io::Result<()> {
+        for shard in self.shards {
+            shard.into_inner().unwrap().close()?;
+        }
+        Ok(())
+    }
+}
+
+fn shard_dir(dir: &Path, i: usize) -> PathBuf {
+    dir.join(format!("shard-{}", i))
+}
+
+// Iterator returned by `ShardedLsmTree::scan`, merging the scans of all shards. A key lives in a
+// single shard, so unlike `ScanIter` there are no duplicates to skip.
+pub struct ShardedScanIter {
+    sources: Vec<Peekable<ScanIter>>,
+}
+
+impl Iterator for ShardedScanIter {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, smallest) = self
+            .sources
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, source)| Some((source.peek()?.0.clone(), i)))
+            .min()?;
+        self.sources[smallest].next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::ShardedLsmTree;
+    use crate::{Options, sim::SimStorage};
+
+    fn options(storage: &SimStorage) -> Options {
+        Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_sharded_tree_spreads_keys_over_shards() {
+        let storage = SimStorage::new(1, Default::default());
+        let tree = ShardedLsmTree::open_with_options("data", 4, options(&storage));
+        thread::scope(|s| {
+            for t in 0..4 {
+                let tree = &tree;
+                s.spawn(move || {
+                    for i in (t..200).step_by(4) {
+                        tree.put(&format!("key{:03}", i), &format!("value{}", i))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        tree.delete("key007");
+
+        assert_eq!(tree.get("key042"), Some("value42".to_string()));
+        assert_eq!(tree.get("key007"), None);
+        let keys: Vec<String> = tree.scan(.."key100").map(|(k, _)| k).collect();
+        let expected: Vec<String> = (0..100)
+            .filter(|i| *i != 7)
+            .map(|i| format!("key{:03}", i))
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(tree.scan_prefix("key19").count(), 10);
+
+        let shard_stats = tree.shard_stats();
+        assert!(shard_stats.iter().all(|s| s.sstables > 0));
+        let stats = tree.stats();
+        assert_eq!(
+            stats.sstables,
+            shard_stats.iter().map(|s| s.sstables).sum::<usize>()
+        );
+        tree.close().unwrap();
+
+        let tree = ShardedLsmTree::open_with_options("data", 4, options(&storage));
+        assert_eq!(tree.get("key199"), Some("value199".to_string()));
+        assert_eq!(tree.scan(..).count(), 199);
+    }
+
+    #[test]
+    #[should_panic(expected = "created with 4 shards")]
+    fn test_sharded_tree_keeps_its_shard_count() {
+        let storage = SimStorage::new(1, Default::default());
+        ShardedLsmTree::open_with_options("data", 4, options(&storage));
+        ShardedLsmTree::open_with_options("data", 2, options(&storage));
+    }
+}
@@ -0,0 +1,166 @@
+//! Sidecar index files for text sstables.
+//!
+//! Sstables written before the block format (see `sstable.rs`) are plain `key:value` lines, with no
+//! index or filter, so a lookup has to read them line by line up to the key. Such files stay in the
+//! tree until a compaction rewrites them as block based sstables, which carry their own index. Until
+//! then, the first lookup in a text sstable builds a compact index for it and saves it next to the
+//! file, as `<id>.idx`, for later lookups and restarts to reuse:
+//!
+//!   | filter: length prefixed | index block | crc32: u32 |
+//!
+//! The filter is a bloom filter over all keys in the file (see `bloom.rs`). The index block (see
+//! `block.rs`) samples the file every `SAMPLE_INTERVAL` lines: it maps the last key of each run of
+//! lines to the offset of the run's first line, so a lookup seeks to the one run that may hold the
+//! key and reads just that.
+//!
+//! A sidecar is derived from its sstable, so one that's missing, or fails its checksum because a
+//! crash cut it short, is simply built again. It's removed along with its sstable.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    block::{Block, BlockBuilder},
+    bloom::{self, FilterBuilder},
+    checksum::crc32,
+    sstable::read_text_entry,
+    storage::Storage,
+    varint::{Decoder, put_length_prefixed},
+};
+
+// number of lines in each run of lines the index points to.
+const SAMPLE_INTERVAL: usize = 16;
+const BLOOM_BITS_PER_KEY: usize = 10;
+
+// The index of a text sstable, as stored in its sidecar.
+pub(crate) struct TextIndex {
+    filter: Vec<u8>,
+    index: Block,
+}
+
+impl TextIndex {
+    // loads the sidecar of the text sstable at `path`, building and saving it if there's no valid
+    // one. Saving it is best effort, the index works all the same if it can't be written.
+    pub fn load_or_build(storage: &dyn Storage, path: &Path) -> Self {
+        let sidecar = sidecar_path(path);
+        if let Some(index) = Self::load(storage, &sidecar) {
+            return index;
+        }
+
+        let (index, encoded) = Self::build(storage, path);
+        let _ = storage.create(&sidecar).and_then(|mut file| {
+            file.write_all(&encoded)?;
+            file.sync()
+        });
+        index
+    }
+
+    fn load(storage: &dyn Storage, sidecar: &Path) -> Option<Self> {
+        let mut data = Vec::new();
+        storage.open(sidecar).ok()?.read_to_end(&mut data).ok()?;
+        let stored = data.split_off(data.len().checked_sub(4)?);
+        if crc32(&data) != u32::from_le_bytes(stored.try_into().unwrap()) {
+            return None;
+        }
+
+        let mut decoder = Decoder::new(&data);
+        let filter = decoder.length_prefixed()?.to_vec();
+        Some(TextIndex {
+            filter,
+            index: Block::new(decoder.remaining().to_vec()),
+        })
+    }
+
+    // reads the text sstable at `path` and returns its index, along with the index encoded as a
+    // sidecar.
+    fn build(storage: &dyn Storage, path: &Path) -> (Self, Vec<u8>) {
+        let mut filter = FilterBuilder::new();
+        let mut index = BlockBuilder::new(SAMPLE_INTERVAL);
+        let mut offset: u64 = 0;
+        let mut run_offset: u64 = 0;
+        let mut last_key = String::new();
+        let mut file = BufReader::new(storage.open(path).unwrap());
+        let mut line = String::new();
+        for i in 0.. {
+            line.clear();
+            let len = file.read_line(&mut line).unwrap();
+            if len == 0 {
+                break;
+            }
+            let (k, _) = read_text_entry(&Ok(line.trim_end_matches('\n').to_string()));
+            filter.add_key(k.as_bytes());
+            if i % SAMPLE_INTERVAL == 0 && i > 0 {
+                index.add(last_key.as_bytes(), &run_offset.to_le_bytes());
+                run_offset = offset;
+            }
+            offset += len as u64;
+            last_key = k;
+        }
+        if offset > 0 {
+            index.add(last_key.as_bytes(), &run_offset.to_le_bytes());
+        }
+
+        let filter = filter.finish(BLOOM_BITS_PER_KEY);
+        let index = index.finish();
+        let mut encoded = Vec::new();
+        put_length_prefixed(&mut encoded, &filter);
+        encoded.extend_from_slice(&index);
+        encoded.extend_from_slice(&crc32(&encoded).to_le_bytes());
+        let index = TextIndex {
+            filter,
+            index: Block::new(index),
+        };
+        (index, encoded)
+    }
+
+    // returns the offset of the run of lines that holds `key`, or `None` if the file definitely
+    // doesn't have it.
+    pub fn find(&self, key: &str) -> Option<u64> {
+        if !bloom::may_contain(&self.filter, key.as_bytes()) {
+            return None;
+        }
+        let (_, offset) = self.index.seek(key.as_bytes()).next()?;
+        Some(u64::from_le_bytes(offset.try_into().unwrap()))
+    }
+}
+
+// the path of the sidecar of the sstable at `path`.
+pub(crate) fn sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("idx")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path};
+
+    use super::{TextIndex, sidecar_path};
+    use crate::{sim::SimStorage, storage::Storage};
+
+    #[test]
+    fn test_text_index_is_saved_and_rebuilt() {
+        let storage = SimStorage::new(1, Default::default());
+        let path = Path::new("1.sst");
+        let mut file = storage.create(path).unwrap();
+        for i in 0..100 {
+            writeln!(file, "key{:03}:value{:03}", i, i).unwrap();
+        }
+        drop(file);
+
+        let index = TextIndex::load_or_build(&storage, path);
+        assert!(storage.exists(&sidecar_path(path)));
+        // key040 is in the third run of 16 lines, the runs before it are 16 lines of 16 bytes.
+        assert_eq!(index.find("key040"), Some(2 * 16 * 16));
+        assert_eq!(index.find("key000"), Some(0));
+        let absent = (100..200).filter_map(|i| index.find(&format!("key{}", i)));
+        assert!(absent.count() < 5);
+
+        // a sidecar cut short fails its checksum, and is built again.
+        storage.truncate(&sidecar_path(path), 10).unwrap();
+        let index = TextIndex::load_or_build(&storage, path);
+        assert_eq!(index.find("key099"), Some(6 * 16 * 16));
+        let len = storage.len(&sidecar_path(path)).unwrap();
+        assert!(len > 10);
+    }
+}
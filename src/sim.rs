@@ -0,0 +1,597 @@
+//! A simulated storage backend and a harness for testing the tree against crashes and I/O faults.
+//!
+//! `SimStorage` keeps every file in memory and tracks which of its bytes are durable, i.e. were
+//! synced before a crash. Like a real disk, data written but not yet synced is lost when `crash`
+//! is called, and with torn writes enabled a random prefix of it may survive. Faults are driven by a
+//! seeded random number generator, so a failing run can be replayed exactly from its seed.
+//!
+//! `Simulation` runs a random workload of puts, deletes, flushes, restarts and crashes against a
+//! tree on a `SimStorage`, and after every recovery checks that the tree holds exactly the data it
+//! had persisted before going down.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Cursor, Write},
+    panic::{AssertUnwindSafe, catch_unwind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, Once},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    ColdTierOptions, Compression, LSMTree, Options,
+    storage::{ReadableFile, Storage, WritableFile},
+};
+
+// every simulated failure carries this in its message, to tell them apart from real bugs.
+const FAULT_MESSAGE: &str = "simulated fault";
+
+// Faults injected by a `SimStorage`.
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    // probability that a write or sync fails.
+    pub write_error_rate: f64,
+    // on a crash, keep a random prefix of the data written since the last sync of each file,
+    // instead of dropping all of it.
+    pub torn_writes: bool,
+    // file creations, renames and removals only survive a crash once their directory is synced.
+    // When unset they are durable right away, as on most journaling filesystems.
+    pub strict_directory_sync: bool,
+}
+
+// A small, seeded pseudo random number generator (splitmix64).
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // a number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    // true with the given probability.
+    pub fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+#[derive(Debug)]
+struct Inode {
+    data: Vec<u8>,
+    // the prefix of `data` that survives a crash.
+    durable_len: usize,
+    modified: SystemTime,
+}
+
+#[derive(Debug)]
+struct SimState {
+    inodes: Vec<Inode>,
+    // the files as seen by the running process, and as they'd be found after a crash.
+    entries: BTreeMap<PathBuf, usize>,
+    durable_entries: BTreeMap<PathBuf, usize>,
+    dirs: BTreeSet<PathBuf>,
+    // simulated time, which only moves when advanced explicitly.
+    clock: SystemTime,
+    faults: FaultConfig,
+    rng: SimRng,
+    // number of mutating operations run so far.
+    ops: u64,
+    // the storage goes down once `ops` reaches this.
+    crash_at: Option<u64>,
+    // set once the storage went down, every operation fails until `crash` is called.
+    down: bool,
+}
+
+impl SimState {
+    // counts a mutating operation and decides whether it fails.
+    fn fault(&mut self, may_fail: bool) -> io::Result<()> {
+        if self.down {
+            return Err(fault("storage is down"));
+        }
+        self.ops += 1;
+        if self.crash_at.is_some_and(|at| self.ops >= at) {
+            self.down = true;
+            return Err(fault("crash"));
+        }
+        if may_fail && self.rng.chance(self.faults.write_error_rate) {
+            return Err(fault("write error"));
+        }
+        Ok(())
+    }
+
+    fn inode(&self, path: &Path) -> io::Result<usize> {
+        self.entries
+            .get(path)
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.display().to_string()))
+    }
+
+    // mirrors directory entry changes to the durable entries, unless directories must be synced.
+    fn entries_changed(&mut self) {
+        if !self.faults.strict_directory_sync {
+            self.durable_entries = self.entries.clone();
+        }
+    }
+}
+
+fn fault(what: &str) -> io::Error {
+    io::Error::other(format!("{}: {}", FAULT_MESSAGE, what))
+}
+
+// In memory storage that injects faults and can simulate crashes. Clones share the same files.
+#[derive(Debug, Clone)]
+pub struct SimStorage {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimStorage {
+    pub fn new(seed: u64, faults: FaultConfig) -> Self {
+        let state = SimState {
+            inodes: Vec::new(),
+            entries: BTreeMap::new(),
+            durable_entries: BTreeMap::new(),
+            dirs: BTreeSet::new(),
+            clock: SystemTime::UNIX_EPOCH,
+            faults,
+            rng: SimRng::new(seed),
+            ops: 0,
+            crash_at: None,
+            down: false,
+        };
+        SimStorage {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SimState> {
+        // a panic while holding the lock doesn't leave the state half updated, so ignore poisoning.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // number of mutating operations run so far.
+    pub fn ops(&self) -> u64 {
+        self.state().ops
+    }
+
+    // moves the simulated clock forward.
+    pub fn advance_clock(&self, by: Duration) {
+        self.state().clock += by;
+    }
+
+    // makes the storage go down, failing every operation, once `ops` more operations have run.
+    pub fn crash_after(&self, ops: u64) {
+        let mut state = self.state();
+        state.crash_at = Some(state.ops + ops.max(1));
+    }
+
+    // loses everything that wasn't made durable and brings the storage back up.
+    pub fn crash(&self) {
+        let mut state = self.state();
+        let state = &mut *state;
+        state.entries = state.durable_entries.clone();
+        for inode in &mut state.inodes {
+            let mut len = inode.durable_len;
+            if state.faults.torn_writes && inode.data.len() > len {
+                len += state.rng.below((inode.data.len() - len) as u64 + 1) as usize;
+            }
+            inode.data.truncate(len);
+            inode.durable_len = len;
+        }
+        state.crash_at = None;
+        state.down = false;
+    }
+}
+
+impl Storage for SimStorage {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state();
+        state.fault(false)?;
+        let inode = Inode {
+            data: Vec::new(),
+            durable_len: 0,
+            modified: state.clock,
+        };
+        state.inodes.push(inode);
+        let inode = state.inodes.len() - 1;
+        state.entries.insert(path.to_path_buf(), inode);
+        state.entries_changed();
+        Ok(Box::new(SimFile {
+            state: Arc::clone(&self.state),
+            inode,
+        }))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>> {
+        let state = self.state();
+        let inode = state.inode(path)?;
+        // readers get a snapshot, so like an open handle on unix they aren't affected by the file
+        // being replaced or removed afterwards.
+        Ok(Box::new(Cursor::new(state.inodes[inode].data.clone())))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.fault(false)?;
+        state.inode(path)?;
+        state.entries.remove(path);
+        state.entries_changed();
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let mut state = self.state();
+        state.fault(true)?;
+        let inode = state.inode(path)?;
+        let clock = state.clock;
+        let inode = &mut state.inodes[inode];
+        inode.modified = clock;
+        inode.data.truncate(len as usize);
+        inode.durable_len = inode.data.len();
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.fault(false)?;
+        let inode = state.inode(from)?;
+        state.entries.remove(from);
+        state.entries.insert(to.to_path_buf(), inode);
+        state.entries_changed();
+        Ok(())
+    }
+
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.fault(false)?;
+        let inode = state.inode(from)?;
+        if state.entries.contains_key(to) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        state.entries.insert(to.to_path_buf(), inode);
+        state.entries_changed();
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state();
+        if !state.dirs.contains(dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                dir.display().to_string(),
+            ));
+        }
+        let files = state.entries.keys().filter(|p| p.parent() == Some(dir));
+        Ok(files.cloned().collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state();
+        state.entries.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        let state = self.state();
+        Ok(state.inodes[state.inode(path)?].data.len() as u64)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        let state = self.state();
+        Ok(state.inodes[state.inode(path)?].modified)
+    }
+
+    fn now(&self) -> SystemTime {
+        self.state().clock
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.fault(false)?;
+        // directories themselves are always durable, only the files within them may be lost.
+        state.dirs.extend(dir.ancestors().map(Path::to_path_buf));
+        Ok(())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.fault(true)?;
+        let state = &mut *state;
+        state.durable_entries.retain(|p, _| p.parent() != Some(dir));
+        let entries = state
+            .entries
+            .iter()
+            .filter(|(p, _)| p.parent() == Some(dir));
+        state
+            .durable_entries
+            .extend(entries.map(|(p, inode)| (p.clone(), *inode)));
+        Ok(())
+    }
+}
+
+// A file being written to a `SimStorage`.
+struct SimFile {
+    state: Arc<Mutex<SimState>>,
+    inode: usize,
+}
+
+impl SimFile {
+    fn state(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Write for SimFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        state.fault(true)?;
+        let clock = state.clock;
+        let inode = &mut state.inodes[self.inode];
+        inode.data.extend_from_slice(buf);
+        inode.modified = clock;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for SimFile {
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.state();
+        state.fault(true)?;
+        let inode = &mut state.inodes[self.inode];
+        inode.durable_len = inode.data.len();
+        Ok(())
+    }
+}
+
+// A randomized workload run against a tree on a `SimStorage`. Runs with the same settings and seed
+// perform exactly the same operations and hit exactly the same faults.
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub seed: u64,
+    // number of operations to run.
+    pub steps: usize,
+    // number of distinct keys written to, fewer keys means more overwrites and deletes of live keys.
+    pub keys: u64,
+    // probability that a crash is scheduled within the next few storage operations at each step.
+    pub crash_rate: f64,
+    pub faults: FaultConfig,
+    pub options: Options,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        let options = Options {
+            memtable_limit: 4,
+            compaction_trigger: 4,
+            block_size: 64,
+            // most values the workload writes are long enough to go to blob files.
+            blob_threshold: Some(3),
+            bottommost_compression: Some(Compression::Lz4),
+            // sstables that outlive a few dozen steps are moved to the cold tier. Its storage is
+            // replaced by the simulated one when the tree is opened.
+            cold_tier: Some(ColdTierOptions::new("cold", Duration::from_secs(30))),
+            ..Options::default()
+        };
+        Simulation {
+            seed,
+            steps: 200,
+            keys: 20,
+            crash_rate: 0.05,
+            faults: FaultConfig {
+                write_error_rate: 0.01,
+                torn_writes: true,
+                strict_directory_sync: true,
+            },
+            options,
+        }
+    }
+
+    // runs the workload, returning the number of crashes recovered from, or a description of the
+    // first recovery that lost or resurrected data.
+    pub fn run(&self) -> Result<usize, String> {
+        silence_fault_panics();
+
+        let storage = SimStorage::new(self.seed, self.faults.clone());
+        let mut rng = SimRng::new(self.seed);
+        let data_dir = Path::new("data");
+        // opening may fail on a fault too, after which we crash and try again.
+        let open = || loop {
+            let mut options = Options {
+                storage: Arc::new(storage.clone()),
+                ..self.options.clone()
+            };
+            if let Some(tier) = &mut options.cold_tier {
+                tier.storage = Arc::new(storage.clone());
+            }
+            match catch_unwind(AssertUnwindSafe(|| {
+                LSMTree::open_with_options(data_dir, options)
+            })) {
+                Ok(tree) => break tree,
+                Err(_) => storage.crash(),
+            }
+        };
+
+        let mut tree = open();
+        // the writes acknowledged by the tree, which must all survive a crash.
+        let mut durable: BTreeMap<String, String> = BTreeMap::new();
+        let mut crashes = 0;
+
+        for step in 0..self.steps {
+            // every step takes a second.
+            storage.advance_clock(Duration::from_secs(1));
+            if rng.chance(self.crash_rate) {
+                storage.crash_after(rng.below(20));
+            }
+
+            let key = format!("key{:03}", rng.below(self.keys));
+            let mut expected = durable.clone();
+            // whether the operation failed part way, after which its write may or may not have
+            // been persisted.
+            let mut failed = false;
+            let restart = match rng.below(20) {
+                0..=11 => {
+                    let value = format!("v{}", step);
+                    expected.insert(key.clone(), value.clone());
+                    // some values are streamed into blob files of their own.
+                    let put = || match step % 5 {
+                        0 => tree.put_reader(&key, value.as_bytes()).unwrap(),
+                        _ => tree.put(&key, &value).unwrap(),
+                    };
+                    failed = catch_unwind(AssertUnwindSafe(put)).is_err();
+                    failed
+                }
+                12..=15 => {
+                    expected.remove(&key);
+                    failed = catch_unwind(AssertUnwindSafe(|| tree.delete(&key))).is_err();
+                    failed
+                }
+                16 => {
+                    failed = catch_unwind(AssertUnwindSafe(|| tree.flush_memtable())).is_err();
+                    failed
+                }
+                17 => {
+                    let collect = || tree.collect_blob_garbage(0.5);
+                    failed = catch_unwind(AssertUnwindSafe(collect)).is_err();
+                    failed
+                }
+                // a clean restart.
+                18 => true,
+                _ => {
+                    storage.crash_after(1);
+                    true
+                }
+            };
+
+            if !restart {
+                // the write is in the log, or an sstable, by the time it returns.
+                durable = expected;
+                continue;
+            }
+
+            crashes += 1;
+            drop(tree);
+            storage.crash();
+            tree = open();
+
+            let recovered: BTreeMap<String, String> = tree.scan(..).collect();
+            if failed && recovered == expected {
+                // the failed operation got its write persisted before going down.
+                durable = expected;
+            } else if recovered != durable {
+                return Err(format!(
+                    "seed {} step {}: recovered {:?}, expected {:?}",
+                    self.seed, step, recovered, durable
+                ));
+            }
+            // point lookups must agree with the scan, deleted keys included.
+            for i in 0..self.keys {
+                let key = format!("key{:03}", i);
+                if tree.get(&key).as_ref() != durable.get(&key) {
+                    return Err(format!(
+                        "seed {} step {}: get({}) returned {:?}, expected {:?}",
+                        self.seed,
+                        step,
+                        key,
+                        tree.get(&key),
+                        durable.get(&key)
+                    ));
+                }
+            }
+        }
+
+        Ok(crashes)
+    }
+}
+
+// keeps the panics of simulated faults from flooding the test output, other panics are still shown.
+pub(crate) fn silence_fault_panics() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let message = info
+                .payload()
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or_else(|| info.payload().downcast_ref::<&str>().copied());
+            if !message.is_some_and(|m| m.contains(FAULT_MESSAGE)) {
+                default_hook(info);
+            }
+        }));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        path::Path,
+    };
+
+    use super::{FaultConfig, SimStorage, Simulation};
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_sim_storage_crash_loses_unsynced_data() {
+        let storage = SimStorage::new(1, FaultConfig::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+
+        let mut file = storage.create(&dir.join("a")).unwrap();
+        file.write_all(b"synced").unwrap();
+        file.sync().unwrap();
+        file.write_all(b" lost").unwrap();
+
+        storage.crash_after(1);
+        assert!(storage.create(&dir.join("b")).is_err());
+        // every operation fails until the crash is over.
+        assert!(storage.remove(&dir.join("a")).is_err());
+        storage.crash();
+
+        let mut data = String::new();
+        let mut file = storage.open(&dir.join("a")).unwrap();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "synced");
+        assert_eq!(storage.list(dir).unwrap(), vec![dir.join("a")]);
+    }
+
+    #[test]
+    fn test_sim_storage_strict_directory_sync() {
+        let faults = FaultConfig {
+            strict_directory_sync: true,
+            ..FaultConfig::default()
+        };
+        let storage = SimStorage::new(1, faults);
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+
+        storage.create(&dir.join("a")).unwrap().sync().unwrap();
+        storage.sync_dir(dir).unwrap();
+        storage.create(&dir.join("b")).unwrap().sync().unwrap();
+        storage.crash();
+        assert_eq!(storage.list(dir).unwrap(), vec![dir.join("a")]);
+    }
+
+    #[test]
+    fn test_simulation_recovers_persisted_data() {
+        let mut crashes = 0;
+        for seed in 0..20 {
+            crashes += Simulation::new(seed).run().unwrap();
+        }
+        assert!(crashes > 20);
+    }
+}
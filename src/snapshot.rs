@@ -0,0 +1,290 @@
+//! Snapshots: the state of a tree as of a sequence number, read from while the tree moves on.
+//!
+//! The memtable keeps only the newest version of a key and compactions only the newest one of each
+//! key across their inputs, so the versions a snapshot sees can't be kept within the tree. Instead a
+//! snapshot keeps what it reads from: a copy of the memtable, and the list of sstables and blob
+//! files as of when it was taken. Compactions go on as usual, but the files they're done with stay
+//! on disk for as long as a snapshot holds them, like they do for a running scan:
+//!
+//!   snapshot at seq 40:  memtable copy + [3.sst 4.sst 5.sst]
+//!   compaction:          [3.sst 4.sst 5.sst] -> 6.sst, the inputs stay until the snapshot's gone
+//!
+//! Every snapshot is registered with the tree, which reports how many there are and how much space
+//! they hold on to, see `LSMTree::stats`, and the oldest sequence number one still reads at, see
+//! `LSMTree::oldest_snapshot_seq`. Compactions don't need either: what a snapshot reads is in the
+//! files it holds, whatever the compactions write, so they're for spotting snapshots that linger.
+//! A snapshot that's forgotten would hold on to its files forever, so with
+//! `Options::max_snapshot_age` set, the tree releases the files of snapshots older than that
+//! whenever it flushes or takes a new snapshot, and reads from them fail with
+//! `Error::SnapshotExpired` rather than returning data that isn't there anymore.
+//! 💡 RocksDB snapshots are sequence numbers instead, and compactions keep the newest version of a
+//! key below every live snapshot's, so a snapshot holds on to versions rather than whole files.
+
+use std::{
+    collections::VecDeque,
+    ops::RangeBounds,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::SystemTime,
+};
+
+use crate::{
+    Entries, Error, KeyRange, LSMTree, Lookup, Record, ScanIter, Value,
+    blob::BlobFile,
+    find_blob_file,
+    sstable::{SSTable, SSTableEntries},
+};
+
+// The live snapshots of a tree, dropped ones are pruned as they're found.
+pub(crate) type SnapshotRegistry = Arc<Mutex<Vec<Weak<SnapshotState>>>>;
+
+// A snapshot, shared with the tree's registry so that the tree can release it once it expires.
+pub(crate) struct SnapshotState {
+    seq: u64,
+    created: SystemTime,
+    // `None` once released.
+    pins: RwLock<Option<Pins>>,
+}
+
+// What a snapshot reads from.
+struct Pins {
+    // the memtable's entries, sorted by key.
+    memtable: Vec<(String, Record)>,
+    sstables: Arc<VecDeque<Arc<SSTable>>>,
+    blob_files: Vec<Arc<BlobFile>>,
+}
+
+// The state of a tree as of the sequence number it was taken at, see `snapshot.rs`.
+pub struct Snapshot(Arc<SnapshotState>);
+
+impl Snapshot {
+    // the sequence number of the newest write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.0.seq
+    }
+}
+
+impl LSMTree {
+    // takes a snapshot of the tree as of now.
+    pub fn snapshot(&self) -> Snapshot {
+        self.release_expired_snapshots();
+        let mgr = &self.sstable_mgr;
+        let state = Arc::new(SnapshotState {
+            seq: self.last_seq,
+            created: mgr.clock.now(),
+            pins: RwLock::new(Some(Pins {
+                memtable: self.memtable.range(&KeyRange::all()),
+                sstables: Arc::clone(&mgr.sstables),
+                blob_files: mgr.blob_files.clone(),
+            })),
+        });
+        (self.snapshots.lock().unwrap()).push(Arc::downgrade(&state));
+        Snapshot(state)
+    }
+
+    // returns the value associated with `k` as of `snapshot`.
+    pub fn get_at(&self, snapshot: &Snapshot, k: &str) -> Result<Option<String>, Error> {
+        let pins = snapshot.0.pins.read().unwrap();
+        let pins = pins.as_ref().ok_or(Error::SnapshotExpired)?;
+        let memtable = (pins.memtable)
+            .binary_search_by(|(key, _)| key.as_str().cmp(k))
+            .map(|i| &pins.memtable[i].1);
+        let lookup = match memtable {
+            Ok(record) => Lookup::from(Some(record)),
+            Err(_) => (pins.sstables.iter().rev())
+                .map(|sst| self.sstable_mgr.get_sstable(sst, k))
+                .find(|l| *l != Lookup::NotFound)
+                .unwrap_or(Lookup::NotFound),
+        };
+        match lookup {
+            Lookup::Found(Value::Inline(v)) => Ok(Some(v)),
+            Lookup::Found(Value::Blob(pointer)) => {
+                let blob_file = find_blob_file(&pins.blob_files, &pointer);
+                Ok(Some(blob_file.read(&pointer).unwrap()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // returns an iterator over the live key value pairs within `range` as of `snapshot`.
+    pub fn scan_at<'a>(
+        &self,
+        snapshot: &Snapshot,
+        range: impl RangeBounds<&'a str>,
+    ) -> Result<ScanIter, Error> {
+        let pins = snapshot.0.pins.read().unwrap();
+        let pins = pins.as_ref().ok_or(Error::SnapshotExpired)?;
+        let range = KeyRange::from(range);
+        let memtable: Vec<(String, Record)> = (pins.memtable.iter())
+            .filter(|(k, _)| range.contains(k))
+            .cloned()
+            .collect();
+        let mut sources = vec![(Box::new(memtable.into_iter()) as Entries).peekable()];
+        for sst in pins.sstables.iter().rev() {
+            let cache = self.sstable_mgr.table_cache(sst);
+            let readahead = self.sstable_mgr.readahead_size;
+            let entries = SSTableEntries::open(sst, &range, cache, readahead);
+            sources.push((Box::new(entries) as Entries).peekable());
+        }
+        Ok(ScanIter {
+            sources,
+            range,
+            _pinned: Arc::clone(&pins.sstables),
+            blob_files: pins.blob_files.clone(),
+            key_filters: Vec::new(),
+            value_filters: Vec::new(),
+        })
+    }
+
+    // the oldest sequence number a live snapshot reads at, `None` without live snapshots. Only
+    // reported, for telling how far behind the oldest snapshot is: compactions don't read it, as
+    // snapshots hold on to files rather than to versions within them.
+    pub fn oldest_snapshot_seq(&self) -> Option<u64> {
+        self.live_snapshots().iter().map(|s| s.seq).min()
+    }
+
+    // releases the files held by the snapshots older than `Options::max_snapshot_age`, returning
+    // how many were released. Done on every flush and every new snapshot.
+    pub fn release_expired_snapshots(&self) -> usize {
+        let Some(max_age) = self.options.max_snapshot_age else {
+            return 0;
+        };
+        let now = self.sstable_mgr.clock.now();
+        let mut released = 0;
+        for snapshot in self.live_snapshots() {
+            let age = now.duration_since(snapshot.created).unwrap_or_default();
+            if age > max_age {
+                *snapshot.pins.write().unwrap() = None;
+                released += 1;
+            }
+        }
+        released
+    }
+
+    // the snapshots that are neither dropped nor released.
+    fn live_snapshots(&self) -> Vec<Arc<SnapshotState>> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.retain(|s| {
+            s.upgrade()
+                .is_some_and(|s| s.pins.read().unwrap().is_some())
+        });
+        snapshots.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    // the number of live snapshots, and the bytes of the sstables they hold that the tree itself
+    // is done with.
+    pub(crate) fn snapshot_stats(&self) -> (usize, u64) {
+        let snapshots = self.live_snapshots();
+        let live: Vec<usize> = self.sstable_mgr.sstables.iter().map(|s| s.id).collect();
+        let mut pinned: Vec<Arc<SSTable>> = Vec::new();
+        for snapshot in &snapshots {
+            let pins = snapshot.pins.read().unwrap();
+            for sst in pins.iter().flat_map(|p| p.sstables.iter()) {
+                if !live.contains(&sst.id) && !pinned.iter().any(|p| p.id == sst.id) {
+                    pinned.push(Arc::clone(sst));
+                }
+            }
+        }
+        let bytes = pinned.iter().map(|s| s.storage.len(&s.path).unwrap_or(0));
+        (snapshots.len(), bytes.sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+
+    use crate::{Error, LSMTree, MockClock, Options, sim::SimStorage, storage::Storage};
+
+    fn open(storage: &SimStorage, clock: &MockClock, max_age: Option<Duration>) -> LSMTree {
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            clock: Some(Arc::new(clock.clone())),
+            memtable_limit: 10,
+            compaction_trigger: 2,
+            max_snapshot_age: max_age,
+            ..Options::default()
+        };
+        LSMTree::open_with_options("data", options)
+    }
+
+    #[test]
+    fn test_snapshot_reads_survive_overwrites_and_compaction() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut lsmtree = open(&storage, &MockClock::default(), None);
+        for i in 0..25 {
+            lsmtree.put(&format!("key{:02}", i), "v1").unwrap();
+        }
+        let snapshot = lsmtree.snapshot();
+        assert_eq!(snapshot.seq(), 25);
+        assert_eq!(lsmtree.oldest_snapshot_seq(), Some(25));
+        let pinned: Vec<PathBuf> = (lsmtree.sstable_mgr.sstables.iter())
+            .map(|sst| sst.path.clone())
+            .collect();
+
+        for i in 0..25 {
+            lsmtree.put(&format!("key{:02}", i), "v2").unwrap();
+        }
+        lsmtree.delete("key03");
+        lsmtree.compact_now();
+        assert_eq!(lsmtree.get("key03"), None);
+        assert_eq!(
+            lsmtree.get_at(&snapshot, "key03"),
+            Ok(Some("v1".to_string()))
+        );
+        assert_eq!(
+            lsmtree.get_at(&snapshot, "key22"),
+            Ok(Some("v1".to_string()))
+        );
+        assert_eq!(lsmtree.get_at(&snapshot, "key25"), Ok(None));
+        let scanned: Vec<(String, String)> =
+            lsmtree.scan_at(&snapshot, "key20"..).unwrap().collect();
+        assert_eq!(scanned.len(), 5);
+        assert!(scanned.iter().all(|(_, v)| v == "v1"));
+
+        // the sstables the snapshot reads from outlive the compaction that replaced them.
+        assert!(pinned.iter().all(|path| storage.exists(path)));
+        let stats = lsmtree.stats();
+        assert_eq!(stats.snapshots, 1);
+        assert!(stats.pinned_bytes > 0);
+
+        drop(snapshot);
+        assert!(pinned.iter().all(|path| !storage.exists(path)));
+        assert_eq!(lsmtree.oldest_snapshot_seq(), None);
+        assert_eq!(lsmtree.stats().pinned_bytes, 0);
+    }
+
+    #[test]
+    fn test_expired_snapshot_releases_its_files() {
+        let storage = SimStorage::new(1, Default::default());
+        let clock = MockClock::default();
+        let mut lsmtree = open(&storage, &clock, Some(Duration::from_secs(60)));
+        for i in 0..10 {
+            lsmtree.put(&format!("key{:02}", i), "v1").unwrap();
+        }
+        let old = lsmtree.snapshot();
+        clock.advance(Duration::from_secs(30));
+        let young = lsmtree.snapshot();
+        let pinned = lsmtree.sstable_mgr.sstables[0].path.clone();
+        for i in 0..80 {
+            lsmtree.put(&format!("key{:02}", i % 10), "v2").unwrap();
+        }
+        assert!(storage.exists(&pinned));
+        assert_eq!(lsmtree.oldest_snapshot_seq(), Some(10));
+
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(lsmtree.release_expired_snapshots(), 1);
+        assert_eq!(lsmtree.get_at(&old, "key01"), Err(Error::SnapshotExpired));
+        assert!(lsmtree.scan_at(&old, ..).is_err());
+        assert_eq!(lsmtree.get_at(&young, "key01"), Ok(Some("v1".to_string())));
+        assert_eq!(lsmtree.stats().snapshots, 1);
+
+        // the next flush releases the other one, and with it the last pin of the file.
+        clock.advance(Duration::from_secs(30));
+        for i in 0..10 {
+            lsmtree.put(&format!("key{:02}", i), "v3").unwrap();
+        }
+        assert_eq!(lsmtree.get_at(&young, "key01"), Err(Error::SnapshotExpired));
+        assert_eq!(lsmtree.oldest_snapshot_seq(), None);
+        assert!(!storage.exists(&pinned));
+    }
+}
@@ -0,0 +1,1490 @@
+//! SSTable files: immutable, sorted runs of key value entries flushed from the memtable.
+//!
+//! An sstable is a sequence of data blocks (see `block.rs`), followed by a filter block, an index
+//! block and a fixed size footer:
+//!
+//!   | data block 0 | data block 1 | ... | filter block | index block | footer |
+//!
+//! Each data block entry maps a key to its record, encoded by a record codec (see `codec.rs`) as
+//! `| seq: varint | kind: u8 | payload |`, where the payload is the value bytes, a pointer to the
+//! value in a blob file (see `blob.rs`), or nothing for a deletion. Files written before records
+//! had a kind are told apart by the magic number in their footer. Their entries are
+//! `| seq: varint | value bytes |`, with the `🪦` sentinel as the value of a deletion.
+//!
+//! The filter block holds a bloom filter (see `bloom.rs`) over all keys in the file. If a prefix
+//! extractor is configured, the prefixes of the keys are added to the same filter and the
+//! extractor's name is stored in front of it, so that prefix scans can tell whether the filter
+//! was built with the prefixes they are looking for:
+//!
+//!   filter block: | extractor name: length prefixed | filter bytes |
+//!
+//! The index block is itself a block, with one entry per data block: the last key in that data
+//! block, mapped to the block's offset and size in the file as varints, followed by a bloom filter
+//! over the keys of just that data block and the codec the data block is compressed with (see
+//! `compression.rs`), which is missing in files written before blocks were compressed. The footer
+//! points at the filter and index blocks, records the highest sequence number in the file and the
+//! table properties, i.e. the number of entries and how many of them are tombstones, along with the
+//! creation time and the size of the data blocks before compression, and ends with a magic number
+//! so that we can tell block based sstables apart from the older line based `key:value` text files,
+//! which are still readable. Lookups in those go through a sidecar index that's built on first use,
+//! see `sidecar.rs`.
+//!
+//!   footer: | filter offset: u64 | filter size: u64 | index offset: u64 | index size: u64 |
+//!           | max seq: u64 | entries: u64 | deletions: u64 | created: u64 |
+//!           | dictionary offset: u64 | dictionary size: u64 | raw data size: u64 |
+//!           | record codec: u64 | magic: u64 |
+//!
+//! Files written with a compression dictionary store it in a block of its own between the data
+//! blocks and the filter block, and every compressed data block of the file is compressed with it,
+//! see `compression.rs`. The dictionary is trained on the first data blocks of the file, so the
+//! writer holds those back until it has enough of them, then writes them all compressed with the
+//! dictionary. The dictionary size in the footer is 0 in files without one.
+//!
+//! `created` is when the newest record in the file was written, in milliseconds since the Unix
+//! epoch, which lets data past its retention period be dropped a whole file at a time. The raw data
+//! size, compared to the size of the data blocks in the index, tells how well the file compressed,
+//! see `LSMTree::sstables`. The record codec is the id of the codec the records in data blocks are
+//! encoded with, see `codec.rs`. Files written before the footer held the record codec, the raw
+//! data size, the dictionary, the creation time or the table properties have a shorter footer
+//! without them, and a different magic number.
+//!
+//! Every block is followed by a CRC32 checksum of its contents (see `checksum.rs`), which isn't
+//! included in the block sizes recorded in the index and footer.
+//!
+//! To look up a key, we first check the whole file filter, so that most files without the key are
+//! skipped without reading any data block. Otherwise we binary search the index for the first data
+//! block whose last key is >= the key, check that block's filter and only then read the one block
+//! from disk.
+//!
+//! Scans and compactions on the other hand read the data blocks one after the other, so they read
+//! ahead: a read of one block fetches the blocks following it too, up to `Options::readahead_size`
+//! bytes, and the next blocks are served from that buffer.
+//! 💡 RocksDB starts with a small read ahead and doubles it as long as reads stay sequential, and
+//! can also prefetch asynchronously while the current block is consumed.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Lines, Seek, SeekFrom, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    KeyRange, PrefixExtractor, Record,
+    block::{Block, BlockBuilder},
+    bloom::{self, FilterBuilder},
+    cache::BlockCache,
+    checksum::crc32,
+    codec::{self, BinaryCodec, KindlessCodec, RecordCodec},
+    compression::Compression,
+    sidecar::{TextIndex, sidecar_path},
+    storage::{ReadableFile, Storage},
+    varint::{Decoder, put_length_prefixed, put_varint},
+};
+
+const FOOTER_SIZE: usize = 104;
+// size of the footer of files written before it held the record codec.
+const FOOTER_SIZE_WITHOUT_CODEC: usize = 96;
+// size of the footer of files written before it held the raw data size.
+const FOOTER_SIZE_WITHOUT_RAW_SIZE: usize = 88;
+// size of the footer of files written before it held the compression dictionary.
+const FOOTER_SIZE_WITHOUT_DICTIONARY: usize = 72;
+// size of the footer of files written before it held the creation time.
+const FOOTER_SIZE_WITHOUT_CREATION_TIME: usize = 64;
+// size of the footer of files written before it held the table properties.
+const FOOTER_SIZE_WITHOUT_PROPERTIES: usize = 48;
+const CHECKSUM_SIZE: u64 = 4;
+// buffer size of text sstable reads without read ahead, the same as `BufReader::new`.
+const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+const MAGIC: u64 = 0x7373_7462_6c6f_6b37;
+// magic number of block based sstables whose footer doesn't have the record codec.
+const MAGIC_WITHOUT_CODEC: u64 = 0x7373_7462_6c6f_6b36;
+// magic number of block based sstables whose footer doesn't have the raw data size.
+const MAGIC_WITHOUT_RAW_SIZE: u64 = 0x7373_7462_6c6f_6b35;
+// magic number of block based sstables whose footer doesn't have the compression dictionary.
+const MAGIC_WITHOUT_DICTIONARY: u64 = 0x7373_7462_6c6f_6b34;
+// magic number of block based sstables whose footer doesn't have the creation time.
+const MAGIC_WITHOUT_CREATION_TIME: u64 = 0x7373_7462_6c6f_6b33;
+// magic number of block based sstables whose footer doesn't have the table properties.
+const MAGIC_WITHOUT_PROPERTIES: u64 = 0x7373_7462_6c6f_6b32;
+// magic number of block based sstables whose entries don't have a kind byte.
+const MAGIC_WITHOUT_KINDS: u64 = 0x7373_7462_6c6f_636b;
+
+// versions of the on-disk sstable format, the one written being `FORMAT_VERSION`:
+// 1: line based `key:value` text files.
+// 2: block based files whose entries have no kind byte, with `MAGIC_WITHOUT_KINDS`.
+// 3: block based files whose entries have a kind byte, with `MAGIC_WITHOUT_PROPERTIES`.
+// 4: block based files with the table properties in their footer, with
+//    `MAGIC_WITHOUT_CREATION_TIME`.
+// 5: block based files with the creation time in their footer too, with `MAGIC_WITHOUT_DICTIONARY`.
+// 6: block based files with the compression dictionary in their footer too, with
+//    `MAGIC_WITHOUT_RAW_SIZE`.
+// 7: block based files with the raw data size in their footer too, with `MAGIC_WITHOUT_CODEC`.
+// 8: block based files with the record codec in their footer too, with `MAGIC`.
+pub(crate) const FORMAT_TEXT: u32 = 1;
+const FORMAT_WITHOUT_KINDS: u32 = 2;
+const FORMAT_WITHOUT_PROPERTIES: u32 = 3;
+const FORMAT_WITHOUT_CREATION_TIME: u32 = 4;
+const FORMAT_WITHOUT_DICTIONARY: u32 = 5;
+const FORMAT_WITHOUT_RAW_SIZE: u32 = 6;
+const FORMAT_WITHOUT_CODEC: u32 = 7;
+pub(crate) const FORMAT_VERSION: u32 = 8;
+
+// bytes of data blocks the writer holds back to train a compression dictionary on, as a multiple
+// of the dictionary size.
+const DICTIONARY_SAMPLE_FACTOR: usize = 8;
+
+// the next id to cache the blocks of an sstable under, see `cache.rs`.
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(1);
+
+// An sstable file tracked by the SSTableManager.
+// It's reference counted: the manager holds one reference and every live iterator reading from the
+// file holds another. When compaction is done with a file, it's only marked obsolete and the file is
+// physically removed when the last reference goes away.
+// 💡 LevelDB and RocksDB do the same by ref counting "versions" (set of live files) and file metadata.
+pub(crate) struct SSTable {
+    pub id: usize,
+    pub path: PathBuf,
+    // the storage of the tier the file is on.
+    pub storage: Arc<dyn Storage>,
+    // whether the file was moved to the cold tier.
+    pub cold: bool,
+    // the blocks of the file are cached under this id, unique within the process.
+    pub cache_id: u64,
+    // set by compaction once the file is no longer part of the tree.
+    obsolete: AtomicBool,
+    // estimated number of entries in the file shadowed by newer versions of their keys in newer
+    // files, see `SSTableManager::count_shadowed`.
+    shadowed: AtomicU64,
+    // the index, filters and footer of the file, read the first time they're needed. `None` for
+    // text sstables.
+    meta: OnceLock<Option<Arc<TableMeta>>>,
+    // the index of a text sstable, loaded from its sidecar on first use, see `sidecar.rs`.
+    text_index: OnceLock<TextIndex>,
+    // bytes of data blocks read from the file, shared with the open tables reading them.
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl SSTable {
+    pub fn new(storage: &Arc<dyn Storage>, data_dir: &Path, id: usize) -> Self {
+        SSTable::at(storage, data_dir.join(format!("{}.sst", id)), id)
+    }
+
+    // the sstable file at `path`, whichever name it has.
+    pub fn at(storage: &Arc<dyn Storage>, path: PathBuf, id: usize) -> Self {
+        SSTable {
+            id,
+            path,
+            storage: Arc::clone(storage),
+            cold: false,
+            cache_id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            obsolete: AtomicBool::new(false),
+            shadowed: AtomicU64::new(0),
+            meta: OnceLock::new(),
+            text_index: OnceLock::new(),
+            bytes_read: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // an sstable that was moved to the cold tier, in `dir` on `storage`.
+    pub fn new_cold(storage: &Arc<dyn Storage>, dir: &Path, id: usize) -> Self {
+        let mut sst = SSTable::new(storage, dir, id);
+        sst.cold = true;
+        sst
+    }
+
+    // the size of the file, or 0 if it can't be told.
+    pub fn size(&self) -> u64 {
+        self.storage.len(&self.path).unwrap_or(0)
+    }
+
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    // returns the metadata of the file, reading it on first use.
+    fn meta(&self) -> Option<Arc<TableMeta>> {
+        let read = || {
+            let mut file = self.storage.open(&self.path).unwrap();
+            TableMeta::read(&mut *file).map(Arc::new)
+        };
+        self.meta.get_or_init(read).clone()
+    }
+
+    // returns the estimated number of entries in the file that newer files shadow.
+    pub fn shadowed(&self) -> u64 {
+        self.shadowed.load(Ordering::Relaxed)
+    }
+
+    pub fn set_shadowed(&self, shadowed: u64) {
+        self.shadowed.store(shadowed, Ordering::Relaxed);
+    }
+
+    // counts one more of the file's entries as shadowed.
+    pub fn add_shadowed(&self) {
+        self.shadowed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // whether the metadata of the file was read already.
+    pub fn is_loaded(&self) -> bool {
+        self.meta.get().is_some()
+    }
+
+    // reads the metadata of the file ahead of its first use.
+    pub fn load(&self) {
+        self.meta();
+    }
+
+    // opens the file for reading, handing it back as an error if it isn't a block based sstable.
+    fn open_table(&self) -> Result<BlockTable, Box<dyn ReadableFile>> {
+        let meta = self.meta();
+        let file = self.storage.open(&self.path).unwrap();
+        match meta {
+            Some(meta) => {
+                let mut table = BlockTable::new(file, meta);
+                table.bytes_read = Some(Arc::clone(&self.bytes_read));
+                Ok(table)
+            }
+            None => Err(file),
+        }
+    }
+
+    // the version of the format the file was written in.
+    pub fn format_version(&self) -> u32 {
+        match self.meta() {
+            None => FORMAT_TEXT,
+            Some(meta) if meta.without_kinds => FORMAT_WITHOUT_KINDS,
+            Some(meta) if meta.properties.is_none() => FORMAT_WITHOUT_PROPERTIES,
+            Some(meta) if meta.created.is_none() => FORMAT_WITHOUT_CREATION_TIME,
+            Some(meta) if !meta.has_dictionary_field => FORMAT_WITHOUT_DICTIONARY,
+            Some(meta) if meta.raw_data_size.is_none() => FORMAT_WITHOUT_RAW_SIZE,
+            Some(meta) if !meta.has_codec_field => FORMAT_WITHOUT_CODEC,
+            Some(_) => FORMAT_VERSION,
+        }
+    }
+
+    // returns the entry counts of the file, unknown for files written before they were recorded.
+    pub fn properties(&self) -> Option<TableProperties> {
+        self.meta()?.properties
+    }
+
+    // returns the size of the data blocks as they're stored, and before they were compressed, the
+    // latter unknown for files written before it was recorded. `None` for text sstables.
+    pub fn data_sizes(&self) -> Option<(u64, Option<u64>)> {
+        let meta = self.meta()?;
+        let stored = (meta.index.iter())
+            .map(|(_, handle)| BlockHandle::decode(&handle).size)
+            .sum();
+        Some((stored, meta.raw_data_size))
+    }
+
+    // the bytes of data blocks read from disk since the file was opened, not counting the ones the
+    // block cache served. Read ahead counts all it reads, whether it's used or not.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    // returns when the newest record in the file was written, as recorded in the file when it was
+    // written: the time of the flush that wrote it, carried over to compaction outputs. Files
+    // written before it was recorded go by the time they were last modified instead.
+    pub fn created(&self) -> SystemTime {
+        self.try_created().unwrap()
+    }
+
+    // like `created`, but returns the error if the modification time of a file that doesn't record
+    // its creation time can't be read.
+    pub fn try_created(&self) -> io::Result<SystemTime> {
+        match self.meta().and_then(|meta| meta.created) {
+            Some(created) => Ok(created),
+            None => self.storage.modified(&self.path),
+        }
+    }
+
+    // returns the highest sequence number of the records in the file.
+    // Text sstables predate sequence numbers, so all their records count as sequence number 0.
+    pub fn max_seq(&self) -> u64 {
+        self.meta().map_or(0, |meta| meta.max_seq)
+    }
+
+    // returns the smallest and the largest key in the file, deleted ones included, or `None` if it
+    // has none. Block based files have the largest one in their index, text files are read through.
+    pub fn fences(&self) -> Option<(String, String)> {
+        let Ok(mut table) = self.open_table() else {
+            let mut entries = SSTableEntries::open(self, &KeyRange::all(), None, 0);
+            let (first, _) = entries.next()?;
+            let last = entries.last().map_or_else(|| first.clone(), |(k, _)| k);
+            return Some((first, last));
+        };
+        let (last, _) = table.meta.index.iter().last()?;
+        let handle = table.block_handles(&[]).pop_front()?;
+        let (first, _) = table.read_block(&handle).iter().next()?;
+        Some((
+            String::from_utf8(first).unwrap(),
+            String::from_utf8(last).unwrap(),
+        ))
+    }
+
+    // estimates the number of live entries within `range`, going by the index alone: the share of
+    // the file's data blocks that overlap the range, times the entries that aren't tombstones.
+    // Files without entry counts, and text files, have theirs counted exactly instead.
+    pub fn approximate_count(&self, range: &KeyRange) -> u64 {
+        let meta = self.meta();
+        let Some((meta, properties)) = meta.as_ref().and_then(|m| Some((m, m.properties?))) else {
+            let entries = SSTableEntries::open(self, range, None, 0);
+            return entries.filter(|(_, record)| record.value.is_some()).count() as u64;
+        };
+
+        // a block holds the keys after the last key of the previous block, up to its own last key.
+        let (mut blocks, mut overlapping) = (0, 0);
+        let mut previous: Option<String> = None;
+        for (last, _) in meta.index.iter() {
+            let last = String::from_utf8(last).unwrap();
+            blocks += 1;
+            if !range.is_before_start(&last) && previous.is_none_or(|p| !range.is_past_end(&p)) {
+                overlapping += 1;
+            }
+            previous = Some(last);
+        }
+        if blocks == 0 {
+            return 0;
+        }
+        (properties.entries - properties.deletions) * overlapping / blocks
+    }
+
+    // returns the last key of every data block that falls within `range`, in order, read from the
+    // index alone. Text sstables have no index, and so no keys to give.
+    pub fn index_keys(&self, range: &KeyRange) -> Vec<String> {
+        let Some(meta) = self.meta() else {
+            return Vec::new();
+        };
+        meta.index
+            .iter()
+            .map(|(last, _)| String::from_utf8(last).unwrap())
+            .skip_while(|k| range.is_before_start(k))
+            .take_while(|k| !range.is_past_end(k))
+            .collect()
+    }
+
+    // returns false if the file definitely doesn't have `key`, going by its filters alone, without
+    // reading any data block. Text sstables have no filters, so they may have any key.
+    pub fn may_contain(&self, key: &str) -> bool {
+        let Some(meta) = self.meta() else {
+            return true;
+        };
+        if !bloom::may_contain(&meta.filter, key.as_bytes()) {
+            return false;
+        }
+        let handle = meta.index.seek(key.as_bytes()).next();
+        handle.is_some_and(|(_, handle)| {
+            bloom::may_contain(&BlockHandle::decode(&handle).filter, key.as_bytes())
+        })
+    }
+
+    // returns false if the file definitely has no keys starting with `prefix`.
+    // That's only known if `prefix` is exactly what `extractor` extracts from such keys, and the
+    // file's filter was built with the same extractor.
+    pub fn may_contain_prefix(&self, extractor: &PrefixExtractor, prefix: &str) -> bool {
+        if extractor.extract(prefix) != Some(prefix) {
+            return true;
+        }
+        match self.meta() {
+            Some(meta) if meta.filter_prefix_extractor == extractor.name() => {
+                bloom::may_contain(&meta.filter, prefix.as_bytes())
+            }
+            _ => true,
+        }
+    }
+
+    // reads the data blocks that `wanted` picks into `cache`, returning how many were picked.
+    // `wanted` is given the last key of the previous block, the last key of the block itself and
+    // the block's offset. Text sstables have no blocks to cache.
+    pub fn warm(
+        &self,
+        cache: TableCache,
+        mut wanted: impl FnMut(Option<&str>, &str, u64) -> bool,
+    ) -> usize {
+        let Ok(mut table) = self.open_table() else {
+            return 0;
+        };
+        table.cache = Some(cache);
+
+        let mut read = 0;
+        let mut prev_last_key: Option<String> = None;
+        let index: Vec<_> = table.meta.index.iter().collect();
+        for (last_key, handle) in index {
+            let last = String::from_utf8(last_key).unwrap();
+            let handle = BlockHandle::decode(&handle);
+            if wanted(prev_last_key.as_deref(), &last, handle.offset) {
+                table.read_block(&handle);
+                read += 1;
+            }
+            prev_last_key = Some(last);
+        }
+        read
+    }
+
+    // looks up the given `key`, reading data blocks through `cache` if set.
+    // Returns the record, whose value is `None` if the file contains a tombstone for the key.
+    pub fn get(&self, key: &str, cache: Option<TableCache>) -> Option<Record> {
+        let file = match self.open_table() {
+            Ok(mut table) => {
+                table.cache = cache;
+                return table.get(key);
+            }
+            Err(file) => file,
+        };
+
+        // a text sstable, whose sidecar points at the lines that may hold the key.
+        let index =
+            (self.text_index).get_or_init(|| TextIndex::load_or_build(&*self.storage, &self.path));
+        let mut file = file;
+        file.seek(SeekFrom::Start(index.find(key)?)).unwrap();
+        for l in BufReader::new(file).lines() {
+            let (k, record) = read_text_entry(&l);
+            if k == key {
+                return Some(record);
+            }
+            if k.as_str() > key {
+                break;
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            let _ = self.storage.remove(&self.path);
+            if self.text_index.get().is_some() {
+                let _ = self.storage.remove(&sidecar_path(&self.path));
+            }
+            // without it, a crash could bring the file back, for recovery to find it again.
+            if let Some(dir) = self.path.parent() {
+                let _ = self.storage.sync_dir(dir);
+            }
+        }
+    }
+}
+
+// The block cache to read an sstable's data blocks through, and the id they're cached under.
+#[derive(Clone)]
+pub(crate) struct TableCache {
+    pub cache: BlockCache,
+    pub cache_id: u64,
+}
+
+// Settings for how sstables are laid out on disk.
+#[derive(Debug, Clone)]
+pub(crate) struct TableOptions {
+    // target size in bytes of the data blocks within an sstable.
+    pub block_size: usize,
+    // number of keys between restart points in a data block, see `block.rs`.
+    pub block_restart_interval: usize,
+    // bits used per key in the bloom filters, 0 disables filters.
+    pub bloom_bits_per_key: usize,
+    // adds key prefixes to the whole file filter.
+    pub prefix_extractor: Option<PrefixExtractor>,
+    // codec the data blocks are compressed with.
+    pub compression: Compression,
+    // size in bytes of the compression dictionary trained for the file, 0 for none. Only used
+    // along with a codec.
+    pub dictionary_size: usize,
+    // codec the records in data blocks are encoded with, see `codec.rs`.
+    pub codec: &'static dyn RecordCodec,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        TableOptions {
+            block_size: 4096,
+            block_restart_interval: 16,
+            bloom_bits_per_key: 10,
+            prefix_extractor: None,
+            compression: Compression::None,
+            dictionary_size: 0,
+            codec: &BinaryCodec,
+        }
+    }
+}
+
+// Writes sorted entries into the block based sstable format.
+pub(crate) struct SSTableWriter<W: Write> {
+    out: W,
+    options: TableOptions,
+    // number of bytes written to `out` so far.
+    offset: u64,
+    block: BlockBuilder,
+    index: BlockBuilder,
+    // filters over the keys of the current data block and of the whole file.
+    block_filter: FilterBuilder,
+    file_filter: FilterBuilder,
+    max_seq: u64,
+    properties: TableProperties,
+    // bytes of the data blocks written so far, before compression.
+    raw_data_size: u64,
+    // recorded in the footer, see `SSTable::created`.
+    created: SystemTime,
+    // the data blocks held back to train the compression dictionary on. `None` once the
+    // dictionary is trained, or if the file gets none.
+    held_back: Option<Vec<HeldBackBlock>>,
+    held_back_size: usize,
+    dictionary: Vec<u8>,
+    // the encoding of the record being added, kept to reuse its allocation for the next one.
+    encoded: Vec<u8>,
+}
+
+impl<W: Write> SSTableWriter<W> {
+    pub fn new(out: W, options: &TableOptions) -> Self {
+        SSTableWriter {
+            out,
+            options: options.clone(),
+            offset: 0,
+            block: BlockBuilder::new(options.block_restart_interval),
+            // every index entry is a restart point, so that the index can be binary searched directly.
+            index: BlockBuilder::new(1),
+            block_filter: FilterBuilder::new(),
+            file_filter: FilterBuilder::new(),
+            max_seq: 0,
+            properties: TableProperties::default(),
+            raw_data_size: 0,
+            created: SystemTime::UNIX_EPOCH,
+            held_back: (options.dictionary_size > 0 && options.compression != Compression::None)
+                .then(Vec::new),
+            held_back_size: 0,
+            dictionary: Vec::new(),
+            encoded: Vec::new(),
+        }
+    }
+
+    // sets the creation time recorded in the file, see `SSTable::created`.
+    pub fn set_created(&mut self, created: SystemTime) {
+        self.created = created;
+    }
+
+    // adds a record for `key` to the sstable. Keys must be added in sorted order.
+    pub fn add(&mut self, key: &str, record: &Record) {
+        let mut encoded = std::mem::take(&mut self.encoded);
+        encoded.clear();
+        self.options.codec.encode(record, &mut encoded);
+        self.properties.entries += 1;
+        self.properties.deletions += record.value.is_none() as u64;
+        self.block.add(key.as_bytes(), &encoded);
+        self.encoded = encoded;
+        if self.options.bloom_bits_per_key > 0 {
+            self.block_filter.add_key(key.as_bytes());
+            self.file_filter.add_key(key.as_bytes());
+            let prefix = self
+                .options
+                .prefix_extractor
+                .as_ref()
+                .map(|e| e.extract(key));
+            if let Some(Some(prefix)) = prefix {
+                self.file_filter.add_key(prefix.as_bytes());
+            }
+        }
+
+        self.max_seq = self.max_seq.max(record.seq);
+        if self.block.estimated_size() >= self.options.block_size {
+            self.flush_block();
+        }
+    }
+
+    fn flush_block(&mut self) {
+        if self.block.is_empty() {
+            return;
+        }
+        let last_key = self.block.last_key().to_vec();
+        let block = self.block.finish();
+        let filter = self.filter(BlockFilter::Block);
+        if let Some(held_back) = &mut self.held_back {
+            self.held_back_size += block.len();
+            held_back.push(HeldBackBlock {
+                last_key,
+                block,
+                filter,
+            });
+            if self.held_back_size >= self.options.dictionary_size * DICTIONARY_SAMPLE_FACTOR {
+                self.train_dictionary();
+            }
+            return;
+        }
+        self.write_data_block(&last_key, &block, &filter);
+    }
+
+    // trains the compression dictionary on the data blocks held back so far, then writes them.
+    fn train_dictionary(&mut self) {
+        let Some(held_back) = self.held_back.take() else {
+            return;
+        };
+        let samples: Vec<&[u8]> = held_back.iter().map(|b| &b.block[..]).collect();
+        let size = self.options.dictionary_size;
+        self.dictionary = self.options.compression.train_dictionary(&samples, size);
+        for b in held_back {
+            self.write_data_block(&b.last_key, &b.block, &b.filter);
+        }
+    }
+
+    // compresses and writes a data block, and adds its index entry.
+    fn write_data_block(&mut self, last_key: &[u8], block: &[u8], filter: &[u8]) {
+        self.raw_data_size += block.len() as u64;
+        let (block, compression) = self.options.compression.compress(block, &self.dictionary);
+        let (offset, size) = self.write_block(&block);
+
+        let mut handle = Vec::new();
+        put_varint(&mut handle, offset);
+        put_varint(&mut handle, size);
+        put_length_prefixed(&mut handle, filter);
+        handle.push(compression.id());
+        self.index.add(last_key, &handle);
+    }
+
+    // writes `block` followed by its checksum, returning the block's offset and size.
+    fn write_block(&mut self, block: &[u8]) -> (u64, u64) {
+        let offset = self.offset;
+        self.out.write_all(block).unwrap();
+        self.out.write_all(&crc32(block).to_le_bytes()).unwrap();
+        self.offset += block.len() as u64 + CHECKSUM_SIZE;
+        (offset, block.len() as u64)
+    }
+
+    // encodes the requested filter, which is empty when filters are disabled.
+    fn filter(&mut self, which: BlockFilter) -> Vec<u8> {
+        let builder = match which {
+            BlockFilter::Block => &mut self.block_filter,
+            BlockFilter::File => &mut self.file_filter,
+        };
+        if builder.is_empty() {
+            return Vec::new();
+        }
+        builder.finish(self.options.bloom_bits_per_key)
+    }
+
+    // writes out the last data block, the filter and index blocks and the footer, returning the
+    // underlying writer.
+    pub fn finish(mut self) -> W {
+        self.flush_block();
+        self.train_dictionary();
+        let dictionary = match self.dictionary.is_empty() {
+            true => (0, 0),
+            false => self.write_block(&self.dictionary.clone()),
+        };
+
+        let extractor = match &self.options.prefix_extractor {
+            Some(e) if self.options.bloom_bits_per_key > 0 => e.name(),
+            _ => String::new(),
+        };
+        let mut filter = Vec::new();
+        put_length_prefixed(&mut filter, extractor.as_bytes());
+        filter.extend(self.filter(BlockFilter::File));
+        let (filter_offset, filter_size) = self.write_block(&filter);
+
+        let index = self.index.finish();
+        let (index_offset, index_size) = self.write_block(&index);
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        footer.extend_from_slice(&filter_offset.to_le_bytes());
+        footer.extend_from_slice(&filter_size.to_le_bytes());
+        footer.extend_from_slice(&index_offset.to_le_bytes());
+        footer.extend_from_slice(&index_size.to_le_bytes());
+        footer.extend_from_slice(&self.max_seq.to_le_bytes());
+        footer.extend_from_slice(&self.properties.entries.to_le_bytes());
+        footer.extend_from_slice(&self.properties.deletions.to_le_bytes());
+        let created = self.created.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        footer.extend_from_slice(&(created.as_millis() as u64).to_le_bytes());
+        footer.extend_from_slice(&dictionary.0.to_le_bytes());
+        footer.extend_from_slice(&dictionary.1.to_le_bytes());
+        footer.extend_from_slice(&self.raw_data_size.to_le_bytes());
+        footer.extend_from_slice(&(self.options.codec.id() as u64).to_le_bytes());
+        footer.extend_from_slice(&MAGIC.to_le_bytes());
+        self.out.write_all(&footer).unwrap();
+
+        self.out
+    }
+}
+
+// A data block not written yet, along with what goes into its index entry.
+struct HeldBackBlock {
+    last_key: Vec<u8>,
+    block: Vec<u8>,
+    filter: Vec<u8>,
+}
+
+enum BlockFilter {
+    Block,
+    File,
+}
+
+// Location of a data block within an sstable, along with the filter over its keys.
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+    filter: Vec<u8>,
+    compression: Compression,
+}
+
+impl BlockHandle {
+    fn decode(encoded: &[u8]) -> Self {
+        let mut decoder = Decoder::new(encoded);
+        BlockHandle {
+            offset: decoder.varint().unwrap(),
+            size: decoder.varint().unwrap(),
+            filter: decoder.length_prefixed().unwrap().to_vec(),
+            compression: decoder
+                .bytes(1)
+                .map_or(Some(Compression::None), |id| Compression::from_id(id[0]))
+                .expect("unknown block compression"),
+        }
+    }
+}
+
+// What's needed to read a block based sstable: its index and filter blocks, and the footer.
+pub(crate) struct TableMeta {
+    index: Block,
+    filter: Vec<u8>,
+    // name of the prefix extractor whose prefixes are in `filter`, empty if none.
+    filter_prefix_extractor: String,
+    max_seq: u64,
+    // whether the file predates the kind byte in entries, and has its records in `KindlessCodec`.
+    without_kinds: bool,
+    // the codec the records of the file are encoded with.
+    codec: &'static dyn RecordCodec,
+    // whether the footer has room for the codec.
+    has_codec_field: bool,
+    // where the data blocks end, and the filter block starts.
+    data_end: u64,
+    // missing in files written before the footer held them.
+    properties: Option<TableProperties>,
+    created: Option<SystemTime>,
+    // the dictionary the data blocks are compressed with, empty if none.
+    dictionary: Vec<u8>,
+    // whether the footer has room for a dictionary.
+    has_dictionary_field: bool,
+    // the size of the data blocks before compression, missing in files written before the footer
+    // held it.
+    raw_data_size: Option<u64>,
+}
+
+// Counts of the entries in an sstable, recorded in its footer when it's written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TableProperties {
+    pub entries: u64,
+    // entries that are tombstones.
+    pub deletions: u64,
+}
+
+// The fixed size footer at the end of a block based sstable.
+struct Footer {
+    filter: (u64, u64),
+    index: (u64, u64),
+    max_seq: u64,
+    properties: Option<TableProperties>,
+    created: Option<SystemTime>,
+    // offset and size of the dictionary block, `None` if the file has none.
+    dictionary: Option<(u64, u64)>,
+    raw_data_size: Option<u64>,
+    codec: &'static dyn RecordCodec,
+    magic: u64,
+}
+
+impl Footer {
+    // returns `None` if `file` doesn't end with the footer of a block based sstable. The magic
+    // number at the very end tells how large the footer is.
+    fn read(file: &mut dyn ReadableFile) -> Option<Self> {
+        let mut magic = [0u8; 8];
+        file.seek(SeekFrom::End(-8)).ok()?;
+        file.read_exact(&mut magic).ok()?;
+        let magic = u64::from_le_bytes(magic);
+        let size = match magic {
+            MAGIC => FOOTER_SIZE,
+            MAGIC_WITHOUT_CODEC => FOOTER_SIZE_WITHOUT_CODEC,
+            MAGIC_WITHOUT_RAW_SIZE => FOOTER_SIZE_WITHOUT_RAW_SIZE,
+            MAGIC_WITHOUT_DICTIONARY => FOOTER_SIZE_WITHOUT_DICTIONARY,
+            MAGIC_WITHOUT_CREATION_TIME => FOOTER_SIZE_WITHOUT_CREATION_TIME,
+            MAGIC_WITHOUT_PROPERTIES | MAGIC_WITHOUT_KINDS => FOOTER_SIZE_WITHOUT_PROPERTIES,
+            _ => return None,
+        };
+
+        let mut footer = vec![0u8; size];
+        file.seek(SeekFrom::End(-(size as i64))).ok()?;
+        file.read_exact(&mut footer).ok()?;
+        Some(Footer {
+            filter: (read_u64(&footer, 0), read_u64(&footer, 8)),
+            index: (read_u64(&footer, 16), read_u64(&footer, 24)),
+            max_seq: read_u64(&footer, 32),
+            properties: (size >= FOOTER_SIZE_WITHOUT_CREATION_TIME).then(|| TableProperties {
+                entries: read_u64(&footer, 40),
+                deletions: read_u64(&footer, 48),
+            }),
+            created: (size >= FOOTER_SIZE_WITHOUT_DICTIONARY)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(read_u64(&footer, 56))),
+            dictionary: (size >= FOOTER_SIZE_WITHOUT_RAW_SIZE)
+                .then(|| (read_u64(&footer, 64), read_u64(&footer, 72)))
+                .filter(|(_, size)| *size > 0),
+            raw_data_size: (size >= FOOTER_SIZE_WITHOUT_CODEC).then(|| read_u64(&footer, 80)),
+            codec: match magic {
+                MAGIC => codec::codec(read_u64(&footer, 88) as u8).expect("unknown record codec"),
+                MAGIC_WITHOUT_KINDS => &KindlessCodec,
+                _ => &BinaryCodec,
+            },
+            magic,
+        })
+    }
+}
+
+impl TableMeta {
+    // returns `None` if `file` isn't a block based sstable.
+    fn read(file: &mut dyn ReadableFile) -> Option<Self> {
+        let footer = Footer::read(file)?;
+        let filter_block = read_block(file, footer.filter.0, footer.filter.1);
+        let mut decoder = Decoder::new(&filter_block);
+        let extractor = decoder.length_prefixed().unwrap();
+        let index = read_block(file, footer.index.0, footer.index.1);
+        let dictionary = footer
+            .dictionary
+            .map(|(offset, size)| read_block(file, offset, size));
+        Some(TableMeta {
+            index: Block::new(index),
+            filter_prefix_extractor: String::from_utf8(extractor.to_vec()).unwrap(),
+            filter: decoder.remaining().to_vec(),
+            max_seq: footer.max_seq,
+            without_kinds: footer.magic == MAGIC_WITHOUT_KINDS,
+            codec: footer.codec,
+            has_codec_field: footer.magic == MAGIC,
+            data_end: footer
+                .dictionary
+                .map_or(footer.filter.0, |(offset, _)| offset),
+            properties: footer.properties,
+            created: footer.created,
+            dictionary: dictionary.unwrap_or_default(),
+            has_dictionary_field: matches!(
+                footer.magic,
+                MAGIC | MAGIC_WITHOUT_CODEC | MAGIC_WITHOUT_RAW_SIZE
+            ),
+            raw_data_size: footer.raw_data_size,
+        })
+    }
+}
+
+// An open block based sstable.
+struct BlockTable {
+    file: Box<dyn ReadableFile>,
+    meta: Arc<TableMeta>,
+    cache: Option<TableCache>,
+    // bytes to read at once when a data block isn't in `readahead` yet, 0 to read single blocks.
+    readahead_size: usize,
+    // the offset and contents of the last read ahead part of the file.
+    readahead: (u64, Vec<u8>),
+    // counts the bytes of data blocks read from disk, see `SSTable::bytes_read`.
+    bytes_read: Option<Arc<AtomicU64>>,
+}
+
+impl BlockTable {
+    fn new(file: Box<dyn ReadableFile>, meta: Arc<TableMeta>) -> Self {
+        BlockTable {
+            file,
+            meta,
+            cache: None,
+            readahead_size: 0,
+            readahead: (0, Vec::new()),
+            bytes_read: None,
+        }
+    }
+
+    fn count_read(&self, len: u64) {
+        if let Some(bytes_read) = &self.bytes_read {
+            bytes_read.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+
+    // returns the handles of data blocks that may contain keys >= `start`.
+    fn block_handles(&self, start: &[u8]) -> VecDeque<BlockHandle> {
+        self.meta
+            .index
+            .seek(start)
+            .map(|(_, handle)| BlockHandle::decode(&handle))
+            .collect()
+    }
+
+    fn read_block(&mut self, handle: &BlockHandle) -> Arc<Block> {
+        if let Some(cached) = &self.cache
+            && let Some(block) = cached.cache.get((cached.cache_id, handle.offset))
+        {
+            return block;
+        }
+
+        let block = self.read_data(handle.offset, handle.size);
+        let block = handle.compression.decompress(block, &self.meta.dictionary);
+        let block = Arc::new(Block::new(block.unwrap()));
+        if let Some(cached) = &self.cache {
+            let key = (cached.cache_id, handle.offset);
+            cached.cache.insert(key, Arc::clone(&block));
+        }
+        block
+    }
+
+    // reads `size` bytes of data blocks at `offset`. With read ahead, a read past what was read
+    // ahead so far reads `readahead_size` bytes at once, so that sequential reads of the following
+    // blocks are served from memory.
+    fn read_data(&mut self, offset: u64, size: u64) -> Vec<u8> {
+        let (start, buf) = &self.readahead;
+        if offset >= *start && offset + size <= start + buf.len() as u64 {
+            let from = (offset - start) as usize;
+            return buf[from..from + size as usize].to_vec();
+        }
+        if self.readahead_size == 0 {
+            self.count_read(size);
+            return read_block(&mut *self.file, offset, size);
+        }
+
+        let len = (self.readahead_size as u64)
+            .min(self.meta.data_end - offset)
+            .max(size);
+        self.count_read(len);
+        self.readahead = (offset, read_block(&mut *self.file, offset, len));
+        self.readahead.1[..size as usize].to_vec()
+    }
+
+    // looks up `key`, consulting the file and block filters before reading the data block.
+    fn get(&mut self, key: &str) -> Option<Record> {
+        if !bloom::may_contain(&self.meta.filter, key.as_bytes()) {
+            return None;
+        }
+        let handle = self.block_handles(key.as_bytes()).pop_front()?;
+        if !bloom::may_contain(&handle.filter, key.as_bytes()) {
+            return None;
+        }
+        let block = self.read_block(&handle);
+        let (k, v) = block.seek(key.as_bytes()).next()?;
+        (k == key.as_bytes()).then(|| self.meta.codec.decode(&v).unwrap())
+    }
+}
+
+// The outcome of `LSMTree::verify_checksums` and `LSMTree::verify_backup`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub files_checked: usize,
+    pub blocks_checked: usize,
+    pub corrupt_files: Vec<CorruptFile>,
+}
+
+#[derive(Debug)]
+pub struct CorruptFile {
+    pub path: PathBuf,
+    // what didn't check out, e.g. which block had a checksum mismatch.
+    pub reason: String,
+}
+
+// re-reads the blocks of the sstable at `path` that may hold keys within `range` and validates their
+// checksums, without serving any of the data. The filter and index blocks are always checked.
+// Returns the number of blocks checked, or the reason why the file is corrupt.
+pub(crate) fn verify(
+    storage: &dyn Storage,
+    path: &Path,
+    range: &KeyRange,
+) -> Result<usize, String> {
+    let mut file = storage.open(path).map_err(|e| e.to_string())?;
+    let Some(footer) = Footer::read(&mut *file) else {
+        return verify_text(file);
+    };
+
+    read_checked(&mut *file, footer.filter.0, footer.filter.1)
+        .map_err(|e| format!("filter block: {}", e))?;
+    let index = read_checked(&mut *file, footer.index.0, footer.index.1)
+        .map_err(|e| format!("index block: {}", e))?;
+
+    let mut checked = 2;
+    if let Some((offset, size)) = footer.dictionary {
+        read_checked(&mut *file, offset, size).map_err(|e| format!("dictionary block: {}", e))?;
+        checked += 1;
+    }
+    // a data block holds the keys after the previous block's last key, up to its own last key.
+    let mut prev_last_key: Option<Vec<u8>> = None;
+    for (last_key, handle) in Block::new(index).iter() {
+        let last = String::from_utf8_lossy(&last_key).to_string();
+        let prev = prev_last_key.replace(last_key);
+        if range.is_before_start(&last) {
+            continue;
+        }
+        if prev.is_some_and(|p| range.is_past_end(&String::from_utf8_lossy(&p))) {
+            break;
+        }
+
+        let mut decoder = Decoder::new(&handle);
+        let offset = decoder.varint().ok_or("malformed index entry")?;
+        let size = decoder.varint().ok_or("malformed index entry")?;
+        read_checked(&mut *file, offset, size)
+            .map_err(|e| format!("data block at offset {}: {}", offset, e))?;
+        checked += 1;
+    }
+
+    Ok(checked)
+}
+
+// checks the whole sstable at `path` like `verify`, then decodes every record in it, passing each
+// to `check`, and makes sure that the keys are in order and that the number of entries and
+// deletions matches the one recorded in the footer. Meant for files that are rarely read, like the
+// ones in a backup. Returns the number of blocks checked, or the reason why the file is corrupt.
+pub(crate) fn verify_records(
+    storage: &dyn Storage,
+    path: &Path,
+    mut check: impl FnMut(&str, &Record) -> Result<(), String>,
+) -> Result<usize, String> {
+    let checked = verify(storage, path, &KeyRange::all())?;
+    let mut file = storage.open(path).map_err(|e| e.to_string())?;
+    let Some(footer) = Footer::read(&mut *file) else {
+        // text sstables have no counts to compare, their lines were checked already.
+        return Ok(checked);
+    };
+    let codec = footer.codec;
+    let index = read_checked(&mut *file, footer.index.0, footer.index.1)?;
+    let dictionary = match footer.dictionary {
+        Some((offset, size)) => read_checked(&mut *file, offset, size)?,
+        None => Vec::new(),
+    };
+
+    let mut counts = TableProperties::default();
+    let mut previous: Option<String> = None;
+    for (_, handle) in Block::new(index).iter() {
+        let handle = BlockHandle::decode(&handle);
+        let at = |e: String| format!("data block at offset {}: {}", handle.offset, e);
+        let data = read_checked(&mut *file, handle.offset, handle.size).map_err(at)?;
+        let data = (handle.compression.decompress(data, &dictionary))
+            .ok_or_else(|| at("malformed".into()))?;
+        for (key, value) in Block::new(data).iter() {
+            let key = String::from_utf8(key).map_err(|e| at(e.to_string()))?;
+            if previous.as_ref().is_some_and(|p| *p >= key) {
+                return Err(at(format!("key `{}` is out of order", key)));
+            }
+            let record = (codec.decode(&value)).map_err(|e| at(format!("key `{}`: {}", key, e)))?;
+            check(&key, &record).map_err(|e| at(format!("key `{}`: {}", key, e)))?;
+            counts.entries += 1;
+            counts.deletions += record.value.is_none() as u64;
+            previous = Some(key);
+        }
+    }
+
+    match footer.properties {
+        Some(recorded) if recorded != counts => Err(format!(
+            "holds {} entries and {} deletions, but the footer records {} and {}",
+            counts.entries, counts.deletions, recorded.entries, recorded.deletions
+        )),
+        _ => Ok(checked),
+    }
+}
+
+// reads the block at `offset` and checks it against the checksum stored after it.
+fn read_checked(file: &mut dyn ReadableFile, offset: u64, size: u64) -> Result<Vec<u8>, String> {
+    let mut buf = vec![0u8; (size + CHECKSUM_SIZE) as usize];
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_exact(&mut buf))
+        .map_err(|e| format!("failed to read: {}", e))?;
+
+    let stored = u32::from_le_bytes(buf.split_off(size as usize).try_into().unwrap());
+    if crc32(&buf) != stored {
+        return Err("checksum mismatch".to_string());
+    }
+    Ok(buf)
+}
+
+// text sstables have no checksums, the best we can do is check that every line parses.
+fn verify_text(mut file: Box<dyn ReadableFile>) -> Result<usize, String> {
+    file.rewind().map_err(|e| e.to_string())?;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        match line {
+            Ok(l) if l.contains(':') => {}
+            _ => return Err(format!("line {} is not a key:value pair", i + 1)),
+        }
+    }
+    Ok(1)
+}
+
+fn read_block(file: &mut dyn ReadableFile, offset: u64, size: u64) -> Vec<u8> {
+    let mut buf = vec![0u8; size as usize];
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    buf
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// helper function to read the entry on a line of a text sstable.
+pub(crate) fn read_text_entry(l: &Result<String, std::io::Error>) -> (String, Record) {
+    let line = l.as_ref().unwrap();
+    codec::decode_line(line.as_bytes()).unwrap()
+}
+
+// An iterator over the entries of a single sstable, starting at the first key within `range`.
+// The file handle is opened upfront, so the iterator keeps reading the same contents even if the file
+// is later replaced by compaction.
+pub(crate) struct SSTableEntries {
+    source: EntrySource,
+    range: KeyRange,
+}
+
+enum EntrySource {
+    Text(Lines<BufReader<Box<dyn ReadableFile>>>),
+    Block {
+        table: BlockTable,
+        // data blocks yet to be read.
+        handles: VecDeque<BlockHandle>,
+        // decoded entries of the current data block.
+        entries: VecDeque<(Vec<u8>, Vec<u8>)>,
+    },
+}
+
+impl SSTableEntries {
+    // opens `sst`, reading its data blocks through `cache` if set, and `readahead_size` bytes of the
+    // file at a time.
+    pub fn open(
+        sst: &SSTable,
+        range: &KeyRange,
+        cache: Option<TableCache>,
+        readahead_size: usize,
+    ) -> Self {
+        let source = match sst.open_table() {
+            Ok(mut table) => {
+                table.cache = cache;
+                table.readahead_size = readahead_size;
+                let start = match &range.start {
+                    Bound::Included(s) | Bound::Excluded(s) => s.as_bytes(),
+                    Bound::Unbounded => &[],
+                };
+                let mut handles = table.block_handles(start);
+                let entries = match handles.pop_front() {
+                    Some(handle) => table.read_block(&handle).seek(start).collect(),
+                    None => VecDeque::new(),
+                };
+                EntrySource::Block {
+                    table,
+                    handles,
+                    entries,
+                }
+            }
+            Err(file) => {
+                let capacity = readahead_size.max(DEFAULT_BUF_SIZE);
+                EntrySource::Text(BufReader::with_capacity(capacity, file).lines())
+            }
+        };
+
+        Self {
+            source,
+            range: range.clone(),
+        }
+    }
+
+    fn next_raw(&mut self) -> Option<(String, Record)> {
+        match &mut self.source {
+            EntrySource::Text(lines) => Some(read_text_entry(&lines.next()?)),
+            EntrySource::Block {
+                table,
+                handles,
+                entries,
+            } => loop {
+                if let Some((k, v)) = entries.pop_front() {
+                    let record = table.meta.codec.decode(&v).unwrap();
+                    return Some((String::from_utf8(k).unwrap(), record));
+                }
+                let handle = handles.pop_front()?;
+                *entries = table.read_block(&handle).iter().collect();
+            },
+        }
+    }
+}
+
+impl Iterator for SSTableEntries {
+    type Item = (String, Record);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((k, v)) = self.next_raw() {
+            if self.range.is_past_end(&k) {
+                return None;
+            }
+            if self.range.contains(&k) {
+                return Some((k, v));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Cursor, Read, Seek, SeekFrom, Write},
+        path::{Path, PathBuf},
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use super::{BlockTable, SSTable, SSTableEntries, SSTableWriter, TableMeta, TableOptions};
+    use crate::{
+        KeyRange, Record, Value, bloom,
+        codec::{BinaryCodec, KindlessCodec, RecordCodec, TextCodec},
+        compression::Compression,
+        sidecar::sidecar_path,
+        storage::{FsStorage, ReadableFile, Storage},
+    };
+
+    fn test_file(name: &str) -> PathBuf {
+        let dir = PathBuf::from("test_data").join("sstable");
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn open(path: &Path) -> SSTable {
+        let storage: Arc<dyn Storage> = Arc::new(FsStorage);
+        SSTable::at(&storage, path.to_path_buf(), 0)
+    }
+
+    fn open_table(mut file: Box<dyn ReadableFile>) -> BlockTable {
+        let meta = TableMeta::read(&mut *file).unwrap();
+        BlockTable::new(file, Arc::new(meta))
+    }
+
+    #[test]
+    fn test_sstable_roundtrip_across_blocks() {
+        let path = test_file("roundtrip.sst");
+        let file = std::fs::File::create(&path).unwrap();
+        // small blocks, so that the entries are spread over many of them.
+        let options = TableOptions {
+            block_size: 64,
+            block_restart_interval: 4,
+            ..TableOptions::default()
+        };
+        let mut writer = SSTableWriter::new(file, &options);
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            let value = (i % 10 != 0).then(|| format!("value{}", i));
+            let value = value.map(Value::Inline);
+            writer.add(&key, &Record { seq: i, value });
+        }
+        writer.finish().sync_data().unwrap();
+
+        // the index and filters are only read on first use, and then kept for later reads.
+        let sst = open(&path);
+        assert!(!sst.is_loaded());
+        assert_eq!(sst.get("key0421", None), None);
+        assert!(sst.is_loaded());
+
+        let record = |seq, value: Option<&str>| {
+            Some(Record {
+                seq,
+                value: value.map(|v| Value::Inline(v.to_string())),
+            })
+        };
+        assert_eq!(open(&path).get("key042", None), record(42, Some("value42")));
+        assert_eq!(open(&path).get("key050", None), record(50, None));
+        assert_eq!(open(&path).get("key0421", None), None);
+        assert_eq!(open(&path).get("zzz", None), None);
+
+        let range = KeyRange::from("key015".."key020");
+        let keys: Vec<String> = SSTableEntries::open(&open(&path), &range, None, 0)
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, ["key015", "key016", "key017", "key018", "key019"]);
+        assert_eq!(
+            SSTableEntries::open(&open(&path), &KeyRange::all(), None, 0).count(),
+            100
+        );
+        assert_eq!(open(&path).max_seq(), 99);
+    }
+
+    #[test]
+    fn test_sstable_records_are_read_with_the_codec_they_were_written_with() {
+        let codecs: [&'static dyn RecordCodec; 3] = [&BinaryCodec, &KindlessCodec, &TextCodec];
+        for codec in codecs {
+            let path = test_file(&format!("codec{}.sst", codec.id()));
+            let file = std::fs::File::create(&path).unwrap();
+            let options = TableOptions {
+                codec,
+                ..TableOptions::default()
+            };
+            let mut writer = SSTableWriter::new(file, &options);
+            for i in 0..10 {
+                let value = (i != 5).then(|| Value::Inline(format!("value{}", i)));
+                writer.add(&format!("key{}", i), &Record { seq: i, value });
+            }
+            writer.finish().sync_data().unwrap();
+
+            let sst = open(&path);
+            let value = Some(Value::Inline("value3".to_string()));
+            assert_eq!(sst.get("key3", None), Some(Record { seq: 3, value }));
+            assert_eq!(sst.get("key5", None).unwrap().value, None);
+            assert_eq!(
+                SSTableEntries::open(&sst, &KeyRange::all(), None, 0).count(),
+                10
+            );
+            assert_eq!(sst.format_version(), 8);
+        }
+
+        // files written before the footer held the codec are in `BinaryCodec`: their footer is
+        // the same without it, and ends with the previous magic number.
+        let path = test_file("codec1.sst");
+        let mut contents = std::fs::read(&path).unwrap();
+        let magic = contents.split_off(contents.len() - 16);
+        assert_eq!(magic[..8], [1, 0, 0, 0, 0, 0, 0, 0]);
+        contents.extend_from_slice(&super::MAGIC_WITHOUT_CODEC.to_le_bytes());
+        std::fs::write(&path, contents).unwrap();
+        let sst = open(&path);
+        assert_eq!(sst.format_version(), 7);
+        assert_eq!(sst.get("key3", None).unwrap().seq, 3);
+    }
+
+    #[test]
+    fn test_sstable_compressed_blocks() {
+        let write = |name, compression| {
+            let path = test_file(name);
+            let file = std::fs::File::create(&path).unwrap();
+            let options = TableOptions {
+                block_size: 256,
+                compression,
+                ..TableOptions::default()
+            };
+            let mut writer = SSTableWriter::new(file, &options);
+            for i in 0..200 {
+                let value = Some(Value::Inline(format!(
+                    "value {} of a rather repetitive key",
+                    i
+                )));
+                writer.add(&format!("key{:03}", i), &Record { seq: i, value });
+            }
+            writer.finish().sync_data().unwrap();
+            path
+        };
+        let plain = write("plain.sst", Compression::None);
+        let compressed = write("compressed.sst", Compression::Lz4);
+        let size = |path| std::fs::metadata(path).unwrap().len();
+        assert!(size(&compressed) < size(&plain) * 3 / 4);
+
+        let value = open(&compressed).get("key150", None).unwrap().value;
+        let expected = "value 150 of a rather repetitive key".to_string();
+        assert_eq!(value, Some(Value::Inline(expected)));
+        let all = SSTableEntries::open(&open(&compressed), &KeyRange::all(), None, 0);
+        assert!(all.eq(SSTableEntries::open(
+            &open(&plain),
+            &KeyRange::all(),
+            None,
+            1024
+        )));
+    }
+
+    #[test]
+    fn test_sstable_reads_ahead() {
+        // a file that counts the reads made from it.
+        struct CountingFile {
+            data: Cursor<Vec<u8>>,
+            reads: Arc<AtomicUsize>,
+        }
+        impl Read for CountingFile {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.reads.fetch_add(1, Ordering::Relaxed);
+                self.data.read(buf)
+            }
+        }
+        impl Seek for CountingFile {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                self.data.seek(pos)
+            }
+        }
+
+        let options = TableOptions {
+            block_size: 64,
+            ..TableOptions::default()
+        };
+        let mut writer = SSTableWriter::new(Vec::new(), &options);
+        for i in 0..100 {
+            let value = Some(Value::Inline(format!("value{}", i)));
+            writer.add(&format!("key{:03}", i), &Record { seq: i, value });
+        }
+        let data = writer.finish();
+
+        let read_all = |readahead_size| {
+            let reads = Arc::new(AtomicUsize::new(0));
+            let file = CountingFile {
+                data: Cursor::new(data.clone()),
+                reads: Arc::clone(&reads),
+            };
+            let mut table = open_table(Box::new(file));
+            table.readahead_size = readahead_size;
+            let opened = reads.load(Ordering::Relaxed);
+            let entries: usize = (table.block_handles(b"").iter())
+                .map(|handle| table.read_block(handle).iter().count())
+                .sum();
+            assert_eq!(entries, 100);
+            reads.load(Ordering::Relaxed) - opened
+        };
+        let blocks = read_all(0);
+        assert!(blocks > 20);
+        assert_eq!(read_all(1024), blocks.div_ceil(1024 / 64));
+        assert_eq!(read_all(1 << 20), 1);
+    }
+
+    #[test]
+    fn test_sstable_filters_skip_absent_keys() {
+        let path = test_file("filters.sst");
+        let file = std::fs::File::create(&path).unwrap();
+        let options = TableOptions {
+            block_size: 256,
+            ..TableOptions::default()
+        };
+        let mut writer = SSTableWriter::new(file, &options);
+        for i in 0..500 {
+            let record = Record {
+                seq: i,
+                value: Some(Value::Inline("v".to_string())),
+            };
+            writer.add(&format!("key{:04}", i * 2), &record);
+        }
+        writer.finish().sync_data().unwrap();
+
+        let file = Box::new(std::fs::File::open(&path).unwrap());
+        let table = open_table(file);
+        let handles = table.block_handles(b"");
+        assert!(handles.len() > 1);
+
+        // odd keys fall between the even keys stored in each block, but are never present.
+        let absent: Vec<String> = (0..500).map(|i| format!("key{:04}", i * 2 + 1)).collect();
+        let passing_file_filter = absent
+            .iter()
+            .filter(|k| bloom::may_contain(&table.meta.filter, k.as_bytes()))
+            .count();
+        assert!(passing_file_filter < 25);
+
+        let first_block = &handles[0].filter;
+        assert!(bloom::may_contain(first_block, b"key0000"));
+        assert!(!bloom::may_contain(first_block, b"key0998"));
+        assert!(open(&path).get("key0001", None).is_none());
+    }
+
+    #[test]
+    fn test_sstable_reads_text_format() {
+        let path = test_file("text.sst");
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "a:v1").unwrap();
+        writeln!(file, "b:🪦").unwrap();
+
+        assert_eq!(
+            open(&path).get("a", None).unwrap().value,
+            Some(Value::Inline("v1".to_string()))
+        );
+        assert_eq!(open(&path).get("b", None).unwrap().value, None);
+        assert!(open(&path).get("c", None).is_none());
+        // lookups seek with the index in the sidecar built by the first one.
+        assert!(sidecar_path(&path).exists());
+        assert_eq!(
+            SSTableEntries::open(&open(&path), &KeyRange::all(), None, 0).count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Warm standby: a tree that follows a primary by applying the write-ahead log segments shipped
+//! from it, ready to take over as soon as the primary is lost.
+//!
+//! The primary's segments are copied to a directory the standby can read, e.g. by archiving them
+//! there, see `Options::wal_archive`, or by a job copying them from the primary's data dir. The
+//! standby replays their records into a tree of its own, under the sequence numbers they had on the
+//! primary, and serves reads from it while refusing writes:
+//!
+//!   primary:  put -> 7.log -> archive dir --(copy)--> shipped dir
+//!   standby:                        catch_up: records since applied_seq -> own tree -> get, scan
+//!
+//! `catch_up` applies the records that arrived since the last call, and is meant to be called in a
+//! loop. A segment that's still being copied is applied up to its last complete record, the rest
+//! follows on a later call. `promote` applies what's left and hands over the tree, which needs no
+//! recovery of its own since it was open all along.
+//! Only writes go through the log, so sstables ingested or absorbed by the primary don't reach the
+//! standby, and neither do records whose segments were removed before it applied them: the
+//! primary's archive retention must keep segments around for longer than the standby lags behind.
+
+use std::{
+    io,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    LSMTree, Options, ScanIter,
+    storage::Storage,
+    wal::{WalOp, WalReader},
+};
+
+pub struct Standby {
+    tree: LSMTree,
+    // where the primary's segments are shipped to.
+    shipped_dir: PathBuf,
+    storage: Arc<dyn Storage>,
+}
+
+impl Standby {
+    // opens the standby's tree in `data_dir`, which follows the segments shipped to `shipped_dir`
+    // on the storage of `options`. The tree continues from the records it applied before.
+    pub fn open(
+        data_dir: impl AsRef<Path>,
+        shipped_dir: impl AsRef<Path>,
+        options: Options,
+    ) -> Self {
+        let storage = Arc::clone(&options.storage);
+        Standby {
+            tree: LSMTree::open_with_options(data_dir, options),
+            shipped_dir: shipped_dir.as_ref().to_path_buf(),
+            storage,
+        }
+    }
+
+    // applies the records shipped since the last call. Returns the number of records applied.
+    pub fn catch_up(&mut self) -> io::Result<usize> {
+        if !self.storage.exists(&self.shipped_dir) {
+            return Ok(0);
+        }
+        let reader = WalReader::open_with_storage(Arc::clone(&self.storage), &self.shipped_dir)?;
+        let mut applied = 0;
+        for record in reader.records_since(self.tree.last_seq + 1) {
+            let record = record?;
+            // applied under the primary's sequence number, which skips those of sstables ingested
+            // by the primary.
+            self.tree.last_seq = record.seq - 1;
+            match record.op {
+                WalOp::Put(value) => self.tree.put(&record.key, &value)?,
+                WalOp::Delete => self.tree.delete(&record.key),
+            }
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    // the sequence number of the last record applied, the position the standby caught up to.
+    pub fn applied_seq(&self) -> u64 {
+        self.tree.last_seq
+    }
+
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.tree.get(k)
+    }
+
+    pub fn scan<'a>(&self, range: impl RangeBounds<&'a str>) -> ScanIter {
+        self.tree.scan(range)
+    }
+
+    // the tree, for reads other than `get` and `scan`.
+    pub fn tree(&self) -> &LSMTree {
+        &self.tree
+    }
+
+    // applies the records shipped last and returns the tree, to serve writes in place of the
+    // primary. The primary must be stopped first, or writes it makes afterwards are lost.
+    pub fn promote(mut self) -> io::Result<LSMTree> {
+        self.catch_up()?;
+        Ok(self.tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::Standby;
+    use crate::{LSMTree, Options, WalArchiveOptions, sim::SimStorage, storage::Storage};
+
+    #[test]
+    fn test_standby_follows_archived_wal_and_promotes() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 10,
+            ..Options::default()
+        };
+        // the primary archives its segments right where the standby reads them.
+        let primary_options = Options {
+            wal_archive: Some(WalArchiveOptions::new("shipped")),
+            ..options.clone()
+        };
+        let mut primary = LSMTree::open_with_options("primary", primary_options);
+        let mut standby = Standby::open("standby", "shipped", options.clone());
+        assert_eq!(standby.catch_up().unwrap(), 0);
+
+        for i in 0..25 {
+            primary.put(&format!("key{:02}", i), "v1").unwrap();
+        }
+        primary.delete("key03");
+        // only the two segments of flushed memtables are archived.
+        assert_eq!(standby.catch_up().unwrap(), 20);
+        assert_eq!(standby.get("key03"), Some("v1".to_string()));
+        assert_eq!(standby.get("key22"), None);
+        assert_eq!(standby.catch_up().unwrap(), 0);
+
+        for i in 25..30 {
+            primary.put(&format!("key{:02}", i), "v2").unwrap();
+        }
+        // the delete and the writes up to key28 filled the next memtable.
+        assert_eq!(standby.catch_up().unwrap(), 10);
+        assert_eq!(standby.applied_seq(), 30);
+        assert_eq!(standby.get("key03"), None);
+        assert_eq!(standby.scan(..).count(), 28);
+
+        // a standby reopened continues from where it left off.
+        drop(standby);
+        let standby = Standby::open("standby", "shipped", options);
+        assert_eq!(standby.applied_seq(), 30);
+        // the write of key29 is still in the primary's live segment, which was never shipped.
+        let mut promoted = standby.promote().unwrap();
+        assert_eq!(promoted.get("key29"), None);
+        promoted.put("key30", "v3").unwrap();
+        assert_eq!(promoted.get("key30"), Some("v3".to_string()));
+        assert_eq!(promoted.scan(..).count(), 29);
+        assert!(storage.exists(Path::new("standby/OPTIONS")));
+    }
+}
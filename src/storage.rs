@@ -0,0 +1,328 @@
+//! The storage abstraction the tree does all its file I/O through.
+//!
+//! By default files live on the local filesystem (`FsStorage`), but any implementation of the
+//! `Storage` trait can be passed in `Options::storage`. The simulated backend in `sim.rs` uses this
+//! to keep files in memory and inject faults like failed writes and crashes that lose unsynced data.
+
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{self, BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+// A file opened for writing. Data written to it is only guaranteed to survive a crash once
+// `sync` returns.
+pub trait WritableFile: Write + Send + Sync {
+    fn sync(&mut self) -> io::Result<()>;
+
+    // reserves space for the file to grow to `len` bytes, so that the filesystem can lay it out in
+    // one piece rather than as it grows. The file's length only covers what's written, and the
+    // space beyond it is given back by the next `sync`. Storage that can't fails with
+    // `Unsupported`, which writers treat as a hint that wasn't taken.
+    fn preallocate(&mut self, _len: u64) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+// A file opened for reading.
+pub trait ReadableFile: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadableFile for T {}
+
+pub trait Storage: Debug + Send + Sync {
+    // creates a file for writing, truncating it if it already exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+
+    // creates a file like `create`, for writes that should bypass the page cache, see
+    // `direct_io.rs`. Storage without a page cache creates it like any other file.
+    fn create_direct(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        self.create(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    // cuts the file down to its first `len` bytes and syncs it.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    // atomically renames `from` to `to`, replacing `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    // makes the file at `from` also available at `to`, which must not exist yet, without copying
+    // it: removing either path afterwards leaves the file at the other one. Storage without hard
+    // links copies the file instead, which is the same to a tree that never modifies its files
+    // once written.
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.exists(to) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        let mut reader = self.open(from)?;
+        let mut writer = self.create(to)?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.sync()
+    }
+
+    // returns the paths of the files within `dir`.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    // size of the file in bytes.
+    fn len(&self, path: &Path) -> io::Result<u64>;
+
+    // when the file was last written to.
+    fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+
+    // the current time, to compare modification times against. Storage provides it so that
+    // simulations can control the clock.
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    // makes the creation, removal and renaming of files within `dir` survive a crash.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    // tells the OS the file won't be read again soon, so that it drops the file's data from the
+    // page cache first, see `background_io.rs`. Storage without a page cache does nothing.
+    fn drop_from_cache(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Storage on the local filesystem.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsStorage;
+
+impl WritableFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+// A file written through a buffer, see `Options::sstable_write_buffer_size`.
+impl WritableFile for BufWriter<Box<dyn WritableFile>> {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().sync()
+    }
+
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().preallocate(len)
+    }
+}
+
+// A file on the local filesystem opened for writing, which keeps track of the space preallocated
+// for it.
+pub(crate) struct FsFile {
+    file: File,
+    // whether space beyond what's written was preallocated since the last sync.
+    preallocated: bool,
+}
+
+impl FsFile {
+    pub(crate) fn new(file: File) -> Self {
+        FsFile {
+            file,
+            preallocated: false,
+        }
+    }
+}
+
+impl Write for FsFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.file.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl WritableFile for FsFile {
+    fn sync(&mut self) -> io::Result<()> {
+        if self.preallocated {
+            // truncating a file to the length it has gives back the space allocated beyond it.
+            let len = self.file.stream_position()?;
+            self.file.set_len(len)?;
+            self.preallocated = false;
+        }
+        self.file.sync_data()
+    }
+
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        allocate(&self.file, len)?;
+        self.preallocated = true;
+        Ok(())
+    }
+}
+
+// allocates the first `len` bytes of `file`, without changing its length.
+#[cfg(target_os = "linux")]
+pub(crate) fn allocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let len = len as libc::off_t;
+    // SAFETY: the descriptor is open for as long as `file` is borrowed.
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// elsewhere the only way to allocate space is `set_len`, which would leave zeros at the end of a
+// file that a crash cuts short, so nothing is allocated.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn allocate(_file: &File, _len: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+impl Storage for FsStorage {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Box::new(FsFile::new(file)))
+    }
+
+    fn create_direct(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        crate::direct_io::create(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let file = std::fs::OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::hard_link(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        std::fs::metadata(path)?.modified()
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        // directories can only be opened and synced like this on unix, elsewhere the filesystem
+        // takes care of persisting directory entries.
+        if cfg!(unix) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn drop_from_cache(&self, path: &Path) -> io::Result<()> {
+        crate::background_io::drop_from_page_cache(&File::open(path)?);
+        Ok(())
+    }
+}
+
+// Storage that only allows reading, for trees opened with `LSMTree::open_read_only`. Every write
+// fails, so that nothing the tree does can modify files it may share with another tree.
+#[derive(Debug)]
+pub(crate) struct ReadOnlyStorage(pub Arc<dyn Storage>);
+
+fn read_only() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "the tree was opened read-only",
+    )
+}
+
+impl Storage for ReadOnlyStorage {
+    fn create(&self, _: &Path) -> io::Result<Box<dyn WritableFile>> {
+        Err(read_only())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadableFile>> {
+        self.0.open(path)
+    }
+
+    fn remove(&self, _: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn truncate(&self, _: &Path, _: u64) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn rename(&self, _: &Path, _: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn link(&self, _: &Path, _: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.0.list(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.0.exists(path)
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        self.0.len(path)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        self.0.modified(path)
+    }
+
+    fn now(&self) -> SystemTime {
+        self.0.now()
+    }
+
+    fn create_dir_all(&self, _: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn sync_dir(&self, _: &Path) -> io::Result<()> {
+        Err(read_only())
+    }
+}
@@ -0,0 +1,150 @@
+//! Scans as async streams, built with the `async` feature.
+//!
+//! A `ScanIter` reads blocks from disk as it goes, so iterating over it in async code would block
+//! the executor's worker thread on every block read. `LSMTree::scan_stream` returns a
+//! `futures::Stream` instead: the scan runs on a thread of its own, which reads ahead into a
+//! bounded buffer of `PREFETCH` pairs, and the stream hands out the pairs from that buffer:
+//!
+//!   scan thread: ScanIter -> | buffer, PREFETCH pairs | -> ScanStream: poll_next
+//!
+//! A full buffer blocks the scan thread until the consumer catches up, so a slow consumer holds
+//! back the scan rather than the scan piling up pairs in memory. Dropping the stream stops the scan.
+//! 💡 Async storage engines avoid the thread by reading with io_uring or a thread pool, e.g.
+//! SlateDB reads sstables from object storage with async requests.
+
+use std::{
+    ops::RangeBounds,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, TryRecvError, sync_channel},
+    },
+    task::{Context, Poll, Waker},
+};
+
+use futures_core::Stream;
+
+use crate::LSMTree;
+
+// number of key value pairs the scan thread reads ahead of the consumer.
+const PREFETCH: usize = 256;
+
+impl LSMTree {
+    // returns a stream over the live key value pairs within `range`, in sorted key order, as of
+    // when it's called, like `scan`.
+    pub fn scan_stream<'a>(&self, range: impl RangeBounds<&'a str>) -> ScanStream {
+        let scan = self.scan(range);
+        let (sender, receiver) = sync_channel(PREFETCH);
+        let waker: Arc<Mutex<Option<Waker>>> = Arc::default();
+        let wake = Arc::clone(&waker);
+        std::thread::spawn(move || {
+            for pair in scan {
+                // the stream was dropped.
+                if sender.send(pair).is_err() {
+                    return;
+                }
+                if let Some(waker) = wake.lock().unwrap().take() {
+                    waker.wake();
+                }
+            }
+            drop(sender);
+            if let Some(waker) = wake.lock().unwrap().take() {
+                waker.wake();
+            }
+        });
+        ScanStream { receiver, waker }
+    }
+}
+
+// Stream returned by `LSMTree::scan_stream`.
+pub struct ScanStream {
+    receiver: Receiver<(String, String)>,
+    // woken by the scan thread when it adds a pair to the buffer, or is done.
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl ScanStream {
+    fn try_next(&self) -> Poll<Option<(String, String)>> {
+        match self.receiver.try_recv() {
+            Ok(pair) => Poll::Ready(Some(pair)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for ScanStream {
+    type Item = (String, String);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(next) = self.try_next() {
+            return Poll::Ready(next);
+        }
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        // the scan thread may have added a pair before the waker was there to be woken.
+        self.try_next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll, Wake},
+        thread::{self, Thread},
+    };
+
+    use futures_core::Stream;
+
+    use crate::{LSMTree, Options, sim::SimStorage};
+
+    // wakes the test thread, which parks while the stream is pending.
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // drains `stream` on the current thread, like an executor would.
+    fn collect(mut stream: impl Stream<Item = (String, String)> + Unpin) -> Vec<(String, String)> {
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut out = Vec::new();
+        loop {
+            match Pin::new(&mut stream).poll_next(&mut cx) {
+                Poll::Ready(Some(pair)) => out.push(pair),
+                Poll::Ready(None) => return out,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_stream() {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        };
+        let mut lsmtree = LSMTree::open_with_options("data", options);
+        for i in 0..1000 {
+            lsmtree
+                .put(&format!("key{:04}", i), &format!("value{}", i))
+                .unwrap();
+        }
+        lsmtree.delete("key0500");
+
+        let stream = lsmtree.scan_stream("key0100".."key0900");
+        // writes after the stream was created aren't part of it.
+        lsmtree.put("key0200", "updated").unwrap();
+        let pairs = collect(stream);
+        let expected: Vec<(String, String)> = lsmtree.scan("key0100".."key0900").collect();
+        assert_eq!(pairs.len(), 799);
+        assert_eq!(pairs[100], ("key0200".to_string(), "value200".to_string()));
+        assert_eq!(pairs[..100], expected[..100]);
+        assert_eq!(pairs[101..], expected[101..]);
+    }
+}
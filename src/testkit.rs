@@ -0,0 +1,357 @@
+//! Model-based testing: random operations run against a tree and a reference model of it side by
+//! side, checking after every one of them that the two agree.
+//!
+//! The model is a plain `BTreeMap` of the live keys and their values, which is easy to get right.
+//! `OpGenerator` produces a seeded random sequence of puts and deletes, mixed with flushes,
+//! compactions and reopens that change how the tree stores its data but never what it holds, and
+//! `ModelChecker` applies each of them to both:
+//!
+//!   OpGenerator -> Op -> ModelChecker -> LSMTree  -> get, scan, keys, count_range -> equal?
+//!                                     -> Model    -> BTreeMap lookups             ->
+//!
+//! Code built on top of the tree, e.g. a wrapper or a new kind of sstable, can reuse the checker by
+//! opening the tree with its own options, or by driving `tree_mut` and `model_mut` with operations
+//! of its own in between. Unlike `sim::Simulation`, nothing crashes here: the focus is on the
+//! results of reads, whatever shape the tree is in.
+//!
+//! `DataGenerator` makes the data of benchmarks, see `benches/lsmtree.rs`, and of tests that need a
+//! lot of it but don't care what it is: keys in order or at random, with values of a fixed size.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{LSMTree, Options, sim::SimRng};
+
+// An operation run against both the tree and the model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Put { key: String, value: String },
+    Delete { key: String },
+    // writes the memtable to an sstable, which may trigger compactions.
+    Flush,
+    // runs the compactions that are due, see `LSMTree::compact_now`.
+    Compact,
+    // closes the tree and opens it again.
+    Reopen,
+}
+
+// The reference the tree is compared with: the live keys and their values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Model {
+    pub entries: BTreeMap<String, String>,
+}
+
+impl Model {
+    // applies the effect of `op` on the contents, i.e. of puts and deletes.
+    pub fn apply(&mut self, op: &Op) {
+        match op {
+            Op::Put { key, value } => {
+                self.entries.insert(key.clone(), value.clone());
+            }
+            Op::Delete { key } => {
+                self.entries.remove(key);
+            }
+            Op::Flush | Op::Compact | Op::Reopen => {}
+        }
+    }
+}
+
+// An endless, seeded random sequence of operations. The same seed always gives the same sequence.
+#[derive(Debug, Clone)]
+pub struct OpGenerator {
+    rng: SimRng,
+    // number of distinct keys written to, fewer keys means more overwrites and deletes of live keys.
+    keys: u64,
+    step: u64,
+}
+
+impl OpGenerator {
+    pub fn new(seed: u64, keys: u64) -> Self {
+        OpGenerator {
+            rng: SimRng::new(seed),
+            keys,
+            step: 0,
+        }
+    }
+}
+
+impl Iterator for OpGenerator {
+    type Item = Op;
+
+    fn next(&mut self) -> Option<Op> {
+        self.step += 1;
+        let key = format!("key{:03}", self.rng.below(self.keys));
+        let op = match self.rng.below(20) {
+            0..=11 => {
+                // values are unique, and some are long enough to go to blob files.
+                let mut value = format!("v{}", self.step);
+                if self.rng.below(10) == 0 {
+                    value.push_str(&".".repeat(100));
+                }
+                Op::Put { key, value }
+            }
+            12..=15 => Op::Delete { key },
+            16 | 17 => Op::Flush,
+            18 => Op::Compact,
+            _ => Op::Reopen,
+        };
+        Some(op)
+    }
+}
+
+// Seeded random keys and values, for filling trees with data whose contents don't matter. Keys are
+// `key` followed by a zero padded number, so that they sort in the order of their numbers.
+#[derive(Debug, Clone)]
+pub struct DataGenerator {
+    rng: SimRng,
+    value_size: usize,
+}
+
+impl DataGenerator {
+    pub fn new(seed: u64, value_size: usize) -> Self {
+        DataGenerator {
+            rng: SimRng::new(seed),
+            value_size,
+        }
+    }
+
+    // the `i`th key.
+    pub fn key(i: u64) -> String {
+        format!("key{:010}", i)
+    }
+
+    // one of the first `keys` keys, at random.
+    pub fn random_key(&mut self, keys: u64) -> String {
+        Self::key(self.rng.below(keys))
+    }
+
+    // `value_size` random lowercase letters.
+    pub fn value(&mut self) -> String {
+        (0..self.value_size)
+            .map(|_| (b'a' + self.rng.below(26) as u8) as char)
+            .collect()
+    }
+
+    // the first `n` keys in order with random values, like the writes of an append only workload.
+    pub fn sequential(&mut self, n: u64) -> Vec<(String, String)> {
+        (0..n).map(|i| (Self::key(i), self.value())).collect()
+    }
+
+    // `n` entries with keys drawn at random from the first `keys` keys, so that the more entries
+    // there are for the keys, the more of them overwrite an earlier one.
+    pub fn random(&mut self, n: u64, keys: u64) -> Vec<(String, String)> {
+        (0..n)
+            .map(|_| (self.random_key(keys), self.value()))
+            .collect()
+    }
+}
+
+// Runs operations against a tree and a `Model`, checking that the tree's reads match the model
+// after each one.
+pub struct ModelChecker {
+    data_dir: PathBuf,
+    options: Options,
+    // `None` only while the tree is reopened.
+    tree: Option<LSMTree>,
+    model: Model,
+    // every key ever written, so that reads of deleted keys are checked too.
+    written: BTreeSet<String>,
+    steps: usize,
+}
+
+impl ModelChecker {
+    // opens the tree in `data_dir`, which must be empty to match the empty model.
+    pub fn new(data_dir: impl AsRef<Path>, options: Options) -> Self {
+        let data_dir = data_dir.as_ref().to_path_buf();
+        let tree = LSMTree::open_with_options(&data_dir, options.clone());
+        ModelChecker {
+            data_dir,
+            options,
+            tree: Some(tree),
+            model: Model::default(),
+            written: BTreeSet::new(),
+            steps: 0,
+        }
+    }
+
+    pub fn tree(&self) -> &LSMTree {
+        self.tree.as_ref().unwrap()
+    }
+
+    // the tree, for operations the checker doesn't know about. Any change they make to the
+    // contents of the tree must be made to `model_mut` too.
+    pub fn tree_mut(&mut self) -> &mut LSMTree {
+        self.tree.as_mut().unwrap()
+    }
+
+    pub fn model(&self) -> &Model {
+        &self.model
+    }
+
+    pub fn model_mut(&mut self) -> &mut Model {
+        &mut self.model
+    }
+
+    // runs `ops`, stopping at the first one after which the tree and the model disagree. Returns
+    // the number of operations run.
+    pub fn run(&mut self, ops: impl IntoIterator<Item = Op>) -> Result<usize, String> {
+        let mut ran = 0;
+        for op in ops {
+            self.apply(&op)?;
+            ran += 1;
+        }
+        Ok(ran)
+    }
+
+    // runs `op` against the tree and the model, then compares them.
+    pub fn apply(&mut self, op: &Op) -> Result<(), String> {
+        self.steps += 1;
+        let tree = self.tree.as_mut().unwrap();
+        match op {
+            Op::Put { key, value } => {
+                if let Err(e) = tree.put(key, value) {
+                    return Err(self.error(op, e));
+                }
+            }
+            Op::Delete { key } => tree.delete(key),
+            Op::Flush => tree.flush_memtable(),
+            Op::Compact => tree.compact_now(),
+            Op::Reopen => {
+                let tree = self.tree.take().unwrap();
+                tree.close().map_err(|e| self.error(op, e))?;
+                let tree = LSMTree::open_with_options(&self.data_dir, self.options.clone());
+                self.tree = Some(tree);
+            }
+        }
+        if let Op::Put { key, .. } | Op::Delete { key } = op {
+            self.written.insert(key.clone());
+        }
+        self.model.apply(op);
+        self.check().map_err(|e| self.error(op, e))
+    }
+
+    // compares the reads of the tree with the model: lookups of every key ever written, full and
+    // partial scans, and counts.
+    pub fn check(&self) -> Result<(), String> {
+        let tree = self.tree();
+        let entries = &self.model.entries;
+        for key in &self.written {
+            let found = tree.get(key);
+            if found.as_ref() != entries.get(key) {
+                return Err(format!(
+                    "get({}) returned {:?}, expected {:?}",
+                    key,
+                    found,
+                    entries.get(key)
+                ));
+            }
+        }
+
+        let scanned: Vec<(String, String)> = tree.scan(..).collect();
+        let expected: Vec<(String, String)> = entries.clone().into_iter().collect();
+        if scanned != expected {
+            return Err(format!(
+                "scan(..) returned {:?}, expected {:?}",
+                scanned, expected
+            ));
+        }
+
+        // a range starting and ending in the middle of the written keys, bounds included or not.
+        let written: Vec<&String> = self.written.iter().collect();
+        let (start, end) = (written.len() / 4, written.len() * 3 / 4);
+        if let (Some(start), Some(end)) = (written.get(start), written.get(end)) {
+            let keys: Vec<String> = tree.keys(start.as_str()..end.as_str()).collect();
+            let expected: Vec<String> = entries
+                .range::<String, _>(*start..*end)
+                .map(|(k, _)| k.clone())
+                .collect();
+            if keys != expected {
+                return Err(format!(
+                    "keys({}..{}) returned {:?}, expected {:?}",
+                    start, end, keys, expected
+                ));
+            }
+            let count = tree.count_range(start.as_str()..=end.as_str());
+            let expected = entries.range::<String, _>(*start..=*end).count();
+            if count != expected {
+                return Err(format!(
+                    "count_range({}..={}) returned {}, expected {}",
+                    start, end, count, expected
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&self, op: &Op, e: impl ToString) -> String {
+        format!("step {} ({:?}): {}", self.steps, op, e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{DataGenerator, ModelChecker, Op, OpGenerator};
+    use crate::{Options, sim::SimStorage};
+
+    fn options(storage: &SimStorage) -> Options {
+        Options {
+            storage: Arc::new(storage.clone()),
+            memtable_limit: 8,
+            compaction_trigger: 4,
+            blob_threshold: Some(64),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_model_checker_random_ops() {
+        for seed in 0..5 {
+            let storage = SimStorage::new(seed, Default::default());
+            let mut checker = ModelChecker::new("data", options(&storage));
+            let ops = OpGenerator::new(seed, 30).take(400);
+            assert_eq!(checker.run(ops), Ok(400), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_model_checker_catches_divergence() {
+        let storage = SimStorage::new(1, Default::default());
+        let mut checker = ModelChecker::new("data", options(&storage));
+        let put = Op::Put {
+            key: "a".to_string(),
+            value: "1".to_string(),
+        };
+        checker.apply(&put).unwrap();
+
+        // a write the model doesn't know about.
+        checker.tree_mut().put("a", "2").unwrap();
+        let error = checker.apply(&Op::Reopen).unwrap_err();
+        assert!(error.contains("get(a) returned Some(\"2\")"), "{}", error);
+
+        checker
+            .model_mut()
+            .entries
+            .insert("a".to_string(), "2".to_string());
+        assert_eq!(checker.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_data_generator_is_seeded() {
+        let mut generator = DataGenerator::new(7, 16);
+        let sequential = generator.sequential(100);
+        assert!(sequential.is_sorted());
+        assert_eq!(sequential[42].0, "key0000000042");
+        assert!(sequential.iter().all(|(_, v)| v.len() == 16));
+
+        let random = generator.random(1000, 50);
+        assert!(random.iter().all(|(k, _)| *k < DataGenerator::key(50)));
+        assert!(!random.is_sorted());
+        let mut again = DataGenerator::new(7, 16);
+        again.sequential(100);
+        assert_eq!(again.random(1000, 50), random);
+    }
+}
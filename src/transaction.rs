@@ -0,0 +1,327 @@
+//! Pessimistic transactions: writes that take a lock on each key they touch, so that transactions
+//! writing the same keys wait for each other instead of overwriting each other's changes.
+//!
+//! A `TransactionalLsmTree` shares a tree between threads, each of which runs its own
+//! `Transaction`s. A transaction locks a key the first time it writes it, or reads it with
+//! `get_for_update`, and holds every lock until it commits or rolls back. Its writes are staged in
+//! a `WriteBatchWithIndex`, which reads them back on top of the tree, and the commit applies all of
+//! them at once, as a single batch record in the log that a crash keeps all or none of:
+//!
+//!   txn 1:  put a  put b  ----------------------------------  commit -> log: [a, b] -> unlock a, b
+//!   txn 2:         put c  put a (waits for txn 1 ...........) locks a  put d  commit
+//!
+//! A transaction waits for a lock held by another one for up to the lock timeout, then gives up
+//! with `Error::TimedOut`. Before waiting, it follows the chain of transactions waiting for each
+//! other from the lock's holder, and gives up with `Error::Deadlock` right away if the chain leads
+//! back to itself, as it would never end otherwise:
+//!
+//!   txn 1 holds a, waits for b  ->  txn 2 holds b, wants a: deadlock
+//!
+//! A transaction that failed keeps its locks and staged writes, it's up to the caller to roll it
+//! back, or drop it which does the same, and retry. Only writes made through transactions take
+//! locks: writes made to the tree directly, e.g. before it was shared, don't wait for any.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Condvar, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{Error, LSMTree, WriteBatchWithIndex};
+
+// The keys locked by transactions, and which transaction waits for which.
+#[derive(Default)]
+struct LockTable {
+    // the id of the transaction holding the lock of each locked key.
+    owners: HashMap<String, u64>,
+    // the id of the transaction each waiting transaction waits for.
+    waits_for: HashMap<u64, u64>,
+}
+
+#[derive(Default)]
+struct LockManager {
+    table: Mutex<LockTable>,
+    // notified whenever locks are released.
+    released: Condvar,
+}
+
+impl LockManager {
+    // locks `key` for the transaction `txn`, waiting up to `timeout` for another transaction to
+    // release it.
+    fn lock(&self, txn: u64, key: &str, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut table = self.table.lock().unwrap();
+        loop {
+            let owner = match table.owners.get(key) {
+                Some(&owner) if owner != txn => owner,
+                Some(_) => return Ok(()),
+                None => {
+                    table.owners.insert(key.to_string(), txn);
+                    table.waits_for.remove(&txn);
+                    return Ok(());
+                }
+            };
+            let mut next = Some(owner);
+            while let Some(waiting) = next {
+                if waiting == txn {
+                    table.waits_for.remove(&txn);
+                    return Err(Error::Deadlock);
+                }
+                next = table.waits_for.get(&waiting).copied();
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                table.waits_for.remove(&txn);
+                return Err(Error::TimedOut);
+            }
+            table.waits_for.insert(txn, owner);
+            table = self.released.wait_timeout(table, deadline - now).unwrap().0;
+        }
+    }
+
+    // releases the locks of `keys` held by the transaction `txn`.
+    fn unlock(&self, txn: u64, keys: impl IntoIterator<Item = String>) {
+        let mut table = self.table.lock().unwrap();
+        for key in keys {
+            if table.owners.get(&key) == Some(&txn) {
+                table.owners.remove(&key);
+            }
+        }
+        table.waits_for.remove(&txn);
+        self.released.notify_all();
+    }
+}
+
+// An LSM Tree shared between threads that write to it through transactions.
+pub struct TransactionalLsmTree {
+    tree: RwLock<LSMTree>,
+    locks: LockManager,
+    lock_timeout: Duration,
+    next_txn: AtomicU64,
+}
+
+impl TransactionalLsmTree {
+    // takes over `tree`, for transactions that wait up to `lock_timeout` for each lock.
+    pub fn new(tree: LSMTree, lock_timeout: Duration) -> Self {
+        TransactionalLsmTree {
+            tree: RwLock::new(tree),
+            locks: LockManager::default(),
+            lock_timeout,
+            next_txn: AtomicU64::new(1),
+        }
+    }
+
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction {
+            db: self,
+            id: self.next_txn.fetch_add(1, Ordering::Relaxed),
+            writes: WriteBatchWithIndex::new(),
+            locked: Vec::new(),
+        }
+    }
+
+    // returns the committed value of `k`, without taking a lock.
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.tree.read().unwrap().get(k)
+    }
+
+    // returns the tree, once no transaction runs anymore.
+    pub fn into_inner(self) -> LSMTree {
+        self.tree.into_inner().unwrap()
+    }
+}
+
+// A transaction on a `TransactionalLsmTree`, see `transaction.rs`. Dropping it rolls it back.
+pub struct Transaction<'a> {
+    db: &'a TransactionalLsmTree,
+    id: u64,
+    // the writes staged.
+    writes: WriteBatchWithIndex,
+    // the keys locked so far.
+    locked: Vec<String>,
+}
+
+impl Transaction<'_> {
+    // stages the write of `v` to `k`, once it has the lock of `k`.
+    pub fn put(&mut self, k: &str, v: &str) -> Result<(), Error> {
+        self.db.tree.read().unwrap().check_size(k, v.len())?;
+        self.lock(k)?;
+        self.writes.put(k, v);
+        Ok(())
+    }
+
+    // stages the delete of `k`, once it has the lock of `k`.
+    pub fn delete(&mut self, k: &str) -> Result<(), Error> {
+        self.lock(k)?;
+        self.writes.delete(k);
+        Ok(())
+    }
+
+    // returns the value of `k` as the transaction sees it: its own write of it, if any, else the
+    // committed value, which another transaction may change before this one commits.
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.writes.get(&self.db.tree.read().unwrap(), k)
+    }
+
+    // returns the value of `k` like `get`, once it has the lock of `k`, so that no other
+    // transaction can change it until this one is done.
+    pub fn get_for_update(&mut self, k: &str) -> Result<Option<String>, Error> {
+        self.lock(k)?;
+        Ok(self.get(k))
+    }
+
+    // applies the staged writes to the tree all at once, and releases the locks. None of them is
+    // applied if any is over the tree's size limits, which may have been lowered since it was
+    // staged.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let writes = std::mem::take(&mut self.writes).into_batch();
+        let mut tree = self.db.tree.write().unwrap();
+        tree.write_batch(writes)
+    }
+
+    // discards the staged writes and releases the locks, like dropping the transaction does.
+    pub fn rollback(self) {}
+
+    fn lock(&mut self, k: &str) -> Result<(), Error> {
+        if self.locked.iter().any(|l| l == k) {
+            return Ok(());
+        }
+        self.db.locks.lock(self.id, k, self.db.lock_timeout)?;
+        self.locked.push(k.to_string());
+        Ok(())
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        self.db.locks.unlock(self.id, self.locked.drain(..));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    use super::TransactionalLsmTree;
+    use crate::{Error, LSMTree, Options, sim::SimStorage};
+
+    fn open(lock_timeout: Duration) -> TransactionalLsmTree {
+        let storage = SimStorage::new(1, Default::default());
+        let options = Options {
+            storage: Arc::new(storage),
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options("data", options);
+        TransactionalLsmTree::new(tree, lock_timeout)
+    }
+
+    #[test]
+    fn test_transaction_commits_all_writes_or_none() {
+        let db = open(Duration::from_secs(10));
+        let mut txn = db.begin();
+        txn.put("a", "1").unwrap();
+        txn.put("b", "1").unwrap();
+        txn.delete("b").unwrap();
+        assert_eq!(txn.get("a"), Some("1".to_string()));
+        assert_eq!(txn.get("b"), None);
+        assert_eq!(db.get("a"), None);
+        txn.commit().unwrap();
+        assert_eq!(db.get("a"), Some("1".to_string()));
+
+        let mut txn = db.begin();
+        assert_eq!(txn.get_for_update("a"), Ok(Some("1".to_string())));
+        txn.put("a", "2").unwrap();
+        txn.rollback();
+        assert_eq!(db.get("a"), Some("1".to_string()));
+
+        // the locks of the rolled back transaction were released.
+        let mut txn = db.begin();
+        txn.put("a", "3").unwrap();
+        txn.commit().unwrap();
+        let tree = db.into_inner();
+        assert_eq!(tree.get("a"), Some("3".to_string()));
+        // every write of the first transaction was applied, the delete of b included.
+        assert_eq!(tree.last_seq, 4);
+    }
+
+    #[test]
+    fn test_transaction_commit_fails_on_writes_over_the_size_limits() {
+        let db = open(Duration::from_secs(10));
+        let mut txn = db.begin();
+        txn.put("a", "1").unwrap();
+        txn.put("b", "12345").unwrap();
+        db.tree
+            .write()
+            .unwrap()
+            .set_option("max_value_size", "4")
+            .unwrap();
+        assert!(matches!(txn.commit(), Err(Error::ValueTooLarge { .. })));
+        assert_eq!(db.get("a"), None);
+
+        // the locks of the failed transaction were released.
+        let mut txn = db.begin();
+        txn.put("a", "2").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.get("a"), Some("2".to_string()));
+    }
+
+    #[test]
+    fn test_transaction_waits_for_locks_until_timeout() {
+        let db = open(Duration::from_millis(50));
+        let mut first = db.begin();
+        first.put("a", "1").unwrap();
+        let mut second = db.begin();
+        assert_eq!(second.put("a", "2"), Err(Error::TimedOut));
+        assert_eq!(second.get_for_update("a"), Err(Error::TimedOut));
+        second.put("b", "2").unwrap();
+
+        // a lock released while waiting is taken over.
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                first.commit().unwrap();
+            });
+            let mut third = db.begin();
+            third.put("a", "3").unwrap();
+            third.commit().unwrap();
+        });
+        second.commit().unwrap();
+        assert_eq!(db.get("a"), Some("3".to_string()));
+        assert_eq!(db.get("b"), Some("2".to_string()));
+    }
+
+    #[test]
+    fn test_transaction_detects_deadlock() {
+        let db = open(Duration::from_secs(10));
+        let barrier = Barrier::new(2);
+        // locks one key, then the other one, which the other transaction locked in the meantime.
+        let run = |first: &str, second: &str, value: &str| {
+            let mut txn = db.begin();
+            txn.put(first, value).unwrap();
+            barrier.wait();
+            txn.put(second, value)?;
+            txn.commit()
+        };
+        let (a, b) = thread::scope(|s| {
+            let a = s.spawn(|| run("a", "b", "1"));
+            let b = s.spawn(|| run("b", "a", "2"));
+            (a.join().unwrap(), b.join().unwrap())
+        });
+        // whichever started waiting last finds the deadlock, and the other one goes on once its
+        // locks are released.
+        let winner = match (a, b) {
+            (Ok(()), Err(Error::Deadlock)) => "1",
+            (Err(Error::Deadlock), Ok(())) => "2",
+            results => panic!("{:?}", results),
+        };
+        assert_eq!(db.get("a").as_deref(), Some(winner));
+        assert_eq!(db.get("b").as_deref(), Some(winner));
+    }
+}
@@ -0,0 +1,111 @@
+//! Variable length integer encoding shared by every binary format in the tree.
+//!
+//! Most lengths and sequence numbers are small, yet a fixed width u64 always takes 8 bytes. A varint
+//! stores 7 bits of the number per byte, using the high bit of each byte to mark that more bytes
+//! follow. Numbers below 128 take a single byte, below 16384 two bytes, and so on.
+//!
+//!   300 = 0b10_0101100 -> | 1_0101100 | 0_0000010 |
+
+// the longest encoding of a u64 is 10 bytes (64 bits / 7 bits per byte, rounded up).
+pub(crate) const MAX_VARINT_LEN: usize = 10;
+
+// appends `v` to `buf` as a varint.
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+// appends `bytes` to `buf`, prefixed by its length as a varint.
+pub(crate) fn put_length_prefixed(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+// A cursor for decoding values from a byte slice.
+// Every method returns `None` if the data ends early or is malformed, instead of panicking,
+// so callers can decide how to treat truncated input.
+pub(crate) struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Decoder { data, pos: 0 }
+    }
+
+    // number of bytes consumed so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    // the bytes not yet consumed.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    pub fn varint(&mut self) -> Option<u64> {
+        let mut v = 0u64;
+        for i in 0..MAX_VARINT_LEN {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            v |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Some(v);
+            }
+        }
+        // more than 10 continuation bytes can't be a valid u64.
+        None
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    pub fn length_prefixed(&mut self) -> Option<&'a [u8]> {
+        let len = self.varint()?;
+        self.bytes(usize::try_from(len).ok()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decoder, put_length_prefixed, put_varint};
+
+    #[test]
+    fn test_varint_roundtrip() {
+        let values = [0, 1, 127, 128, 300, 16383, 16384, u32::MAX as u64, u64::MAX];
+        let mut buf = Vec::new();
+        for v in values {
+            put_varint(&mut buf, v);
+        }
+        // small numbers only take a single byte.
+        assert_eq!(buf[..3], [0, 1, 127]);
+
+        let mut decoder = Decoder::new(&buf);
+        for v in values {
+            assert_eq!(decoder.varint(), Some(v));
+        }
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn test_varint_truncated_input() {
+        let mut buf = Vec::new();
+        put_length_prefixed(&mut buf, b"hello");
+        assert_eq!(Decoder::new(&buf).length_prefixed(), Some(&b"hello"[..]));
+
+        // cut off in the middle of the payload, and in the middle of a multi byte varint.
+        assert_eq!(Decoder::new(&buf[..4]).length_prefixed(), None);
+        assert_eq!(Decoder::new(&[0x80]).varint(), None);
+    }
+}
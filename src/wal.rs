@@ -0,0 +1,834 @@
+//! The write-ahead log (WAL), which makes writes durable before they reach an sstable.
+//!
+//! The memtable lives in memory, so without a log every write since the last flush would be lost on
+//! a crash. Instead, every write is appended and synced to the current WAL segment before it's
+//! applied to the memtable, and on open the segments are replayed to rebuild the memtable. Once a
+//! memtable is flushed, its segments aren't needed anymore and are removed, or moved to an archive
+//! when one is configured. An archived segment gets a `<id>.archived` file next to it, holding when
+//! it was archived in milliseconds since the Unix epoch, which the archive's `max_age` goes by.
+//!
+//! Segments are named `<id>.log`. Each starts with a header holding a magic number and the id of
+//! the codec its records are encoded with, the one of `Options::record_format` when the segment was
+//! created, see `codec.rs`, and every record after it is framed as:
+//!
+//!   header: | magic (u64) | codec id (u8) |
+//!   record: | crc32 (u32) | payload length (u32) | payload |
+//!
+//! where the payload is the sequence number, the key and the value (or a deletion marker, or a pointer
+//! to a value streamed into a blob file by `LSMTree::put_reader`), encoded as an entry by the codec.
+//! Segments written before they had a header are in `BinaryCodec`. A group of writes appended at
+//! once, e.g. a committed transaction, is framed as a single batch record, whose payload starts
+//! with a 0 byte, which no entry does, followed by the number of writes and their payloads. A crash
+//! in the middle of an append leaves a partial record at the end of the last segment. Its checksum
+//! won't match, so recovery can tell where the valid records end, and keeps all writes of a batch
+//! or none of them. A crash right after a segment was created may leave it with part of its header
+//! only, and no records.
+//!
+//! `WalReader` reads the segments of a data or archive directory without opening the tree, for
+//! tools that audit recent writes or replicate them elsewhere.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    Record, Value, blob,
+    checksum::crc32,
+    clock::Clock,
+    codec::{self, BinaryCodec, RecordCodec},
+    options::{WalArchiveOptions, WalRecoveryMode},
+    recovery::{RecoveryPhase, RecoveryReporter},
+    storage::{FsStorage, Storage, WritableFile},
+    varint::{Decoder, put_length_prefixed, put_varint},
+};
+
+// size of the crc and length in front of every record.
+const HEADER_SIZE: usize = 8;
+
+// the sequence number a batch record starts with.
+const BATCH: u64 = 0;
+
+// the magic number segments start with, followed by the id of the codec of their records.
+const SEGMENT_MAGIC: u64 = 0x6c6f_6773_6567_6d31;
+
+// size of the magic number and codec id at the start of a segment.
+const SEGMENT_HEADER_SIZE: usize = 9;
+
+// The log the tree appends its writes to.
+pub(crate) struct Wal {
+    storage: Arc<dyn Storage>,
+    dir: PathBuf,
+    // id of the segment being written to, every older segment belongs to the same memtable.
+    segment_id: u64,
+    file: Box<dyn WritableFile>,
+    // the codec writes are encoded with, see `codec.rs`.
+    codec: &'static dyn RecordCodec,
+    archive: Option<WalArchiveOptions>,
+    // the clock the age of archived segments is measured by, see `clock.rs`.
+    clock: Arc<dyn Clock>,
+}
+
+impl Wal {
+    // replays the segments in `dir` and starts a new segment for the writes to come, whose records
+    // are encoded with `codec`. Returns the log along with the latest record of every key found in
+    // the segments.
+    pub fn open(
+        storage: Arc<dyn Storage>,
+        dir: &Path,
+        mode: WalRecoveryMode,
+        codec: &'static dyn RecordCodec,
+        archive: Option<WalArchiveOptions>,
+        clock: Arc<dyn Clock>,
+        recovery: &RecoveryReporter,
+    ) -> io::Result<(Wal, BTreeMap<String, Record>)> {
+        if let Some(archive) = &archive {
+            storage.create_dir_all(&archive.dir)?;
+        }
+
+        let (records, last_segment_id) = replay(&*storage, dir, mode, true, recovery)?;
+        let segment_id = last_segment_id + 1;
+        let file = create_segment(&*storage, dir, segment_id, codec)?;
+        let wal = Wal {
+            storage,
+            dir: dir.to_path_buf(),
+            segment_id,
+            file,
+            codec,
+            archive,
+            clock,
+        };
+        Ok((wal, records))
+    }
+
+    // replays the segments in `dir` like `open`, but leaves them as they are: a partial record at
+    // the end of the last segment is skipped rather than cut off. For trees opened read-only.
+    pub fn read(
+        storage: &dyn Storage,
+        dir: &Path,
+        mode: WalRecoveryMode,
+        recovery: &RecoveryReporter,
+    ) -> io::Result<BTreeMap<String, Record>> {
+        replay(storage, dir, mode, false, recovery).map(|(records, _)| records)
+    }
+
+    // durably appends a write of `key` to the log.
+    pub fn append(&mut self, key: &str, record: &Record) -> io::Result<()> {
+        self.append_group(&[(key, record)])
+    }
+
+    // durably and atomically appends several writes to the log, as one batch record with a single
+    // sync for all of them. A crash in the middle of it loses all of them.
+    pub fn append_group(&mut self, writes: &[(&str, &Record)]) -> io::Result<()> {
+        let payload = match writes {
+            [(key, record)] => self.record_payload(key, record),
+            _ => {
+                let mut payload = Vec::new();
+                put_varint(&mut payload, BATCH);
+                put_varint(&mut payload, writes.len() as u64);
+                for (key, record) in writes {
+                    put_length_prefixed(&mut payload, &self.record_payload(key, record));
+                }
+                payload
+            }
+        };
+        let mut frame = Vec::new();
+        put_frame(&mut frame, &payload);
+        self.file.write_all(&frame)?;
+        self.file.sync()
+    }
+
+    // the payload of the record of a write of `key`.
+    fn record_payload(&self, key: &str, record: &Record) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.codec.encode_entry(key, record, &mut payload);
+        payload
+    }
+
+    // starts a new segment for the writes to come, once the memtable the current one belongs to is
+    // being flushed. Unlike on open, the directory isn't synced: the flush syncs it along with its
+    // sstable, before the manifest lists the sstable and before anything is appended to the segment.
+    pub fn start_segment(&mut self) -> io::Result<()> {
+        let segment_id = self.segment_id + 1;
+        let mut file = (self.storage).create(&self.dir.join(format!("{}.log", segment_id)))?;
+        file.write_all(&segment_header(self.codec))?;
+        self.file = file;
+        self.segment_id = segment_id;
+        Ok(())
+    }
+
+    // removes or archives the segments older than the current one, once the memtable they hold is
+    // flushed.
+    pub fn remove_flushed_segments(&mut self) -> io::Result<()> {
+        let mut removed = false;
+        for (id, path) in segments(&*self.storage, &self.dir)? {
+            if id >= self.segment_id {
+                continue;
+            }
+            removed = true;
+            match &self.archive {
+                Some(archive) => {
+                    let archived = archive.dir.join(path.file_name().unwrap());
+                    self.storage.rename(&path, &archived)?;
+                    self.record_archived_at(&archived)?;
+                }
+                None => self.storage.remove(&path)?,
+            }
+        }
+
+        // a segment that came back after a crash would be replayed over newer sstables.
+        if removed {
+            self.storage.sync_dir(&self.dir)?;
+        }
+        if let Some(archive) = &self.archive {
+            self.storage.sync_dir(&archive.dir)?;
+            self.apply_retention(archive)?;
+        }
+        Ok(())
+    }
+
+    // removes the archived segments that are too old, then the oldest ones until the archive fits
+    // within its size limit.
+    fn apply_retention(&self, archive: &WalArchiveOptions) -> io::Result<()> {
+        let mut total_size = 0;
+        let mut kept = Vec::new();
+        let mut removed = false;
+        for (_, path) in segments(&*self.storage, &archive.dir)? {
+            let age = self.archived_age(&path)?;
+            if archive.max_age.is_some_and(|max_age| age > max_age) {
+                self.remove_archived(&path)?;
+                removed = true;
+                continue;
+            }
+            let size = self.storage.len(&path)?;
+            total_size += size;
+            kept.push((path, size));
+        }
+
+        if let Some(max_size) = archive.max_size {
+            for (path, size) in kept {
+                if total_size <= max_size {
+                    break;
+                }
+                self.remove_archived(&path)?;
+                total_size -= size;
+                removed = true;
+            }
+        }
+        if removed {
+            self.storage.sync_dir(&archive.dir)?;
+        }
+        Ok(())
+    }
+
+    // records when the segment just moved to `archived` was archived, by the tree's clock, in a
+    // file next to it.
+    fn record_archived_at(&self, archived: &Path) -> io::Result<()> {
+        let since_epoch = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut file = self.storage.create(&archived_at_path(archived))?;
+        file.write_all(since_epoch.as_millis().to_string().as_bytes())?;
+        file.sync()
+    }
+
+    // how long ago the archived segment at `path` was archived, by the tree's clock. A segment
+    // without a record of it, e.g. archived before a crash that lost the record, goes by its
+    // modification time and the storage's clock instead.
+    fn archived_age(&self, path: &Path) -> io::Result<Duration> {
+        let read_millis = || -> Option<u64> {
+            let mut contents = String::new();
+            let mut file = self.storage.open(&archived_at_path(path)).ok()?;
+            file.read_to_string(&mut contents).ok()?;
+            contents.parse().ok()
+        };
+        let (now, archived_at) = match read_millis() {
+            Some(millis) => (self.clock.now(), UNIX_EPOCH + Duration::from_millis(millis)),
+            None => (self.storage.now(), self.storage.modified(path)?),
+        };
+        Ok(now.duration_since(archived_at).unwrap_or_default())
+    }
+
+    // removes an archived segment along with the record of when it was archived.
+    fn remove_archived(&self, path: &Path) -> io::Result<()> {
+        self.storage.remove(path)?;
+        let archived_at = archived_at_path(path);
+        if self.storage.exists(&archived_at) {
+            self.storage.remove(&archived_at)?;
+        }
+        Ok(())
+    }
+}
+
+// the path of the file recording when the archived segment at `path` was archived.
+fn archived_at_path(path: &Path) -> PathBuf {
+    path.with_extension("archived")
+}
+
+// reads the segments in `dir`, returning the latest record of every key in them and the id of the
+// last segment, 0 if there are none. With `repair`, a partial record at the end of the last segment
+// is cut off. Reports the bytes replayed after every segment.
+fn replay(
+    storage: &dyn Storage,
+    dir: &Path,
+    mode: WalRecoveryMode,
+    repair: bool,
+    recovery: &RecoveryReporter,
+) -> io::Result<(BTreeMap<String, Record>, u64)> {
+    let segments = segments(storage, dir)?;
+    let mut total = 0;
+    for (_, path) in &segments {
+        total += storage.len(path)?;
+    }
+    let mut replayed = 0;
+    recovery.advance(RecoveryPhase::ReplayWal, replayed, total);
+    let mut records = BTreeMap::new();
+    for (i, (_, path)) in segments.iter().enumerate() {
+        let is_last = i == segments.len() - 1;
+        let mut data = Vec::new();
+        storage.open(path)?.read_to_end(&mut data)?;
+        replayed += data.len() as u64;
+        recovery.advance(RecoveryPhase::ReplayWal, replayed, total);
+
+        let (segment_records, valid_len) = decode_segment(&data).map_err(|e| invalid(path, &e))?;
+        records.extend(segment_records);
+        if valid_len < data.len() {
+            // only the last segment can have been cut short by a crash, anything else is damage.
+            if mode == WalRecoveryMode::Strict || !is_last {
+                let message = format!("corrupt wal record at offset {}", valid_len);
+                return Err(invalid(path, &message));
+            }
+            // drop the partial record, so it isn't mistaken for corruption once more segments
+            // follow this one.
+            if repair {
+                storage.truncate(path, valid_len as u64)?;
+            }
+        }
+    }
+    recovery.finish(RecoveryPhase::ReplayWal, total);
+    Ok((records, segments.last().map_or(0, |(id, _)| *id)))
+}
+
+// appends `payload` to `out`, framed with its checksum and length.
+fn put_frame(out: &mut Vec<u8>, payload: &[u8]) {
+    out.extend_from_slice(&crc32(payload).to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+fn create_segment(
+    storage: &dyn Storage,
+    dir: &Path,
+    id: u64,
+    codec: &dyn RecordCodec,
+) -> io::Result<Box<dyn WritableFile>> {
+    let mut file = storage.create(&dir.join(format!("{}.log", id)))?;
+    file.write_all(&segment_header(codec))?;
+    // the segment must still be there after a crash, for the records synced to it to be.
+    storage.sync_dir(dir)?;
+    Ok(file)
+}
+
+// returns the ids and paths of the wal segments in `dir`, oldest first.
+fn segments(storage: &dyn Storage, dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments: Vec<(u64, PathBuf)> = storage
+        .list(dir)?
+        .into_iter()
+        .filter(|p| p.extension().is_some_and(|ext| ext == "log"))
+        .filter_map(|p| Some((p.file_stem()?.to_str()?.parse().ok()?, p)))
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+// the header a segment whose records are encoded with `codec` starts with.
+fn segment_header(codec: &dyn RecordCodec) -> Vec<u8> {
+    let mut header = SEGMENT_MAGIC.to_le_bytes().to_vec();
+    header.push(codec.id());
+    header
+}
+
+// reads the header at the start of a segment, returning the codec of its records and where they
+// start. Fails if the codec is unknown.
+fn read_header(data: &[u8]) -> Result<(&'static dyn RecordCodec, usize), String> {
+    let magic = SEGMENT_MAGIC.to_le_bytes();
+    let n = data.len().min(magic.len());
+    if data[..n] != magic[..n] {
+        // a segment written before segments had a header.
+        return Ok((&BinaryCodec, 0));
+    }
+    match data.get(magic.len()) {
+        Some(id) => match codec::codec(*id) {
+            Some(codec) => Ok((codec, SEGMENT_HEADER_SIZE)),
+            None => Err(format!("unknown record codec {}", id)),
+        },
+        // cut short within its header, so it has no records.
+        None => Ok((&BinaryCodec, data.len())),
+    }
+}
+
+// decodes the records of a segment, up to the first one that's incomplete or corrupt.
+// Returns them along with the length of the valid part of the segment.
+fn decode_segment(data: &[u8]) -> Result<(Vec<(String, Record)>, usize), String> {
+    let (codec, mut offset) = read_header(data)?;
+    let mut records = Vec::new();
+    while let Some((frame_records, len)) = decode_frame(codec, &data[offset..]) {
+        records.extend(frame_records);
+        offset += len;
+    }
+    Ok((records, offset))
+}
+
+// decodes the record at the start of `data`, returning the writes in it with the length of its
+// frame.
+fn decode_frame(codec: &dyn RecordCodec, data: &[u8]) -> Option<(Vec<(String, Record)>, usize)> {
+    let header = data.get(..HEADER_SIZE)?;
+    let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    let payload = data.get(HEADER_SIZE..HEADER_SIZE.checked_add(len)?)?;
+    if crc32(payload) != crc {
+        return None;
+    }
+
+    let mut decoder = Decoder::new(payload);
+    if decoder.varint()? != BATCH {
+        return Some((vec![codec.decode_entry(payload).ok()?], HEADER_SIZE + len));
+    }
+    let count = decoder.varint()?;
+    let mut records = Vec::new();
+    for _ in 0..count {
+        records.push(codec.decode_entry(decoder.length_prefixed()?).ok()?);
+    }
+    Some((records, HEADER_SIZE + len))
+}
+
+// A write read back from the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalRecord {
+    pub seq: u64,
+    pub key: String,
+    pub op: WalOp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalOp {
+    Put(String),
+    Delete,
+}
+
+impl WalRecord {
+    // converts a record read from a segment in `dir`. A value streamed into a blob file is read
+    // from the blob file in the same directory, which fails once garbage collection removed it, or
+    // if the segment was moved to an archive without it.
+    fn read(storage: &dyn Storage, dir: &Path, key: String, record: Record) -> io::Result<Self> {
+        let op = match record.value {
+            Some(Value::Inline(value)) => WalOp::Put(value),
+            Some(Value::Blob(pointer)) => {
+                let path = blob::blob_path(dir, pointer.file_id);
+                WalOp::Put(blob::read_value(storage, &path, &pointer)?)
+            }
+            None => WalOp::Delete,
+        };
+        Ok(WalRecord {
+            seq: record.seq,
+            key,
+            op,
+        })
+    }
+}
+
+// Reads the write-ahead log segments in a directory, either the data dir of a tree, which may be
+// open and still appending to it, or a wal archive.
+pub struct WalReader {
+    storage: Arc<dyn Storage>,
+    // the segments found when the reader was opened, oldest first.
+    segments: Vec<PathBuf>,
+}
+
+impl WalReader {
+    // opens the segments in `dir` on the local filesystem.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with_storage(Arc::new(FsStorage), dir)
+    }
+
+    // opens the segments in `dir` on the given `storage`.
+    pub fn open_with_storage(storage: Arc<dyn Storage>, dir: impl AsRef<Path>) -> io::Result<Self> {
+        let segments = segments(&*storage, dir.as_ref())?;
+        Ok(WalReader {
+            storage,
+            segments: segments.into_iter().map(|(_, path)| path).collect(),
+        })
+    }
+
+    // returns the records with a sequence number of `seq` or higher, in the order they were written.
+    // A segment that ends in a torn or corrupt record is read up to that record.
+    pub fn records_since(&self, seq: u64) -> WalRecords {
+        WalRecords {
+            storage: Arc::clone(&self.storage),
+            segments: self.segments.iter().cloned().collect(),
+            records: VecDeque::new(),
+            dir: PathBuf::new(),
+            since: seq,
+        }
+    }
+}
+
+// Iterator returned by `WalReader::records_since`, reading one segment at a time.
+pub struct WalRecords {
+    storage: Arc<dyn Storage>,
+    segments: VecDeque<PathBuf>,
+    // decoded records of the current segment, and the directory it's in.
+    records: VecDeque<(String, Record)>,
+    dir: PathBuf,
+    since: u64,
+}
+
+impl Iterator for WalRecords {
+    type Item = io::Result<WalRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, record)) = self.records.pop_front() {
+                if record.seq >= self.since {
+                    return Some(WalRecord::read(&*self.storage, &self.dir, key, record));
+                }
+                continue;
+            }
+
+            let path = self.segments.pop_front()?;
+            let mut data = Vec::new();
+            let read = self
+                .storage
+                .open(&path)
+                .and_then(|mut f| f.read_to_end(&mut data));
+            if let Err(e) = read {
+                return Some(Err(e));
+            }
+            let records = match decode_segment(&data) {
+                Ok((records, _)) => records,
+                Err(e) => return Some(Err(invalid(&path, &e))),
+            };
+            self.records = records.into();
+            self.dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        }
+    }
+}
+
+fn invalid(path: &Path, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        path::Path,
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::{SEGMENT_HEADER_SIZE, SEGMENT_MAGIC, Wal, WalOp, WalReader, WalRecord};
+    use crate::{
+        MockClock, Record, SystemClock, Value,
+        codec::{BinaryCodec, TextCodec},
+        options::{WalArchiveOptions, WalRecoveryMode},
+        recovery::RecoveryReporter,
+        sim::SimStorage,
+        storage::Storage,
+    };
+
+    fn record(seq: u64, value: Option<&str>) -> Record {
+        Record {
+            seq,
+            value: value.map(|v| Value::Inline(v.to_string())),
+        }
+    }
+
+    #[test]
+    fn test_wal_replays_records_and_drops_torn_tail() {
+        let storage = SimStorage::new(1, Default::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+
+        let mode = WalRecoveryMode::TolerateCorruptedTail;
+        let (mut wal, records) = Wal::open(
+            Arc::new(storage.clone()),
+            dir,
+            mode,
+            &BinaryCodec,
+            None,
+            Arc::new(SystemClock),
+            &RecoveryReporter::default(),
+        )
+        .unwrap();
+        assert!(records.is_empty());
+        wal.append("a", &record(1, Some("v1"))).unwrap();
+        wal.append("b", &record(2, Some("v1"))).unwrap();
+        wal.append("a", &record(3, None)).unwrap();
+        drop(wal);
+
+        // simulate a crash in the middle of appending a record, by cutting the last one short.
+        let segment = dir.join("1.log");
+        let mut data = Vec::new();
+        storage
+            .open(&segment)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        let mut file = storage.create(&segment).unwrap();
+        file.write_all(&data[..data.len() - 3]).unwrap();
+        file.sync().unwrap();
+        drop(file);
+
+        let strict = Wal::open(
+            Arc::new(storage.clone()),
+            dir,
+            WalRecoveryMode::Strict,
+            &BinaryCodec,
+            None,
+            Arc::new(SystemClock),
+            &RecoveryReporter::default(),
+        );
+        assert!(strict.is_err());
+
+        let (_, records) = Wal::open(
+            Arc::new(storage.clone()),
+            dir,
+            mode,
+            &BinaryCodec,
+            None,
+            Arc::new(SystemClock),
+            &RecoveryReporter::default(),
+        )
+        .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records["a"], record(1, Some("v1")));
+        assert_eq!(records["b"], record(2, Some("v1")));
+
+        // the torn record was truncated away, so the segment is valid now even in strict mode.
+        let (_, records) = Wal::open(
+            Arc::new(storage),
+            dir,
+            WalRecoveryMode::Strict,
+            &BinaryCodec,
+            None,
+            Arc::new(SystemClock),
+            &RecoveryReporter::default(),
+        )
+        .unwrap();
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn test_wal_segments_are_replayed_with_the_codec_in_their_header() {
+        let storage = SimStorage::new(1, Default::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+        let read = |segment: &str| {
+            let mut data = Vec::new();
+            let mut file = storage.open(&dir.join(segment)).unwrap();
+            file.read_to_end(&mut data).unwrap();
+            data
+        };
+        let write = |segment: &str, data: &[u8]| {
+            let mut file = storage.create(&dir.join(segment)).unwrap();
+            file.write_all(data).unwrap();
+            file.sync().unwrap();
+        };
+        let open = |codec| {
+            Wal::open(
+                Arc::new(storage.clone()),
+                dir,
+                WalRecoveryMode::Strict,
+                codec,
+                None,
+                Arc::new(SystemClock),
+                &RecoveryReporter::default(),
+            )
+            .unwrap()
+        };
+
+        let (mut wal, _) = open(&TextCodec);
+        wal.append("a", &record(1, Some("v1"))).unwrap();
+        let (b, c) = (record(2, Some("v1")), record(3, None));
+        wal.append_group(&[("b", &b), ("c", &c)]).unwrap();
+        drop(wal);
+        let data = read("1.log");
+        assert_eq!(data[..8], SEGMENT_MAGIC.to_le_bytes());
+        assert_eq!(data[8], 2);
+
+        // the codec can change from one open to the next, older segments keep theirs.
+        let (mut wal, records) = open(&BinaryCodec);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records["c"], record(3, None));
+        wal.append("a", &record(4, Some("v2"))).unwrap();
+        drop(wal);
+        assert_eq!(read("2.log")[8], 1);
+
+        // a segment from before segments had a header is in `BinaryCodec`, and one cut short
+        // within its header has no records.
+        let data = read("2.log");
+        write("2.log", &data[SEGMENT_HEADER_SIZE..]);
+        write("3.log", &SEGMENT_MAGIC.to_le_bytes()[..5]);
+        let (_, records) = open(&TextCodec);
+        assert_eq!(records["a"], record(4, Some("v2")));
+        assert_eq!(records["b"], record(2, Some("v1")));
+
+        // a codec it doesn't know makes the segment unreadable rather than truncated away.
+        let mut data = read("1.log");
+        data[8] = 255;
+        write("1.log", &data);
+        let result = Wal::open(
+            Arc::new(storage.clone()),
+            dir,
+            WalRecoveryMode::TolerateCorruptedTail,
+            &BinaryCodec,
+            None,
+            Arc::new(SystemClock),
+            &RecoveryReporter::default(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_wal_keeps_all_or_none_of_a_group() {
+        let storage = SimStorage::new(1, Default::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+        let open = || {
+            let mode = WalRecoveryMode::TolerateCorruptedTail;
+            let recovery = RecoveryReporter::default();
+            let clock = Arc::new(SystemClock);
+            Wal::open(
+                Arc::new(storage.clone()),
+                dir,
+                mode,
+                &BinaryCodec,
+                None,
+                clock,
+                &recovery,
+            )
+            .unwrap()
+        };
+
+        let (mut wal, _) = open();
+        wal.append("a", &record(1, Some("v1"))).unwrap();
+        let (b, c) = (record(2, Some("v1")), record(3, None));
+        wal.append_group(&[("b", &b), ("c", &c)]).unwrap();
+        drop(wal);
+        let reader = WalReader::open_with_storage(Arc::new(storage.clone()), dir).unwrap();
+        let seqs: Vec<u64> = (reader.records_since(2)).map(|r| r.unwrap().seq).collect();
+        assert_eq!(seqs, [2, 3]);
+
+        // a crash in the middle of appending the group loses all of it.
+        let segment = dir.join("1.log");
+        let len = storage.len(&segment).unwrap();
+        storage.truncate(&segment, len - 3).unwrap();
+        let (_, records) = open();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records["a"], record(1, Some("v1")));
+    }
+
+    #[test]
+    fn test_wal_archives_flushed_segments_with_retention() {
+        let storage = SimStorage::new(1, Default::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+        let archive_dir = Path::new("archive");
+        let archive = WalArchiveOptions {
+            dir: archive_dir.to_path_buf(),
+            max_age: Some(Duration::from_secs(60)),
+            max_size: Some(60),
+        };
+        // ages go by the injected clock, which is well ahead of the storage's, and the storage's
+        // stands still.
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+
+        let mode = WalRecoveryMode::TolerateCorruptedTail;
+        let (mut wal, _) = Wal::open(
+            Arc::new(storage.clone()),
+            dir,
+            mode,
+            &BinaryCodec,
+            Some(archive),
+            Arc::new(clock.clone()),
+            &RecoveryReporter::default(),
+        )
+        .unwrap();
+        // each segment holds its header of 9 bytes and a single record of 44 bytes.
+        for i in 0..3 {
+            wal.append("key", &record(i, Some(&"v".repeat(30))))
+                .unwrap();
+            wal.start_segment().unwrap();
+            wal.remove_flushed_segments().unwrap();
+            clock.advance(Duration::from_secs(40));
+        }
+
+        // only the latest segment is left in the data dir.
+        assert_eq!(storage.list(dir).unwrap(), vec![dir.join("4.log")]);
+        // the oldest segment was too old, and the archive couldn't hold the other two.
+        assert_eq!(
+            storage.list(archive_dir).unwrap(),
+            vec![archive_dir.join("3.archived"), archive_dir.join("3.log")]
+        );
+    }
+
+    #[test]
+    fn test_wal_reader_records_since() {
+        let storage = SimStorage::new(1, Default::default());
+        let dir = Path::new("data");
+        storage.create_dir_all(dir).unwrap();
+
+        let mode = WalRecoveryMode::TolerateCorruptedTail;
+        let (mut wal, _) = Wal::open(
+            Arc::new(storage.clone()),
+            dir,
+            mode,
+            &BinaryCodec,
+            None,
+            Arc::new(SystemClock),
+            &RecoveryReporter::default(),
+        )
+        .unwrap();
+        wal.append("a", &record(1, Some("v1"))).unwrap();
+        wal.append("b", &record(2, Some("v1"))).unwrap();
+        // reopening starts a new segment, the reader follows the records across both.
+        drop(wal);
+        let (mut wal, _) = Wal::open(
+            Arc::new(storage.clone()),
+            dir,
+            mode,
+            &BinaryCodec,
+            None,
+            Arc::new(SystemClock),
+            &RecoveryReporter::default(),
+        )
+        .unwrap();
+        wal.append("a", &record(3, None)).unwrap();
+
+        let reader = WalReader::open_with_storage(Arc::new(storage), dir).unwrap();
+        let records: Vec<WalRecord> = reader.records_since(2).map(Result::unwrap).collect();
+        let expected = vec![
+            WalRecord {
+                seq: 2,
+                key: "b".to_string(),
+                op: WalOp::Put("v1".to_string()),
+            },
+            WalRecord {
+                seq: 3,
+                key: "a".to_string(),
+                op: WalOp::Delete,
+            },
+        ];
+        assert_eq!(records, expected);
+        assert_eq!(reader.records_since(0).count(), 3);
+    }
+}